@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+
+use ethers::{
+    abi::{AbiParser, Function, Token},
+    types::{Transaction, H160, U256},
+};
+
+use crate::{
+    exchanges::{erc20::get_transfer_fee, get_router_market, get_router_weth, is_pair_router},
+    price_oracle::PriceOracle,
+    types::{market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, ReverseReserves},
+};
+
+#[cfg(test)]
+mod tests;
+
+lazy_static! {
+    static ref SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD: Function = AbiParser::default()
+        .parse_function("swapExactTokensForTokens(uint256,uint256,address[],address,uint256)")
+        .unwrap();
+    static ref SWAP_TOKENS_FOR_EXACT_TOKENS_METHOD: Function = AbiParser::default()
+        .parse_function("swapTokensForExactTokens(uint256,uint256,address[],address,uint256)")
+        .unwrap();
+    static ref SWAP_EXACT_ETH_FOR_TOKENS_METHOD: Function = AbiParser::default()
+        .parse_function("swapExactETHForTokens(uint256,address[],address,uint256)")
+        .unwrap();
+    static ref SWAP_TOKENS_FOR_EXACT_ETH_METHOD: Function = AbiParser::default()
+        .parse_function("swapTokensForExactETH(uint256,uint256,address[],address,uint256)")
+        .unwrap();
+    static ref SWAP_EXACT_TOKENS_FOR_ETH_METHOD: Function = AbiParser::default()
+        .parse_function("swapExactTokensForETH(uint256,uint256,address[],address,uint256)")
+        .unwrap();
+    static ref SWAP_ETH_FOR_EXACT_TOKENS_METHOD: Function = AbiParser::default()
+        .parse_function("swapETHForExactTokens(uint256,address[],address,uint256)")
+        .unwrap();
+}
+
+// A router swap along `path`, either selling an exact input or buying an exact output. The
+// router reverts once the block timestamp is past `deadline`.
+enum RouterSwap {
+    ExactIn {
+        amount_in: U256,
+        amount_out_min: U256,
+        path: Vec<H160>,
+        deadline: U256,
+    },
+    ExactOut {
+        amount_out: U256,
+        amount_in_max: U256,
+        path: Vec<H160>,
+        deadline: U256,
+    },
+}
+
+#[inline(always)]
+fn decode_input(function: &Function, input: &[u8]) -> Option<Vec<Token>> {
+    if input.len() < 4 || input[..4] != function.short_signature() {
+        return None;
+    }
+
+    return function.decode_input(&input[4..]).ok();
+}
+
+#[inline(always)]
+fn into_path(token: &Token) -> Option<Vec<H160>> {
+    return token
+        .clone()
+        .into_array()?
+        .into_iter()
+        .map(|x| x.into_address())
+        .collect();
+}
+
+// ETH functions wrap or unwrap the native token at the ends of the path, which the router
+// requires to be its own WETH
+#[inline(always)]
+fn decode_router_swap(tx: &Transaction, weth: Option<H160>) -> Option<RouterSwap> {
+    let input = tx.input.as_ref();
+
+    for (function, for_eth) in [
+        (&*SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD, false),
+        (&*SWAP_EXACT_TOKENS_FOR_ETH_METHOD, true),
+    ] {
+        if let Some(tokens) = decode_input(function, input) {
+            let path = into_path(&tokens[2])?;
+            if for_eth && path.last().copied() != Some(weth?) {
+                return None;
+            }
+
+            return Some(RouterSwap::ExactIn {
+                amount_in: tokens[0].clone().into_uint()?,
+                amount_out_min: tokens[1].clone().into_uint()?,
+                path,
+                deadline: tokens[4].clone().into_uint()?,
+            });
+        }
+    }
+
+    for (function, for_eth) in [
+        (&*SWAP_TOKENS_FOR_EXACT_TOKENS_METHOD, false),
+        (&*SWAP_TOKENS_FOR_EXACT_ETH_METHOD, true),
+    ] {
+        if let Some(tokens) = decode_input(function, input) {
+            let path = into_path(&tokens[2])?;
+            if for_eth && path.last().copied() != Some(weth?) {
+                return None;
+            }
+
+            return Some(RouterSwap::ExactOut {
+                amount_out: tokens[0].clone().into_uint()?,
+                amount_in_max: tokens[1].clone().into_uint()?,
+                path,
+                deadline: tokens[4].clone().into_uint()?,
+            });
+        }
+    }
+
+    // the sent value is the input, or the most the router may spend of it
+    if let Some(tokens) = decode_input(&SWAP_EXACT_ETH_FOR_TOKENS_METHOD, input) {
+        let path = into_path(&tokens[1])?;
+        if path.first().copied() != Some(weth?) {
+            return None;
+        }
+
+        return Some(RouterSwap::ExactIn {
+            amount_in: tx.value,
+            amount_out_min: tokens[0].clone().into_uint()?,
+            path,
+            deadline: tokens[3].clone().into_uint()?,
+        });
+    }
+
+    if let Some(tokens) = decode_input(&SWAP_ETH_FOR_EXACT_TOKENS_METHOD, input) {
+        let path = into_path(&tokens[1])?;
+        if path.first().copied() != Some(weth?) {
+            return None;
+        }
+
+        return Some(RouterSwap::ExactOut {
+            amount_out: tokens[0].clone().into_uint()?,
+            amount_in_max: tx.value,
+            path,
+            deadline: tokens[3].clone().into_uint()?,
+        });
+    }
+
+    return None;
+}
+
+// Input needed for `amount_out`, as the router's `getAmountIn`. `None` when the pair can't
+// provide it.
+#[inline(always)]
+fn get_amount_in(market: &Market, reserves: &Reserves, amount_out: U256) -> Option<U256> {
+    if amount_out >= reserves.1 {
+        return None;
+    }
+
+    let (fee_multiplier, multiplier) = market.get_fee_data();
+    let numerator = reserves.0 * amount_out * multiplier;
+    let denominator = (reserves.1 - amount_out) * fee_multiplier;
+
+    return Some(numerator / denominator + 1);
+}
+
+// Markets of every hop with their reserves oriented from the hop's input to its output
+#[inline(always)]
+async fn get_path_reserves(
+    router: H160,
+    path: &Vec<H160>,
+    local_reserves: &HashMap<usize, Reserves>,
+    markets: &'static OrganizedList<Market>,
+) -> Option<Vec<(&'static OrgValue<Market>, bool, Reserves)>> {
+    let mut result = vec![];
+
+    for hop in path.windows(2) {
+        let market = get_router_market(router, hop[0], hop[1], markets)?;
+
+        // transfer taxes change what the pairs receive and the router checks
+        if market.value.tokens.iter().any(|x| get_transfer_fee(x) > 0) {
+            return None;
+        }
+
+        let reserves = match local_reserves.get(&market.id) {
+            Some(value) => *value,
+            None => PriceOracle::get_market_reserve(market.id).await?,
+        };
+
+        let zero_for_one = market.value.tokens[0].contract_address == hop[0];
+        let reserves = if zero_for_one {
+            reserves
+        } else {
+            reserves.reverse()
+        };
+
+        result.push((market, zero_for_one, reserves));
+    }
+
+    return Some(result);
+}
+
+/// Balance changes of a transaction calling a known pair router, derived from its calldata and
+/// the reserve table instead of a trace. `None` when the call can't be predicted and has to be
+/// traced: unknown routers or functions, ETH swaps through another token than the router's
+/// WETH, paths through untracked pairs or taxed tokens, and missing reserves. Predictions move
+/// `local_reserves`, so transactions predicted on the same map stack on each other. Calls past
+/// their deadline at `timestamp` change nothing.
+pub async fn predict_balance_changes(
+    tx: &Transaction,
+    timestamp: u64,
+    local_reserves: &mut HashMap<usize, Reserves>,
+    markets: &'static OrganizedList<Market>,
+) -> Option<Vec<BalanceChange>> {
+    let router = tx.to?;
+    if !is_pair_router(&router) {
+        return None;
+    }
+
+    let router_swap = decode_router_swap(tx, get_router_weth(&router))?;
+    let deadline = match &router_swap {
+        RouterSwap::ExactIn { deadline, .. } | RouterSwap::ExactOut { deadline, .. } => *deadline,
+    };
+
+    if deadline < U256::from(timestamp) {
+        return Some(vec![]);
+    }
+
+    let (path, amounts) = match router_swap {
+        RouterSwap::ExactIn {
+            amount_in,
+            amount_out_min,
+            path,
+            ..
+        } => {
+            if amount_in.bits() > 128 {
+                return Some(vec![]);
+            }
+
+            let path_reserves =
+                get_path_reserves(router, &path, local_reserves, markets).await?;
+
+            let mut amounts = vec![amount_in];
+            for (market, zero_for_one, reserves) in &path_reserves {
+                let amount_out =
+                    market
+                        .value
+                        .amount_out(reserves, amounts.last().unwrap(), *zero_for_one);
+                amounts.push(amount_out);
+            }
+
+            // a reverted swap leaves the reserves as they are
+            if *amounts.last().unwrap() < amount_out_min {
+                return Some(vec![]);
+            }
+
+            (path_reserves, amounts)
+        }
+        RouterSwap::ExactOut {
+            amount_out,
+            amount_in_max,
+            path,
+            ..
+        } => {
+            if amount_out.bits() > 128 {
+                return Some(vec![]);
+            }
+
+            let path_reserves =
+                get_path_reserves(router, &path, local_reserves, markets).await?;
+
+            let mut amounts = vec![amount_out];
+            for (market, _, reserves) in path_reserves.iter().rev() {
+                match get_amount_in(&market.value, reserves, *amounts.last().unwrap()) {
+                    Some(amount_in) => amounts.push(amount_in),
+                    None => return Some(vec![]),
+                }
+            }
+            amounts.reverse();
+
+            if amounts[0] > amount_in_max {
+                return Some(vec![]);
+            }
+
+            (path_reserves, amounts)
+        }
+    };
+
+    // swap events carry amounts up to 112 bits, the call couldn't succeed with more
+    if amounts.iter().any(|x| x.bits() > 128) {
+        return Some(vec![]);
+    }
+
+    let mut result: Vec<BalanceChange> = vec![];
+    for (i, (market, zero_for_one, reserves)) in path.into_iter().enumerate() {
+        let (amount_in, amount_out) = (amounts[i], amounts[i + 1]);
+        let reserves = (reserves.0 + amount_in, reserves.1 - amount_out);
+
+        let (amount_in, amount_out) = (amount_in.as_u128(), amount_out.as_u128());
+        if zero_for_one {
+            local_reserves.insert(market.id, reserves);
+            result.push(BalanceChange {
+                market,
+                amount_0_in: amount_in,
+                amount_1_in: 0,
+                amount_0_out: 0,
+                amount_1_out: amount_out,
+                source: None,
+            });
+        } else {
+            local_reserves.insert(market.id, reserves.reverse());
+            result.push(BalanceChange {
+                market,
+                amount_0_in: 0,
+                amount_1_in: amount_in,
+                amount_0_out: amount_out,
+                amount_1_out: 0,
+                source: None,
+            });
+        }
+    }
+
+    return Some(result);
+}
@@ -0,0 +1,432 @@
+use crate::{
+    exchanges::{register_router, register_router_pair, types::Protocol},
+    types::Token as NetworkToken,
+};
+
+use super::*;
+
+const TIMESTAMP: u64 = 1_700_000_000;
+
+lazy_static! {
+    // A/B and B/WETH pairs of the router, and a pair with a taxed token
+    static ref MARKETS: OrganizedList<Market> = get_markets();
+}
+
+fn get_address(value: u64) -> H160 {
+    return H160::from_low_u64_be(value);
+}
+
+fn get_token(address: u64, transfer_fee: Option<u32>) -> &'static NetworkToken {
+    return Box::leak(Box::new(NetworkToken {
+        contract_address: get_address(address),
+        flash_loan_enabled: false,
+        decimals: 18,
+        ref_symbol: None,
+        transfer_fee,
+        rebasing: false,
+    }));
+}
+
+fn get_markets() -> OrganizedList<Market> {
+    let token_a = get_token(0x1000, None);
+    let token_b = get_token(0x2000, None);
+    let weth = get_token(0x3000, None);
+    let taxed = get_token(0x4000, Some(100));
+
+    let mut result = OrganizedList::new();
+    for (pair, tokens) in [
+        (0xa0, [token_a, token_b]),
+        (0xb0, [token_b, weth]),
+        (0xc0, [token_a, taxed]),
+    ] {
+        result.add_value(Market::new(
+            get_address(pair),
+            tokens,
+            30,
+            false,
+            Protocol::UniswapV2,
+        ));
+    }
+
+    return result;
+}
+
+fn get_router() -> H160 {
+    let router = get_address(0x9000);
+    register_router(router, Some(get_address(0x3000)));
+    for (pair, token_a, token_b) in [
+        (0xa0, 0x1000, 0x2000),
+        (0xb0, 0x2000, 0x3000),
+        (0xc0, 0x1000, 0x4000),
+    ] {
+        register_router_pair(
+            router,
+            get_address(token_a),
+            get_address(token_b),
+            get_address(pair),
+        );
+    }
+
+    return router;
+}
+
+fn get_reserves() -> HashMap<usize, Reserves> {
+    return HashMap::from([
+        (0, (U256::exp10(21), U256::exp10(21) * 2)),
+        (1, (U256::exp10(21) * 5, U256::exp10(21))),
+    ]);
+}
+
+fn get_path(path: &[u64]) -> Token {
+    return Token::Array(
+        path.iter()
+            .map(|x| Token::Address(get_address(*x)))
+            .collect(),
+    );
+}
+
+fn get_transaction(
+    router: H160,
+    function: &Function,
+    tokens: &[Token],
+    value: U256,
+) -> Transaction {
+    return Transaction {
+        to: Some(router),
+        input: function.encode_input(tokens).unwrap().into(),
+        value,
+        ..Default::default()
+    };
+}
+
+fn get_tokens_swap(amount: U256, limit: U256, path: &[u64], deadline: u64) -> Vec<Token> {
+    return vec![
+        Token::Uint(amount),
+        Token::Uint(limit),
+        get_path(path),
+        Token::Address(get_address(0x5000)),
+        Token::Uint(U256::from(deadline)),
+    ];
+}
+
+fn get_eth_swap(limit: U256, path: &[u64]) -> Vec<Token> {
+    return vec![
+        Token::Uint(limit),
+        get_path(path),
+        Token::Address(get_address(0x5000)),
+        Token::Uint(U256::from(TIMESTAMP)),
+    ];
+}
+
+fn get_amounts(changes: &[BalanceChange]) -> Vec<(usize, u128, u128, u128, u128)> {
+    return changes
+        .iter()
+        .map(|x| {
+            (
+                x.market.id,
+                x.amount_0_in,
+                x.amount_1_in,
+                x.amount_0_out,
+                x.amount_1_out,
+            )
+        })
+        .collect();
+}
+
+fn to_u256(value: u128) -> U256 {
+    return U256::from(value);
+}
+
+// Amounts below are the router's getAmountsOut and getAmountsIn over the fixture reserves
+#[tokio::test]
+async fn exact_input_swaps_follow_get_amounts_out() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD,
+        &get_tokens_swap(
+            U256::exp10(19),
+            U256::zero(),
+            &[0x1000, 0x2000, 0x3000],
+            TIMESTAMP,
+        ),
+        U256::zero(),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_amounts(&changes),
+        vec![
+            (
+                0,
+                10_000_000_000_000_000_000,
+                0,
+                0,
+                19_743_160_687_941_225_977
+            ),
+            (
+                1,
+                19_743_160_687_941_225_977,
+                0,
+                0,
+                3_921_348_729_450_528_666
+            ),
+        ]
+    );
+    assert_eq!(
+        reserves[&0],
+        (
+            to_u256(1_010_000_000_000_000_000_000),
+            to_u256(1_980_256_839_312_058_774_023)
+        )
+    );
+    assert_eq!(
+        reserves[&1],
+        (
+            to_u256(5_019_743_160_687_941_225_977),
+            to_u256(996_078_651_270_549_471_334)
+        )
+    );
+}
+
+#[tokio::test]
+async fn exact_output_swaps_follow_get_amounts_in() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_TOKENS_FOR_EXACT_TOKENS_METHOD,
+        &get_tokens_swap(
+            U256::exp10(18),
+            U256::MAX,
+            &[0x1000, 0x2000, 0x3000],
+            TIMESTAMP,
+        ),
+        U256::zero(),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_amounts(&changes),
+        vec![
+            (
+                0,
+                2_523_920_479_055_533_127,
+                0,
+                0,
+                5_020_065_200_606_825_482
+            ),
+            (
+                1,
+                5_020_065_200_606_825_482,
+                0,
+                0,
+                1_000_000_000_000_000_000
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn swaps_past_their_deadline_change_nothing() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD,
+        &get_tokens_swap(
+            U256::exp10(19),
+            U256::zero(),
+            &[0x1000, 0x2000],
+            TIMESTAMP - 1,
+        ),
+        U256::zero(),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS).await;
+    assert_eq!(changes.map(|x| x.len()), Some(0));
+    assert_eq!(reserves, get_reserves());
+}
+
+#[tokio::test]
+async fn swaps_missing_their_limits_change_nothing() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+
+    // one more than getAmountsOut returns, and one less than getAmountsIn needs
+    for tx in [
+        get_transaction(
+            router,
+            &SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD,
+            &get_tokens_swap(
+                U256::exp10(19),
+                to_u256(3_921_348_729_450_528_667),
+                &[0x1000, 0x2000, 0x3000],
+                TIMESTAMP,
+            ),
+            U256::zero(),
+        ),
+        get_transaction(
+            router,
+            &SWAP_TOKENS_FOR_EXACT_TOKENS_METHOD,
+            &get_tokens_swap(
+                U256::exp10(18),
+                to_u256(2_523_920_479_055_533_126),
+                &[0x1000, 0x2000, 0x3000],
+                TIMESTAMP,
+            ),
+            U256::zero(),
+        ),
+    ] {
+        let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS).await;
+        assert_eq!(changes.map(|x| x.len()), Some(0));
+    }
+
+    assert_eq!(reserves, get_reserves());
+}
+
+#[tokio::test]
+async fn predictions_stack_on_local_reserves() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD,
+        &get_tokens_swap(
+            U256::exp10(19),
+            U256::zero(),
+            &[0x1000, 0x2000, 0x3000],
+            TIMESTAMP,
+        ),
+        U256::zero(),
+    );
+
+    predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_amounts(&changes),
+        vec![
+            (
+                0,
+                10_000_000_000_000_000_000,
+                0,
+                0,
+                19_356_609_202_173_814_893
+            ),
+            (
+                1,
+                19_356_609_202_173_814_893,
+                0,
+                0,
+                3_814_785_472_299_649_038
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn paths_through_taxed_tokens_are_traced() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    reserves.insert(2, (U256::exp10(21), U256::exp10(21)));
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_TOKENS_FOR_TOKENS_METHOD,
+        &get_tokens_swap(U256::exp10(19), U256::zero(), &[0x1000, 0x4000], TIMESTAMP),
+        U256::zero(),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS).await;
+    assert!(changes.is_none());
+}
+
+#[tokio::test]
+async fn eth_swaps_go_through_the_router_weth() {
+    let router = get_router();
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_ETH_FOR_TOKENS_METHOD,
+        &get_eth_swap(U256::zero(), &[0x3000, 0x2000]),
+        U256::exp10(18),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_amounts(&changes),
+        vec![(
+            1,
+            0,
+            1_000_000_000_000_000_000,
+            4_980_034_905_199_516_082,
+            0
+        )]
+    );
+
+    let mut reserves = get_reserves();
+    let tx = get_transaction(
+        router,
+        &SWAP_EXACT_TOKENS_FOR_ETH_METHOD,
+        &get_tokens_swap(U256::exp10(19), U256::zero(), &[0x2000, 0x3000], TIMESTAMP),
+        U256::zero(),
+    );
+
+    let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_amounts(&changes),
+        vec![(
+            1,
+            10_000_000_000_000_000_000,
+            0,
+            0,
+            1_990_031_876_438_381_866
+        )]
+    );
+
+    // sending ETH into a path, or unwrapping out of one, that doesn't end in its WETH reverts
+    let mut reserves = get_reserves();
+    for tx in [
+        get_transaction(
+            router,
+            &SWAP_EXACT_ETH_FOR_TOKENS_METHOD,
+            &get_eth_swap(U256::zero(), &[0x1000, 0x2000]),
+            U256::exp10(18),
+        ),
+        get_transaction(
+            router,
+            &SWAP_ETH_FOR_EXACT_TOKENS_METHOD,
+            &get_eth_swap(U256::exp10(18), &[0x2000, 0x3000]),
+            U256::exp10(19),
+        ),
+        get_transaction(
+            router,
+            &SWAP_EXACT_TOKENS_FOR_ETH_METHOD,
+            &get_tokens_swap(U256::exp10(19), U256::zero(), &[0x3000, 0x2000], TIMESTAMP),
+            U256::zero(),
+        ),
+        get_transaction(
+            router,
+            &SWAP_TOKENS_FOR_EXACT_ETH_METHOD,
+            &get_tokens_swap(U256::exp10(18), U256::MAX, &[0x2000, 0x1000], TIMESTAMP),
+            U256::zero(),
+        ),
+    ] {
+        let changes = predict_balance_changes(&tx, TIMESTAMP, &mut reserves, &MARKETS).await;
+        assert!(changes.is_none());
+    }
+
+    assert_eq!(reserves, get_reserves());
+}
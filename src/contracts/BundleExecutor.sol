@@ -6,8 +6,14 @@ pragma experimental ABIEncoderV2;
 import {FlashLoanSimpleReceiverBase} from "./aave/FlashLoanSimpleReceiverBase.sol";
 import {IPoolAddressesProvider} from "./aave/interfaces/IPoolAddressesProvider.sol";
 import {IERC20} from "./interfaces/IERC20.sol";
+import {IBalancerVault} from "./interfaces/IBalancerVault.sol";
+import {IUniswapV2Pair} from "./interfaces/IUniswapV2Pair.sol";
+import {IArbSys} from "./interfaces/IArbSys.sol";
 
-// This contract simply calls multiple targets sequentially, ensuring WETH balance before and after
+// This contract simply calls multiple targets sequentially, ensuring WETH balance before and after.
+// The first market is funded by an Aave or Balancer flash loan, a flash swap from the first
+// market itself, or the contract's own balance. Batches of routes share a multi-asset Aave flash
+// loan. Only the profit leaves the contract.
 
 struct Balances {
     uint256 _ethBalance;
@@ -15,9 +21,58 @@ struct Balances {
     uint256 _endBalance;
 }
 
+// A bundle reverts when it lands after `maxBlockNumber` (an L2 block number) or `deadline`, or
+// when it would send less than `minProfit` to the owner. Batches check the `minProfit` of every
+// loan instead.
+struct Guards {
+    uint256 minProfit;
+    uint256 maxBlockNumber;
+    uint256 deadline;
+}
+
+// One flash loaned token of a batch of routes, the routes sharing a base token share its loan
+struct Loan {
+    address token;
+    uint256 amount;
+    uint256 minProfit;
+}
+
+// Header of a packed bundle, the flash swap fields are only set for flash swaps
+struct PackedHeader {
+    uint8 source;
+    address token;
+    uint256 amount;
+    Guards guards;
+    uint8 flags;
+    address pair;
+    uint256 amountOut;
+}
+
+// Packed bundles are sent as raw calldata to the fallback:
+//   source (1) | token (20) | amount | minProfit | maxBlockNumber | deadline
+//   [flash swap: flags (1) | pair (20) | amountOut]
+//   records...
+// Amounts are a length byte followed by that many big-endian bytes. A record is a flags byte and
+// a target (20), pair swaps add their amount out, calls add a payload length (2) and the payload.
+// A pair swap sends its output to the next record's target when that record is a pair swap or a
+// call flagged as a push target, otherwise to this contract.
+uint8 constant SOURCE_AAVE = 0;
+uint8 constant SOURCE_BALANCER = 1;
+uint8 constant SOURCE_INVENTORY = 2;
+uint8 constant SOURCE_FLASH_SWAP = 3;
+uint8 constant RECORD_CALL = 1;
+uint8 constant ZERO_FOR_ONE = 2;
+uint8 constant PUSH_TARGET = 4;
+
 contract BundleExecutor is FlashLoanSimpleReceiverBase {
+    address private constant BALANCER_VAULT = 0xBA12222222228d8Ba445958a75a0704d566BF2C8;
+    // block.number is the L1 block number on Arbitrum, ArbSys has the L2 one
+    address private constant ARB_SYS = 0x0000000000000000000000000000000000000064;
+
     address private executor = 0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2;
     address private owner = 0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2;
+    // the only address allowed to call back while a flash loan or flash swap is open
+    address private pendingLender;
 
     constructor(
         address _executor,
@@ -30,13 +85,15 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
     function executeTxBundle(
         address _token,
         uint256 _amountToFirstMarket,
-        address[] calldata _targets,
-        bytes[] calldata _payloads
-    ) public payable onlyExecutor {
+        address[] memory _targets,
+        bytes[] memory _payloads,
+        Guards memory _guards
+    ) public payable onlyExecutor withinDeadline(_guards) {
         address receiverAddress = address(this);
         bytes memory params = abi.encode(
             _targets,
-            _payloads
+            _payloads,
+            _guards.minProfit
         );
         uint16 referralCode = 0;
 
@@ -49,6 +106,106 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
         );
     }
 
+    // Several routes in one bundle, funded by a single multi-asset Aave flash loan
+    function executeTxBundle(
+        Loan[] memory _loans,
+        address[] memory _targets,
+        bytes[] memory _payloads,
+        Guards memory _guards
+    ) public payable onlyExecutor withinDeadline(_guards) {
+        address[] memory assets = new address[](_loans.length);
+        uint256[] memory amounts = new uint256[](_loans.length);
+        uint256[] memory minProfits = new uint256[](_loans.length);
+        // 0: no debt is opened, every loan is repaid in the callback
+        uint256[] memory interestRateModes = new uint256[](_loans.length);
+
+        for (uint256 i = 0; i < _loans.length; ) {
+            assets[i] = _loans[i].token;
+            amounts[i] = _loans[i].amount;
+            minProfits[i] = _loans[i].minProfit;
+
+            unchecked {
+                ++i;
+            }
+        }
+
+        POOL.flashLoan(
+            address(this),
+            assets,
+            amounts,
+            interestRateModes,
+            address(this),
+            abi.encode(_targets, _payloads, minProfits),
+            0
+        );
+    }
+
+    function executeBalancerTxBundle(
+        address _token,
+        uint256 _amountToFirstMarket,
+        address[] memory _targets,
+        bytes[] memory _payloads,
+        Guards memory _guards
+    ) public payable onlyExecutor withinDeadline(_guards) {
+        IERC20[] memory tokens = new IERC20[](1);
+        tokens[0] = IERC20(_token);
+        uint256[] memory amounts = new uint256[](1);
+        amounts[0] = _amountToFirstMarket;
+
+        pendingLender = BALANCER_VAULT;
+        IBalancerVault(BALANCER_VAULT).flashLoan(
+            address(this),
+            tokens,
+            amounts,
+            abi.encode(_targets, _payloads, _guards.minProfit)
+        );
+        pendingLender = address(0);
+    }
+
+    // The pair sends the first hop's output up front, the bundle starts at the second market
+    // and the pair is repaid with `_repayAmount` of `_token`
+    function executeFlashSwapTxBundle(
+        address _pair,
+        address _token,
+        uint256 _repayAmount,
+        uint256 _amount0Out,
+        uint256 _amount1Out,
+        address[] memory _targets,
+        bytes[] memory _payloads,
+        Guards memory _guards
+    ) public payable onlyExecutor withinDeadline(_guards) {
+        pendingLender = _pair;
+        IUniswapV2Pair(_pair).swap(
+            _amount0Out,
+            _amount1Out,
+            address(this),
+            abi.encode(_token, _repayAmount, _targets, _payloads, _guards.minProfit)
+        );
+        pendingLender = address(0);
+    }
+
+    function executeInventoryTxBundle(
+        address _token,
+        uint256 _amountToFirstMarket,
+        address[] memory _targets,
+        bytes[] memory _payloads,
+        Guards memory _guards
+    ) public payable onlyExecutor withinDeadline(_guards) {
+        IERC20 token = IERC20(_token);
+        uint256 startBalance = token.balanceOf(address(this));
+        require(startBalance >= _amountToFirstMarket, "insufficient inventory");
+
+        executeCalls(_targets, _payloads);
+
+        uint256 endBalance = token.balanceOf(address(this));
+        require(endBalance > startBalance, "no profit made");
+
+        uint256 netProfit = endBalance - startBalance;
+        require(netProfit >= _guards.minProfit, "profit below minimum");
+
+        token.transfer(owner, netProfit);
+    }
+
     function executeOperation(
         address asset,
         uint256 amount,
@@ -56,12 +213,16 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
         address initiator,
         bytes calldata params
     ) external returns (bool) {
+        require(
+            msg.sender == address(POOL) && initiator == address(this),
+            "unexpected lender"
+        );
+
         (
             address[] memory targets,
-            bytes[] memory payloads
-        ) = abi.decode(params, (address[], bytes[]));
-
-        require(targets.length == payloads.length, "invalid data");
+            bytes[] memory payloads,
+            uint256 minProfit
+        ) = abi.decode(params, (address[], bytes[], uint256));
 
         IERC20 token = IERC20(asset);
         Balances memory balances = Balances(
@@ -70,12 +231,156 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
             0
         );
 
-        token.transfer(targets[0], amount);
+        executeCalls(targets, payloads);
+
+        // Ensure Profit
+        balances._endBalance = token.balanceOf(address(this));
+        require(
+            balances._endBalance > balances._startBalance + premium,
+            "no profit made"
+        );
+
+        // Transfer net profit back to wallet and approve flash loan return
+        uint256 amountOwed = amount + premium;
+        uint256 netProfit = balances._endBalance - balances._startBalance - premium;
+        require(netProfit >= minProfit, "profit below minimum");
+
+        token.transfer(owner, netProfit);
+        token.approve(address(POOL), amountOwed);
+
+        return true;
+    }
+
+    function executeOperation(
+        address[] calldata assets,
+        uint256[] calldata amounts,
+        uint256[] calldata premiums,
+        address initiator,
+        bytes calldata params
+    ) external returns (bool) {
+        require(
+            msg.sender == address(POOL) && initiator == address(this),
+            "unexpected lender"
+        );
+
+        (
+            address[] memory targets,
+            bytes[] memory payloads,
+            uint256[] memory minProfits
+        ) = abi.decode(params, (address[], bytes[], uint256[]));
+
+        uint256[] memory startBalances = new uint256[](assets.length);
+        for (uint256 i = 0; i < assets.length; ) {
+            startBalances[i] = IERC20(assets[i]).balanceOf(address(this));
+
+            unchecked {
+                ++i;
+            }
+        }
+
+        executeCalls(targets, payloads);
+
+        // every token of the batch has to make its own profit
+        for (uint256 i = 0; i < assets.length; ) {
+            IERC20 token = IERC20(assets[i]);
+            uint256 endBalance = token.balanceOf(address(this));
+            require(
+                endBalance > startBalances[i] + premiums[i],
+                "no profit made"
+            );
+
+            uint256 netProfit = endBalance - startBalances[i] - premiums[i];
+            require(netProfit >= minProfits[i], "profit below minimum");
+
+            token.transfer(owner, netProfit);
+            token.approve(address(POOL), amounts[i] + premiums[i]);
+
+            unchecked {
+                ++i;
+            }
+        }
+
+        return true;
+    }
+
+    function receiveFlashLoan(
+        IERC20[] memory tokens,
+        uint256[] memory amounts,
+        uint256[] memory feeAmounts,
+        bytes memory userData
+    ) external {
+        require(
+            msg.sender == BALANCER_VAULT && pendingLender == BALANCER_VAULT,
+            "unexpected lender"
+        );
+
+        (
+            address[] memory targets,
+            bytes[] memory payloads,
+            uint256 minProfit
+        ) = abi.decode(userData, (address[], bytes[], uint256));
+
+        IERC20 token = tokens[0];
+        uint256 startBalance = token.balanceOf(address(this));
+
+        executeCalls(targets, payloads);
+
+        uint256 endBalance = token.balanceOf(address(this));
+        require(endBalance > startBalance + feeAmounts[0], "no profit made");
+
+        uint256 netProfit = endBalance - startBalance - feeAmounts[0];
+        require(netProfit >= minProfit, "profit below minimum");
+
+        // Balancer expects the loan back by transfer before the callback returns
+        token.transfer(BALANCER_VAULT, amounts[0] + feeAmounts[0]);
+        token.transfer(owner, netProfit);
+    }
+
+    function uniswapV2Call(
+        address sender,
+        uint256,
+        uint256,
+        bytes calldata data
+    ) external {
+        require(
+            msg.sender == pendingLender && sender == address(this),
+            "unexpected lender"
+        );
+
+        (
+            address tokenAddress,
+            uint256 repayAmount,
+            address[] memory targets,
+            bytes[] memory payloads,
+            uint256 minProfit
+        ) = abi.decode(data, (address, uint256, address[], bytes[], uint256));
+
+        IERC20 token = IERC20(tokenAddress);
+        uint256 startBalance = token.balanceOf(address(this));
+
+        executeCalls(targets, payloads);
+
+        uint256 endBalance = token.balanceOf(address(this));
+        require(endBalance > startBalance + repayAmount, "no profit made");
+
+        uint256 netProfit = endBalance - startBalance - repayAmount;
+        require(netProfit >= minProfit, "profit below minimum");
+
+        token.transfer(msg.sender, repayAmount);
+        token.transfer(owner, netProfit);
+    }
+
+    // Token movements are part of the bundle (transfers to pairs, approvals for pools that pull)
+    function executeCalls(
+        address[] memory targets,
+        bytes[] memory payloads
+    ) internal {
+        require(targets.length == payloads.length, "invalid data");
 
         uint256 i = 0;
         bool _success;
         bytes memory _response;
-        do {
+        while (i < targets.length) {
             (_success, _response) = targets[i].call(payloads[i]);
             require(_success, "FAILED");
             _response;
@@ -83,25 +388,133 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
             unchecked {
                 ++i;
             }
-        } while (i < targets.length);
+        }
+    }
 
-        // Ensure Profit
-        unchecked {
-            balances._endBalance = token.balanceOf(address(this));
-            require(
-                balances._endBalance > balances._startBalance,
-                "no profit made"
+    fallback() external payable onlyExecutor {
+        (PackedHeader memory header, uint256 offset) = readPackedHeader();
+        (address[] memory targets, bytes[] memory payloads) = readPackedRecords(offset);
+
+        if (header.source == SOURCE_AAVE) {
+            executeTxBundle(header.token, header.amount, targets, payloads, header.guards);
+        } else if (header.source == SOURCE_BALANCER) {
+            executeBalancerTxBundle(header.token, header.amount, targets, payloads, header.guards);
+        } else if (header.source == SOURCE_INVENTORY) {
+            executeInventoryTxBundle(header.token, header.amount, targets, payloads, header.guards);
+        } else if (header.source == SOURCE_FLASH_SWAP) {
+            (uint256 amount0Out, uint256 amount1Out) = header.flags & ZERO_FOR_ONE != 0
+                ? (uint256(0), header.amountOut)
+                : (header.amountOut, uint256(0));
+
+            executeFlashSwapTxBundle(
+                header.pair,
+                header.token,
+                header.amount,
+                amount0Out,
+                amount1Out,
+                targets,
+                payloads,
+                header.guards
             );
+        } else {
+            revert("invalid data");
         }
+    }
 
-        // Transfer net profit back to wallet and approve flash loan return
-        uint256 amountOwed = amount + premium;
-        uint256 netProfit = balances._endBalance - amountOwed;
+    function readPackedAmount(
+        uint256 offset
+    ) internal pure returns (uint256 amount, uint256 next) {
+        uint256 length = uint8(msg.data[offset]);
+        next = offset + 1 + length;
+        amount = uint256(bytes32(msg.data[offset + 1:next])) >> (256 - 8 * length);
+    }
 
-        token.transfer(owner, netProfit);
-        token.approve(address(POOL), amountOwed);
+    function readPackedHeader()
+        internal
+        pure
+        returns (PackedHeader memory header, uint256 offset)
+    {
+        header.source = uint8(msg.data[0]);
+        header.token = address(bytes20(msg.data[1:21]));
 
-        return true;
+        (header.amount, offset) = readPackedAmount(21);
+        (header.guards.minProfit, offset) = readPackedAmount(offset);
+        (header.guards.maxBlockNumber, offset) = readPackedAmount(offset);
+        (header.guards.deadline, offset) = readPackedAmount(offset);
+
+        if (header.source == SOURCE_FLASH_SWAP) {
+            header.flags = uint8(msg.data[offset]);
+            header.pair = address(bytes20(msg.data[offset + 1:offset + 21]));
+            (header.amountOut, offset) = readPackedAmount(offset + 21);
+        }
+    }
+
+    function countPackedRecords(uint256 offset) internal pure returns (uint256 count) {
+        while (offset < msg.data.length) {
+            uint8 flags = uint8(msg.data[offset]);
+            offset += 21;
+
+            if (flags & RECORD_CALL != 0) {
+                offset += 2 + uint16(bytes2(msg.data[offset:offset + 2]));
+            } else {
+                offset += 1 + uint8(msg.data[offset]);
+            }
+
+            unchecked {
+                ++count;
+            }
+        }
+    }
+
+    function readPackedRecords(
+        uint256 offset
+    ) internal view returns (address[] memory targets, bytes[] memory payloads) {
+        uint256 count = countPackedRecords(offset);
+        targets = new address[](count);
+        payloads = new bytes[](count);
+        uint8[] memory flags = new uint8[](count);
+        uint256[] memory amountsOut = new uint256[](count);
+
+        for (uint256 i = 0; i < count; ++i) {
+            flags[i] = uint8(msg.data[offset]);
+            targets[i] = address(bytes20(msg.data[offset + 1:offset + 21]));
+            offset += 21;
+
+            if (flags[i] & RECORD_CALL != 0) {
+                uint256 length = uint16(bytes2(msg.data[offset:offset + 2]));
+                payloads[i] = msg.data[offset + 2:offset + 2 + length];
+                offset += 2 + length;
+            } else {
+                (amountsOut[i], offset) = readPackedAmount(offset);
+            }
+        }
+
+        // pair swaps are built once every target is known
+        for (uint256 i = 0; i < count; ++i) {
+            if (flags[i] & RECORD_CALL != 0) {
+                continue;
+            }
+
+            address recipient = address(this);
+            if (
+                i + 1 < count &&
+                (flags[i + 1] & RECORD_CALL == 0 || flags[i + 1] & PUSH_TARGET != 0)
+            ) {
+                recipient = targets[i + 1];
+            }
+
+            (uint256 amount0Out, uint256 amount1Out) = flags[i] & ZERO_FOR_ONE != 0
+                ? (uint256(0), amountsOut[i])
+                : (amountsOut[i], uint256(0));
+
+            payloads[i] = abi.encodeWithSelector(
+                IUniswapV2Pair.swap.selector,
+                amount0Out,
+                amount1Out,
+                recipient,
+                new bytes(0)
+            );
+        }
     }
 
     function call(
@@ -134,6 +547,14 @@ contract BundleExecutor is FlashLoanSimpleReceiverBase {
         require(msg.sender == _owner, "owner access only");
         _;
     }
+    modifier withinDeadline(Guards memory _guards) {
+        require(
+            IArbSys(ARB_SYS).arbBlockNumber() <= _guards.maxBlockNumber &&
+                block.timestamp <= _guards.deadline,
+            "bundle expired"
+        );
+        _;
+    }
     modifier onlyExecutor() {
         address _executor = executor;
         require(msg.sender == _executor, "executor access only");
@@ -0,0 +1,100 @@
+//SPDX-License-Identifier: UNLICENSED
+pragma solidity 0.8.19;
+
+import "./interfaces/IERC20.sol";
+import "./interfaces/IUniswapV2Pair.sol";
+
+// Placed on a holder of the base token of a UniswapV2 pair through a state override to measure
+// what buying and selling the other token actually moves. Called with
+// abi.encode(pair, base, token, amountIn, baseIsToken0, feeMultiplier), it sends `amountIn` of the
+// base to the pair and swaps it for the token, then sends everything received back and swaps it
+// for the base. Returns the quoted buy, the token received, the token the pair received, the
+// quoted sell and the base received. Reverts when a call fails.
+contract SwapProbe {
+    struct Probe {
+        IUniswapV2Pair pair;
+        IERC20 base;
+        IERC20 token;
+        uint256 amountIn;
+        bool baseIsToken0;
+        uint256 feeMultiplier;
+    }
+
+    fallback(bytes calldata _input) external returns (bytes memory) {
+        Probe memory probe;
+        (
+            probe.pair,
+            probe.base,
+            probe.token,
+            probe.amountIn,
+            probe.baseIsToken0,
+            probe.feeMultiplier
+        ) = abi.decode(_input, (IUniswapV2Pair, IERC20, IERC20, uint256, bool, uint256));
+
+        (uint256 baseReserve, uint256 tokenReserve) = getReserves(probe);
+        transfer(probe.base, address(probe.pair), probe.amountIn);
+        uint256 bought = getAmountOut(probe, probe.amountIn, baseReserve, tokenReserve);
+        uint256 received = swap(probe, probe.token, bought, !probe.baseIsToken0);
+
+        // the pair's token balance shows what arrived of the transfer back
+        (baseReserve, tokenReserve) = getReserves(probe);
+        transfer(probe.token, address(probe.pair), received);
+        uint256 sold;
+        unchecked {
+            sold = probe.token.balanceOf(address(probe.pair)) - tokenReserve;
+        }
+        uint256 sellQuote = getAmountOut(probe, sold, tokenReserve, baseReserve);
+        uint256 returned = swap(probe, probe.base, sellQuote, probe.baseIsToken0);
+
+        return abi.encode(bought, received, sold, sellQuote, returned);
+    }
+
+    function getReserves(
+        Probe memory _probe
+    ) internal view returns (uint256 baseReserve, uint256 tokenReserve) {
+        (uint112 reserve0, uint112 reserve1, ) = _probe.pair.getReserves();
+        if (_probe.baseIsToken0) {
+            return (reserve0, reserve1);
+        }
+        return (reserve1, reserve0);
+    }
+
+    // `feeMultiplier` is the share of the amount left after the pair's fee, in 1/10000
+    function getAmountOut(
+        Probe memory _probe,
+        uint256 _amountIn,
+        uint256 _reserveIn,
+        uint256 _reserveOut
+    ) internal pure returns (uint256) {
+        unchecked {
+            uint256 amountIn = _probe.feeMultiplier * _amountIn;
+            return (amountIn * _reserveOut) / (_reserveIn * 10000 + amountIn);
+        }
+    }
+
+    // The return value of `transfer` is ignored, tokens that don't return one are probed as well
+    function transfer(IERC20 _token, address _recipient, uint256 _amount) internal {
+        (bool success, ) = address(_token).call(
+            abi.encodeWithSelector(IERC20.transfer.selector, _recipient, _amount)
+        );
+        require(success);
+    }
+
+    // Takes `_amountOut` of `_tokenOut` from the pair, returns what this contract received
+    function swap(
+        Probe memory _probe,
+        IERC20 _tokenOut,
+        uint256 _amountOut,
+        bool _isToken0
+    ) internal returns (uint256 received) {
+        uint256 balance = _tokenOut.balanceOf(address(this));
+        if (_isToken0) {
+            _probe.pair.swap(_amountOut, 0, address(this), "");
+        } else {
+            _probe.pair.swap(0, _amountOut, address(this), "");
+        }
+        unchecked {
+            received = _tokenOut.balanceOf(address(this)) - balance;
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//SPDX-License-Identifier: UNLICENSED
+pragma solidity 0.8.19;
+
+import "./interfaces/IERC20.sol";
+
+// Placed on a token holder through a state override to measure what a transfer actually moves.
+// Called with abi.encode(token, recipient, amount), it transfers `amount` to `recipient` and
+// returns the recipient's balance increase and its own balance decrease. With a fourth word the
+// recipient, which has to run the same code, is then called to send the received amount back,
+// and its two values are appended. Reverts when a call fails.
+contract TransferProbe {
+    fallback(bytes calldata _input) external returns (bytes memory) {
+        (IERC20 _token, address _recipient, uint256 _amount) = abi.decode(
+            _input[:96],
+            (IERC20, address, uint256)
+        );
+
+        (uint256 received, uint256 spent) = measureTransfer(_token, _recipient, _amount);
+        if (_input.length < 128) {
+            return abi.encode(received, spent);
+        }
+
+        (bool success, bytes memory result) = _recipient.call(
+            abi.encode(_token, address(this), received)
+        );
+        require(success);
+
+        (uint256 returned, uint256 returnedSpent) = abi.decode(result, (uint256, uint256));
+        return abi.encode(received, spent, returned, returnedSpent);
+    }
+
+    // The return value of `transfer` is ignored, tokens that don't return one are probed as well
+    function measureTransfer(
+        IERC20 _token,
+        address _recipient,
+        uint256 _amount
+    ) internal returns (uint256 received, uint256 spent) {
+        uint256 recipientBalance = _token.balanceOf(_recipient);
+        uint256 balance = _token.balanceOf(address(this));
+
+        (bool success, ) = address(_token).call(
+            abi.encodeWithSelector(IERC20.transfer.selector, _recipient, _amount)
+        );
+        require(success);
+
+        unchecked {
+            received = _token.balanceOf(_recipient) - recipientBalance;
+            spent = balance - _token.balanceOf(address(this));
+        }
+    }
+}
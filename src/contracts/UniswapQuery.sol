@@ -3,6 +3,7 @@ pragma solidity 0.8.19;
 
 pragma experimental ABIEncoderV2;
 
+import "./interfaces/IERC20.sol";
 import "./interfaces/IUniswapV2Pair.sol";
 import "./interfaces/IStableSwapPair.sol";
 
@@ -15,6 +16,67 @@ abstract contract UniswapV2Factory {
 
 // In order to quickly load up data from Uniswap-like market, this contract allows easy iteration with a single eth_call
 contract UniswapQuery {
+    struct PairState {
+        bool ok;
+        uint112 reserve0;
+        uint112 reserve1;
+        uint32 blockTimestampLast;
+        uint256 balance0;
+        uint256 balance1;
+    }
+
+    // Reserves and token balances of every pair along with the block they were read at. The hash
+    // of the current block isn't available to the EVM, its parent hash identifies it instead. A
+    // pair with a read that reverts is returned with `ok` unset instead of failing the call.
+    function getPairStates(
+        IUniswapV2Pair[] calldata _pairs
+    )
+        external
+        view
+        returns (uint256 blockNumber, bytes32 parentHash, PairState[] memory states)
+    {
+        blockNumber = block.number;
+        parentHash = blockhash(block.number - 1);
+        states = new PairState[](_pairs.length);
+
+        for (uint i = 0; i < _pairs.length; i++) {
+            states[i] = getPairState(_pairs[i]);
+        }
+    }
+
+    function getPairState(IUniswapV2Pair _pair) internal view returns (PairState memory state) {
+        try _pair.getReserves() returns (uint112 reserve0, uint112 reserve1, uint32 timestamp) {
+            (state.reserve0, state.reserve1, state.blockTimestampLast) = (
+                reserve0,
+                reserve1,
+                timestamp
+            );
+        } catch {
+            return state;
+        }
+
+        bool ok0;
+        bool ok1;
+        (ok0, state.balance0) = getPairBalance(_pair, _pair.token0);
+        (ok1, state.balance1) = getPairBalance(_pair, _pair.token1);
+        state.ok = ok0 && ok1;
+    }
+
+    function getPairBalance(
+        IUniswapV2Pair _pair,
+        function() external view returns (address) _getToken
+    ) internal view returns (bool, uint256) {
+        try _getToken() returns (address token) {
+            try IERC20(token).balanceOf(address(_pair)) returns (uint256 balance) {
+                return (true, balance);
+            } catch {
+                return (false, 0);
+            }
+        } catch {
+            return (false, 0);
+        }
+    }
+
     function getReservesByPairs(
         IUniswapV2Pair[] calldata _pairs
     ) external view returns (uint112[3][] memory) {
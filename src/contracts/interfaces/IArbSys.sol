@@ -0,0 +1,8 @@
+//SPDX-License-Identifier: UNLICENSED
+pragma solidity 0.8.19;
+
+pragma experimental ABIEncoderV2;
+
+interface IArbSys {
+    function arbBlockNumber() external view returns (uint256);
+}
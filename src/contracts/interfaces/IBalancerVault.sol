@@ -0,0 +1,15 @@
+//SPDX-License-Identifier: UNLICENSED
+pragma solidity 0.8.19;
+
+pragma experimental ABIEncoderV2;
+
+import {IERC20} from "./IERC20.sol";
+
+interface IBalancerVault {
+    function flashLoan(
+        address recipient,
+        IERC20[] memory tokens,
+        uint256[] memory amounts,
+        bytes memory userData
+    ) external;
+}
@@ -1,28 +1,44 @@
 use ethers::{
     contract::abigen,
     middleware::SignerMiddleware,
-    providers::{Http, Middleware, Provider, Ws},
+    providers::{Http, Middleware, Provider},
     signers::LocalWallet,
     types::{H160, U256},
 };
 
 use super::{
     config::RuntimeConfig,
+    query,
     types::{BundleExecutorContract, RuntimeClient, UniswapQueryContract},
 };
 
 use crate::{
-    exchanges::get_exchange_markets,
+    exchanges::{
+        erc20::{detect_transfer_fee, set_transfer_fee},
+        get_cached_reserves, get_exchange_markets, get_pair_states,
+        types::{PairBalance, Protocol},
+    },
     networks::Network,
-    types::{market::Market, OrganizedList, Reserves, Route},
+    provider_pool::ProviderPool,
+    rpc_batching::call_batched,
+    token_safety::{denylist, screen_tokens},
+    types::{market::Market, OrganizedList, Reserves, Route, Token},
     utils::parse::*,
 };
-use futures::executor::block_on;
-use std::{io::Error, sync::Arc};
+use futures::{executor::block_on, future::join_all};
+use std::{
+    collections::HashMap,
+    io::Error,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 abigen!(UniswapQuery, "src/contracts/abi/UniswapQuery.json");
 abigen!(BundleExecutor, "src/contracts/abi/BundleExecutor.json");
 
+// pairs without a sync for longer are abandoned, their reserves no longer follow the market
+const MAX_PAIR_IDLE_TIME: u64 = 60 * 60 * 24 * 30;
+
 #[derive(Clone)]
 pub struct RuntimeCache {
     pub client: Arc<RuntimeClient>,
@@ -32,12 +48,47 @@ pub struct RuntimeCache {
     pub routes: Vec<Route>,
 }
 
+#[inline(always)]
+fn is_liquid_market(market: &Market, reserves: &Reserves, config: &RuntimeConfig) -> bool {
+    let min_reserve_0 = dec_to_u256(&config.min_market_reserves, market.tokens[0].decimals);
+    let min_reserve_1 = dec_to_u256(&config.min_market_reserves, market.tokens[1].decimals);
+
+    return reserves.0.ge(&min_reserve_0) && reserves.1.ge(&min_reserve_1);
+}
+
+// The market holding the most of every token, with its reserve, used to simulate transfers of
+// the token, balancer pools are skipped as the vault holds their tokens
+fn get_token_holders(liquid_markets: &Vec<(Market, Reserves)>) -> HashMap<H160, (H160, U256)> {
+    let mut holders: HashMap<H160, (H160, U256)> = HashMap::new();
+
+    for (market, reserves) in liquid_markets {
+        if market.protocol == Protocol::BalancerV2 {
+            continue;
+        }
+
+        for (token, reserve) in market.tokens.iter().zip([reserves.0, reserves.1]) {
+            let holder = holders
+                .entry(token.contract_address)
+                .or_insert((market.contract_address, reserve));
+
+            if reserve > holder.1 {
+                *holder = (market.contract_address, reserve);
+            }
+        }
+    }
+
+    return holders;
+}
+
 impl RuntimeCache {
     pub fn new(
         config: &'static RuntimeConfig,
         network: &'static Network,
     ) -> Result<RuntimeCache, Error> {
-        let provider: Provider<Ws> = block_on(Provider::<Ws>::connect(config.rpc_endpoint.as_str())).unwrap();
+        let endpoints = [vec![config.rpc_endpoint.clone()], config.rpc_endpoints.clone()].concat();
+        let pool = block_on(ProviderPool::connect(&endpoints, &config.debug_rpc_endpoints))
+            .map_err(|err| Error::new(std::io::ErrorKind::ConnectionRefused, err.to_string()))?;
+        let provider: Provider<ProviderPool> = Provider::new(pool);
         let wallet = config
             .private_key
             .parse::<LocalWallet>()
@@ -46,7 +97,8 @@ impl RuntimeCache {
         let client: Arc<RuntimeClient> = Arc::new(SignerMiddleware::new(provider, wallet.clone()));
 
         let uniswap_query: UniswapQueryContract =
-            UniswapQuery::new(network.uniswap_query_address, client.clone());
+            UniswapQuery::new(query::QUERY_SCRATCH_ADDRESS, client.clone());
+        query::init(network.uniswap_query_address);
 
         let bundle_executor: BundleExecutorContract =
             BundleExecutor::new(config.executor_address, client.clone());
@@ -82,29 +134,51 @@ impl RuntimeCache {
     }
 
     async fn init_markets(&mut self, network: &'static Network, config: &'static RuntimeConfig) {
-        _ = self.client.get_block_number().await;
+        let block_number = self.client.get_block_number().await;
+        denylist::load(network, &config.denylist_path);
+
+        let mut liquid_markets: Vec<(Market, Reserves)> = vec![];
 
         match get_exchange_markets(network, self, config).await {
             Ok(result) => {
-                let market_addressess: Vec<H160> =
-                    result.iter().map(|x| x.contract_address).collect();
-                if let Ok(response) = self
-                    .uniswap_query
-                    .get_reserves_by_pairs(market_addressess.clone())
-                    .await
-                {
-                    for i in 0..response.len() {
-                        let reserves: Reserves =
-                            (U256::from(response[i][0]), U256::from(response[i][1]));
-                        let market = &result[i];
-
-                        let min_reserve_0 =
-                            dec_to_u256(&config.min_market_reserves, market.tokens[0].decimals);
-                        let min_reserve_1 =
-                            dec_to_u256(&config.min_market_reserves, market.tokens[1].decimals);
-
-                        if reserves.0.ge(&min_reserve_0) && reserves.1.ge(&min_reserve_1) {
-                            self.markets.add_value(market.clone());
+                let (pair_markets, pool_markets): (Vec<Market>, Vec<Market>) = result
+                    .into_iter()
+                    .partition(|x| x.protocol == Protocol::UniswapV2);
+
+                if let Ok(block_number) = block_number {
+                    let uniswap_query = &self.uniswap_query;
+                    let pair_states = call_batched(
+                        "getPairStates",
+                        config.small_chunk_size,
+                        pair_markets,
+                        |chunk| async move {
+                            let addressess = chunk.iter().map(|x| x.contract_address).collect();
+                            return get_pair_states(uniswap_query, addressess, block_number).await;
+                        },
+                    )
+                    .await;
+
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |x| x.as_secs());
+
+                    // pairs holding less than their reserves can't be swapped through
+                    for (market, pair_reading) in pair_states {
+                        let last_sync = pair_reading.block_timestamp_last as u64;
+                        if pair_reading.balance != Some(PairBalance::Desynchronised)
+                            && now.saturating_sub(last_sync) <= MAX_PAIR_IDLE_TIME
+                            && is_liquid_market(&market, &pair_reading.reserves, config)
+                        {
+                            liquid_markets.push((market, pair_reading.reserves));
+                        }
+                    }
+                }
+
+                // pool based markets are loaded with their state during discovery
+                for market in pool_markets {
+                    if let Some(reserves) = get_cached_reserves(&market) {
+                        if is_liquid_market(&market, &reserves, config) {
+                            liquid_markets.push((market, reserves));
                         }
                     }
                 }
@@ -112,9 +186,75 @@ impl RuntimeCache {
             Err(_) => {}
         };
 
+        let holders = get_token_holders(&liquid_markets);
+        self.detect_transfer_fees(network, &holders, config).await;
+        screen_tokens(network, &liquid_markets, &holders, config, &self.client).await;
+
+        // tokens flagged by the screening never enter the route set
+        for (market, _) in liquid_markets {
+            if !market
+                .tokens
+                .iter()
+                .any(|x| denylist::is_denied(&x.contract_address))
+            {
+                self.markets.add_value(market);
+            }
+        }
+
         // self.markets.sort_unstable_by(|x| x.)
     }
 
+    // Tokens without a declared transfer fee are probed with a transfer from their holder to the
+    // executor
+    async fn detect_transfer_fees(
+        &self,
+        network: &'static Network,
+        holders: &HashMap<H160, (H160, U256)>,
+        config: &'static RuntimeConfig,
+    ) {
+        let probes: Vec<(&'static Token, H160, U256)> = network
+            .tokens
+            .iter()
+            .filter(|token| token.transfer_fee.is_none())
+            .filter_map(|token| {
+                let (holder, reserve) = *holders.get(&token.contract_address)?;
+                return Some((token, holder, (reserve / 1000).max(U256::one())));
+            })
+            .collect();
+
+        let client = &self.client;
+        let transfer_fees = call_batched(
+            "transfer_probe",
+            config.small_chunk_size,
+            probes.clone(),
+            |chunk| async move {
+                let transfer_fees = join_all(chunk.into_iter().map(|(token, holder, amount)| {
+                    detect_transfer_fee(token, holder, config.executor_address, amount, client)
+                }))
+                .await;
+
+                return transfer_fees
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|x| (x, 0));
+            },
+        )
+        .await;
+
+        for (token, _, _) in probes {
+            let transfer_fee = transfer_fees
+                .iter()
+                .find(|x| x.0 .0.contract_address == token.contract_address)
+                .and_then(|x| x.1);
+
+            match transfer_fee {
+                Some(0) => {}
+                Some(transfer_fee) => set_transfer_fee(token, transfer_fee),
+                None => println!("Transfer probe failed for {:?}", token.contract_address),
+            }
+        }
+    }
+
     /*
     pub fn calculate_routes(
         &mut self,
@@ -1,12 +1,19 @@
+use crate::handlers::FeedMode;
+use crate::log_tracer::TracerBackend;
 use crate::utils::parse;
 use dotenv::dotenv;
 use ethers::types::Address;
 use url::Url;
 
+/// Share of the expected profit given up to slippage when `SLIPPAGE_TOLERANCE` is not set, in bps
+pub const DEFAULT_SLIPPAGE_TOLERANCE: u32 = 1000;
+
 #[derive(Clone)]
 pub struct RuntimeConfig {
     pub chain_id: u32,
     pub rpc_endpoint: Url,
+    pub rpc_endpoints: Vec<Url>,
+    pub debug_rpc_endpoints: Vec<Url>,
     pub feed_endpoint: Url,
     pub executor_address: Address,
     pub private_key: String,
@@ -14,6 +21,13 @@ pub struct RuntimeConfig {
     pub small_chunk_size: usize,
     pub large_chunk_size: usize,
     pub min_market_reserves: String,
+    pub price_cross_check: bool,
+    pub slippage_tolerance: u32,
+    pub sequential_tracing: bool,
+    pub tracer_backend: TracerBackend,
+    pub prefilter_unknown_contracts: bool,
+    pub feed_mode: FeedMode,
+    pub denylist_path: String,
 }
 
 impl RuntimeConfig {
@@ -23,13 +37,22 @@ impl RuntimeConfig {
         return RuntimeConfig {
             chain_id: read_u32("CHAIN_ID"),
             rpc_endpoint: read_url("RPC_ENDPOINT"),
+            rpc_endpoints: read_optional_urls("RPC_ENDPOINTS"),
+            debug_rpc_endpoints: read_optional_urls("DEBUG_RPC_ENDPOINTS"),
             feed_endpoint: read_url("FEED_ENDPOINT"),
             executor_address: read_address("BUNDLE_EXECUTOR"),
             private_key: read_string("PRIVATE_KEY"),
             route_restraints: (read_u32("MIN_ROUTE_LENGTH") as usize, read_u32("MAX_ROUTE_LENGTH") as usize),
             min_market_reserves: read_string("MIN_MARKET_RESERVES"),
             small_chunk_size: read_u32("SMALL_CHUNK_SIZE") as usize,
-            large_chunk_size: read_u32("LARGE_CHUNK_SIZE") as usize
+            large_chunk_size: read_u32("LARGE_CHUNK_SIZE") as usize,
+            price_cross_check: read_optional_bool("PRICE_CROSS_CHECK"),
+            slippage_tolerance: read_optional_u32("SLIPPAGE_TOLERANCE", DEFAULT_SLIPPAGE_TOLERANCE),
+            sequential_tracing: read_optional_bool("SEQUENTIAL_TRACING"),
+            tracer_backend: read_tracer_backend("TRACER_BACKEND"),
+            prefilter_unknown_contracts: read_optional_bool("PREFILTER_UNKNOWN_CONTRACTS"),
+            feed_mode: read_feed_mode("MARKET_FEED"),
+            denylist_path: read_optional_string("DENYLIST_PATH", "denylist.json"),
         };
     }
 }
@@ -42,10 +65,63 @@ fn read_url(input: &str) -> Url {
     return parse::url(read_string(input));
 }
 
+// comma separated, empty when not set
+fn read_optional_urls(input: &str) -> Vec<Url> {
+    return match std::env::var(input) {
+        Ok(value) => value
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| parse::url(x.to_string()))
+            .collect(),
+        Err(_) => vec![],
+    };
+}
+
 fn read_u32(input: &str) -> u32 {
     return parse::u32(read_string(input));
 }
 
+fn read_optional_u32(input: &str, default: u32) -> u32 {
+    return match std::env::var(input) {
+        Ok(value) => parse::u32(value),
+        Err(_) => default,
+    };
+}
+
+fn read_optional_bool(input: &str) -> bool {
+    return match std::env::var(input) {
+        Ok(value) => parse::bool(value),
+        Err(_) => false,
+    };
+}
+
+// the JS tracer when not set
+fn read_tracer_backend(input: &str) -> TracerBackend {
+    return match std::env::var(input) {
+        Ok(value) => match TracerBackend::from_name(&value) {
+            Some(tracer_backend) => tracer_backend,
+            None => panic!("not a valid tracer backend: {}", value),
+        },
+        Err(_) => TracerBackend::Js,
+    };
+}
+
+// the sequencer feed when not set
+fn read_feed_mode(input: &str) -> FeedMode {
+    return match std::env::var(input) {
+        Ok(value) => match FeedMode::from_name(&value) {
+            Some(feed_mode) => feed_mode,
+            None => panic!("not a valid market feed: {}", value),
+        },
+        Err(_) => FeedMode::Sequencer,
+    };
+}
+
+fn read_optional_string(input: &str, default: &str) -> String {
+    return std::env::var(input).unwrap_or(default.to_string());
+}
+
 fn read_string(input: &str) -> String {
     let read_result: Result<String, _> = std::env::var(input);
     if read_result.is_err() {
@@ -1,5 +1,6 @@
 mod cache;
 mod config;
+pub mod query;
 pub mod types;
 
 pub use cache::RuntimeCache;
@@ -0,0 +1,139 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+use ethers::{
+    abi::Detokenize,
+    contract::{decode_function_data, ContractCall, ContractError},
+    providers::{call_raw::RawCall, JsonRpcError, ProviderError, RpcError},
+    types::{spoof, Address, Bytes, H160},
+};
+
+use super::{cache::UNISWAPQUERY_DEPLOYED_BYTECODE, types::RuntimeClient};
+
+// Account the query contract is injected at, far from the precompiles and ArbOS contracts
+pub const QUERY_SCRATCH_ADDRESS: H160 = H160([0x51; 20]);
+
+lazy_static! {
+    static ref QUERY_OVERRIDE: spoof::State = spoof::code(
+        QUERY_SCRATCH_ADDRESS,
+        UNISWAPQUERY_DEPLOYED_BYTECODE.clone()
+    );
+    static ref DEPLOYED_QUERY_ADDRESS: RwLock<Option<Address>> = RwLock::new(None);
+    static ref OVERRIDES_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+}
+
+// The deployed contract answers the calls of nodes without state overrides
+pub fn init(deployed_address: Option<Address>) {
+    *DEPLOYED_QUERY_ADDRESS.write().unwrap() = deployed_address;
+}
+
+#[inline(always)]
+pub fn get_deployed_address() -> Option<Address> {
+    return *DEPLOYED_QUERY_ADDRESS.read().unwrap();
+}
+
+// A node rejecting the override answers that the method or its parameter isn't supported, one
+// ignoring it returns the empty output of an account without code. Reverts, rate limits and
+// transport errors aren't caused by it.
+#[inline(always)]
+fn is_unsupported_response(response: &JsonRpcError) -> bool {
+    let message = response.message.to_lowercase();
+
+    return response.code == -32601
+        || response.code == -32602
+        || message.contains("not supported")
+        || message.contains("unsupported");
+}
+
+#[inline(always)]
+fn is_override_error(response: &Result<Bytes, ProviderError>) -> bool {
+    return match response {
+        Ok(bytes) => bytes.is_empty(),
+        Err(error) => error
+            .as_error_response()
+            .map_or(false, is_unsupported_response),
+    };
+}
+
+/// Data of a call the node reverted, `None` for other errors
+#[inline(always)]
+pub fn get_revert_data(error: &ContractError<RuntimeClient>) -> Option<Bytes> {
+    if let Some(data) = error.as_revert() {
+        return Some(data.clone());
+    }
+
+    return error
+        .as_provider_error()?
+        .as_error_response()?
+        .as_revert_data();
+}
+
+/// Whether a call failed on the way to or in the node, e.g. a transport error or a rate limit,
+/// and may succeed when retried. Reverts and undecodable outputs are answers of the contract.
+#[inline(always)]
+pub fn is_transient_error(error: &ContractError<RuntimeClient>) -> bool {
+    if get_revert_data(error).is_some() {
+        return false;
+    }
+
+    return match error.as_provider_error() {
+        Some(provider_error) => provider_error
+            .as_error_response()
+            .map_or(true, |x| !x.message.to_lowercase().contains("revert")),
+        None => false,
+    };
+}
+
+/// Calls a view of a query contract bound to `QUERY_SCRATCH_ADDRESS`, its code is injected with
+/// a state override so nothing has to be deployed. Once a node turns out not to support
+/// overrides, calls go to the deployed contract instead. Returns the decoded output and its size
+/// in bytes.
+pub async fn call_query_sized<D: Detokenize>(
+    mut call: ContractCall<RuntimeClient, D>,
+) -> Result<(D, usize), ContractError<RuntimeClient>> {
+    let deployed_address = get_deployed_address();
+    let decode = |bytes: Bytes| -> Result<(D, usize), ContractError<RuntimeClient>> {
+        let output = decode_function_data(&call.function, &bytes, false)?;
+        return Ok((output, bytes.len()));
+    };
+
+    if !OVERRIDES_UNSUPPORTED.load(Ordering::Relaxed) {
+        let response = call.call_raw_bytes().state(&QUERY_OVERRIDE).await;
+
+        if deployed_address.is_none() || !is_override_error(&response) {
+            return match response {
+                Ok(bytes) => decode(bytes),
+                Err(err) => Err(err.into()),
+            };
+        }
+    }
+
+    if let Some(deployed_address) = deployed_address {
+        call.tx.set_to(deployed_address);
+    }
+
+    let result = match call.call_raw_bytes().await {
+        Ok(bytes) => decode(bytes),
+        Err(err) => Err(err.into()),
+    };
+
+    if let Some(deployed_address) = deployed_address {
+        if result.is_ok() && !OVERRIDES_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+            println!(
+                "State overrides unsupported, querying {:?}\n",
+                deployed_address
+            );
+        }
+    }
+
+    return result;
+}
+
+#[inline(always)]
+pub async fn call_query<D: Detokenize>(
+    call: ContractCall<RuntimeClient, D>,
+) -> Result<D, ContractError<RuntimeClient>> {
+    return call_query_sized(call).await.map(|x| x.0);
+}
@@ -1,16 +1,34 @@
-use ethers::abi::{AbiParser, Function};
+use ethers::abi::{AbiParser, Function, HumanReadableParser};
 use ethers::prelude::k256::ecdsa::SigningKey;
 use ethers::prelude::*;
 use ethers::{prelude::SignerMiddleware, providers::Provider};
 
 use super::cache::{BundleExecutor, UniswapQuery};
+use crate::provider_pool::ProviderPool;
+pub use super::cache::PairState;
 
-pub type RuntimeClient = SignerMiddleware<Provider<Ws>, Wallet<SigningKey>>;
+pub type RuntimeClient = SignerMiddleware<Provider<ProviderPool>, Wallet<SigningKey>>;
 pub type BundleExecutorContract = BundleExecutor<RuntimeClient>;
 pub type UniswapQueryContract = UniswapQuery<RuntimeClient>;
 
 lazy_static! {
     pub static ref EXECUTE_TX_BUNDLE_FUNCTION: Function = AbiParser::default()
-        .parse_function("executeTxBundle(address,uint256,address[],bytes[])")
+        .parse_function("executeTxBundle(address,uint256,address[],bytes[],(uint256,uint256,uint256))")
+        .unwrap();
+    // `AbiParser` can't read arrays of tuples
+    pub static ref EXECUTE_BATCH_TX_BUNDLE_FUNCTION: Function = HumanReadableParser::parse_function(
+        "function executeTxBundle((address,uint256,uint256)[],address[],bytes[],(uint256,uint256,uint256))"
+    )
+    .unwrap();
+    pub static ref EXECUTE_BALANCER_TX_BUNDLE_FUNCTION: Function = AbiParser::default()
+        .parse_function("executeBalancerTxBundle(address,uint256,address[],bytes[],(uint256,uint256,uint256))")
+        .unwrap();
+    pub static ref EXECUTE_FLASH_SWAP_TX_BUNDLE_FUNCTION: Function = AbiParser::default()
+        .parse_function(
+            "executeFlashSwapTxBundle(address,address,uint256,uint256,uint256,address[],bytes[],(uint256,uint256,uint256))"
+        )
+        .unwrap();
+    pub static ref EXECUTE_INVENTORY_TX_BUNDLE_FUNCTION: Function = AbiParser::default()
+        .parse_function("executeInventoryTxBundle(address,uint256,address[],bytes[],(uint256,uint256,uint256))")
         .unwrap();
 }
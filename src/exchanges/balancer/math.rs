@@ -0,0 +1,499 @@
+use ethers::types::{I256, U256};
+
+// Ports of the Balancer V2 FixedPoint, LogExpMath, WeightedMath and StableMath libraries,
+// rounding directions follow the Solidity implementation
+
+const ONE: u64 = 1_000_000_000_000_000_000;
+const MAX_POW_RELATIVE_ERROR: u64 = 10000;
+const MAX_IN_RATIO: u64 = 300_000_000_000_000_000;
+const AMP_PRECISION: u64 = 1000;
+const MAX_ITERATIONS: usize = 255;
+
+lazy_static! {
+    static ref ONE_18: I256 = I256::exp10(18);
+    static ref ONE_20: I256 = I256::exp10(20);
+    static ref ONE_36: I256 = I256::exp10(36);
+    static ref MAX_NATURAL_EXPONENT: I256 = I256::from(130) * *ONE_18;
+    static ref MIN_NATURAL_EXPONENT: I256 = I256::from(-41) * *ONE_18;
+    static ref LN_36_LOWER_BOUND: I256 = *ONE_18 - I256::exp10(17);
+    static ref LN_36_UPPER_BOUND: I256 = *ONE_18 + I256::exp10(17);
+    static ref MILD_EXPONENT_BOUND: U256 = (U256::one() << 254) / U256::exp10(20);
+
+    // (x_n, a_n = e^x_n), the first two in 18 decimals without decimals on a_n, the rest in 20
+    // decimals
+    static ref EXP_TABLE: [(I256, I256); 12] = [
+        (
+            parse("128000000000000000000"),
+            parse("38877084059945950922200000000000000000000000000000000000"),
+        ),
+        (parse("64000000000000000000"), parse("6235149080811616882910000000")),
+        (parse("3200000000000000000000"), parse("7896296018268069516100000000000000")),
+        (parse("1600000000000000000000"), parse("888611052050787263676000000")),
+        (parse("800000000000000000000"), parse("298095798704172827474000")),
+        (parse("400000000000000000000"), parse("5459815003314423907810")),
+        (parse("200000000000000000000"), parse("738905609893065022723")),
+        (parse("100000000000000000000"), parse("271828182845904523536")),
+        (parse("50000000000000000000"), parse("164872127070012814685")),
+        (parse("25000000000000000000"), parse("128402541668774148407")),
+        (parse("12500000000000000000"), parse("113314845306682631683")),
+        (parse("6250000000000000000"), parse("106449445891785942956")),
+    ];
+}
+
+#[inline(always)]
+fn parse(value: &str) -> I256 {
+    return I256::from_dec_str(value).unwrap();
+}
+
+#[inline(always)]
+pub fn mul_down(a: U256, b: U256) -> U256 {
+    return a * b / ONE;
+}
+
+#[inline(always)]
+pub fn mul_up(a: U256, b: U256) -> U256 {
+    let product = a * b;
+    if product.is_zero() {
+        return product;
+    }
+
+    return (product - 1) / ONE + 1;
+}
+
+#[inline(always)]
+pub fn div_down(a: U256, b: U256) -> U256 {
+    return a * ONE / b;
+}
+
+#[inline(always)]
+pub fn div_up(a: U256, b: U256) -> U256 {
+    if a.is_zero() {
+        return a;
+    }
+
+    return (a * ONE - 1) / b + 1;
+}
+
+#[inline(always)]
+fn raw_div_up(a: U256, b: U256) -> U256 {
+    if a.is_zero() {
+        return a;
+    }
+
+    return (a - 1) / b + 1;
+}
+
+#[inline(always)]
+fn complement(x: U256) -> U256 {
+    if x < U256::from(ONE) {
+        return U256::from(ONE) - x;
+    }
+
+    return U256::zero();
+}
+
+fn exp(mut x: I256) -> Option<I256> {
+    if x < *MIN_NATURAL_EXPONENT || x > *MAX_NATURAL_EXPONENT {
+        return None;
+    }
+
+    if x.is_negative() {
+        return Some(*ONE_18 * *ONE_18 / exp(-x)?);
+    }
+
+    let first_an = if x >= EXP_TABLE[0].0 {
+        x -= EXP_TABLE[0].0;
+        EXP_TABLE[0].1
+    } else if x >= EXP_TABLE[1].0 {
+        x -= EXP_TABLE[1].0;
+        EXP_TABLE[1].1
+    } else {
+        I256::one()
+    };
+
+    x *= 100;
+
+    let mut product = *ONE_20;
+    for (x_n, a_n) in EXP_TABLE.iter().take(10).skip(2) {
+        if x >= *x_n {
+            x -= *x_n;
+            product = product * *a_n / *ONE_20;
+        }
+    }
+
+    let mut series_sum = *ONE_20;
+    let mut term = x;
+    series_sum += term;
+
+    for i in 2..13 {
+        term = term * x / *ONE_20 / i;
+        series_sum += term;
+    }
+
+    return Some(product * series_sum / *ONE_20 * first_an / 100);
+}
+
+fn ln(mut a: I256) -> I256 {
+    if a < *ONE_18 {
+        return -ln(*ONE_18 * *ONE_18 / a);
+    }
+
+    let mut sum = I256::zero();
+    for (x_n, a_n) in EXP_TABLE.iter().take(2) {
+        if a >= *a_n * *ONE_18 {
+            a /= *a_n;
+            sum += *x_n;
+        }
+    }
+
+    sum *= 100;
+    a *= 100;
+
+    for (x_n, a_n) in EXP_TABLE.iter().skip(2) {
+        if a >= *a_n {
+            a = a * *ONE_20 / *a_n;
+            sum += *x_n;
+        }
+    }
+
+    let z = (a - *ONE_20) * *ONE_20 / (a + *ONE_20);
+    let z_squared = z * z / *ONE_20;
+
+    let mut num = z;
+    let mut series_sum = num;
+    for i in [3, 5, 7, 9, 11] {
+        num = num * z_squared / *ONE_20;
+        series_sum += num / i;
+    }
+
+    return (sum + series_sum * 2) / 100;
+}
+
+fn ln_36(mut x: I256) -> I256 {
+    x *= *ONE_18;
+
+    let z = (x - *ONE_36) * *ONE_36 / (x + *ONE_36);
+    let z_squared = z * z / *ONE_36;
+
+    let mut num = z;
+    let mut series_sum = num;
+    for i in [3, 5, 7, 9, 11, 13, 15] {
+        num = num * z_squared / *ONE_36;
+        series_sum += num / i;
+    }
+
+    return series_sum * 2;
+}
+
+/// `x^y` for 18 decimal fixed point values, `LogExpMath.pow`
+pub fn pow(x: U256, y: U256) -> Option<U256> {
+    if y.is_zero() {
+        return Some(U256::from(ONE));
+    }
+
+    if x.is_zero() {
+        return Some(U256::zero());
+    }
+
+    if x.bit(255) || y >= *MILD_EXPONENT_BOUND {
+        return None;
+    }
+
+    let x_int = I256::from_raw(x);
+    let y_int = I256::from_raw(y);
+
+    let logx_times_y = if *LN_36_LOWER_BOUND < x_int && x_int < *LN_36_UPPER_BOUND {
+        let ln_36_x = ln_36(x_int);
+        (ln_36_x / *ONE_18) * y_int + (ln_36_x % *ONE_18) * y_int / *ONE_18
+    } else {
+        ln(x_int) * y_int
+    } / *ONE_18;
+
+    return Some(exp(logx_times_y)?.into_raw());
+}
+
+/// `FixedPoint.powUp`, rounds the result up by the maximum relative error of `pow`
+pub fn pow_up(x: U256, y: U256) -> Option<U256> {
+    let one = U256::from(ONE);
+
+    if y == one {
+        return Some(x);
+    } else if y == one * 2 {
+        return Some(mul_up(x, x));
+    } else if y == one * 4 {
+        let square = mul_up(x, x);
+        return Some(mul_up(square, square));
+    }
+
+    let raw = pow(x, y)?;
+    let max_error = mul_up(raw, U256::from(MAX_POW_RELATIVE_ERROR)) + 1;
+    return Some(raw + max_error);
+}
+
+/// `WeightedMath._calcOutGivenIn` on upscaled balances, `amount_in` already excludes the swap fee
+pub fn weighted_out_given_in(
+    balance_in: U256,
+    weight_in: U256,
+    balance_out: U256,
+    weight_out: U256,
+    amount_in: U256,
+) -> Option<U256> {
+    if amount_in > mul_down(balance_in, U256::from(MAX_IN_RATIO)) {
+        return None;
+    }
+
+    let base = div_up(balance_in, balance_in + amount_in);
+    let exponent = div_down(weight_in, weight_out);
+    let power = pow_up(base, exponent)?;
+
+    return Some(mul_down(balance_out, complement(power)));
+}
+
+/// `StableMath._calculateInvariant`, `amp` includes `AMP_PRECISION`
+pub fn stable_invariant(amp: U256, balances: &[U256]) -> Option<U256> {
+    let n = U256::from(balances.len());
+    let amp_precision = U256::from(AMP_PRECISION);
+    let sum: U256 = balances.iter().fold(U256::zero(), |acc, x| acc + x);
+
+    if sum.is_zero() {
+        return Some(U256::zero());
+    }
+
+    let amp_times_total = amp * n;
+    let mut invariant = sum;
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut d_p = invariant;
+        for balance in balances {
+            d_p = d_p * invariant / balance.checked_mul(n).filter(|x| !x.is_zero())?;
+        }
+
+        let prev_invariant = invariant;
+        invariant = (amp_times_total * sum / amp_precision + d_p * n) * invariant
+            / ((amp_times_total.checked_sub(amp_precision)?) * invariant / amp_precision
+                + (n + 1) * d_p);
+
+        if invariant.max(prev_invariant) - invariant.min(prev_invariant) <= U256::one() {
+            return Some(invariant);
+        }
+    }
+
+    return None;
+}
+
+fn stable_balance_given_invariant(
+    amp: U256,
+    balances: &[U256],
+    invariant: U256,
+    token_index: usize,
+) -> Option<U256> {
+    let n = U256::from(balances.len());
+    let amp_precision = U256::from(AMP_PRECISION);
+    let amp_times_total = amp * n;
+
+    let mut sum = balances[0];
+    let mut p_d = balances[0] * n;
+    for balance in balances.iter().skip(1) {
+        p_d = p_d * balance * n / invariant;
+        sum += *balance;
+    }
+    sum -= balances[token_index];
+
+    let inv2 = invariant * invariant;
+    let c = raw_div_up(
+        inv2,
+        amp_times_total.checked_mul(p_d).filter(|x| !x.is_zero())?,
+    ) * amp_precision
+        * balances[token_index];
+    let b = sum + invariant / amp_times_total * amp_precision;
+
+    let mut token_balance = raw_div_up(inv2 + c, invariant + b);
+
+    for _ in 0..MAX_ITERATIONS {
+        let prev_token_balance = token_balance;
+        token_balance = raw_div_up(
+            token_balance * token_balance + c,
+            (token_balance + token_balance + b)
+                .checked_sub(invariant)
+                .filter(|x| !x.is_zero())?,
+        );
+
+        if token_balance.max(prev_token_balance) - token_balance.min(prev_token_balance)
+            <= U256::one()
+        {
+            return Some(token_balance);
+        }
+    }
+
+    return None;
+}
+
+/// `StableMath._calcOutGivenIn` on upscaled balances, `amount_in` already excludes the swap fee
+pub fn stable_out_given_in(
+    amp: U256,
+    balances: &[U256],
+    token_index_in: usize,
+    token_index_out: usize,
+    amount_in: U256,
+) -> Option<U256> {
+    let invariant = stable_invariant(amp, balances)?;
+
+    let mut balances = balances.to_vec();
+    balances[token_index_in] += amount_in;
+
+    let final_balance_out =
+        stable_balance_given_invariant(amp, &balances, invariant, token_index_out)?;
+
+    return balances[token_index_out]
+        .checked_sub(final_balance_out)?
+        .checked_sub(U256::one());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected outputs are those of the Solidity libraries for the same pool state, through a
+    // GIVEN_IN `onSwap`: the fee is taken from the input, which is then upscaled, and the output
+    // is downscaled rounding down
+
+    fn parse_u256(value: &str) -> U256 {
+        return U256::from_dec_str(value).unwrap();
+    }
+
+    fn swap_given_in(
+        amount: &str,
+        balances: &[&str],
+        scaling_factors: &[U256],
+        swap_fee: U256,
+        (i, j): (usize, usize),
+        out_given_in: impl Fn(&[U256], U256) -> Option<U256>,
+    ) -> Option<U256> {
+        let amount = parse_u256(amount);
+        let amount_in = mul_down(amount - mul_up(amount, swap_fee), scaling_factors[i]);
+        let balances: Vec<U256> = balances
+            .iter()
+            .zip(scaling_factors)
+            .map(|(balance, scaling_factor)| mul_down(parse_u256(balance), *scaling_factor))
+            .collect();
+
+        return Some(div_down(
+            out_given_in(&balances, amount_in)?,
+            scaling_factors[j],
+        ));
+    }
+
+    #[test]
+    fn weighted_out_given_in_matches_weighted_pools() {
+        let one = U256::exp10(18);
+
+        // 80/20 BAL / WETH with a 1% fee, the exponents are 4 and 0.25
+        let balances = ["3456789000000000123456789", "1234000000000987654321"];
+        let weights = [one * 8 / 10, one * 2 / 10];
+
+        for (i, j, amount, amount_out) in [
+            (0, 1, "1000000000000000000000", "1412624043561201435"),
+            (1, 0, "5000000000000000000", "3457928504510501531051"),
+        ] {
+            let result = swap_given_in(
+                amount,
+                &balances,
+                &[one, one],
+                one / 100,
+                (i, j),
+                |balances, amount_in| {
+                    weighted_out_given_in(
+                        balances[i],
+                        weights[i],
+                        balances[j],
+                        weights[j],
+                        amount_in,
+                    )
+                },
+            );
+
+            assert_eq!(result, Some(parse_u256(amount_out)));
+        }
+
+        // 50/50 USDC / WETH with a 0.3% fee
+        let balances = ["2500000123456", "1380000000000000000555"];
+        let weights = [one / 2, one / 2];
+
+        for (i, j, amount, amount_out) in [
+            (0, 1, "10000000000", "5481579191490798362"),
+            (1, 0, "3000000000000000000", "5406759963"),
+        ] {
+            let result = swap_given_in(
+                amount,
+                &balances,
+                &[U256::exp10(30), one],
+                one * 3 / 1000,
+                (i, j),
+                |balances, amount_in| {
+                    weighted_out_given_in(
+                        balances[i],
+                        weights[i],
+                        balances[j],
+                        weights[j],
+                        amount_in,
+                    )
+                },
+            );
+
+            assert_eq!(result, Some(parse_u256(amount_out)));
+        }
+    }
+
+    #[test]
+    fn stable_out_given_in_matches_composable_stable_pools() {
+        let one = U256::exp10(18);
+
+        // wstETH / WETH, A of 50, a wstETH rate of 1.15 and a 0.01% fee
+        let balances = ["9876000000000000000001", "11234000000000000000007"];
+        let scaling_factors = [one * 115 / 100, one];
+
+        for (i, j, amount, amount_out) in [
+            (0, 1, "100000000000000000000", "114940918528815683038"),
+            (1, 0, "250000000000000000000", "217321806800576279497"),
+        ] {
+            let result = swap_given_in(
+                amount,
+                &balances,
+                &scaling_factors,
+                one / 10000,
+                (i, j),
+                |balances, amount_in| {
+                    stable_out_given_in(U256::from(50_000u64), balances, i, j, amount_in)
+                },
+            );
+
+            assert_eq!(result, Some(parse_u256(amount_out)));
+        }
+
+        // USDC / USDT / DAI, A of 200 and a 0.005% fee
+        let balances = [
+            "4000000000000",
+            "3500000000000",
+            "4200000000000000000000000",
+        ];
+        let scaling_factors = [U256::exp10(30), U256::exp10(30), one];
+
+        for (i, j, amount, amount_out) in [
+            (0, 2, "50000000000", "50006236079439198329201"),
+            (2, 1, "75000000000000000000000", "74918612581"),
+        ] {
+            let result = swap_given_in(
+                amount,
+                &balances,
+                &scaling_factors,
+                one / 20000,
+                (i, j),
+                |balances, amount_in| {
+                    stable_out_given_in(U256::from(200_000u64), balances, i, j, amount_in)
+                },
+            );
+
+            assert_eq!(result, Some(parse_u256(amount_out)));
+        }
+    }
+}
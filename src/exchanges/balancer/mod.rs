@@ -0,0 +1,556 @@
+use ethers::{
+    abi::{AbiParser, Function, Token as AbiToken},
+    prelude::*,
+};
+use futures::future::join_all;
+use itertools::Itertools;
+use std::{
+    io::{Error, ErrorKind},
+    sync::{Arc, RwLock},
+};
+use tokio::task::JoinSet;
+
+use self::types::{
+    balancer_vault, BalancerPool, BalancerPoolContract, BalancerPoolKind, BalancerPoolState,
+    BalancerVault, BalancerVaultContract,
+};
+
+use super::types::{Exchange, MarketPool, Protocol};
+use crate::{
+    env::{query::is_transient_error, types::RuntimeClient, RuntimeCache, RuntimeConfig},
+    networks::Network,
+    rpc_batching::{call_batched, call_with_retries},
+    types::{
+        market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, SwapLog, Token,
+        TransactionLog,
+    },
+};
+
+mod math;
+mod types;
+
+// pool registrations are queried in steps of this many blocks, 1,000,000 blocks per request
+// until a node refuses the range
+const LOG_BLOCK_STEP: u64 = 10_000;
+const LOG_CHUNK_SIZE: usize = 100;
+const GIVEN_IN: u8 = 0;
+
+lazy_static! {
+    static ref VAULT_SWAP_METHOD: Function = AbiParser::default()
+        .parse_function("swap((bytes32,uint8,address,address,uint256,bytes),(address,bool,address,bool),uint256,uint256)")
+        .unwrap();
+    static ref BALANCER_POOLS: RwLock<Vec<BalancerPoolState>> = RwLock::new(vec![]);
+    static ref BALANCER_VAULTS: RwLock<Vec<Address>> = RwLock::new(vec![]);
+}
+
+#[inline(always)]
+pub fn init_handler() {
+    let _ = { &VAULT_SWAP_METHOD.name };
+}
+
+#[inline(always)]
+pub fn is_vault(address: &H160) -> bool {
+    return BALANCER_VAULTS.read().unwrap().contains(address);
+}
+
+#[inline(always)]
+pub fn get_vault(market: &Market) -> Option<Address> {
+    let market_pool = market.pool?;
+    return Some(BALANCER_POOLS.read().unwrap().get(market_pool.id)?.vault);
+}
+
+// Weighted pools price a swap on the balances and weights of its two coins alone
+#[inline(always)]
+pub fn is_weighted(market: &Market) -> bool {
+    return market.pool.map_or(false, |market_pool| {
+        BALANCER_POOLS
+            .read()
+            .unwrap()
+            .get(market_pool.id)
+            .map_or(false, |x| x.kind == BalancerPoolKind::Weighted)
+    });
+}
+
+// Pools are discovered from the vault's `PoolRegistered` events, only weighted and
+// composable stable pools whose tokens are all tracked are kept
+#[inline(always)]
+pub async fn get_markets(
+    exchange: &'static Exchange,
+    network: &'static Network,
+    runtime_cache: &RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> Result<Vec<Market>, Error> {
+    let vault_address = exchange.factory_address;
+    let vault_contract: BalancerVaultContract =
+        BalancerVault::new(vault_address, runtime_cache.client.clone());
+    let mut result: Vec<Market> = vec![];
+
+    let current_block = runtime_cache
+        .client
+        .get_block_number()
+        .await
+        .map_err(|err| Error::new(ErrorKind::ConnectionRefused, err))?
+        .as_u64();
+
+    let block_ranges = (exchange.start_block.unwrap_or_default()..=current_block)
+        .step_by(LOG_BLOCK_STEP as usize)
+        .map(|from| (from, (from + LOG_BLOCK_STEP - 1).min(current_block)))
+        .collect_vec();
+    let range_count = block_ranges.len();
+
+    // the ranges of a chunk follow each other and are queried at once, chunks of ranges a node
+    // refuses are split
+    let vault_contract = &vault_contract;
+    let registrations = call_batched(
+        "PoolRegistered",
+        LOG_CHUNK_SIZE,
+        block_ranges,
+        |chunk| async move {
+            let events = vault_contract
+                .pool_registered_filter()
+                .from_block(chunk[0].0)
+                .to_block(chunk[chunk.len() - 1].1)
+                .query_with_meta()
+                .await?;
+
+            let registrations = chunk
+                .iter()
+                .map(|(from, to)| {
+                    events
+                        .iter()
+                        .filter(|(_, meta)| (*from..=*to).contains(&meta.block_number.as_u64()))
+                        .map(|(event, _)| (event.pool_id, event.pool_address))
+                        .collect_vec()
+                })
+                .collect();
+
+            return Ok::<_, ContractError<RuntimeClient>>((registrations, 0));
+        },
+    )
+    .await;
+
+    if registrations.len() < range_count {
+        println!(
+            "Balancer pools registered in {} of {} block ranges unread",
+            range_count - registrations.len(),
+            range_count
+        );
+    }
+
+    BALANCER_VAULTS.write().unwrap().push(vault_address);
+
+    let registrations = registrations.into_iter().flat_map(|x| x.1).collect_vec();
+    let client = &runtime_cache.client;
+    let pools = call_batched(
+        "balancer_pool",
+        runtime_config.small_chunk_size,
+        registrations,
+        |chunk| async move {
+            let pools = join_all(chunk.into_iter().map(|(pool_id, pool_address)| {
+                load_pool(pool_id, pool_address, vault_address, network, client.clone())
+            }))
+            .await;
+
+            return pools
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await;
+
+    for pool in pools.into_iter().filter_map(|x| x.1) {
+        result.append(&mut register_pool(pool, exchange));
+    }
+
+    return Ok(result);
+}
+
+#[inline(always)]
+fn register_pool(pool: BalancerPoolState, exchange: &'static Exchange) -> Vec<Market> {
+    let mut pools = BALANCER_POOLS.write().unwrap();
+    let id = pools.len();
+
+    // fee in the same 1/10000 unit as the other protocols, informative only
+    let fee = (pool.swap_fee / U256::exp10(14)).as_u32() as i32;
+
+    let markets = (0..pool.coins.len())
+        .tuple_combinations()
+        .map(|(coin_0, coin_1)| {
+            Market::from_pool(
+                pool.address,
+                [pool.coins[coin_0], pool.coins[coin_1]],
+                fee,
+                exchange.protocol,
+                MarketPool {
+                    id,
+                    coins: [coin_0, coin_1],
+                },
+            )
+        })
+        .collect();
+
+    pools.push(pool);
+    return markets;
+}
+
+// `Ok(None)` for pools that can't be tracked, errors are those of calls worth retrying
+#[inline(always)]
+async fn load_pool(
+    pool_id: [u8; 32],
+    address: Address,
+    vault: Address,
+    network: &'static Network,
+    client: Arc<RuntimeClient>,
+) -> Result<Option<BalancerPoolState>, ContractError<RuntimeClient>> {
+    let vault_contract: BalancerVaultContract = BalancerVault::new(vault, client.clone());
+    let pool_contract: BalancerPoolContract = BalancerPool::new(address, client);
+
+    let (vault_tokens, _, _) = vault_contract.get_pool_tokens(pool_id).call().await?;
+
+    let mut coins: Vec<&'static Token> = vec![];
+    let mut vault_indices: Vec<usize> = vec![];
+
+    for (index, token_address) in vault_tokens.iter().enumerate() {
+        // composable pools list their own BPT as a pool token
+        if *token_address == address {
+            continue;
+        }
+
+        let token = network
+            .tokens
+            .iter()
+            .find(|x| x.contract_address == *token_address && x.decimals <= 18);
+
+        match token {
+            Some(token) => {
+                coins.push(token);
+                vault_indices.push(index);
+            }
+            None => return Ok(None),
+        }
+    }
+
+    if coins.len() < 2 {
+        return Ok(None);
+    }
+
+    let mut pool = BalancerPoolState {
+        pool_id,
+        address,
+        vault,
+        kind: BalancerPoolKind::Weighted,
+        balances: vec![U256::zero(); coins.len()],
+        scaling_factors: coins
+            .iter()
+            .map(|x| U256::exp10(18 - x.decimals as usize) * U256::exp10(18))
+            .collect(),
+        coins,
+        vault_indices,
+        swap_fee: U256::zero(),
+        weights: vec![],
+        amp: U256::zero(),
+    };
+
+    // the pool type shows in the views it reverts on
+    match pool_contract.get_normalized_weights().call().await {
+        Ok(weights) => {
+            if weights.len() != vault_tokens.len() {
+                return Ok(None);
+            }
+
+            pool.weights = pool.vault_indices.iter().map(|x| weights[*x]).collect();
+        }
+        Err(err) if is_transient_error(&err) => return Err(err),
+        Err(_) => match pool_contract.get_amplification_parameter().call().await {
+            Ok(_) => pool.kind = BalancerPoolKind::ComposableStable,
+            Err(err) if is_transient_error(&err) => return Err(err),
+            Err(_) => return Ok(None),
+        },
+    }
+
+    return refresh_pool(
+        pool,
+        &vault_contract,
+        &pool_contract,
+        BlockNumber::Latest.into(),
+    )
+    .await
+    .map(Some);
+}
+
+#[inline(always)]
+async fn refresh_pool(
+    mut pool: BalancerPoolState,
+    vault_contract: &BalancerVaultContract,
+    pool_contract: &BalancerPoolContract,
+    block: BlockId,
+) -> Result<BalancerPoolState, ContractError<RuntimeClient>> {
+    let (_, balances, _) = vault_contract
+        .get_pool_tokens(pool.pool_id)
+        .block(block)
+        .call()
+        .await?;
+
+    pool.balances = pool.vault_indices.iter().map(|x| balances[*x]).collect();
+    pool.swap_fee = pool_contract
+        .get_swap_fee_percentage()
+        .block(block)
+        .call()
+        .await?;
+
+    if pool.kind == BalancerPoolKind::ComposableStable {
+        let (amp, _, _) = pool_contract
+            .get_amplification_parameter()
+            .block(block)
+            .call()
+            .await?;
+        pool.amp = amp;
+
+        // scaling factors include the token rates, which move over time
+        if let Ok(scaling_factors) = pool_contract
+            .get_scaling_factors()
+            .block(block)
+            .call()
+            .await
+        {
+            pool.scaling_factors = pool
+                .vault_indices
+                .iter()
+                .map(|x| scaling_factors[*x])
+                .collect();
+        }
+    }
+
+    return Ok(pool);
+}
+
+#[inline(always)]
+pub fn get_cached_reserves(market: &Market) -> Option<Reserves> {
+    let market_pool = market.pool?;
+    let pools = BALANCER_POOLS.read().unwrap();
+    let pool = pools.get(market_pool.id)?;
+
+    return Some((
+        pool.balances[market_pool.coins[0]],
+        pool.balances[market_pool.coins[1]],
+    ));
+}
+
+// Vault balances at `block`, failed pools are retried and dropped from the result once the
+// attempts run out
+#[inline(always)]
+pub async fn get_market_reserves(
+    markets: Vec<&'static OrgValue<Market>>,
+    block: BlockId,
+    runtime_cache: &'static RuntimeCache,
+) -> OrganizedList<Reserves> {
+    let mut join_set: JoinSet<Option<(usize, BalancerPoolState)>> = JoinSet::new();
+
+    let pool_ids: Vec<usize> = markets
+        .iter()
+        .filter_map(|x| x.value.pool)
+        .map(|x| x.id)
+        .unique()
+        .collect();
+
+    for pool_id in pool_ids {
+        let pool = BALANCER_POOLS.read().unwrap()[pool_id].clone();
+
+        join_set.spawn(async move {
+            let client = runtime_cache.client.clone();
+            let vault_contract = BalancerVault::new(pool.vault, client.clone());
+            let pool_contract = BalancerPool::new(pool.address, client);
+
+            let pool = call_with_retries("balancer_pool_state", || {
+                refresh_pool(pool.clone(), &vault_contract, &pool_contract, block)
+            })
+            .await;
+
+            return pool.ok().map(|x| (pool_id, x));
+        });
+    }
+
+    let mut refreshed_ids: Vec<usize> = vec![];
+    while let Some(Ok(result)) = join_set.join_next().await {
+        if let Some((pool_id, pool)) = result {
+            BALANCER_POOLS.write().unwrap()[pool_id] = pool;
+            refreshed_ids.push(pool_id);
+        }
+    }
+
+    let mut res: OrganizedList<Reserves> = OrganizedList::new();
+    for market in markets {
+        if !market
+            .value
+            .pool
+            .map_or(false, |x| refreshed_ids.contains(&x.id))
+        {
+            continue;
+        }
+
+        if let Some(reserves) = get_cached_reserves(&market.value) {
+            res.add_pair(OrgValue {
+                id: market.id,
+                value: reserves,
+            });
+        }
+    }
+
+    res.sort();
+    return res;
+}
+
+// Mirrors the pools' `onSwap` for GIVEN_IN: the fee is taken before upscaling, the output is
+// downscaled rounding down
+#[inline(always)]
+pub fn calculate_amount_out(
+    market: &Market,
+    reserves: &Reserves,
+    input_amount: &U256,
+    zero_for_one: bool,
+) -> U256 {
+    if let Some(market_pool) = market.pool {
+        let pools = BALANCER_POOLS.read().unwrap();
+
+        if let Some(pool) = pools.get(market_pool.id) {
+            let (i, j) = if zero_for_one {
+                (market_pool.coins[0], market_pool.coins[1])
+            } else {
+                (market_pool.coins[1], market_pool.coins[0])
+            };
+
+            let mut balances = pool.balances.clone();
+            balances[i] = reserves.0;
+            balances[j] = reserves.1;
+
+            let amount_in = *input_amount - math::mul_up(*input_amount, pool.swap_fee);
+            let amount_in = math::mul_down(amount_in, pool.scaling_factors[i]);
+            let balances: Vec<U256> = balances
+                .iter()
+                .zip(&pool.scaling_factors)
+                .map(|(balance, scaling_factor)| math::mul_down(*balance, *scaling_factor))
+                .collect();
+
+            let amount_out = match pool.kind {
+                BalancerPoolKind::Weighted => math::weighted_out_given_in(
+                    balances[i],
+                    pool.weights[i],
+                    balances[j],
+                    pool.weights[j],
+                    amount_in,
+                ),
+                BalancerPoolKind::ComposableStable => {
+                    math::stable_out_given_in(pool.amp, &balances, i, j, amount_in)
+                }
+            };
+
+            if let Some(amount_out) = amount_out {
+                return math::div_down(amount_out, pool.scaling_factors[j]);
+            }
+        }
+    }
+
+    return U256::zero();
+}
+
+#[inline(always)]
+pub fn populate_swap(swap: &SwapLog, recipient: &H160, sender: &H160) -> Result<Bytes, AbiError> {
+    let market_pool = swap.market.value.pool.ok_or(AbiError::WrongSelector)?;
+    let pool_id = BALANCER_POOLS
+        .read()
+        .unwrap()
+        .get(market_pool.id)
+        .ok_or(AbiError::WrongSelector)?
+        .pool_id;
+
+    let tokens = swap.market.value.tokens;
+    let (asset_in, asset_out) = if swap.zero_for_one() {
+        (tokens[0].contract_address, tokens[1].contract_address)
+    } else {
+        (tokens[1].contract_address, tokens[0].contract_address)
+    };
+
+    let single_swap = AbiToken::Tuple(vec![
+        AbiToken::FixedBytes(pool_id.to_vec()),
+        AbiToken::Uint(U256::from(GIVEN_IN)),
+        AbiToken::Address(asset_in),
+        AbiToken::Address(asset_out),
+        AbiToken::Uint(swap.amount_in),
+        AbiToken::Bytes(vec![]),
+    ]);
+
+    let fund_management = AbiToken::Tuple(vec![
+        AbiToken::Address(*sender),
+        AbiToken::Bool(false),
+        AbiToken::Address(*recipient),
+        AbiToken::Bool(false),
+    ]);
+
+    return Ok(VAULT_SWAP_METHOD
+        .encode_input(&[
+            single_swap,
+            fund_management,
+            AbiToken::Uint(swap.amount_out()),
+            AbiToken::Uint(U256::MAX),
+        ])?
+        .into());
+}
+
+#[inline(always)]
+pub fn get_event_topics() -> Vec<H256> {
+    return vec![balancer_vault::SwapFilter::signature()];
+}
+
+// The vault emits the swap, the pool is resolved through the pool id
+#[inline(always)]
+pub fn parse_balance_changes(
+    logs: Vec<&TransactionLog>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    let mut result: Vec<BalanceChange> = vec![];
+
+    for transaction_log in logs {
+        if let Ok(filters) =
+            ethers::contract::decode_logs::<balancer_vault::SwapFilter>(&[transaction_log
+                .raw
+                .clone()])
+        {
+            for swap in filters {
+                let pool_lookup = BALANCER_POOLS
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .find_position(|x| x.pool_id == swap.pool_id)
+                    .map(|(id, pool)| {
+                        let position = |address: Address| {
+                            pool.coins.iter().position(|x| x.contract_address == address)
+                        };
+                        (id, position(swap.token_in), position(swap.token_out))
+                    });
+
+                let amounts = (
+                    u128::try_from(swap.amount_in),
+                    u128::try_from(swap.amount_out),
+                );
+
+                if let (
+                    Some((pool_id, Some(coin_in), Some(coin_out))),
+                    (Ok(amount_in), Ok(amount_out)),
+                ) = (pool_lookup, amounts)
+                {
+                    result.append(&mut super::get_pool_balance_changes(
+                        runtime_cache.markets.iter().filter(|x| {
+                            x.value.protocol == Protocol::BalancerV2
+                                && x.value.pool.map(|pool| pool.id) == Some(pool_id)
+                        }),
+                        (coin_in, amount_in),
+                        (coin_out, amount_out),
+                    ));
+                }
+            }
+        }
+    }
+
+    return result;
+}
@@ -0,0 +1,35 @@
+use ethers::prelude::*;
+
+use crate::{env::types::RuntimeClient, types::Token};
+
+abigen!(BalancerVault, "src/exchanges/balancer/_vault.json");
+abigen!(BalancerPool, "src/exchanges/balancer/_pool.json");
+
+pub type BalancerVaultContract = BalancerVault<RuntimeClient>;
+pub type BalancerPoolContract = BalancerPool<RuntimeClient>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BalancerPoolKind {
+    Weighted,
+    ComposableStable,
+}
+
+#[derive(Clone, Debug)]
+pub struct BalancerPoolState {
+    pub pool_id: [u8; 32],
+    pub address: Address,
+    pub vault: Address,
+    pub kind: BalancerPoolKind,
+    pub coins: Vec<&'static Token>,
+    // positions of the coins in the vault's token list, which includes the BPT for composable pools
+    pub vault_indices: Vec<usize>,
+    pub balances: Vec<U256>,
+    pub scaling_factors: Vec<U256>,
+    pub swap_fee: U256,
+
+    // weighted only
+    pub weights: Vec<U256>,
+
+    // composable stable only, includes AMP_PRECISION
+    pub amp: U256,
+}
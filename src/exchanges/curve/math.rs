@@ -0,0 +1,459 @@
+use ethers::types::U256;
+
+use super::types::StableSwapVersion;
+
+// Integer math mirroring the Vyper implementations of the Curve pools, every division rounds
+// down exactly like the on-chain code so quotes match `get_dy`
+
+const MAX_ITERATIONS: usize = 255;
+const A_PRECISION: u64 = 100;
+const A_MULTIPLIER: u64 = 10000;
+const FEE_DENOMINATOR: u64 = 10_000_000_000;
+const PRECISION: u64 = 1_000_000_000_000_000_000;
+
+#[inline(always)]
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        return a - b;
+    }
+
+    return b - a;
+}
+
+/// StableSwap invariant `D` for normalized balances, `amp` includes `A_PRECISION`. Stableswap-ng
+/// pools divide `D_P` by `N**N` once instead of by `N` with every coin.
+pub fn stable_get_d(xp: &[U256], amp: U256, version: StableSwapVersion) -> Option<U256> {
+    let n = U256::from(xp.len());
+    let a_precision = U256::from(A_PRECISION);
+    let sum: U256 = xp.iter().fold(U256::zero(), |acc, x| acc + x);
+
+    if sum.is_zero() {
+        return Some(U256::zero());
+    }
+
+    let ann = amp * n;
+    let mut d = sum;
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut d_p = d;
+        if version == StableSwapVersion::Ng {
+            for x in xp {
+                d_p = (d_p * d).checked_div(*x)?;
+            }
+            d_p /= n.pow(n);
+        } else {
+            for x in xp {
+                d_p = d_p * d / x.checked_mul(n).filter(|x| !x.is_zero())?;
+            }
+        }
+
+        let d_prev = d;
+        d = (ann * sum / a_precision + d_p * n) * d
+            / ((ann.checked_sub(a_precision)?) * d / a_precision + (n + 1) * d_p);
+
+        if abs_diff(d, d_prev) <= U256::one() {
+            return Some(d);
+        }
+    }
+
+    return None;
+}
+
+/// Balance of coin `j` that keeps `D` constant after coin `i` is set to `x`
+pub fn stable_get_y(i: usize, j: usize, x: U256, xp: &[U256], amp: U256, d: U256) -> Option<U256> {
+    let n = U256::from(xp.len());
+    let a_precision = U256::from(A_PRECISION);
+    let ann = amp * n;
+
+    let mut c = d;
+    let mut sum = U256::zero();
+
+    for (k, balance) in xp.iter().enumerate() {
+        let _x = if k == i {
+            x
+        } else if k != j {
+            *balance
+        } else {
+            continue;
+        };
+
+        sum += _x;
+        c = c * d / _x.checked_mul(n).filter(|x| !x.is_zero())?;
+    }
+
+    c = (c * d * a_precision).checked_div(ann * n)?;
+    let b = sum + (d * a_precision).checked_div(ann)?;
+    let mut y = d;
+
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        y = (y * y + c) / (y + y + b).checked_sub(d).filter(|x| !x.is_zero())?;
+
+        if abs_diff(y, y_prev) <= U256::one() {
+            return Some(y);
+        }
+    }
+
+    return None;
+}
+
+/// Dynamic fee of stableswap-ng pools, plain pools pass a zero `offpeg_fee_multiplier`
+pub fn stable_dynamic_fee(xpi: U256, xpj: U256, fee: U256, offpeg_fee_multiplier: U256) -> U256 {
+    let fee_denominator = U256::from(FEE_DENOMINATOR);
+
+    if offpeg_fee_multiplier <= fee_denominator {
+        return fee;
+    }
+
+    let xps2 = (xpi + xpj) * (xpi + xpj);
+    if xps2.is_zero() {
+        return fee;
+    }
+
+    return offpeg_fee_multiplier * fee
+        / ((offpeg_fee_multiplier - fee_denominator) * 4 * xpi * xpj / xps2 + fee_denominator);
+}
+
+/// Output of `exchange(i, j, dx)` on a StableSwap pool, `precisions` scales each coin to 18
+/// decimals
+#[allow(clippy::too_many_arguments)]
+pub fn stable_get_dy(
+    i: usize,
+    j: usize,
+    dx: U256,
+    balances: &[U256],
+    precisions: &[U256],
+    amp: U256,
+    fee: U256,
+    offpeg_fee_multiplier: U256,
+    version: StableSwapVersion,
+) -> Option<U256> {
+    let xp: Vec<U256> = balances
+        .iter()
+        .zip(precisions)
+        .map(|(balance, precision)| balance * precision)
+        .collect();
+
+    let x = xp[i] + dx * precisions[i];
+    let d = stable_get_d(&xp, amp, version)?;
+    let y = stable_get_y(i, j, x, &xp, amp, d)?;
+    let dy = xp[j].checked_sub(y)?.checked_sub(U256::one())?;
+
+    if version == StableSwapVersion::Plain {
+        let dy = dy.checked_div(precisions[j])?;
+        return Some(dy - fee * dy / U256::from(FEE_DENOMINATOR));
+    }
+
+    let fee = stable_dynamic_fee((xp[i] + x) / 2, (xp[j] + y) / 2, fee, offpeg_fee_multiplier);
+
+    return (dy - fee * dy / U256::from(FEE_DENOMINATOR)).checked_div(precisions[j]);
+}
+
+/// Solves the CryptoSwap invariant for coin `i`, `ann` is `A * N**N * A_MULTIPLIER` as returned
+/// by `A()`
+pub fn crypto_newton_y(ann: U256, gamma: U256, x: &[U256], d: U256, i: usize) -> Option<U256> {
+    let n_coins = x.len();
+    let n = U256::from(n_coins);
+    let precision = U256::from(PRECISION);
+
+    let mut y = d.checked_div(n)?;
+    let mut k0_i = precision;
+    let mut s_i = U256::zero();
+
+    let mut x_sorted = x.to_vec();
+    x_sorted[i] = U256::zero();
+    x_sorted.sort_unstable_by(|a, b| b.cmp(a));
+
+    let convergence_limit = (x_sorted[0] / 100_000_000_000_000u64)
+        .max(d / 100_000_000_000_000u64)
+        .max(U256::from(100u8));
+
+    for j in 2..(n_coins + 1) {
+        let _x = x_sorted[n_coins - j];
+        y = y * d / _x.checked_mul(n).filter(|x| !x.is_zero())?;
+        s_i += _x;
+    }
+
+    for _x in x_sorted.iter().take(n_coins - 1) {
+        k0_i = (k0_i * _x * n).checked_div(d)?;
+    }
+
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+
+        let k0 = (k0_i * y * n).checked_div(d)?;
+        let s = s_i + y;
+
+        let mut g1k0 = gamma + precision;
+        if g1k0 > k0 {
+            g1k0 = g1k0 - k0 + 1;
+        } else {
+            g1k0 = k0 - g1k0 + 1;
+        }
+
+        let mul1 = ((precision * d).checked_div(gamma)? * g1k0).checked_div(gamma)? * g1k0;
+        let mul1 = (mul1 * A_MULTIPLIER).checked_div(ann)?;
+        let mul2 = precision + precision * 2 * k0 / g1k0;
+
+        let mut yfprime = precision * y + s * mul2 + mul1;
+        let dyfprime = d * mul2;
+
+        if yfprime < dyfprime {
+            y = y_prev / 2;
+            continue;
+        } else {
+            yfprime -= dyfprime;
+        }
+
+        let fprime = yfprime.checked_div(y)?;
+        if fprime.is_zero() || k0.is_zero() {
+            return None;
+        }
+
+        let mut y_minus = mul1 / fprime;
+        let y_plus = (yfprime + precision * d) / fprime + y_minus * precision / k0;
+        y_minus += precision * s / fprime;
+
+        if y_plus < y_minus {
+            y = y_prev / 2;
+        } else {
+            y = y_plus - y_minus;
+        }
+
+        if abs_diff(y, y_prev) < convergence_limit.max(y / 100_000_000_000_000u64) {
+            let frac = (y * precision).checked_div(d)?;
+            if frac <= U256::from(10_000_000_000_000_000u64) - 1
+                || frac >= U256::from(100_000_000_000_000_000_000u128) + 1
+            {
+                return None;
+            }
+
+            return Some(y);
+        }
+    }
+
+    return None;
+}
+
+/// Fee of a CryptoSwap pool in `FEE_DENOMINATOR` units for the balances after a trade
+pub fn crypto_fee(xp: &[U256], mid_fee: U256, out_fee: U256, fee_gamma: U256) -> U256 {
+    let precision = U256::from(PRECISION);
+    let n = U256::from(xp.len());
+    let sum: U256 = xp.iter().fold(U256::zero(), |acc, x| acc + x);
+
+    if sum.is_zero() {
+        return out_fee;
+    }
+
+    let mut k = precision;
+    for x in xp {
+        k = k * n * x / sum;
+    }
+
+    if !fee_gamma.is_zero() {
+        k = fee_gamma * precision / (fee_gamma + precision - k);
+    }
+
+    return (mid_fee * k + out_fee * (precision - k)) / precision;
+}
+
+/// Output of `exchange(i, j, dx)` on a CryptoSwap pool, `price_scale` holds the `N - 1` scales
+/// of coins `1..N` against coin `0`
+#[allow(clippy::too_many_arguments)]
+pub fn crypto_get_dy(
+    i: usize,
+    j: usize,
+    dx: U256,
+    balances: &[U256],
+    precisions: &[U256],
+    price_scale: &[U256],
+    ann: U256,
+    gamma: U256,
+    d: U256,
+    fees: (U256, U256, U256),
+) -> Option<U256> {
+    let precision = U256::from(PRECISION);
+    let mut xp: Vec<U256> = balances.to_vec();
+    xp[i] += dx;
+    xp[0] *= precisions[0];
+
+    for k in 1..xp.len() {
+        xp[k] = xp[k] * price_scale[k - 1] * precisions[k] / precision;
+    }
+
+    let y = crypto_newton_y(ann, gamma, &xp, d, j)?;
+    let mut dy = xp[j].checked_sub(y)?.checked_sub(U256::one())?;
+    xp[j] = y;
+
+    if j > 0 {
+        dy = (dy * precision).checked_div(price_scale[j - 1])?;
+    }
+    dy = dy.checked_div(precisions[j])?;
+
+    let (mid_fee, out_fee, fee_gamma) = fees;
+    let fee = crypto_fee(&xp, mid_fee, out_fee, fee_gamma);
+
+    return Some(dy - fee * dy / U256::from(FEE_DENOMINATOR));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expected outputs are those of the pools' Vyper `get_dy` for the same state: 2pool
+    // (StableSwap2), stableswap-ng (its views contract) and tricrypto (CurveCryptoSwap)
+
+    fn get_precisions(decimals: &[u32]) -> Vec<U256> {
+        return decimals
+            .iter()
+            .map(|x| U256::exp10(18 - *x as usize))
+            .collect();
+    }
+
+    #[test]
+    fn stable_get_dy_matches_2pool() {
+        // USDC.e / USDT, A of 2000 and a 0.01% fee
+        let balances = [
+            U256::from(12_345_678_901_234u64),
+            U256::from(11_876_543_210_987u64),
+        ];
+        let precisions = get_precisions(&[6, 6]);
+
+        for (i, j, dx, dy) in [
+            (0, 1, 100_000_000u64, 99_988_063u64),
+            (0, 1, 1_000_000_000_000, 999_838_728_365),
+            (1, 0, 2_500_000_000_000, 2_499_532_871_191),
+        ] {
+            let result = stable_get_dy(
+                i,
+                j,
+                U256::from(dx),
+                &balances,
+                &precisions,
+                U256::from(200_000u64),
+                U256::from(1_000_000u64),
+                U256::zero(),
+                StableSwapVersion::Plain,
+            );
+
+            assert_eq!(result, Some(U256::from(dy)));
+        }
+    }
+
+    #[test]
+    fn stable_get_dy_matches_ng_pool() {
+        // USDC / crvUSD, A of 1000, a 0.01% fee and an off-peg multiplier of 5
+        let balances = [
+            U256::from(3_210_987_654_321u64),
+            U256::from_dec_str("3456789012345678901234567").unwrap(),
+        ];
+        let precisions = get_precisions(&[6, 18]);
+
+        for (i, j, dx, dy) in [
+            (0, 1, "1000000000", "999973440068005172984"),
+            (1, 0, "500000000000000000000000", "499833599092"),
+            (0, 1, "2000000000000", "1998250717896694713419655"),
+        ] {
+            let result = stable_get_dy(
+                i,
+                j,
+                U256::from_dec_str(dx).unwrap(),
+                &balances,
+                &precisions,
+                U256::from(100_000u64),
+                U256::from(1_000_000u64),
+                U256::from(50_000_000_000u64),
+                StableSwapVersion::Ng,
+            );
+
+            assert_eq!(result, Some(U256::from_dec_str(dy).unwrap()));
+        }
+    }
+
+    #[test]
+    fn crypto_get_dy_matches_tricrypto() {
+        // USDT / WBTC / WETH with the parameters of tricrypto2
+        let balances = [
+            U256::from(5_012_345_678_901u64),
+            U256::from(18_512_345_678u64),
+            U256::from_dec_str("2750500000000000000000").unwrap(),
+        ];
+        let precisions = get_precisions(&[6, 8, 18]);
+        let price_scale = [U256::exp10(18) * 27_000, U256::exp10(18) * 1_820];
+        let d = U256::from_dec_str("15016588784047267146506075").unwrap();
+        let fees = (
+            U256::from(3_000_000u64),
+            U256::from(30_000_000u64),
+            U256::from(500_000_000_000_000u64),
+        );
+
+        for (i, j, dx, dy) in [
+            (0, 1, "10000000000", "37011520"),
+            (2, 0, "50000000000000000000", "89894920240"),
+            (1, 2, "500000000", "72604244735053273263"),
+        ] {
+            let result = crypto_get_dy(
+                i,
+                j,
+                U256::from_dec_str(dx).unwrap(),
+                &balances,
+                &precisions,
+                &price_scale,
+                U256::from(1_707_629u64),
+                U256::from(11_809_167_828_997u64),
+                d,
+                fees,
+            );
+
+            assert_eq!(result, Some(U256::from_dec_str(dy).unwrap()));
+        }
+    }
+
+    #[test]
+    fn zero_parameters_are_not_quoted() {
+        let balances = [U256::exp10(24), U256::exp10(24)];
+        let precisions = get_precisions(&[18, 18]);
+        let price_scale = [U256::exp10(18)];
+        let fees = (U256::zero(), U256::zero(), U256::zero());
+        let dx = U256::exp10(18);
+
+        let stable_get_dy_with = |amp: u64| {
+            return stable_get_dy(
+                0,
+                1,
+                dx,
+                &balances,
+                &precisions,
+                U256::from(amp),
+                U256::zero(),
+                U256::zero(),
+                StableSwapVersion::Plain,
+            );
+        };
+        let crypto_get_dy_with = |ann: u64, gamma: u64, d: U256| {
+            return crypto_get_dy(
+                0,
+                1,
+                dx,
+                &balances,
+                &precisions,
+                &price_scale,
+                U256::from(ann),
+                U256::from(gamma),
+                d,
+                fees,
+            );
+        };
+
+        let d = U256::exp10(24) * 2;
+        assert!(stable_get_dy_with(200_000).is_some());
+        assert_eq!(stable_get_dy_with(0), None);
+        assert!(crypto_get_dy_with(400_000, 145_000_000_000_000, d).is_some());
+        assert_eq!(crypto_get_dy_with(0, 145_000_000_000_000, d), None);
+        assert_eq!(crypto_get_dy_with(400_000, 0, d), None);
+        assert_eq!(
+            crypto_get_dy_with(400_000, 145_000_000_000_000, U256::zero()),
+            None
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use ethers::{
+    abi::{AbiParser, Function},
+    prelude::*,
+};
+use futures::future::join_all;
+use itertools::Itertools;
+use std::{
+    io::{Error, ErrorKind},
+    sync::{Arc, RwLock},
+};
+use tokio::task::JoinSet;
+
+use self::types::{
+    curve_crypto_pool, curve_stable_pool, CurveCryptoPool, CurveCryptoPoolContract, CurvePool,
+    CurvePoolKind, CurveRegistry, CurveRegistryContract, CurveStablePool, CurveStablePoolContract,
+    StableSwapVersion,
+};
+
+use super::types::{Exchange, MarketPool};
+use crate::{
+    env::{query::is_transient_error, types::RuntimeClient, RuntimeCache, RuntimeConfig},
+    networks::Network,
+    rpc_batching::{call_batched, call_with_retries},
+    types::{
+        market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, SwapLog, Token,
+        TransactionLog,
+    },
+};
+
+mod math;
+mod types;
+
+const MAX_COINS: usize = 8;
+
+lazy_static! {
+    static ref STABLE_EXCHANGE_METHOD: Function = AbiParser::default()
+        .parse_function("exchange(int128,int128,uint256,uint256)")
+        .unwrap();
+    static ref CRYPTO_EXCHANGE_METHOD: Function = AbiParser::default()
+        .parse_function("exchange(uint256,uint256,uint256,uint256)")
+        .unwrap();
+    static ref CURVE_POOLS: RwLock<Vec<CurvePool>> = RwLock::new(vec![]);
+}
+
+#[inline(always)]
+pub fn init_handler() {
+    let _ = { (&STABLE_EXCHANGE_METHOD.name, &CRYPTO_EXCHANGE_METHOD.name) };
+}
+
+#[inline(always)]
+pub async fn get_markets(
+    exchange: &'static Exchange,
+    network: &'static Network,
+    runtime_cache: &RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> Result<Vec<Market>, Error> {
+    let registry_contract: CurveRegistryContract =
+        CurveRegistry::new(exchange.factory_address, runtime_cache.client.clone());
+    let mut result: Vec<Market> = vec![];
+
+    let registry_contract = &registry_contract;
+    let pool_count = call_with_retries("pool_count", || async move {
+        return registry_contract.pool_count().call().await;
+    })
+    .await
+    .map_err(|err| Error::new(ErrorKind::ConnectionRefused, err))?;
+
+    // one call per index, several chunks of them are in flight
+    let pool_addresses: Vec<Address> = call_batched(
+        "pool_list",
+        runtime_config.small_chunk_size,
+        (0..pool_count.as_u64()).collect_vec(),
+        |chunk| async move {
+            let responses = join_all(chunk.into_iter().map(|i| async move {
+                return registry_contract.pool_list(U256::from(i)).call().await;
+            }))
+            .await;
+
+            return responses
+                .into_iter()
+                .collect::<Result<Vec<Address>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await
+    .into_iter()
+    .map(|x| x.1)
+    .collect();
+
+    let client = &runtime_cache.client;
+    let pools = call_batched(
+        "curve_pool",
+        runtime_config.small_chunk_size,
+        pool_addresses,
+        |chunk| async move {
+            let pools = join_all(
+                chunk
+                    .into_iter()
+                    .map(|address| load_pool(address, network, client.clone())),
+            )
+            .await;
+
+            return pools
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await;
+
+    for pool in pools.into_iter().filter_map(|x| x.1) {
+        result.append(&mut register_pool(pool, exchange));
+    }
+
+    return Ok(result);
+}
+
+// Expands a pool into one market per coin pair so every pair can be used as a route hop
+#[inline(always)]
+fn register_pool(pool: CurvePool, exchange: &'static Exchange) -> Vec<Market> {
+    let mut pools = CURVE_POOLS.write().unwrap();
+    let id = pools.len();
+
+    // registries and factories can list the same pool
+    if pools.iter().any(|x| x.address == pool.address) {
+        return vec![];
+    }
+
+    // fee in the same 1/10000 unit as the other protocols, informative only
+    let fee = (pool.fee / U256::from(1_000_000u64)).as_u32() as i32;
+
+    let markets = (0..pool.coins.len())
+        .tuple_combinations()
+        .map(|(coin_0, coin_1)| {
+            Market::from_pool(
+                pool.address,
+                [pool.coins[coin_0], pool.coins[coin_1]],
+                fee,
+                exchange.protocol,
+                MarketPool {
+                    id,
+                    coins: [coin_0, coin_1],
+                },
+            )
+        })
+        .collect();
+
+    pools.push(pool);
+    return markets;
+}
+
+// `Ok(None)` for pools that can't be tracked, errors are those of calls worth retrying
+#[inline(always)]
+async fn load_pool(
+    address: Address,
+    network: &'static Network,
+    client: Arc<RuntimeClient>,
+) -> Result<Option<CurvePool>, ContractError<RuntimeClient>> {
+    let stable_contract: CurveStablePoolContract = CurveStablePool::new(address, client.clone());
+    let mut coins: Vec<&'static Token> = vec![];
+
+    for i in 0..MAX_COINS {
+        match stable_contract.coins(U256::from(i)).call().await {
+            Ok(coin_address) => {
+                // every coin has to be tracked, the invariant depends on all balances
+                let token = network
+                    .tokens
+                    .iter()
+                    .find(|x| x.contract_address == coin_address && x.decimals <= 18);
+
+                match token {
+                    Some(token) => coins.push(token),
+                    None => return Ok(None),
+                }
+            }
+            // past the last coin the view reverts
+            Err(err) if is_transient_error(&err) => return Err(err),
+            Err(_) => break,
+        }
+    }
+
+    if coins.len() < 2 {
+        return Ok(None);
+    }
+
+    let precisions = coins
+        .iter()
+        .map(|x| U256::exp10(18 - x.decimals as usize))
+        .collect();
+
+    let crypto_contract: CurveCryptoPoolContract = CurveCryptoPool::new(address, client);
+    let kind = match crypto_contract.gamma().call().await {
+        Ok(_) => CurvePoolKind::CryptoSwap,
+        Err(err) if is_transient_error(&err) => return Err(err),
+        Err(_) => CurvePoolKind::StableSwap,
+    };
+
+    let mut pool = CurvePool {
+        address,
+        kind,
+        version: StableSwapVersion::Plain,
+        balances: vec![U256::zero(); coins.len()],
+        coins,
+        precisions,
+        amp: U256::zero(),
+        fee: U256::zero(),
+        offpeg_fee_multiplier: U256::zero(),
+        gamma: U256::zero(),
+        d: U256::zero(),
+        price_scale: vec![],
+        fee_gamma: U256::zero(),
+        out_fee: U256::zero(),
+    };
+
+    match kind {
+        CurvePoolKind::StableSwap => {
+            pool.fee = stable_contract.fee().call().await?;
+            // only stableswap-ng pools have an off-peg fee
+            match stable_contract.offpeg_fee_multiplier().call().await {
+                Ok(offpeg_fee_multiplier) => {
+                    pool.offpeg_fee_multiplier = offpeg_fee_multiplier;
+                    pool.version = StableSwapVersion::Ng;
+                }
+                Err(err) if is_transient_error(&err) => return Err(err),
+                Err(_) => {}
+            }
+        }
+        CurvePoolKind::CryptoSwap => {
+            pool.fee = crypto_contract.mid_fee().call().await?;
+            pool.out_fee = crypto_contract.out_fee().call().await?;
+            pool.fee_gamma = crypto_contract.fee_gamma().call().await?;
+            pool.gamma = crypto_contract.gamma().call().await?;
+
+            // the invariant divides by gamma, a pool without one can't be quoted
+            if pool.gamma.is_zero() {
+                return Ok(None);
+            }
+        }
+    }
+
+    return refresh_pool(
+        pool,
+        &stable_contract,
+        &crypto_contract,
+        BlockNumber::Latest.into(),
+    )
+    .await;
+}
+
+// Reads the state that changes with every exchange or parameter ramp, `Ok(None)` when the pool
+// can't be quoted in that state
+#[inline(always)]
+async fn refresh_pool(
+    mut pool: CurvePool,
+    stable_contract: &CurveStablePoolContract,
+    crypto_contract: &CurveCryptoPoolContract,
+    block: BlockId,
+) -> Result<Option<CurvePool>, ContractError<RuntimeClient>> {
+    for i in 0..pool.coins.len() {
+        pool.balances[i] = stable_contract
+            .balances(U256::from(i))
+            .block(block)
+            .call()
+            .await?;
+    }
+
+    match pool.kind {
+        CurvePoolKind::StableSwap => {
+            pool.amp = match stable_contract.a_precise().block(block).call().await {
+                Ok(amp) => amp,
+                Err(err) if is_transient_error(&err) => return Err(err),
+                Err(_) => stable_contract.a().block(block).call().await? * 100,
+            };
+        }
+        CurvePoolKind::CryptoSwap => {
+            pool.amp = crypto_contract.a().block(block).call().await?;
+            pool.d = crypto_contract.d().block(block).call().await?;
+
+            if pool.d.is_zero() {
+                return Ok(None);
+            }
+
+            pool.price_scale = if pool.coins.len() == 2 {
+                vec![crypto_contract.price_scale().block(block).call().await?]
+            } else {
+                let mut price_scale = vec![];
+                for k in 0..(pool.coins.len() - 1) {
+                    price_scale.push(
+                        crypto_contract
+                            .price_scale_with_k(U256::from(k))
+                            .block(block)
+                            .call()
+                            .await?,
+                    );
+                }
+
+                price_scale
+            };
+        }
+    }
+
+    // an empty or misread amplification would make every quote divide by zero
+    if pool.amp.is_zero() {
+        return Ok(None);
+    }
+
+    return Ok(Some(pool));
+}
+
+#[inline(always)]
+pub fn get_cached_reserves(market: &Market) -> Option<Reserves> {
+    let market_pool = market.pool?;
+    let pools = CURVE_POOLS.read().unwrap();
+    let pool = pools.get(market_pool.id)?;
+
+    return Some((
+        pool.balances[market_pool.coins[0]],
+        pool.balances[market_pool.coins[1]],
+    ));
+}
+
+// Reserves of the markets at `block`. A pool is read again when a call fails, the markets of
+// pools that can't be read or quoted are left out instead of keeping the state of an earlier
+// block.
+#[inline(always)]
+pub async fn get_market_reserves(
+    markets: Vec<&'static OrgValue<Market>>,
+    block: BlockId,
+    runtime_cache: &'static RuntimeCache,
+) -> OrganizedList<Reserves> {
+    let mut join_set: JoinSet<Option<(usize, CurvePool)>> = JoinSet::new();
+
+    let pool_ids: Vec<usize> = markets
+        .iter()
+        .filter_map(|x| x.value.pool)
+        .map(|x| x.id)
+        .unique()
+        .collect();
+
+    for pool_id in pool_ids {
+        let pool = CURVE_POOLS.read().unwrap()[pool_id].clone();
+
+        join_set.spawn(async move {
+            let client = runtime_cache.client.clone();
+            let stable_contract = CurveStablePool::new(pool.address, client.clone());
+            let crypto_contract = CurveCryptoPool::new(pool.address, client);
+
+            let pool = call_with_retries("curve_pool_state", || {
+                refresh_pool(pool.clone(), &stable_contract, &crypto_contract, block)
+            })
+            .await;
+
+            return pool.ok()?.map(|x| (pool_id, x));
+        });
+    }
+
+    let mut refreshed_ids: Vec<usize> = vec![];
+    while let Some(Ok(result)) = join_set.join_next().await {
+        if let Some((pool_id, pool)) = result {
+            CURVE_POOLS.write().unwrap()[pool_id] = pool;
+            refreshed_ids.push(pool_id);
+        }
+    }
+
+    let mut res: OrganizedList<Reserves> = OrganizedList::new();
+    for market in markets {
+        if !market
+            .value
+            .pool
+            .map_or(false, |x| refreshed_ids.contains(&x.id))
+        {
+            continue;
+        }
+
+        if let Some(reserves) = get_cached_reserves(&market.value) {
+            res.add_pair(OrgValue {
+                id: market.id,
+                value: reserves,
+            });
+        }
+    }
+
+    res.sort();
+    return res;
+}
+
+// The reserve table holds the balances of the market's coin pair, the remaining coins of the
+// pool are taken from the last refreshed pool state
+#[inline(always)]
+pub fn calculate_amount_out(
+    market: &Market,
+    reserves: &Reserves,
+    input_amount: &U256,
+    zero_for_one: bool,
+) -> U256 {
+    if let Some(market_pool) = market.pool {
+        let pools = CURVE_POOLS.read().unwrap();
+
+        if let Some(pool) = pools.get(market_pool.id) {
+            let (i, j) = if zero_for_one {
+                (market_pool.coins[0], market_pool.coins[1])
+            } else {
+                (market_pool.coins[1], market_pool.coins[0])
+            };
+
+            let mut balances = pool.balances.clone();
+            balances[i] = reserves.0;
+            balances[j] = reserves.1;
+
+            let amount_out = match pool.kind {
+                CurvePoolKind::StableSwap => math::stable_get_dy(
+                    i,
+                    j,
+                    *input_amount,
+                    &balances,
+                    &pool.precisions,
+                    pool.amp,
+                    pool.fee,
+                    pool.offpeg_fee_multiplier,
+                    pool.version,
+                ),
+                CurvePoolKind::CryptoSwap => math::crypto_get_dy(
+                    i,
+                    j,
+                    *input_amount,
+                    &balances,
+                    &pool.precisions,
+                    &pool.price_scale,
+                    pool.amp,
+                    pool.gamma,
+                    pool.d,
+                    (pool.fee, pool.out_fee, pool.fee_gamma),
+                ),
+            };
+
+            return amount_out.unwrap_or_default();
+        }
+    }
+
+    return U256::zero();
+}
+
+#[inline(always)]
+pub fn populate_swap(swap: &SwapLog) -> Result<Bytes, AbiError> {
+    let market_pool = swap.market.value.pool.ok_or(AbiError::WrongSelector)?;
+    let kind = CURVE_POOLS
+        .read()
+        .unwrap()
+        .get(market_pool.id)
+        .ok_or(AbiError::WrongSelector)?
+        .kind;
+
+    let (i, j) = if swap.zero_for_one() {
+        (market_pool.coins[0], market_pool.coins[1])
+    } else {
+        (market_pool.coins[1], market_pool.coins[0])
+    };
+
+    match kind {
+        CurvePoolKind::StableSwap => {
+            return ethers::contract::encode_function_data::<curve_stable_pool::ExchangeCall>(
+                &STABLE_EXCHANGE_METHOD,
+                curve_stable_pool::ExchangeCall {
+                    i: i as i128,
+                    j: j as i128,
+                    dx: swap.amount_in,
+                    min_dy: swap.amount_out(),
+                },
+            );
+        }
+        CurvePoolKind::CryptoSwap => {
+            return ethers::contract::encode_function_data::<curve_crypto_pool::ExchangeCall>(
+                &CRYPTO_EXCHANGE_METHOD,
+                curve_crypto_pool::ExchangeCall {
+                    i: U256::from(i),
+                    j: U256::from(j),
+                    dx: swap.amount_in,
+                    min_dy: swap.amount_out(),
+                },
+            );
+        }
+    }
+}
+
+#[inline(always)]
+fn decode_token_exchange(transaction_log: &TransactionLog) -> Option<(usize, U256, usize, U256)> {
+    let raw = [transaction_log.raw.clone()];
+
+    if let Ok(filters) =
+        ethers::contract::decode_logs::<curve_stable_pool::TokenExchangeFilter>(&raw)
+    {
+        let event = filters.first()?;
+        return Some((
+            usize::try_from(event.sold_id).ok()?,
+            event.tokens_sold,
+            usize::try_from(event.bought_id).ok()?,
+            event.tokens_bought,
+        ));
+    }
+
+    if let Ok(filters) =
+        ethers::contract::decode_logs::<curve_crypto_pool::TokenExchangeFilter>(&raw)
+    {
+        let event = filters.first()?;
+        return Some((
+            usize::try_from(event.sold_id).ok()?,
+            event.tokens_sold,
+            usize::try_from(event.bought_id).ok()?,
+            event.tokens_bought,
+        ));
+    }
+
+    return None;
+}
+
+#[inline(always)]
+pub fn get_event_topics() -> Vec<H256> {
+    return vec![
+        curve_stable_pool::TokenExchangeFilter::signature(),
+        curve_crypto_pool::TokenExchangeFilter::signature(),
+    ];
+}
+
+// A single exchange moves the balances of every coin pair that contains the sold or bought coin
+#[inline(always)]
+pub fn parse_balance_changes(
+    logs: Vec<&TransactionLog>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    let mut result: Vec<BalanceChange> = vec![];
+
+    for transaction_log in logs {
+        if let Some((sold_id, tokens_sold, bought_id, tokens_bought)) =
+            decode_token_exchange(transaction_log)
+        {
+            // amounts past u128 can't be held by a balance change
+            let (tokens_sold, tokens_bought) =
+                match (u128::try_from(tokens_sold), u128::try_from(tokens_bought)) {
+                    (Ok(sold), Ok(bought)) => (sold, bought),
+                    _ => continue,
+                };
+
+            result.append(&mut super::get_pool_balance_changes(
+                runtime_cache
+                    .markets
+                    .iter()
+                    .filter(|x| x.value.contract_address == transaction_log.address),
+                (sold_id, tokens_sold),
+                (bought_id, tokens_bought),
+            ));
+        }
+    }
+
+    return result;
+}
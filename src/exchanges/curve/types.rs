@@ -0,0 +1,48 @@
+use ethers::prelude::*;
+
+use crate::{env::types::RuntimeClient, types::Token};
+
+abigen!(CurveRegistry, "src/exchanges/curve/_registry.json");
+abigen!(CurveStablePool, "src/exchanges/curve/_stable_pool.json");
+abigen!(CurveCryptoPool, "src/exchanges/curve/_crypto_pool.json");
+
+pub type CurveRegistryContract = CurveRegistry<RuntimeClient>;
+pub type CurveStablePoolContract = CurveStablePool<RuntimeClient>;
+pub type CurveCryptoPoolContract = CurveCryptoPool<RuntimeClient>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurvePoolKind {
+    StableSwap,
+    CryptoSwap,
+}
+
+// Plain pools take the fee after scaling the output down to the coin's decimals, stableswap-ng
+// pools before
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StableSwapVersion {
+    Plain,
+    Ng,
+}
+
+#[derive(Clone, Debug)]
+pub struct CurvePool {
+    pub address: Address,
+    pub kind: CurvePoolKind,
+    pub version: StableSwapVersion,
+    pub coins: Vec<&'static Token>,
+    pub precisions: Vec<U256>,
+    pub balances: Vec<U256>,
+
+    // stableswap: A * A_PRECISION, cryptoswap: A * N**N * A_MULTIPLIER
+    pub amp: U256,
+    // stableswap: fee, cryptoswap: mid_fee
+    pub fee: U256,
+    pub offpeg_fee_multiplier: U256,
+
+    // cryptoswap only
+    pub gamma: U256,
+    pub d: U256,
+    pub price_scale: Vec<U256>,
+    pub fee_gamma: U256,
+    pub out_fee: U256,
+}
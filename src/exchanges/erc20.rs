@@ -0,0 +1,245 @@
+use ethers::{
+    abi::{AbiParser, Function, Token},
+    prelude::*,
+    providers::call_raw::RawCall,
+    types::{spoof, transaction::eip2718::TypedTransaction},
+};
+use std::{collections::HashMap, sync::RwLock};
+
+use crate::{
+    env::types::RuntimeClient,
+    types::{market::Market, Token as NetworkToken},
+};
+
+const FEE_DENOMINATOR: u32 = 10000;
+
+// placed on token holders by a state override, see TransferProbe.sol and SwapProbe.sol
+abigen!(TransferProbe, "src/contracts/abi/TransferProbe.json");
+abigen!(SwapProbe, "src/contracts/abi/SwapProbe.json");
+
+lazy_static! {
+    static ref TRANSFER_METHOD: Function = AbiParser::default()
+        .parse_function("transfer(address,uint256)")
+        .unwrap();
+    static ref APPROVE_METHOD: Function = AbiParser::default()
+        .parse_function("approve(address,uint256)")
+        .unwrap();
+    static ref TRANSFER_FEES: RwLock<HashMap<H160, u32>> = RwLock::new(HashMap::new());
+}
+
+#[inline(always)]
+pub fn init_handler() {
+    let _ = { (&TRANSFER_METHOD.name, &APPROVE_METHOD.name) };
+}
+
+#[inline(always)]
+pub fn populate_transfer(to: &H160, amount: U256) -> Result<Bytes, AbiError> {
+    return Ok(TRANSFER_METHOD
+        .encode_input(&[Token::Address(*to), Token::Uint(amount)])?
+        .into());
+}
+
+#[inline(always)]
+pub fn populate_approve(spender: &H160, amount: U256) -> Result<Bytes, AbiError> {
+    return Ok(APPROVE_METHOD
+        .encode_input(&[Token::Address(*spender), Token::Uint(amount)])?
+        .into());
+}
+
+// Transfer tax in 1/10000 of the amount, declared fees take precedence over detected ones
+#[inline(always)]
+pub fn get_transfer_fee(token: &NetworkToken) -> u32 {
+    if let Some(transfer_fee) = token.transfer_fee {
+        return transfer_fee;
+    }
+
+    return TRANSFER_FEES
+        .read()
+        .unwrap()
+        .get(&token.contract_address)
+        .copied()
+        .unwrap_or_default();
+}
+
+#[inline(always)]
+pub fn set_transfer_fee(token: &NetworkToken, transfer_fee: u32) {
+    TRANSFER_FEES
+        .write()
+        .unwrap()
+        .insert(token.contract_address, transfer_fee);
+}
+
+// Amount received when `amount` of the token is transferred
+#[inline(always)]
+pub fn apply_transfer_fee(token: &NetworkToken, amount: U256) -> U256 {
+    let transfer_fee = get_transfer_fee(token);
+    if transfer_fee == 0 {
+        return amount;
+    }
+
+    return amount * (FEE_DENOMINATOR - transfer_fee.min(FEE_DENOMINATOR)) / FEE_DENOMINATOR;
+}
+
+// Runs the transfer probe on `holder`, the probe code is also placed on `recipient` for round
+// trips. Returns `None` when the simulated transfers revert.
+#[inline(always)]
+async fn call_transfer_probe(
+    token: &NetworkToken,
+    holder: H160,
+    recipient: H160,
+    amount: U256,
+    round_trip: bool,
+    client: &RuntimeClient,
+) -> Result<Option<Vec<U256>>, ProviderError> {
+    let code = TRANSFERPROBE_DEPLOYED_BYTECODE.clone();
+    let mut state = spoof::state();
+    state.account(holder).code(code.clone());
+
+    let mut arguments = vec![
+        Token::Address(token.contract_address),
+        Token::Address(recipient),
+        Token::Uint(amount),
+    ];
+
+    if round_trip {
+        state.account(recipient).code(code);
+        arguments.push(Token::Bool(true));
+    }
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(holder)
+        .data(ethers::abi::encode(&arguments))
+        .into();
+
+    match client.provider().call_raw(&tx).state(&state).await {
+        Ok(response) => {
+            return Ok(Some(
+                response.chunks_exact(32).map(U256::from_big_endian).collect(),
+            ));
+        }
+        Err(err) if RpcError::as_error_response(&err).is_some() => return Ok(None),
+        Err(err) => return Err(err),
+    }
+}
+
+// Simulates a transfer of `amount` from `holder` to `recipient` and returns the tax, rounded up.
+// `Ok(None)` when the transfer reverts.
+#[inline(always)]
+pub async fn detect_transfer_fee(
+    token: &NetworkToken,
+    holder: H160,
+    recipient: H160,
+    amount: U256,
+    client: &RuntimeClient,
+) -> Result<Option<u32>, ProviderError> {
+    if amount.is_zero() {
+        return Ok(None);
+    }
+
+    let response = call_transfer_probe(token, holder, recipient, amount, false, client).await?;
+    let received = match response.as_ref().and_then(|x| x.first()) {
+        Some(received) => *received,
+        None => return Ok(None),
+    };
+
+    if received >= amount {
+        return Ok(Some(0));
+    }
+
+    let tax = (amount - received) * FEE_DENOMINATOR;
+    return Ok(Some(
+        ((tax + amount - 1) / amount).as_u32().min(FEE_DENOMINATOR),
+    ));
+}
+
+/// Balance changes of a transfer from `holder` to `recipient` and back.
+#[derive(Clone, Copy, Debug)]
+pub struct RoundTrip {
+    pub received: U256,
+    pub spent: U256,
+    pub returned: U256,
+    pub returned_spent: U256,
+}
+
+// `Ok(None)` when one of the two transfers reverts
+#[inline(always)]
+pub async fn simulate_round_trip(
+    token: &NetworkToken,
+    holder: H160,
+    recipient: H160,
+    amount: U256,
+    client: &RuntimeClient,
+) -> Result<Option<RoundTrip>, ProviderError> {
+    let response = call_transfer_probe(token, holder, recipient, amount, true, client).await?;
+
+    return Ok(response.and_then(|response| {
+        if response.len() < 4 {
+            return None;
+        }
+
+        return Some(RoundTrip {
+            received: response[0],
+            spent: response[1],
+            returned: response[2],
+            returned_spent: response[3],
+        });
+    }));
+}
+
+/// Amounts of a buy through a UniswapV2 pair and the sale of everything bought.
+#[derive(Clone, Copy, Debug)]
+pub struct SwapRoundTrip {
+    pub bought: U256,
+    pub received: U256,
+    pub sold: U256,
+    pub sell_quote: U256,
+    pub returned: U256,
+}
+
+// Buys the token other than `base` from the pair with `amount` of the base held by `holder`, and
+// sells it back. `Ok(None)` when one of the swaps or transfers reverts.
+#[inline(always)]
+pub async fn simulate_swap_round_trip(
+    market: &Market,
+    base: &NetworkToken,
+    holder: H160,
+    amount: U256,
+    client: &RuntimeClient,
+) -> Result<Option<SwapRoundTrip>, ProviderError> {
+    let code = SWAPPROBE_DEPLOYED_BYTECODE.clone();
+    let base_is_token_0 = market.tokens[0].contract_address == base.contract_address;
+    let token = if base_is_token_0 { market.tokens[1] } else { market.tokens[0] };
+
+    let mut state = spoof::state();
+    state.account(holder).code(code);
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(holder)
+        .data(ethers::abi::encode(&[
+            Token::Address(market.contract_address),
+            Token::Address(base.contract_address),
+            Token::Address(token.contract_address),
+            Token::Uint(amount),
+            Token::Bool(base_is_token_0),
+            Token::Uint(*market.get_fee_data().0),
+        ]))
+        .into();
+
+    let response: Vec<U256> = match client.provider().call_raw(&tx).state(&state).await {
+        Ok(response) => response.chunks_exact(32).map(U256::from_big_endian).collect(),
+        Err(err) if RpcError::as_error_response(&err).is_some() => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    if response.len() < 5 {
+        return Ok(None);
+    }
+
+    return Ok(Some(SwapRoundTrip {
+        bought: response[0],
+        received: response[1],
+        sold: response[2],
+        sell_quote: response[3],
+        returned: response[4],
+    }));
+}
@@ -0,0 +1,201 @@
+use ethers::types::{U256, U512};
+
+use super::types::FeeParameters;
+
+// Ports of the Liquidity Book V2.1 Uint128x128Math, PriceHelper, FeeHelper, PairParameterHelper
+// and Bin libraries, prices are 128.128 fixed point values of Y per X
+
+const SCALE_OFFSET: usize = 128;
+const REAL_ID_SHIFT: i64 = 1 << 23;
+const BASIS_POINT_MAX: u64 = 10_000;
+const PRECISION: u64 = 1_000_000_000_000_000_000;
+const MAX_POW_EXPONENT: u64 = 0x100000;
+
+lazy_static! {
+    static ref SCALE: U256 = U256::one() << SCALE_OFFSET;
+    static ref SCALE_MASK: U512 = (U512::one() << SCALE_OFFSET) - 1;
+}
+
+#[inline(always)]
+fn to_u256(value: U512) -> Option<U256> {
+    return U256::try_from(value).ok();
+}
+
+#[inline(always)]
+fn to_u128(value: U256) -> Option<u128> {
+    if value.bits() > 128 {
+        return None;
+    }
+
+    return Some(value.as_u128());
+}
+
+/// `Uint128x128Math.pow`, `x` is a 128.128 value above one
+fn pow(x: U256, y: i64) -> Option<U256> {
+    if y == 0 {
+        return Some(*SCALE);
+    }
+
+    let mut invert = y < 0;
+    let abs_y = y.unsigned_abs();
+
+    if abs_y >= MAX_POW_EXPONENT {
+        return None;
+    }
+
+    let mut squared = x;
+    if x > U256::from(u128::MAX) {
+        squared = U256::MAX / squared;
+        invert = !invert;
+    }
+
+    let mut result = *SCALE;
+    for bit in 0..20 {
+        if abs_y & (1 << bit) != 0 {
+            result = (result * squared) >> SCALE_OFFSET;
+        }
+
+        squared = (squared * squared) >> SCALE_OFFSET;
+    }
+
+    if result.is_zero() {
+        return None;
+    }
+
+    if invert {
+        return Some(U256::MAX / result);
+    }
+
+    return Some(result);
+}
+
+/// `PriceHelper.getPriceFromId`
+pub fn get_price_from_id(id: u32, bin_step: u16) -> Option<U256> {
+    let base = *SCALE + (U256::from(bin_step) << SCALE_OFFSET) / BASIS_POINT_MAX;
+    return pow(base, id as i64 - REAL_ID_SHIFT);
+}
+
+#[inline(always)]
+fn mul_shift(x: U256, y: U256, round_up: bool) -> Option<U256> {
+    let product = U512::from(x) * U512::from(y);
+    let mut result = product >> SCALE_OFFSET;
+
+    if round_up && !(product & *SCALE_MASK).is_zero() {
+        result += U512::one();
+    }
+
+    return to_u256(result);
+}
+
+#[inline(always)]
+fn shift_div(x: U256, denominator: U256, round_up: bool) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+
+    let numerator = U512::from(x) << SCALE_OFFSET;
+    let denominator = U512::from(denominator);
+    let mut result = numerator / denominator;
+
+    if round_up && !(numerator % denominator).is_zero() {
+        result += U512::one();
+    }
+
+    return to_u256(result);
+}
+
+/// `FeeHelper.getFeeAmount`, the fee to add on top of an amount without fees
+#[inline(always)]
+fn get_fee_amount(amount: u128, total_fee: U256) -> U256 {
+    let denominator = U256::from(PRECISION) - total_fee;
+    return (U256::from(amount) * total_fee + denominator - 1) / denominator;
+}
+
+/// `FeeHelper.getFeeAmountFrom`, the fee included in an amount with fees
+#[inline(always)]
+fn get_fee_amount_from(amount_with_fees: u128, total_fee: U256) -> U256 {
+    return (U256::from(amount_with_fees) * total_fee + PRECISION - 1) / PRECISION;
+}
+
+/// `PairParameterHelper.getTotalFee`, base fee plus variable fee in 1e18 precision
+pub fn get_total_fee(parameters: &FeeParameters, bin_step: u16) -> U256 {
+    let bin_step = U256::from(bin_step);
+    let base_fee = U256::from(parameters.base_factor) * bin_step * U256::exp10(10);
+
+    if parameters.variable_fee_control == 0 {
+        return base_fee;
+    }
+
+    let product = U256::from(parameters.volatility_accumulator) * bin_step;
+    let variable_fee =
+        (product * product * U256::from(parameters.variable_fee_control) + 99) / 100;
+
+    return base_fee + variable_fee;
+}
+
+/// `PairParameterHelper.updateReferences`, applied once at the start of every swap
+pub fn update_references(parameters: &mut FeeParameters, active_id: u32, timestamp: u64) {
+    let dt = timestamp.saturating_sub(parameters.time_of_last_update);
+
+    if dt >= parameters.filter_period as u64 {
+        parameters.id_reference = active_id;
+        parameters.volatility_reference = if dt < parameters.decay_period as u64 {
+            (parameters.volatility_accumulator as u64 * parameters.reduction_factor as u64
+                / BASIS_POINT_MAX) as u32
+        } else {
+            0
+        };
+    }
+
+    parameters.time_of_last_update = timestamp;
+}
+
+/// `PairParameterHelper.updateVolatilityAccumulator`, applied for every bin a swap goes through
+pub fn update_volatility_accumulator(parameters: &mut FeeParameters, active_id: u32) {
+    let delta_id = active_id.abs_diff(parameters.id_reference) as u64;
+    let volatility_accumulator =
+        parameters.volatility_reference as u64 + delta_id * BASIS_POINT_MAX;
+
+    parameters.volatility_accumulator =
+        volatility_accumulator.min(parameters.max_volatility_accumulator as u64) as u32;
+}
+
+/// `Bin.getAmounts`, returns the amount taken from `amount_in_left` including fees and the
+/// amount sent out of the bin
+pub fn get_amounts(
+    bin_reserves: (u128, u128),
+    total_fee: U256,
+    price: U256,
+    swap_for_y: bool,
+    amount_in_left: u128,
+) -> Option<(u128, u128)> {
+    let bin_reserve_out = if swap_for_y {
+        bin_reserves.1
+    } else {
+        bin_reserves.0
+    };
+
+    let max_amount_in = if swap_for_y {
+        shift_div(U256::from(bin_reserve_out), price, true)?
+    } else {
+        mul_shift(U256::from(bin_reserve_out), price, true)?
+    };
+    let max_amount_in = to_u128(max_amount_in)?;
+    let max_fee = to_u128(get_fee_amount(max_amount_in, total_fee))?;
+    let max_amount_in = max_amount_in.checked_add(max_fee)?;
+
+    if amount_in_left >= max_amount_in {
+        return Some((max_amount_in, bin_reserve_out));
+    }
+
+    let fee = to_u128(get_fee_amount_from(amount_in_left, total_fee))?;
+    let amount_in = U256::from(amount_in_left - fee);
+
+    let amount_out = if swap_for_y {
+        mul_shift(amount_in, price, false)?
+    } else {
+        shift_div(amount_in, price, false)?
+    };
+
+    return Some((amount_in_left, to_u128(amount_out)?.min(bin_reserve_out)));
+}
@@ -0,0 +1,697 @@
+use ethers::{
+    abi::{AbiParser, Function},
+    prelude::*,
+};
+use futures::future::join_all;
+use itertools::Itertools;
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinSet;
+
+use self::types::{
+    lb_pair::{self, LBPairEvents},
+    FeeParameters, LBFactory, LBFactoryContract, LBPair, LBPairContract, LiquidityBookPair,
+};
+
+use super::types::{Exchange, MarketPool};
+use crate::{
+    env::{types::RuntimeClient, RuntimeCache, RuntimeConfig},
+    networks::Network,
+    rpc_batching::{call_batched, call_with_retries},
+    types::{
+        market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, SwapLog, TransactionLog,
+    },
+};
+
+mod math;
+mod types;
+
+// non-empty bins tracked on each side of the active bin
+const NEIGHBOUR_BINS: usize = 8;
+const MAX_BIN_ID: u32 = 0xFFFFFF;
+
+lazy_static! {
+    static ref SWAP_METHOD: Function = AbiParser::default()
+        .parse_function("swap(bool,address)")
+        .unwrap();
+    static ref LB_PAIRS: RwLock<Vec<LiquidityBookPair>> = RwLock::new(vec![]);
+}
+
+#[inline(always)]
+pub fn init_handler() {
+    let _ = { &SWAP_METHOD.name };
+}
+
+#[inline(always)]
+fn get_timestamp() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+}
+
+// Bin reserves and event amounts are packed as two uint128, X in the lower half
+#[inline(always)]
+fn decode_amounts(packed: &[u8; 32]) -> (u128, u128) {
+    let value = U256::from_big_endian(packed);
+    return (value.low_u128(), (value >> 128).low_u128());
+}
+
+#[inline(always)]
+pub async fn get_markets(
+    exchange: &'static Exchange,
+    network: &'static Network,
+    runtime_cache: &RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> Result<Vec<Market>, Error> {
+    let factory_contract: LBFactoryContract =
+        LBFactory::new(exchange.factory_address, runtime_cache.client.clone());
+    let mut result: Vec<Market> = vec![];
+
+    let factory_contract = &factory_contract;
+    let pair_count = call_with_retries("getNumberOfLBPairs", || async move {
+        return factory_contract.get_number_of_lb_pairs().call().await;
+    })
+    .await
+    .map_err(|err| Error::new(ErrorKind::ConnectionRefused, err))?;
+
+    let pair_addresses: Vec<Address> = call_batched(
+        "getLBPairAtIndex",
+        runtime_config.small_chunk_size,
+        (0..pair_count.as_u64()).collect_vec(),
+        |chunk| async move {
+            let responses = join_all(chunk.into_iter().map(|i| async move {
+                return factory_contract
+                    .get_lb_pair_at_index(U256::from(i))
+                    .call()
+                    .await;
+            }))
+            .await;
+
+            return responses
+                .into_iter()
+                .collect::<Result<Vec<Address>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await
+    .into_iter()
+    .map(|x| x.1)
+    .collect();
+
+    let client = &runtime_cache.client;
+    let pairs = call_batched(
+        "lb_pair",
+        runtime_config.small_chunk_size,
+        pair_addresses,
+        |chunk| async move {
+            let pairs = join_all(
+                chunk
+                    .into_iter()
+                    .map(|address| load_pair(address, network, client.clone())),
+            )
+            .await;
+
+            return pairs
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await;
+
+    for pair in pairs.into_iter().filter_map(|x| x.1) {
+        result.push(register_pair(pair, exchange));
+    }
+
+    return Ok(result);
+}
+
+#[inline(always)]
+fn register_pair(pair: LiquidityBookPair, exchange: &'static Exchange) -> Market {
+    let mut pairs = LB_PAIRS.write().unwrap();
+    let id = pairs.len();
+
+    // base fee in the same 1/10000 unit as the other protocols, informative only
+    let fee = pair.fee_parameters.base_factor as i32 * pair.bin_step as i32 / 10000;
+
+    let market = Market::from_pool(
+        pair.address,
+        pair.tokens,
+        fee,
+        exchange.protocol,
+        MarketPool { id, coins: [0, 1] },
+    );
+
+    pairs.push(pair);
+    return market;
+}
+
+// `Ok(None)` for pairs of untracked tokens, errors are those of calls worth retrying
+#[inline(always)]
+async fn load_pair(
+    address: Address,
+    network: &'static Network,
+    client: Arc<RuntimeClient>,
+) -> Result<Option<LiquidityBookPair>, ContractError<RuntimeClient>> {
+    let pair_contract: LBPairContract = LBPair::new(address, client);
+
+    let token_x = pair_contract.get_token_x().call().await?;
+    let token_y = pair_contract.get_token_y().call().await?;
+
+    let token_x = network
+        .tokens
+        .iter()
+        .find(|x| x.contract_address == token_x);
+    let token_y = network
+        .tokens
+        .iter()
+        .find(|x| x.contract_address == token_y);
+    let (token_x, token_y) = match (token_x, token_y) {
+        (Some(token_x), Some(token_y)) => (token_x, token_y),
+        _ => return Ok(None),
+    };
+
+    let (
+        base_factor,
+        filter_period,
+        decay_period,
+        reduction_factor,
+        variable_fee_control,
+        _,
+        max_volatility_accumulator,
+    ) = pair_contract.get_static_fee_parameters().call().await?;
+
+    let pair = LiquidityBookPair {
+        address,
+        tokens: [token_x, token_y],
+        bin_step: pair_contract.get_bin_step().call().await?,
+        active_id: 0,
+        reserves: (U256::zero(), U256::zero()),
+        fee_parameters: FeeParameters {
+            base_factor,
+            filter_period,
+            decay_period,
+            reduction_factor,
+            variable_fee_control,
+            max_volatility_accumulator,
+            ..Default::default()
+        },
+        bins: BTreeMap::new(),
+        lower_id: 0,
+        upper_id: 0,
+    };
+
+    return refresh_pair(pair, &pair_contract, BlockNumber::Latest.into())
+        .await
+        .map(Some);
+}
+
+// Collects the next non-empty bins in one direction, the returned bound is the last id known to
+// the walk, the outermost id when the pair has no more liquidity in that direction
+#[inline(always)]
+async fn walk_bins(
+    pair_contract: &LBPairContract,
+    active_id: u32,
+    swap_for_y: bool,
+    block: BlockId,
+) -> Result<(Vec<(u32, (u128, u128))>, u32), ContractError<RuntimeClient>> {
+    let mut bins = vec![];
+    let mut id = active_id;
+
+    for _ in 0..NEIGHBOUR_BINS {
+        let next_id = pair_contract
+            .get_next_non_empty_bin(swap_for_y, id)
+            .block(block)
+            .call()
+            .await?;
+
+        if next_id == 0 || next_id == MAX_BIN_ID {
+            return Ok((bins, if swap_for_y { 0 } else { MAX_BIN_ID }));
+        }
+
+        bins.push((
+            next_id,
+            pair_contract.get_bin(next_id).block(block).call().await?,
+        ));
+        id = next_id;
+    }
+
+    return Ok((bins, id));
+}
+
+// Reads the active bin, its neighbours and the variable fee state
+#[inline(always)]
+async fn refresh_pair(
+    mut pair: LiquidityBookPair,
+    pair_contract: &LBPairContract,
+    block: BlockId,
+) -> Result<LiquidityBookPair, ContractError<RuntimeClient>> {
+    let active_id = pair_contract.get_active_id().block(block).call().await?;
+    let (reserve_x, reserve_y) = pair_contract.get_reserves().block(block).call().await?;
+    let (volatility_accumulator, volatility_reference, id_reference, time_of_last_update) =
+        pair_contract
+            .get_variable_fee_parameters()
+            .block(block)
+            .call()
+            .await?;
+
+    let active_bin = pair_contract.get_bin(active_id).block(block).call().await?;
+    let (lower, upper) = tokio::join!(
+        walk_bins(pair_contract, active_id, true, block),
+        walk_bins(pair_contract, active_id, false, block)
+    );
+    let ((lower_bins, lower_id), (upper_bins, upper_id)) = (lower?, upper?);
+
+    pair.active_id = active_id;
+    pair.reserves = (U256::from(reserve_x), U256::from(reserve_y));
+    pair.fee_parameters.volatility_accumulator = volatility_accumulator;
+    pair.fee_parameters.volatility_reference = volatility_reference;
+    pair.fee_parameters.id_reference = id_reference;
+    pair.fee_parameters.time_of_last_update = time_of_last_update;
+
+    pair.bins = lower_bins.into_iter().chain(upper_bins).collect();
+    if active_bin.0 > 0 || active_bin.1 > 0 {
+        pair.bins.insert(active_id, active_bin);
+    }
+    pair.lower_id = lower_id;
+    pair.upper_id = upper_id;
+
+    return Ok(pair);
+}
+
+#[inline(always)]
+pub fn get_cached_reserves(market: &Market) -> Option<Reserves> {
+    let market_pool = market.pool?;
+    return Some(LB_PAIRS.read().unwrap().get(market_pool.id)?.reserves);
+}
+
+// Bins of every pair read at `block`, a pair whose bin walk keeps failing has no reserves in
+// the result
+#[inline(always)]
+pub async fn get_market_reserves(
+    markets: Vec<&'static OrgValue<Market>>,
+    block: BlockId,
+    runtime_cache: &'static RuntimeCache,
+) -> OrganizedList<Reserves> {
+    let mut join_set: JoinSet<Option<(usize, LiquidityBookPair)>> = JoinSet::new();
+
+    let pair_ids: Vec<usize> = markets
+        .iter()
+        .filter_map(|x| x.value.pool)
+        .map(|x| x.id)
+        .unique()
+        .collect();
+
+    for pair_id in pair_ids {
+        let pair = LB_PAIRS.read().unwrap()[pair_id].clone();
+
+        join_set.spawn(async move {
+            let pair_contract = LBPair::new(pair.address, runtime_cache.client.clone());
+
+            let pair = call_with_retries("lb_pair_state", || {
+                refresh_pair(pair.clone(), &pair_contract, block)
+            })
+            .await;
+
+            return pair.ok().map(|x| (pair_id, x));
+        });
+    }
+
+    let mut refreshed_ids: Vec<usize> = vec![];
+    while let Some(Ok(result)) = join_set.join_next().await {
+        if let Some((pair_id, pair)) = result {
+            LB_PAIRS.write().unwrap()[pair_id] = pair;
+            refreshed_ids.push(pair_id);
+        }
+    }
+
+    let mut res: OrganizedList<Reserves> = OrganizedList::new();
+    for market in markets {
+        if !market
+            .value
+            .pool
+            .map_or(false, |x| refreshed_ids.contains(&x.id))
+        {
+            continue;
+        }
+
+        if let Some(reserves) = get_cached_reserves(&market.value) {
+            res.add_pair(OrgValue {
+                id: market.id,
+                value: reserves,
+            });
+        }
+    }
+
+    res.sort();
+    return res;
+}
+
+// Mirrors `LBPair.swap`, walking the tracked bins away from the active bin until the input is
+// consumed, swaps that leave the tracked range are not quoted. `on_bin` gets every bin the swap
+// goes through with its reserves after the swap.
+#[inline(always)]
+fn swap_through_bins<F: FnMut(u32, (u128, u128))>(
+    pair: &LiquidityBookPair,
+    amount_in: u128,
+    swap_for_y: bool,
+    mut on_bin: F,
+) -> Option<u128> {
+    let mut parameters = pair.fee_parameters;
+    math::update_references(&mut parameters, pair.active_id, get_timestamp());
+
+    let bins: Box<dyn Iterator<Item = (&u32, &(u128, u128))>> = if swap_for_y {
+        Box::new(pair.bins.range(pair.lower_id..=pair.active_id).rev())
+    } else {
+        Box::new(pair.bins.range(pair.active_id..=pair.upper_id))
+    };
+
+    let mut amount_left = amount_in;
+    let mut amount_out: u128 = 0;
+
+    for (id, bin_reserves) in bins {
+        let bin_reserve_out = if swap_for_y {
+            bin_reserves.1
+        } else {
+            bin_reserves.0
+        };
+
+        if bin_reserve_out > 0 {
+            math::update_volatility_accumulator(&mut parameters, *id);
+
+            let (amount_in_with_fees, amount_out_of_bin) = math::get_amounts(
+                *bin_reserves,
+                math::get_total_fee(&parameters, pair.bin_step),
+                math::get_price_from_id(*id, pair.bin_step)?,
+                swap_for_y,
+                amount_left,
+            )?;
+
+            amount_left -= amount_in_with_fees;
+            amount_out += amount_out_of_bin;
+
+            on_bin(
+                *id,
+                if swap_for_y {
+                    (
+                        bin_reserves.0 + amount_in_with_fees,
+                        bin_reserves.1 - amount_out_of_bin,
+                    )
+                } else {
+                    (
+                        bin_reserves.0 - amount_out_of_bin,
+                        bin_reserves.1 + amount_in_with_fees,
+                    )
+                },
+            );
+        }
+
+        if amount_left == 0 {
+            return Some(amount_out);
+        }
+    }
+
+    return None;
+}
+
+#[inline(always)]
+fn quote(pair: &LiquidityBookPair, amount_in: u128, swap_for_y: bool) -> Option<u128> {
+    return swap_through_bins(pair, amount_in, swap_for_y, |_, _| {});
+}
+
+// Pending swaps move the pair totals in the reserve table away from the tracked ones. The net
+// flow is swapped through a copy of the bins, deposits and withdrawals don't say which bins
+// they touch and leave them as they are.
+fn get_pending_pair(pair: &LiquidityBookPair, reserves: (U256, U256)) -> Option<LiquidityBookPair> {
+    let mut pending_pair = pair.clone();
+    pending_pair.reserves = reserves;
+
+    let (amount_in, swap_for_y) = if reserves.0 > pair.reserves.0 && reserves.1 <= pair.reserves.1 {
+        (reserves.0 - pair.reserves.0, true)
+    } else if reserves.1 > pair.reserves.1 && reserves.0 <= pair.reserves.0 {
+        (reserves.1 - pair.reserves.1, false)
+    } else {
+        return Some(pending_pair);
+    };
+
+    let mut moved_bins: Vec<(u32, (u128, u128))> = vec![];
+    swap_through_bins(
+        pair,
+        u128::try_from(amount_in).ok()?,
+        swap_for_y,
+        |id, bin_reserves| moved_bins.push((id, bin_reserves)),
+    )?;
+
+    for (id, bin_reserves) in moved_bins {
+        if bin_reserves.0 == 0 && bin_reserves.1 == 0 {
+            pending_pair.bins.remove(&id);
+        } else {
+            pending_pair.bins.insert(id, bin_reserves);
+        }
+        pending_pair.active_id = id;
+    }
+
+    return Some(pending_pair);
+}
+
+// The reserve table only holds the pair totals, the quote is taken from the tracked bins moved
+// by the pending flow the totals differ by
+#[inline(always)]
+pub fn calculate_amount_out(
+    market: &Market,
+    reserves: &Reserves,
+    input_amount: &U256,
+    zero_for_one: bool,
+) -> U256 {
+    let pair_reserves = if zero_for_one {
+        *reserves
+    } else {
+        (reserves.1, reserves.0)
+    };
+
+    if let Some(market_pool) = market.pool {
+        if let Some(pair) = LB_PAIRS.read().unwrap().get(market_pool.id) {
+            if input_amount.bits() <= 128 {
+                let amount_out = if pair_reserves == pair.reserves {
+                    quote(pair, input_amount.as_u128(), zero_for_one)
+                } else {
+                    get_pending_pair(pair, pair_reserves)
+                        .and_then(|x| quote(&x, input_amount.as_u128(), zero_for_one))
+                };
+
+                if let Some(amount_out) = amount_out {
+                    return U256::from(amount_out);
+                }
+            }
+        }
+    }
+
+    return U256::zero();
+}
+
+// The input has to be transferred to the pair beforehand, X is the market's first token
+#[inline(always)]
+pub fn populate_swap(swap: &SwapLog, to: &H160) -> Result<Bytes, AbiError> {
+    return ethers::contract::encode_function_data::<lb_pair::SwapCall>(
+        &SWAP_METHOD,
+        lb_pair::SwapCall {
+            swap_for_y: swap.zero_for_one(),
+            to: *to,
+        },
+    );
+}
+
+// Amounts moved into and out of the pair's reserves, protocol fees are kept by the pair but are
+// not part of the bin or pair reserves
+#[inline(always)]
+fn get_swap_amounts(event: &lb_pair::SwapFilter) -> ((u128, u128), (u128, u128)) {
+    let amounts_in = decode_amounts(&event.amounts_in);
+    let amounts_out = decode_amounts(&event.amounts_out);
+    let protocol_fees = decode_amounts(&event.protocol_fees);
+
+    return (
+        (
+            amounts_in.0.saturating_sub(protocol_fees.0),
+            amounts_in.1.saturating_sub(protocol_fees.1),
+        ),
+        amounts_out,
+    );
+}
+
+#[inline(always)]
+fn get_bin_totals(amounts: &Vec<[u8; 32]>) -> (u128, u128) {
+    return amounts
+        .iter()
+        .map(decode_amounts)
+        .fold((0, 0), |acc, x| (acc.0 + x.0, acc.1 + x.1));
+}
+
+#[inline(always)]
+fn get_balance_change(
+    market: &'static OrgValue<Market>,
+    amounts_in: (u128, u128),
+    amounts_out: (u128, u128),
+) -> BalanceChange {
+    return BalanceChange {
+        market,
+        amount_0_in: amounts_in.0,
+        amount_1_in: amounts_in.1,
+        amount_0_out: amounts_out.0,
+        amount_1_out: amounts_out.1,
+        source: None,
+    };
+}
+
+#[inline(always)]
+fn apply_swap(pair: &mut LiquidityBookPair, event: &lb_pair::SwapFilter) {
+    let (amounts_in, amounts_out) = get_swap_amounts(event);
+
+    math::update_references(&mut pair.fee_parameters, pair.active_id, get_timestamp());
+    pair.fee_parameters.volatility_accumulator = event.volatility_accumulator;
+    pair.active_id = event.id;
+    pair.lower_id = pair.lower_id.min(event.id);
+    pair.upper_id = pair.upper_id.max(event.id);
+
+    let bin_reserves = pair.bins.entry(event.id).or_default();
+    bin_reserves.0 = (bin_reserves.0 + amounts_in.0).saturating_sub(amounts_out.0);
+    bin_reserves.1 = (bin_reserves.1 + amounts_in.1).saturating_sub(amounts_out.1);
+
+    apply_reserves(pair, amounts_in, amounts_out);
+}
+
+// Deposits and withdrawals only touch bins in the tracked range, the reserves of bins outside
+// of it are not known
+#[inline(always)]
+fn apply_bin_amounts(
+    pair: &mut LiquidityBookPair,
+    ids: &Vec<U256>,
+    amounts: &Vec<[u8; 32]>,
+    deposit: bool,
+) {
+    for (id, amounts) in ids.iter().zip(amounts) {
+        let id = id.as_u32();
+        let amounts = decode_amounts(amounts);
+
+        if id < pair.lower_id || id > pair.upper_id {
+            continue;
+        }
+
+        let bin_reserves = pair.bins.entry(id).or_default();
+        if deposit {
+            *bin_reserves = (bin_reserves.0 + amounts.0, bin_reserves.1 + amounts.1);
+        } else {
+            *bin_reserves = (
+                bin_reserves.0.saturating_sub(amounts.0),
+                bin_reserves.1.saturating_sub(amounts.1),
+            );
+        }
+
+        if bin_reserves.0 == 0 && bin_reserves.1 == 0 {
+            pair.bins.remove(&id);
+        }
+    }
+
+    let total = get_bin_totals(amounts);
+    if deposit {
+        apply_reserves(pair, total, (0, 0));
+    } else {
+        apply_reserves(pair, (0, 0), total);
+    }
+}
+
+#[inline(always)]
+fn apply_reserves(
+    pair: &mut LiquidityBookPair,
+    amounts_in: (u128, u128),
+    amounts_out: (u128, u128),
+) {
+    pair.reserves = (
+        (pair.reserves.0 + amounts_in.0).saturating_sub(amounts_out.0.into()),
+        (pair.reserves.1 + amounts_in.1).saturating_sub(amounts_out.1.into()),
+    );
+}
+
+#[inline(always)]
+pub fn get_event_topics() -> Vec<H256> {
+    return vec![
+        lb_pair::SwapFilter::signature(),
+        lb_pair::DepositedToBinsFilter::signature(),
+        lb_pair::WithdrawnFromBinsFilter::signature(),
+    ];
+}
+
+// Swaps emit one event per bin they go through. Pending logs may never land, the tracked bins
+// are left as they are.
+#[inline(always)]
+pub fn parse_balance_changes(
+    logs: Vec<&TransactionLog>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    let mut result: Vec<BalanceChange> = vec![];
+
+    for transaction_log in logs {
+        if let Ok(events) =
+            ethers::contract::decode_logs::<LBPairEvents>(&[transaction_log.raw.clone()])
+        {
+            let market = match Market::from_address(&transaction_log.address, runtime_cache) {
+                Some(market) => market,
+                None => continue,
+            };
+
+            for event in events {
+                result.push(match event {
+                    LBPairEvents::SwapFilter(event) => {
+                        let (amounts_in, amounts_out) = get_swap_amounts(&event);
+                        get_balance_change(market, amounts_in, amounts_out)
+                    }
+                    LBPairEvents::DepositedToBinsFilter(event) => {
+                        get_balance_change(market, get_bin_totals(&event.amounts), (0, 0))
+                    }
+                    LBPairEvents::WithdrawnFromBinsFilter(event) => {
+                        get_balance_change(market, (0, 0), get_bin_totals(&event.amounts))
+                    }
+                });
+            }
+        }
+    }
+
+    return result;
+}
+
+/// Moves the tracked bins of the pairs by the logs of a confirmed block
+#[inline(always)]
+pub fn apply_confirmed_logs(logs: Vec<&TransactionLog>, runtime_cache: &'static RuntimeCache) {
+    for transaction_log in logs {
+        if let Ok(events) =
+            ethers::contract::decode_logs::<LBPairEvents>(&[transaction_log.raw.clone()])
+        {
+            let market = match Market::from_address(&transaction_log.address, runtime_cache) {
+                Some(market) => market,
+                None => continue,
+            };
+
+            let mut pairs = LB_PAIRS.write().unwrap();
+            let pair = match market.value.pool.and_then(|x| pairs.get_mut(x.id)) {
+                Some(pair) => pair,
+                None => continue,
+            };
+
+            for event in events {
+                match event {
+                    LBPairEvents::SwapFilter(event) => apply_swap(pair, &event),
+                    LBPairEvents::DepositedToBinsFilter(event) => {
+                        apply_bin_amounts(pair, &event.ids, &event.amounts, true)
+                    }
+                    LBPairEvents::WithdrawnFromBinsFilter(event) => {
+                        apply_bin_amounts(pair, &event.ids, &event.amounts, false)
+                    }
+                }
+            }
+        }
+    }
+}
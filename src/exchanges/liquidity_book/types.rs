@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+
+use ethers::prelude::*;
+
+use crate::{env::types::RuntimeClient, types::Token};
+
+abigen!(LBFactory, "src/exchanges/liquidity_book/_factory.json");
+abigen!(LBPair, "src/exchanges/liquidity_book/_pair.json");
+
+pub type LBFactoryContract = LBFactory<RuntimeClient>;
+pub type LBPairContract = LBPair<RuntimeClient>;
+
+// Unpacked `PairParameterHelper` parameters of a V2.1 pair
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FeeParameters {
+    pub base_factor: u16,
+    pub filter_period: u16,
+    pub decay_period: u16,
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub id_reference: u32,
+    pub time_of_last_update: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LiquidityBookPair {
+    pub address: Address,
+    pub tokens: [&'static Token; 2],
+    pub bin_step: u16,
+    pub active_id: u32,
+    pub reserves: (U256, U256),
+    pub fee_parameters: FeeParameters,
+
+    // non-empty bins between lower_id and upper_id, bins in that range that are missing are empty
+    pub bins: BTreeMap<u32, (u128, u128)>,
+    pub lower_id: u32,
+    pub upper_id: u32,
+}
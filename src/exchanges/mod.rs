@@ -1,26 +1,36 @@
 use std::{io::Error, vec};
 
 use ethers::{
+    abi::RawLog,
     prelude::AbiError,
+    providers::{Middleware, ProviderError},
     types::Bytes,
-    types::{H160, U256},
+    types::{BlockId, BlockNumber, Filter, H160, H256, U256, U64},
 };
 
 use crate::{
     env::{RuntimeCache, RuntimeConfig, EXECUTE_TX_BUNDLE_FUNCTION},
-    exchanges::types::Protocol,
+    exchanges::types::{PairBalance, Protocol, SwapMode},
     networks::Network,
+    token_safety::denylist,
     types::{
-        market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, SwapLog, TransactionLog,
+        market::Market, BalanceChange, OrgValue, OrganizedList, ReserveSnapshot, Reserves, SwapLog,
+        TransactionLog,
     },
 };
 
 use self::types::Exchange;
 
+mod balancer;
+mod curve;
+pub mod erc20;
+mod liquidity_book;
 mod stable_swap;
 pub mod types;
 mod uniswap_v2;
 
+pub use uniswap_v2::{get_pair_states, register_router, register_router_pair};
+
 /*
 pub struct ExchangeHandler {
     network: &'static Network,
@@ -161,15 +171,46 @@ pub async fn get_exchange_markets(
             };
         } else if exchange.protocol == Protocol::StableSwap {
             // return stable_swap::get_markets(exchange);
+        } else if exchange.protocol == Protocol::Curve {
+            if let Ok(mut response) =
+                curve::get_markets(exchange, network, runtime_cache, runtime_config).await
+            {
+                result.append(&mut response);
+            };
+        } else if exchange.protocol == Protocol::BalancerV2 {
+            if let Ok(mut response) =
+                balancer::get_markets(exchange, network, runtime_cache, runtime_config).await
+            {
+                result.append(&mut response);
+            };
+        } else if exchange.protocol == Protocol::LiquidityBook {
+            if let Ok(mut response) =
+                liquidity_book::get_markets(exchange, network, runtime_cache, runtime_config)
+                    .await
+            {
+                result.append(&mut response);
+            };
         }
     }
 
+    // reserves of markets holding rebasing tokens drift from their balances between syncs
+    result.retain(|market| {
+        !market
+            .tokens
+            .iter()
+            .any(|token| token.rebasing || denylist::is_denied(&token.contract_address))
+    });
+
     return Ok(result);
 }
 
 pub fn init_exchange_handlers() {
     let _ = &EXECUTE_TX_BUNDLE_FUNCTION.name;
+    erc20::init_handler();
     uniswap_v2::init_handler();
+    curve::init_handler();
+    balancer::init_handler();
+    liquidity_book::init_handler();
 }
 
 #[inline(always)]
@@ -187,45 +228,344 @@ pub fn parse_balance_changes(
         runtime_cache,
     ));
 
+    // Curve
+    result.append(&mut curve::parse_balance_changes(
+        logs.iter()
+            .filter(|x| x.protocol == Protocol::Curve)
+            .collect(),
+        runtime_cache,
+    ));
+
+    // Balancer V2
+    result.append(&mut balancer::parse_balance_changes(
+        logs.iter()
+            .filter(|x| x.protocol == Protocol::BalancerV2)
+            .collect(),
+        runtime_cache,
+    ));
+
+    // Liquidity Book
+    result.append(&mut liquidity_book::parse_balance_changes(
+        logs.iter()
+            .filter(|x| x.protocol == Protocol::LiquidityBook)
+            .collect(),
+        runtime_cache,
+    ));
+
     return result;
 }
 
+// Pool registries that track more than the reserve table, e.g. the Liquidity Book bins, are only
+// moved by confirmed logs
+#[inline(always)]
+pub fn apply_confirmed_logs(logs: &Vec<TransactionLog>, runtime_cache: &'static RuntimeCache) {
+    liquidity_book::apply_confirmed_logs(
+        logs.iter()
+            .filter(|x| x.protocol == Protocol::LiquidityBook)
+            .collect(),
+        runtime_cache,
+    );
+}
+
+// Balance changes of every market expanded from a multi-coin pool, given the pool indices and
+// amounts of the sold and bought coins
+#[inline(always)]
+fn get_pool_balance_changes(
+    markets: impl Iterator<Item = &'static OrgValue<Market>>,
+    sold: (usize, u128),
+    bought: (usize, u128),
+) -> Vec<BalanceChange> {
+    let mut result: Vec<BalanceChange> = vec![];
+
+    for market in markets {
+        if let Some(market_pool) = market.value.pool {
+            let mut balance_change = BalanceChange {
+                market,
+                amount_0_in: 0,
+                amount_1_in: 0,
+                amount_0_out: 0,
+                amount_1_out: 0,
+                source: None,
+            };
+
+            if market_pool.coins[0] == sold.0 {
+                balance_change.amount_0_in = sold.1;
+            } else if market_pool.coins[1] == sold.0 {
+                balance_change.amount_1_in = sold.1;
+            }
+
+            if market_pool.coins[0] == bought.0 {
+                balance_change.amount_0_out = bought.1;
+            } else if market_pool.coins[1] == bought.0 {
+                balance_change.amount_1_out = bought.1;
+            }
+
+            if balance_change.amount_0_in
+                + balance_change.amount_1_in
+                + balance_change.amount_0_out
+                + balance_change.amount_1_out
+                > 0
+            {
+                result.push(balance_change);
+            }
+        }
+    }
+
+    return result;
+}
+
+/// Reserves of every market read at `block_number`. The snapshot is partial when some markets
+/// couldn't be read at that block.
 #[inline(always)]
 pub async fn get_market_reserves(
     markets: &'static OrganizedList<Market>,
+    block_number: U64,
     runtime_cache: &'static RuntimeCache,
     runtime_config: &'static RuntimeConfig,
-) -> OrganizedList<Reserves> {
+) -> ReserveSnapshot {
+    return get_market_reserves_where(
+        markets,
+        |_| true,
+        block_number,
+        runtime_cache,
+        runtime_config,
+    )
+    .await;
+}
+
+/// Reserves at `block_number` of the markets whose id matches `predicate`, partial when some of
+/// them couldn't be read
+pub async fn get_market_reserves_where<P>(
+    markets: &'static OrganizedList<Market>,
+    predicate: P,
+    block_number: U64,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> ReserveSnapshot
+where
+    P: Fn(usize) -> bool + Sync,
+{
+    let block = BlockId::Number(BlockNumber::Number(block_number));
     let filtered_markets: Vec<&OrgValue<Market>> = markets.filter(|x| {
-        x.value.protocol == Protocol::UniswapV2 || x.value.protocol == Protocol::StableSwap
+        (x.value.protocol == Protocol::UniswapV2 || x.value.protocol == Protocol::StableSwap)
+            && predicate(x.id)
     });
+    let mut market_count = filtered_markets.len();
 
     // Uniswap V2
-    let uniswap_v2_markets =
-        uniswap_v2::get_market_reserves(filtered_markets, runtime_cache, runtime_config).await;
+    let mut market_reserves = uniswap_v2::get_market_reserves(
+        filtered_markets,
+        block_number,
+        runtime_cache,
+        runtime_config,
+    )
+    .await;
 
-    return uniswap_v2_markets;
+    // Curve
+    let curve_markets: Vec<&OrgValue<Market>> =
+        markets.filter(|x| x.value.protocol == Protocol::Curve && predicate(x.id));
+    market_count += curve_markets.len();
+
+    for reserves in curve::get_market_reserves(curve_markets, block, runtime_cache).await {
+        market_reserves.add_pair(reserves);
+    }
+
+    // Balancer V2
+    let balancer_markets: Vec<&OrgValue<Market>> =
+        markets.filter(|x| x.value.protocol == Protocol::BalancerV2 && predicate(x.id));
+    market_count += balancer_markets.len();
+
+    for reserves in balancer::get_market_reserves(balancer_markets, block, runtime_cache).await {
+        market_reserves.add_pair(reserves);
+    }
+
+    // Liquidity Book
+    let liquidity_book_markets: Vec<&OrgValue<Market>> =
+        markets.filter(|x| x.value.protocol == Protocol::LiquidityBook && predicate(x.id));
+    market_count += liquidity_book_markets.len();
+
+    for reserves in
+        liquidity_book::get_market_reserves(liquidity_book_markets, block, runtime_cache).await
+    {
+        market_reserves.add_pair(reserves);
+    }
+
+    market_reserves.sort();
+    return ReserveSnapshot {
+        block_number,
+        partial: market_reserves.len() < market_count,
+        reserves: market_reserves,
+    };
+}
+
+// Logs of a block that move tracked markets, read with a single request
+#[inline(always)]
+pub async fn get_block_logs(
+    block_hash: H256,
+    runtime_cache: &'static RuntimeCache,
+) -> Result<Vec<TransactionLog>, ProviderError> {
+    let mut topics: Vec<H256> = uniswap_v2::get_event_topics();
+    topics.append(&mut curve::get_event_topics());
+    topics.append(&mut balancer::get_event_topics());
+    topics.append(&mut liquidity_book::get_event_topics());
+
+    let filter = Filter::new().at_block_hash(block_hash).topic0(topics);
+    let logs = runtime_cache.client.provider().get_logs(&filter).await?;
+
+    return Ok(logs
+        .into_iter()
+        .filter_map(|log| {
+            let protocol = Market::from_address(&log.address, runtime_cache)
+                .map(|market| market.value.protocol)
+                .or_else(|| get_router_protocol(&log.address))?;
+
+            Some(TransactionLog {
+                address: log.address,
+                protocol,
+                raw: RawLog {
+                    topics: log.topics,
+                    data: log.data.to_vec(),
+                },
+            })
+        })
+        .collect());
 }
 
+// Reserves set by `Sync` events, for the markets that emit them
 #[inline(always)]
-pub fn calculate_amount_out(reserves: &Reserves, input_amount: &U256, market: &Market) -> U256 {
+pub fn parse_synced_reserves(
+    logs: &Vec<TransactionLog>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<(usize, Reserves)> {
+    return uniswap_v2::parse_synced_reserves(
+        logs.iter()
+            .filter(|x| x.protocol == Protocol::UniswapV2)
+            .collect(),
+        runtime_cache,
+    );
+}
+
+// Reserves known from market discovery, used before the runtime cache is available
+#[inline(always)]
+pub fn get_cached_reserves(market: &Market) -> Option<Reserves> {
+    match market.protocol {
+        Protocol::Curve => return curve::get_cached_reserves(market),
+        Protocol::BalancerV2 => return balancer::get_cached_reserves(market),
+        Protocol::LiquidityBook => return liquidity_book::get_cached_reserves(market),
+        Protocol::UniswapV2 | Protocol::StableSwap => return None,
+    }
+}
+
+#[inline(always)]
+pub fn calculate_amount_out(
+    reserves: &Reserves,
+    input_amount: &U256,
+    market: &Market,
+    zero_for_one: bool,
+) -> U256 {
     let protocol = &market.protocol;
 
     if protocol == &Protocol::UniswapV2
         || (protocol == &Protocol::StableSwap && market.stable == false)
     {
         return uniswap_v2::calculate_amount_out(market, reserves, input_amount);
+    } else if protocol == &Protocol::Curve {
+        return curve::calculate_amount_out(market, reserves, input_amount, zero_for_one);
+    } else if protocol == &Protocol::BalancerV2 {
+        return balancer::calculate_amount_out(market, reserves, input_amount, zero_for_one);
+    } else if protocol == &Protocol::LiquidityBook {
+        return liquidity_book::calculate_amount_out(market, reserves, input_amount, zero_for_one);
     }
 
     return U256::zero();
 }
 
+// Whether quotes depend on the reserves of the market's pair alone. Liquidity Book quotes come
+// from the bin registry, Curve and stable Balancer pools from the balances of every coin.
 #[inline(always)]
-pub fn populate_swap(swap_log: &SwapLog, recipient: &H160) -> Result<Bytes, AbiError> {
+pub fn is_priced_on_reserves(market: &Market) -> bool {
+    match market.protocol {
+        Protocol::UniswapV2 | Protocol::StableSwap => return true,
+        Protocol::BalancerV2 => return balancer::is_weighted(market),
+        Protocol::Curve | Protocol::LiquidityBook => return false,
+    }
+}
+
+#[inline(always)]
+pub fn get_swap_mode(market: &Market) -> SwapMode {
+    match market.protocol {
+        Protocol::UniswapV2 | Protocol::StableSwap | Protocol::LiquidityBook => {
+            return SwapMode::Push
+        }
+        Protocol::Curve => return SwapMode::Pull,
+        Protocol::BalancerV2 => {
+            if let Some(vault) = balancer::get_vault(market) {
+                return SwapMode::Routed(vault);
+            }
+
+            return SwapMode::Pull;
+        }
+    }
+}
+
+// Protocol of a shared router contract, e.g. the Balancer Vault, which emits the swap events
+// of the markets it routes
+#[inline(always)]
+pub fn get_router_protocol(address: &H160) -> Option<Protocol> {
+    if balancer::is_vault(address) {
+        return Some(Protocol::BalancerV2);
+    }
+
+    return None;
+}
+
+// Balance state of a pair as of the last reserve load, pool markets are always synced
+#[inline(always)]
+pub fn get_pair_balance(market_id: usize) -> PairBalance {
+    return uniswap_v2::get_pair_balance_state(market_id);
+}
+
+#[inline(always)]
+pub fn is_pair_router(address: &H160) -> bool {
+    return uniswap_v2::is_router(address);
+}
+
+#[inline(always)]
+pub fn get_router_weth(router: &H160) -> Option<H160> {
+    return uniswap_v2::get_router_weth(router);
+}
+
+// Tracked market a pair router swaps through between two tokens
+#[inline(always)]
+pub fn get_router_market(
+    router: H160,
+    token_a: H160,
+    token_b: H160,
+    markets: &'static OrganizedList<Market>,
+) -> Option<&'static OrgValue<Market>> {
+    let pair_address = uniswap_v2::get_router_pair(router, token_a, token_b)?;
+    return Market::from_list(&pair_address, markets);
+}
+
+#[inline(always)]
+pub fn populate_swap(
+    swap_log: &SwapLog,
+    recipient: &H160,
+    sender: &H160,
+) -> Result<Bytes, AbiError> {
     match swap_log.market.value.protocol {
         Protocol::StableSwap => return Err(AbiError::WrongSelector),
         Protocol::UniswapV2 => {
             return uniswap_v2::populate_swap(&swap_log, recipient);
         }
+        Protocol::Curve => {
+            return curve::populate_swap(&swap_log);
+        }
+        Protocol::BalancerV2 => {
+            return balancer::populate_swap(&swap_log, recipient, sender);
+        }
+        Protocol::LiquidityBook => {
+            return liquidity_book::populate_swap(&swap_log, recipient);
+        }
     }
 }
@@ -1,6 +1,8 @@
 use ethers::prelude::*;
 use serde::Deserialize;
 
+use crate::types::Reserves;
+
 mod protocol_handler;
 
 // pub use protocol_handler::ProtocolHandler;
@@ -9,6 +11,9 @@ mod protocol_handler;
 pub enum Protocol {
     UniswapV2,
     StableSwap,
+    Curve,
+    BalancerV2,
+    LiquidityBook,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,4 +23,53 @@ pub struct Exchange {
     pub protocol: Protocol,
     pub base_fee: i32,
     pub stable_fee: Option<i32>,
+    pub start_block: Option<u64>,
+    // router whose swaps through the exchange's pairs are predicted from calldata
+    pub router_address: Option<Address>,
+    // pairs call `uniswapV2Call` on flash swaps, the only callback the executor implements
+    #[serde(default)]
+    pub flash_swaps: bool,
+}
+
+/// Links a two-token market to the multi-coin pool it was expanded from.
+///
+/// `id` indexes the pool registry of the market's protocol, `coins` holds the
+/// pool indices of `tokens[0]` and `tokens[1]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MarketPool {
+    pub id: usize,
+    pub coins: [usize; 2],
+}
+
+/// How the token balances of a pair compare to its reserves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PairBalance {
+    Synced,
+    /// Balances above the reserves, the surplus goes to the next swap or `skim` call.
+    Skimmable,
+    /// Balances below the reserves, e.g. after a rebase, swaps priced on the reserves revert
+    /// until the pair is synced.
+    Desynchronised,
+}
+
+/// Reserves of a pair with the block timestamp of their last sync. The balance state is `None`
+/// when only the reserves could be read.
+#[derive(Clone, Copy, Debug)]
+pub struct PairReading {
+    pub reserves: Reserves,
+    pub block_timestamp_last: u32,
+    pub balance: Option<PairBalance>,
+}
+
+/// How tokens reach a market when a swap is executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapMode {
+    /// Input tokens are transferred to the market before calling it, the output is sent to a
+    /// recipient.
+    Push,
+    /// The market pulls the input from the caller's allowance and returns the output to the caller.
+    Pull,
+    /// A shared router (e.g. the Balancer Vault) is called instead of the market, it pulls the
+    /// input from the caller's allowance and sends the output to a recipient.
+    Routed(Address),
 }
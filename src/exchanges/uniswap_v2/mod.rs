@@ -1,11 +1,15 @@
 use ethers::{
     abi::{AbiParser, Function},
     prelude::*,
+    types::transaction::eip2718::TypedTransaction,
 };
 use itertools::Itertools;
 use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
-use std::io::{Error, ErrorKind};
-use tokio::task::JoinSet;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::RwLock,
+};
 
 use self::types::{
     uniswap_v2_pair::{self, SwapCall},
@@ -13,10 +17,19 @@ use self::types::{
 };
 use ethers::types::U256;
 
-use super::Exchange;
+use super::{
+    types::{PairBalance, PairReading},
+    Exchange,
+};
 use crate::{
-    env::{RuntimeCache, RuntimeConfig},
+    env::{
+        query::{call_query_sized, get_revert_data},
+        types::{PairState, UniswapQueryContract},
+        RuntimeCache, RuntimeConfig,
+    },
     networks::Network,
+    price_oracle::{BlockHeader, PriceOracle},
+    rpc_batching::{call_batched, call_with_retries},
     types::{
         market::Market, BalanceChange, OrgValue, OrganizedList, Reserves, SwapLog, TransactionLog,
     },
@@ -28,6 +41,16 @@ lazy_static! {
     static ref SWAP_METHOD: Function = AbiParser::default()
         .parse_function("swap(uint256,uint256,address,bytes)")
         .unwrap();
+    static ref WETH_METHOD: Function = AbiParser::default()
+        .parse_function("WETH()(address)")
+        .unwrap();
+    // configured routers with the wrapped native token their ETH functions swap through
+    static ref ROUTERS: RwLock<HashMap<H160, Option<H160>>> = RwLock::new(HashMap::new());
+    // pairs whose balances differed from their reserves at the last reserve load
+    static ref PAIR_BALANCES: RwLock<HashMap<usize, PairBalance>> = RwLock::new(HashMap::new());
+    // pairs reachable through each configured router, by router and sorted token pair
+    static ref ROUTER_PAIRS: RwLock<HashMap<(H160, H160, H160), H160>> =
+        RwLock::new(HashMap::new());
 }
 
 /*
@@ -138,6 +161,7 @@ impl ProtocolHandler for UniswapV2Handler {
                     amount_1_in: swap.amount_1_in.as_u128(),
                     amount_0_out: swap.amount_0_out.as_u128(),
                     amount_1_out: swap.amount_1_out.as_u128(),
+                    source: None,
                 });
             }
 
@@ -209,6 +233,69 @@ pub fn init_handler() {
     let _ = { &SWAP_METHOD.name };
 }
 
+#[inline(always)]
+fn get_router_pair_key(router: H160, token_a: H160, token_b: H160) -> (H160, H160, H160) {
+    if token_a < token_b {
+        return (router, token_a, token_b);
+    }
+
+    return (router, token_b, token_a);
+}
+
+#[inline(always)]
+pub fn is_router(address: &H160) -> bool {
+    return ROUTERS.read().unwrap().contains_key(address);
+}
+
+// Wrapped native token of the router, `None` when it couldn't be read
+#[inline(always)]
+pub fn get_router_weth(router: &H160) -> Option<H160> {
+    return ROUTERS.read().unwrap().get(router).copied().flatten();
+}
+
+#[inline(always)]
+pub fn register_router(router: H160, weth: Option<H160>) {
+    ROUTERS.write().unwrap().insert(router, weth);
+}
+
+#[inline(always)]
+pub fn register_router_pair(router: H160, token_a: H160, token_b: H160, pair: H160) {
+    ROUTER_PAIRS
+        .write()
+        .unwrap()
+        .insert(get_router_pair_key(router, token_a, token_b), pair);
+}
+
+#[inline(always)]
+async fn get_weth(router: H160, runtime_cache: &RuntimeCache) -> Option<H160> {
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(router)
+        .data(WETH_METHOD.encode_input(&[]).ok()?)
+        .into();
+    let tx = &tx;
+    let output = call_with_retries("WETH", || async move {
+        return runtime_cache.client.provider().call(tx, None).await;
+    })
+    .await
+    .ok()?;
+
+    return WETH_METHOD
+        .decode_output(&output)
+        .ok()?
+        .pop()?
+        .into_address();
+}
+
+// Pair the router swaps through for two tokens, as computed by its `pairFor`
+#[inline(always)]
+pub fn get_router_pair(router: H160, token_a: H160, token_b: H160) -> Option<H160> {
+    return ROUTER_PAIRS
+        .read()
+        .unwrap()
+        .get(&get_router_pair_key(router, token_a, token_b))
+        .copied();
+}
+
 #[inline(always)]
 pub fn populate_swap(swap: &SwapLog, to: &H160) -> Result<Bytes, AbiError> {
     return ethers::contract::encode_function_data::<uniswap_v2_pair::SwapCall>(
@@ -233,48 +320,57 @@ pub async fn get_markets(
         UniswapV2Factory::new(exchange.factory_address, runtime_cache.client.clone());
     let mut result: Vec<Market> = vec![];
 
-    if let Ok(market_count) = factory_contract.all_pairs_length().await {
-        let total_market_count: u128 = market_count.as_u128();
-        
-        for chunk in &(0..total_market_count)
-        .into_iter()
-        .chunks(runtime_config.large_chunk_size)
-        {
-            let (start, stop) = {
-                let chunk = chunk.collect_vec();
-                (U256::from(chunk[0]), U256::from(*chunk.last().unwrap()))
-            };
-            
-            match runtime_cache
-                .uniswap_query
-                .get_uniswap_v2_markets(exchange.factory_address, start, stop)
-                .await
-            {
-                Ok(response) => {
-                    for element in response {
-                        let token_0 = network
-                            .tokens
-                            .iter()
-                            .find(|s| s.contract_address.0 == element[0].0);
-                        let token_1 = network
-                            .tokens
-                            .iter()
-                            .find(|s| s.contract_address.0 == element[1].0);
-
-                        if token_0.is_some() && token_1.is_some() {
-                            result.push(Market::new(
-                                element[2],
-                                [token_0.unwrap(), token_1.unwrap()],
-                                exchange.base_fee,
-                                false,
-                                exchange.protocol,
-                            ));
-                        }
-                    }
-                }
-                Err(err) => {
-                    return Err(Error::new(ErrorKind::ConnectionRefused, err));
-                }
+    if let Some(router) = exchange.router_address {
+        register_router(router, get_weth(router, runtime_cache).await);
+    }
+
+    let factory_contract = &factory_contract;
+    let market_count = call_with_retries("allPairsLength", || async move {
+        return factory_contract.all_pairs_length().call().await;
+    })
+    .await
+    .map_err(|err| Error::new(ErrorKind::ConnectionRefused, err))?;
+
+    // every chunk reads the pairs from its first index up to, excluding, the index after it
+    let elements = call_batched(
+        "getUniswapV2Markets",
+        runtime_config.large_chunk_size,
+        (0..market_count.as_u128()).collect_vec(),
+        |chunk| async move {
+            let (start, stop) = (U256::from(chunk[0]), U256::from(*chunk.last().unwrap() + 1));
+            return call_query_sized(runtime_cache.uniswap_query.get_uniswap_v2_markets(
+                exchange.factory_address,
+                start,
+                stop,
+            ))
+            .await;
+        },
+    )
+    .await;
+
+    for (_, element) in elements {
+        let token_0 = network
+            .tokens
+            .iter()
+            .find(|s| s.contract_address.0 == element[0].0);
+        let token_1 = network
+            .tokens
+            .iter()
+            .find(|s| s.contract_address.0 == element[1].0);
+
+        if token_0.is_some() && token_1.is_some() {
+            let mut market = Market::new(
+                element[2],
+                [token_0.unwrap(), token_1.unwrap()],
+                exchange.base_fee,
+                false,
+                exchange.protocol,
+            );
+            market.flash_swaps = exchange.flash_swaps;
+            result.push(market);
+
+            if let Some(router) = exchange.router_address {
+                register_router_pair(router, element[0], element[1], element[2]);
             }
         }
     }
@@ -306,6 +402,7 @@ pub fn parse_balance_changes(
                             amount_1_in: swap.amount_1_in.as_u128(),
                             amount_0_out: swap.amount_0_out.as_u128(),
                             amount_1_out: swap.amount_1_out.as_u128(),
+                            source: None,
                         });
                     }
 
@@ -329,55 +426,123 @@ pub fn parse_balance_changes(
     return vec![];
 }
 
+// Pairs with a read that reverted, e.g. a token without `balanceOf`, can't be trusted
+#[inline(always)]
+fn get_pair_balance(state: &PairState) -> PairBalance {
+    if !state.ok {
+        return PairBalance::Desynchronised;
+    }
+
+    let (reserve_0, reserve_1) = (U256::from(state.reserve_0), U256::from(state.reserve_1));
+
+    if state.balance_0 < reserve_0 || state.balance_1 < reserve_1 {
+        return PairBalance::Desynchronised;
+    }
+
+    if state.balance_0 > reserve_0 || state.balance_1 > reserve_1 {
+        return PairBalance::Skimmable;
+    }
+
+    return PairBalance::Synced;
+}
+
+/// Reserves and balance states of the pairs read in a single call at `block_number`, with the
+/// size of the response. Fails when the node answers from another block, or from a block of a
+/// branch the tracked chain has left.
+pub async fn get_pair_states(
+    uniswap_query: &UniswapQueryContract,
+    pairs: Vec<H160>,
+    block_number: U64,
+) -> Result<(Vec<PairReading>, usize), String> {
+    let block = BlockId::Number(BlockNumber::Number(block_number));
+
+    match call_query_sized(uniswap_query.get_pair_states(pairs.clone()).block(block)).await {
+        Ok(((state_block_number, parent_hash, states), response_bytes)) => {
+            // a lagging node may answer with the state of an older block
+            if state_block_number != U256::from(block_number.as_u64()) {
+                return Err(format!("state of block {} read", state_block_number));
+            }
+
+            // only the number and hash of the parent are compared
+            let parent = BlockHeader {
+                number: block_number.saturating_sub(U64::one()),
+                hash: H256::from(parent_hash),
+                parent_hash: H256::zero(),
+            };
+            if !PriceOracle::is_canonical(&parent) {
+                return Err(format!("state of an orphaned block {} read", block_number));
+            }
+
+            let pair_states = states
+                .iter()
+                .map(|x| PairReading {
+                    reserves: (U256::from(x.reserve_0), U256::from(x.reserve_1)),
+                    block_timestamp_last: x.block_timestamp_last,
+                    balance: Some(get_pair_balance(x)),
+                })
+                .collect();
+
+            return Ok((pair_states, response_bytes));
+        }
+        Err(error) => {
+            // deployments predating `getPairStates` revert without data on its selector and only
+            // return the reserves
+            if get_revert_data(&error).map_or(true, |x| !x.is_empty()) {
+                return Err(error.to_string());
+            }
+
+            let (response, response_bytes) =
+                call_query_sized(uniswap_query.get_reserves_by_pairs(pairs).block(block))
+                    .await
+                    .map_err(|err| err.to_string())?;
+
+            let pair_states = response
+                .iter()
+                .map(|x| PairReading {
+                    reserves: (U256::from(x[0]), U256::from(x[1])),
+                    block_timestamp_last: x[2] as u32,
+                    balance: None,
+                })
+                .collect();
+
+            return Ok((pair_states, response_bytes));
+        }
+    }
+}
+
+// Reserves of the pairs at `block_number`, pairs of chunks failing every attempt are left out
 #[inline(always)]
 pub async fn get_market_reserves(
     markets: Vec<&'static OrgValue<Market>>,
+    block_number: U64,
     runtime_cache: &'static RuntimeCache,
     runtime_config: &RuntimeConfig,
 ) -> OrganizedList<Reserves> {
-    let mut join_set: JoinSet<Vec<(usize, Reserves)>> = JoinSet::new();
+    let pair_states = call_batched(
+        "getPairStates",
+        runtime_config.small_chunk_size,
+        markets,
+        |chunk| async move {
+            let addressess: Vec<H160> = chunk.iter().map(|x| x.value.contract_address).collect();
+            return get_pair_states(&runtime_cache.uniswap_query, addressess, block_number).await;
+        },
+    )
+    .await;
 
-    for market_chunk in &markets.into_iter().chunks(runtime_config.small_chunk_size) {
-        let market_values = market_chunk.collect_vec();
-        let addressess: Vec<H160> = market_values
-            .iter()
-            .map(|x| x.value.contract_address)
-            .collect();
-
-        join_set.spawn(async move {
-            match runtime_cache
-                .uniswap_query
-                .get_reserves_by_pairs(addressess.clone())
-                .await
-            {
-                Ok(response) => {
-                    let mut result: Vec<(usize, Reserves)> = Vec::new();
-
-                    for i in 0..market_values.len() {
-                        let raw_reserves: [u128; 3] = response[i];
-                        result.push((
-                            market_values[i].id,
-                            (U256::from(raw_reserves[0]), U256::from(raw_reserves[1])),
-                        ))
-                    }
+    let mut res: OrganizedList<Reserves> = OrganizedList::new();
+    let mut pair_balances = PAIR_BALANCES.write().unwrap();
 
-                    return result;
-                }
-                Err(_) => {
-                    return vec![];
-                }
-            }
+    for (market, pair_reading) in pair_states {
+        res.add_pair(OrgValue {
+            id: market.id,
+            value: pair_reading.reserves,
         });
-    }
 
-    let mut res: OrganizedList<Reserves> = OrganizedList::new();
-    while let Some(Ok(result)) = join_set.join_next().await {
-        // println!("{}", result.len());
-        for i in 0..result.len() {
-            res.add_pair(OrgValue {
-                id: result[i].0,
-                value: result[i].1,
-            });
+        // the last balance state holds when only the reserves were read
+        match pair_reading.balance {
+            Some(PairBalance::Synced) => _ = pair_balances.remove(&market.id),
+            Some(balance) => _ = pair_balances.insert(market.id, balance),
+            None => {}
         }
     }
 
@@ -385,6 +550,55 @@ pub async fn get_market_reserves(
     return res;
 }
 
+#[inline(always)]
+pub fn get_pair_balance_state(market_id: usize) -> PairBalance {
+    return PAIR_BALANCES
+        .read()
+        .unwrap()
+        .get(&market_id)
+        .copied()
+        .unwrap_or(PairBalance::Synced);
+}
+
+#[inline(always)]
+pub fn get_event_topics() -> Vec<H256> {
+    return vec![
+        uniswap_v2_pair::SwapFilter::signature(),
+        uniswap_v2_pair::SyncFilter::signature(),
+    ];
+}
+
+// Reserves the pairs were synced to, in log order
+#[inline(always)]
+pub fn parse_synced_reserves(
+    logs: Vec<&TransactionLog>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<(usize, Reserves)> {
+    let mut result: Vec<(usize, Reserves)> = vec![];
+
+    for transaction_log in logs {
+        if let Ok(filters) =
+            ethers::contract::decode_logs::<uniswap_v2_pair::SyncFilter>(&[transaction_log
+                .raw
+                .clone()])
+        {
+            let market = match Market::from_address(&transaction_log.address, runtime_cache) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            for sync in filters {
+                result.push((
+                    market.id,
+                    (U256::from(sync.reserve_0), U256::from(sync.reserve_1)),
+                ));
+            }
+        }
+    }
+
+    return result;
+}
+
 #[inline(always)]
 pub fn calculate_amount_out(market: &Market, reserves: &Reserves, input_amount: &U256) -> U256 {
     let (fee_multiplier, multiplier) = market.get_fee_data();
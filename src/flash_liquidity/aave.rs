@@ -0,0 +1,238 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use ethers::{
+    abi::{AbiParser, Function, Token as AbiToken},
+    prelude::*,
+    types::transaction::eip2718::TypedTransaction,
+};
+use futures::future::join_all;
+
+use crate::{
+    env::types::RuntimeClient,
+    networks::Network,
+    rpc_batching::{call_batched, call_with_retries},
+    types::Token,
+};
+
+// `PercentageMath.PERCENTAGE_FACTOR`, premiums are in 1/10000
+const PERCENTAGE_FACTOR: u64 = 10000;
+// `ReserveConfiguration` bit positions of Aave V3
+const ACTIVE_BIT: usize = 56;
+const FROZEN_BIT: usize = 57;
+const PAUSED_BIT: usize = 60;
+const FLASH_LOAN_ENABLED_BIT: usize = 63;
+
+lazy_static! {
+    static ref GET_POOL_METHOD: Function = AbiParser::default()
+        .parse_function("getPool()(address)")
+        .unwrap();
+    static ref GET_RESERVE_DATA_METHOD: Function = AbiParser::default()
+        .parse_function(
+            "getReserveData(address)((uint256,uint128,uint128,uint128,uint128,uint128,uint40,uint16,address,address,address,address,uint128,uint128,uint128))"
+        )
+        .unwrap();
+    static ref FLASHLOAN_PREMIUM_TOTAL_METHOD: Function = AbiParser::default()
+        .parse_function("FLASHLOAN_PREMIUM_TOTAL()(uint128)")
+        .unwrap();
+    static ref BALANCE_OF_METHOD: Function = AbiParser::default()
+        .parse_function("balanceOf(address)(uint256)")
+        .unwrap();
+    static ref FLASH_LOAN_RESERVES: RwLock<HashMap<H160, FlashLoanReserve>> =
+        RwLock::new(HashMap::new());
+    static ref FLASH_LOAN_PREMIUM: RwLock<U256> = RwLock::new(U256::zero());
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlashLoanReserve {
+    // underlying balance of the aToken, what the pool can lend out
+    pub available_liquidity: U256,
+    pub active: bool,
+    // frozen reserves still lend through flash loans
+    pub frozen: bool,
+    pub paused: bool,
+    pub flash_loan_enabled: bool,
+}
+
+impl FlashLoanReserve {
+    #[inline(always)]
+    pub fn get_borrowable_amount(&self) -> U256 {
+        if !self.active || self.paused || !self.flash_loan_enabled {
+            return U256::zero();
+        }
+
+        return self.available_liquidity;
+    }
+}
+
+// Undecodable responses fail like the call itself, an `eth_call` gets retried with the others
+#[inline(always)]
+async fn call_method(
+    to: Address,
+    method: &Function,
+    args: &[AbiToken],
+    client: &RuntimeClient,
+) -> Result<Vec<AbiToken>, ProviderError> {
+    let data = method
+        .encode_input(args)
+        .map_err(|err| ProviderError::CustomError(err.to_string()))?;
+    let tx: TypedTransaction = TransactionRequest::new().to(to).data(data).into();
+
+    let response = client.provider().call(&tx, None).await?;
+    return method
+        .decode_output(&response)
+        .map_err(|err| ProviderError::CustomError(err.to_string()));
+}
+
+#[inline(always)]
+fn unexpected_output() -> ProviderError {
+    return ProviderError::CustomError(String::from("unexpected output"));
+}
+
+/// Resolves the Aave `IPool` from the network's `PoolAddressesProvider`
+pub async fn get_pool_address(
+    network: &'static Network,
+    client: &RuntimeClient,
+) -> Option<Address> {
+    let response = call_with_retries("getPool", || {
+        call_method(
+            network.flashloan_pool_address_provider,
+            &GET_POOL_METHOD,
+            &[],
+            client,
+        )
+    })
+    .await
+    .ok()?;
+
+    return response.get(0)?.clone().into_address();
+}
+
+async fn get_reserve(
+    pool_address: Address,
+    token: &Token,
+    client: &RuntimeClient,
+) -> Result<FlashLoanReserve, ProviderError> {
+    let reserve_data = call_method(
+        pool_address,
+        &GET_RESERVE_DATA_METHOD,
+        &[AbiToken::Address(token.contract_address)],
+        client,
+    )
+    .await?
+    .pop()
+    .and_then(|x| x.into_tuple())
+    .ok_or_else(unexpected_output)?;
+
+    let configuration = reserve_data.get(0).and_then(|x| x.clone().into_uint());
+    let a_token_address = reserve_data.get(8).and_then(|x| x.clone().into_address());
+    let (configuration, a_token_address) = configuration
+        .zip(a_token_address)
+        .ok_or_else(unexpected_output)?;
+
+    // assets that aren't listed come back zeroed and can't be borrowed
+    if a_token_address.is_zero() {
+        return Ok(FlashLoanReserve {
+            available_liquidity: U256::zero(),
+            active: false,
+            frozen: false,
+            paused: false,
+            flash_loan_enabled: false,
+        });
+    }
+
+    let available_liquidity = call_method(
+        token.contract_address,
+        &BALANCE_OF_METHOD,
+        &[AbiToken::Address(a_token_address)],
+        client,
+    )
+    .await?
+    .pop()
+    .and_then(|x| x.into_uint())
+    .ok_or_else(unexpected_output)?;
+
+    return Ok(FlashLoanReserve {
+        available_liquidity,
+        active: configuration.bit(ACTIVE_BIT),
+        frozen: configuration.bit(FROZEN_BIT),
+        paused: configuration.bit(PAUSED_BIT),
+        flash_loan_enabled: configuration.bit(FLASH_LOAN_ENABLED_BIT),
+    });
+}
+
+/// Refreshes the premium and the reserve of every flash loan enabled token, values that couldn't
+/// be read after the retries keep their last known state
+pub async fn update_reserves(
+    network: &'static Network,
+    pool_address: Address,
+    client: &RuntimeClient,
+) {
+    let tokens: Vec<&'static Token> = network
+        .tokens
+        .iter()
+        .filter(|x| x.flash_loan_enabled)
+        .collect();
+
+    let reserves = call_batched("getReserveData", tokens.len(), tokens, |chunk| async move {
+        let reserves = join_all(
+            chunk
+                .into_iter()
+                .map(|token| get_reserve(pool_address, token, client)),
+        )
+        .await;
+
+        return reserves
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map(|x| (x, 0));
+    })
+    .await;
+
+    let premium = call_with_retries("FLASHLOAN_PREMIUM_TOTAL", || {
+        call_method(pool_address, &FLASHLOAN_PREMIUM_TOTAL_METHOD, &[], client)
+    })
+    .await
+    .ok()
+    .and_then(|mut x| x.pop()?.into_uint());
+    if let Some(premium) = premium {
+        *FLASH_LOAN_PREMIUM.write().unwrap() = premium;
+    }
+
+    for (token, reserve) in reserves {
+        set_reserve(token, reserve);
+    }
+}
+
+#[inline(always)]
+pub fn set_reserve(token: &Token, reserve: FlashLoanReserve) {
+    FLASH_LOAN_RESERVES
+        .write()
+        .unwrap()
+        .insert(token.contract_address, reserve);
+}
+
+#[inline(always)]
+pub fn get_reserve_status(token: &Token) -> Option<FlashLoanReserve> {
+    return FLASH_LOAN_RESERVES
+        .read()
+        .unwrap()
+        .get(&token.contract_address)
+        .copied();
+}
+
+/// Largest amount of `token` a flash loan can currently borrow, `None` until the reserve has been
+/// read once
+#[inline(always)]
+pub fn get_borrowable_amount(token: &Token) -> Option<U256> {
+    return get_reserve_status(token).map(|x| x.get_borrowable_amount());
+}
+
+#[inline(always)]
+pub fn get_premium() -> U256 {
+    return *FLASH_LOAN_PREMIUM.read().unwrap();
+}
+
+#[inline(always)]
+pub fn get_fee(amount: U256) -> U256 {
+    return (amount * get_premium() + PERCENTAGE_FACTOR / 2) / PERCENTAGE_FACTOR;
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use ethers::{
+    abi::{AbiParser, Function, Token as AbiToken},
+    prelude::*,
+    types::transaction::eip2718::TypedTransaction,
+};
+use futures::future::join_all;
+
+use crate::{
+    env::types::RuntimeClient, networks::Network, rpc_batching::call_batched, types::Token,
+};
+
+// the Vault the executor borrows from, the same address on every network
+pub const VAULT_ADDRESS: &str = "0xBA12222222228d8Ba445958a75a0704d566BF2C8";
+
+lazy_static! {
+    static ref BALANCE_OF_METHOD: Function = AbiParser::default()
+        .parse_function("balanceOf(address)(uint256)")
+        .unwrap();
+    static ref VAULT_BALANCES: RwLock<HashMap<H160, U256>> = RwLock::new(HashMap::new());
+}
+
+#[inline(always)]
+async fn get_vault_balance(token: &Token, client: &RuntimeClient) -> Result<U256, ProviderError> {
+    let vault_address: Address = VAULT_ADDRESS.parse().unwrap();
+    let data = BALANCE_OF_METHOD
+        .encode_input(&[AbiToken::Address(vault_address)])
+        .map_err(|err| ProviderError::CustomError(err.to_string()))?;
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(token.contract_address)
+        .data(data)
+        .into();
+
+    let response = client.provider().call(&tx, None).await?;
+    return BALANCE_OF_METHOD
+        .decode_output(&response)
+        .ok()
+        .and_then(|mut x| x.pop()?.into_uint())
+        .ok_or_else(|| ProviderError::CustomError(String::from("unexpected output")));
+}
+
+/// Refreshes the Vault's balance of every flash loan enabled token, it lends all of it
+pub async fn update_balances(network: &'static Network, client: &RuntimeClient) {
+    let tokens: Vec<&'static Token> = network
+        .tokens
+        .iter()
+        .filter(|x| x.flash_loan_enabled)
+        .collect();
+
+    let balances = call_batched(
+        "vault_balanceOf",
+        tokens.len(),
+        tokens,
+        |chunk| async move {
+            let balances = join_all(
+                chunk
+                    .into_iter()
+                    .map(|token| get_vault_balance(token, client)),
+            )
+            .await;
+
+            return balances
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|x| (x, 0));
+        },
+    )
+    .await;
+
+    let mut w_reference = VAULT_BALANCES.write().unwrap();
+    for (token, balance) in balances {
+        w_reference.insert(token.contract_address, balance);
+    }
+}
+
+#[inline(always)]
+pub fn get_borrowable_amount(token: &Token) -> Option<U256> {
+    return VAULT_BALANCES
+        .read()
+        .unwrap()
+        .get(&token.contract_address)
+        .copied();
+}
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use ethers::prelude::*;
+use futures::future::join_all;
+
+use crate::{env::RuntimeCache, networks::Network, rpc_batching::call_batched, types::Token};
+
+lazy_static! {
+    static ref INVENTORY_BALANCES: RwLock<HashMap<H160, U256>> = RwLock::new(HashMap::new());
+}
+
+/// Refreshes the executor's own balance of every flash loan enabled token
+pub async fn update_balances(network: &'static Network, runtime_cache: &'static RuntimeCache) {
+    let tokens: Vec<&'static Token> = network
+        .tokens
+        .iter()
+        .filter(|x| x.flash_loan_enabled)
+        .collect();
+
+    let balances = call_batched("getBalance", tokens.len(), tokens, |chunk| async move {
+        let balances = join_all(chunk.into_iter().map(|token| async move {
+            return runtime_cache
+                .bundle_executor
+                .get_balance(token.contract_address)
+                .call()
+                .await;
+        }))
+        .await;
+
+        return balances
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map(|x| (x, 0));
+    })
+    .await;
+
+    let mut w_reference = INVENTORY_BALANCES.write().unwrap();
+    for (token, balance) in balances {
+        w_reference.insert(token.contract_address, balance);
+    }
+}
+
+#[inline(always)]
+pub fn get_balance(token: &Token) -> Option<U256> {
+    return INVENTORY_BALANCES
+        .read()
+        .unwrap()
+        .get(&token.contract_address)
+        .copied();
+}
@@ -0,0 +1,256 @@
+use ethers::{abi::AbiError, prelude::*};
+
+use crate::{
+    env::{
+        types::{
+            EXECUTE_BALANCER_TX_BUNDLE_FUNCTION, EXECUTE_BATCH_TX_BUNDLE_FUNCTION,
+            EXECUTE_FLASH_SWAP_TX_BUNDLE_FUNCTION, EXECUTE_INVENTORY_TX_BUNDLE_FUNCTION,
+        },
+        RuntimeCache, EXECUTE_TX_BUNDLE_FUNCTION,
+    },
+    exchanges::types::Protocol,
+    networks::Network,
+    price_oracle::GasPrices,
+    types::{
+        market::Market, BatchExecutionCall, BundleExecutionCall, BundleGuards, BundleLoan,
+        FlashSwapExecutionCall, PriceTable, RouteResult, Token,
+    },
+};
+
+pub mod aave;
+mod balancer;
+mod inventory;
+
+// Where the executor gets the input of the first market from, each source has its own
+// `BundleExecutor` entry point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlashLiquiditySource {
+    // the executor's own balance
+    Inventory,
+    // a fee-less Balancer Vault flash loan
+    Balancer,
+    // the first market sends its output before it's paid, it has to be a Uniswap V2 pair
+    FlashSwap,
+    // an Aave flash loan, paying the pool's premium
+    Aave,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlashLiquidity {
+    pub source: FlashLiquiditySource,
+    // paid in the borrowed token on top of the borrowed amount
+    pub cost: U256,
+}
+
+/// L2 gas the entry point of `source` spends besides the swaps, lending included
+#[inline(always)]
+pub fn get_entry_gas(source: FlashLiquiditySource) -> u64 {
+    return match source {
+        FlashLiquiditySource::Inventory => 30_000,
+        FlashLiquiditySource::Balancer => 80_000,
+        FlashLiquiditySource::FlashSwap => 50_000,
+        FlashLiquiditySource::Aave => 120_000,
+    };
+}
+
+// Bytes the source adds to a packed bundle, a flash swap's pair and amount out are in the header
+// and its first market drops out of the records
+#[inline(always)]
+fn get_entry_calldata_size(source: FlashLiquiditySource) -> usize {
+    return match source {
+        FlashLiquiditySource::FlashSwap => 0,
+        _ => 32,
+    };
+}
+
+// Only pairs calling back `uniswapV2Call` can lend to the executor
+#[inline(always)]
+fn supports_flash_swaps(market: &Market) -> bool {
+    return market.protocol == Protocol::UniswapV2 && market.flash_swaps;
+}
+
+// Sources in order of preference when they cost the same
+#[inline(always)]
+fn get_available_sources(
+    token: &Token,
+    amount: U256,
+    first_market: &Market,
+) -> Vec<FlashLiquidity> {
+    let mut sources: Vec<FlashLiquidity> = vec![];
+    let is_available = |borrowable_amount: Option<U256>| match borrowable_amount {
+        Some(borrowable_amount) => borrowable_amount >= amount,
+        None => false,
+    };
+
+    if is_available(inventory::get_balance(token)) {
+        sources.push(FlashLiquidity {
+            source: FlashLiquiditySource::Inventory,
+            cost: U256::zero(),
+        });
+    }
+
+    if is_available(balancer::get_borrowable_amount(token)) {
+        sources.push(FlashLiquidity {
+            source: FlashLiquiditySource::Balancer,
+            cost: U256::zero(),
+        });
+    }
+
+    if supports_flash_swaps(first_market) {
+        sources.push(FlashLiquidity {
+            source: FlashLiquiditySource::FlashSwap,
+            cost: U256::zero(),
+        });
+    }
+
+    // Aave stays the fallback until its reserve has been read
+    if aave::get_borrowable_amount(token).map_or(true, |x| x >= amount) {
+        sources.push(FlashLiquidity {
+            source: FlashLiquiditySource::Aave,
+            cost: aave::get_fee(amount),
+        });
+    }
+
+    return sources;
+}
+
+/// Cheapest source able to provide `amount` of `token` to a route starting at `first_market`,
+/// its fee valued in the reference token along with the gas and L1 data of its entry point
+#[inline(always)]
+pub fn select_source(
+    token: &Token,
+    amount: U256,
+    first_market: &Market,
+    price_table: &PriceTable,
+    gas_prices: &GasPrices,
+) -> Option<FlashLiquidity> {
+    let mut selected: Option<(FlashLiquidity, U256)> = None;
+
+    for source in get_available_sources(token, amount, first_market) {
+        let cost = price_table.get_ref_price(token, source.cost)
+            + gas_prices.cost_of_size(
+                get_entry_calldata_size(source.source),
+                U256::from(get_entry_gas(source.source)),
+            );
+
+        if selected.map_or(true, |x| cost < x.1) {
+            selected = Some((source, cost));
+        }
+    }
+
+    return selected.map(|x| x.0);
+}
+
+/// Largest input any source can provide, `None` when the first market can flash swap or before
+/// the Aave reserve has been read
+#[inline(always)]
+pub fn get_max_amount(token: &Token, first_market: &Market) -> Option<U256> {
+    if supports_flash_swaps(first_market) {
+        return None;
+    }
+
+    let aave_amount = aave::get_borrowable_amount(token)?;
+    let max_amount = [
+        inventory::get_balance(token),
+        balancer::get_borrowable_amount(token),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(aave_amount, |max_amount, x| max_amount.max(x));
+
+    return Some(max_amount);
+}
+
+/// Refreshes what every source can provide, the Aave pool is resolved by the caller
+pub async fn update_sources(
+    network: &'static Network,
+    aave_pool_address: Option<Address>,
+    runtime_cache: &'static RuntimeCache,
+) {
+    let aave_update = async {
+        if let Some(pool_address) = aave_pool_address {
+            aave::update_reserves(network, pool_address, &runtime_cache.client).await;
+        }
+    };
+
+    tokio::join!(
+        aave_update,
+        balancer::update_balances(network, &runtime_cache.client),
+        inventory::update_balances(network, runtime_cache)
+    );
+}
+
+/// Encodes the `BundleExecutor` call of the route's flash liquidity source. With a flash swap
+/// the first market is called by the executor itself, `targets` and `payloads` start at the
+/// second market.
+pub fn encode_bundle(
+    route_result: &RouteResult,
+    targets: Vec<Address>,
+    payloads: Vec<Bytes>,
+    guards: BundleGuards,
+) -> Result<Bytes, AbiError> {
+    let bundle_execution_call = BundleExecutionCall {
+        token: route_result.base_token.contract_address,
+        amount_to_first_market: route_result.start_balance,
+        targets,
+        payloads,
+        guards,
+    };
+
+    match route_result.flash_liquidity.source {
+        FlashLiquiditySource::Aave => {
+            return ethers::contract::encode_function_data::<BundleExecutionCall>(
+                &EXECUTE_TX_BUNDLE_FUNCTION,
+                bundle_execution_call,
+            );
+        }
+        FlashLiquiditySource::Balancer => {
+            return ethers::contract::encode_function_data::<BundleExecutionCall>(
+                &EXECUTE_BALANCER_TX_BUNDLE_FUNCTION,
+                bundle_execution_call,
+            );
+        }
+        FlashLiquiditySource::Inventory => {
+            return ethers::contract::encode_function_data::<BundleExecutionCall>(
+                &EXECUTE_INVENTORY_TX_BUNDLE_FUNCTION,
+                bundle_execution_call,
+            );
+        }
+        FlashLiquiditySource::FlashSwap => {
+            let first_swap = &route_result.transactions[0].value;
+
+            return ethers::contract::encode_function_data::<FlashSwapExecutionCall>(
+                &EXECUTE_FLASH_SWAP_TX_BUNDLE_FUNCTION,
+                FlashSwapExecutionCall {
+                    pair: first_swap.market.value.contract_address,
+                    token: bundle_execution_call.token,
+                    repay_amount: first_swap.amount_in,
+                    amount_0_out: first_swap.amount_0_out,
+                    amount_1_out: first_swap.amount_1_out,
+                    targets: bundle_execution_call.targets,
+                    payloads: bundle_execution_call.payloads,
+                    guards,
+                },
+            );
+        }
+    }
+}
+
+/// Encodes the multi-asset Aave flash loan call of a batch of routes, `targets` and `payloads`
+/// hold the calls of every route in order
+pub fn encode_batch_bundle(
+    loans: Vec<BundleLoan>,
+    targets: Vec<Address>,
+    payloads: Vec<Bytes>,
+    guards: BundleGuards,
+) -> Result<Bytes, AbiError> {
+    return ethers::contract::encode_function_data::<BatchExecutionCall>(
+        &EXECUTE_BATCH_TX_BUNDLE_FUNCTION,
+        BatchExecutionCall {
+            loans,
+            targets,
+            payloads,
+            guards,
+        },
+    );
+}
@@ -1,14 +1,17 @@
-use ethers::types::Transaction;
+use ethers::types::{Transaction, U64};
 use futures::{SinkExt, StreamExt};
-use std::time::Instant;
+use std::{collections::HashMap, time::Instant};
 
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinSet;
 use websocket_lite::{ClientBuilder, Message, Opcode};
 
 use crate::env::{RuntimeCache, RuntimeConfig};
-use crate::types::{BalanceChange, RelayMessage, TransactionDecodeResult};
-use crate::{exchanges, log_tracer, price_oracle};
+use crate::types::{
+    BalanceChange, MarketUpdate, RelayMessage, TransactionDecodeResult, TransactionLog,
+    TransactionSource,
+};
+use crate::{calldata_decoder, exchanges, log_tracer, prefilter, price_oracle};
 
 use super::MarketDataFeed;
 
@@ -18,7 +21,7 @@ pub struct ArbitrumDataFeed;
 impl MarketDataFeed for ArbitrumDataFeed {
     async fn init(
         &self,
-        sender: Sender<Vec<BalanceChange>>,
+        sender: Sender<MarketUpdate>,
         runtime_config: &'static RuntimeConfig,
         runtime_cache: &'static RuntimeCache,
     ) -> websocket_lite::Result<()> {
@@ -28,7 +31,10 @@ impl MarketDataFeed for ArbitrumDataFeed {
         while let Some(msg) = stream.next().await {
             if let Ok(incomming) = msg {
                 match incomming.opcode() {
-                    Opcode::Text => handle_text_message(incomming, &sender, &runtime_cache).await,
+                    Opcode::Text => {
+                        handle_text_message(incomming, &sender, runtime_config, &runtime_cache)
+                            .await
+                    }
                     Opcode::Ping => stream.send(Message::pong(incomming.into_data())).await?,
                     Opcode::Close => break,
                     Opcode::Pong | Opcode::Binary => {}
@@ -43,49 +49,44 @@ impl MarketDataFeed for ArbitrumDataFeed {
 #[inline(always)]
 async fn handle_text_message(
     incomming: Message,
-    sender: &Sender<Vec<BalanceChange>>,
+    sender: &Sender<MarketUpdate>,
+    runtime_config: &'static RuntimeConfig,
     runtime_cache: &'static RuntimeCache,
 ) {
     if let Some(message_text) = incomming.as_text() {
         if let Some(relay_message) = RelayMessage::from_json(message_text) {
-            let decoded_transactions: Vec<TransactionDecodeResult> = relay_message.decode();
-            
+            let decoded_transactions: Vec<TransactionDecodeResult> = relay_message
+                .decode()
+                .into_iter()
+                .filter(|x| prefilter::is_relevant(&x.transaction, runtime_config))
+                .collect();
+
             if decoded_transactions.len() > 0 {
                 let inst = Instant::now();
+                let block = price_oracle::PriceOracle::get_block_header();
                 let block_number = price_oracle::PriceOracle::get_block_number();
-                let mut balance_changes: Vec<BalanceChange> = vec![];
-                
-                let mut call_set: JoinSet<Vec<BalanceChange>> = JoinSet::new();
-                
-                for decoded in decoded_transactions {
-                    call_set.spawn(async move {
-                        if decoded.transaction.to.is_some() {
-                            if let Some(transaction_logs) = log_tracer::trace_transaction(
-                                Transaction {
-                                    block_number: Some(block_number),
-                                    ..decoded.transaction
-                                },
-                                &runtime_cache,
-                            )
-                            .await
-                            {
-                                if transaction_logs.len() > 0 {
-                                    return exchanges::parse_balance_changes(
-                                        &transaction_logs,
-                                        &runtime_cache,
-                                    );
-                                }
-                            }
-                        }
-
-                        return vec![];
-                    });
-                }
 
-                while let Some(Ok(mut changes)) = call_set.join_next().await {
-                    if changes.len() > 0 {
-                        balance_changes.append(&mut changes);
-                    }
+                let balance_changes = if runtime_config.sequential_tracing {
+                    trace_sequentially(
+                        decoded_transactions,
+                        block_number,
+                        runtime_config,
+                        runtime_cache,
+                    )
+                    .await
+                } else {
+                    trace_concurrently(
+                        decoded_transactions,
+                        block_number,
+                        runtime_config,
+                        runtime_cache,
+                    )
+                    .await
+                };
+
+                // a reorg while tracing orphans the state the changes were traced on
+                if block.map_or(false, |x| !price_oracle::PriceOracle::is_canonical(&x)) {
+                    return;
                 }
 
                 if balance_changes.len() > 0 {
@@ -95,9 +96,161 @@ async fn handle_text_message(
                         inst.elapsed()
                     );
 
-                    _ = sender.send(balance_changes).await;
+                    _ = sender
+                        .send(MarketUpdate {
+                            block,
+                            confirmed: false,
+                            balance_changes,
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+// ties every change to the transaction it can backrun
+#[inline(always)]
+fn tag_balance_changes(
+    mut changes: Vec<BalanceChange>,
+    source: TransactionSource,
+) -> Vec<BalanceChange> {
+    for change in changes.iter_mut() {
+        change.source = Some(source);
+    }
+
+    return changes;
+}
+
+#[inline(always)]
+fn parse_tagged_balance_changes(
+    transaction_logs: &Vec<TransactionLog>,
+    source: TransactionSource,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    return tag_balance_changes(
+        exchanges::parse_balance_changes(transaction_logs, runtime_cache),
+        source,
+    );
+}
+
+// Every transaction is traced on the state before the message, independently of the others
+#[inline(always)]
+async fn trace_concurrently(
+    decoded_transactions: Vec<TransactionDecodeResult>,
+    block_number: U64,
+    runtime_config: &'static RuntimeConfig,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    let mut balance_changes: Vec<BalanceChange> = vec![];
+    let mut call_set: JoinSet<Vec<BalanceChange>> = JoinSet::new();
+
+    for decoded in decoded_transactions {
+        call_set.spawn(async move {
+            let source = decoded.get_source();
+
+            if let Some(changes) = calldata_decoder::predict_balance_changes(
+                &decoded.transaction,
+                decoded.timestamp,
+                &mut HashMap::new(),
+                &runtime_cache.markets,
+            )
+            .await
+            {
+                return tag_balance_changes(changes, source);
+            }
+
+            if decoded.transaction.to.is_some() {
+                if let Some(transaction_logs) = log_tracer::trace_transaction(
+                    Transaction {
+                        block_number: Some(block_number),
+                        ..decoded.transaction
+                    },
+                    runtime_config.tracer_backend,
+                    &runtime_cache,
+                )
+                .await
+                {
+                    if transaction_logs.len() > 0 {
+                        return parse_tagged_balance_changes(
+                            &transaction_logs,
+                            source,
+                            runtime_cache,
+                        );
+                    }
                 }
             }
+
+            return vec![];
+        });
+    }
+
+    while let Some(Ok(mut changes)) = call_set.join_next().await {
+        if changes.len() > 0 {
+            balance_changes.append(&mut changes);
         }
     }
+
+    return balance_changes;
+}
+
+// Transactions are handled in sequencer order, each on the state left by the ones before it, so
+// several transactions through the same pool produce the changes that will land. Predicted router
+// calls move the state later traces run on, traced ones the reserves later predictions use.
+#[inline(always)]
+async fn trace_sequentially(
+    mut decoded_transactions: Vec<TransactionDecodeResult>,
+    block_number: U64,
+    runtime_config: &'static RuntimeConfig,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<BalanceChange> {
+    decoded_transactions.sort_by_key(|x| (x.sequence_number, x.index));
+
+    let mut balance_changes: Vec<BalanceChange> = vec![];
+    let mut local_reserves = HashMap::new();
+    let mut tracer =
+        log_tracer::SequentialTracer::new(runtime_config.tracer_backend, runtime_cache);
+
+    for decoded in decoded_transactions {
+        let source = decoded.get_source();
+        let transaction = Transaction {
+            block_number: Some(block_number),
+            ..decoded.transaction
+        };
+
+        if let Some(changes) = calldata_decoder::predict_balance_changes(
+            &transaction,
+            decoded.timestamp,
+            &mut local_reserves,
+            &runtime_cache.markets,
+        )
+        .await
+        {
+            // calls predicted to revert leave the state as it is
+            if changes.len() > 0 {
+                tracer.apply(transaction);
+                balance_changes.append(&mut tag_balance_changes(changes, source));
+            }
+
+            continue;
+        }
+
+        if let Some(transaction_logs) = tracer.trace(transaction).await {
+            for (market_id, reserves) in
+                exchanges::parse_synced_reserves(&transaction_logs, runtime_cache)
+            {
+                local_reserves.insert(market_id, reserves);
+            }
+
+            if transaction_logs.len() > 0 {
+                balance_changes.append(&mut parse_tagged_balance_changes(
+                    &transaction_logs,
+                    source,
+                    runtime_cache,
+                ));
+            }
+        }
+    }
+
+    return balance_changes;
 }
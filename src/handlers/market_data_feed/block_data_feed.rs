@@ -0,0 +1,33 @@
+use tokio::sync::{broadcast::error::RecvError, mpsc::Sender};
+
+use crate::env::{RuntimeCache, RuntimeConfig};
+use crate::price_oracle::PriceOracle;
+use crate::types::MarketUpdate;
+
+use super::MarketDataFeed;
+
+// Forwards the balance changes of confirmed blocks, the reserve table loaded at startup follows
+// the same blocks
+pub struct BlockDataFeed;
+
+#[async_trait::async_trait]
+impl MarketDataFeed for BlockDataFeed {
+    async fn init(
+        &self,
+        sender: Sender<MarketUpdate>,
+        _runtime_config: &'static RuntimeConfig,
+        _runtime_cache: &'static RuntimeCache,
+    ) -> websocket_lite::Result<()> {
+        let mut receiver = PriceOracle::subscribe_block_updates();
+
+        loop {
+            match receiver.recv().await {
+                Ok(market_update) => _ = sender.send(market_update).await,
+                Err(RecvError::Lagged(count)) => println!("Skipped {} block updates", count),
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        Ok(())
+    }
+}
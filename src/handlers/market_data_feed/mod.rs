@@ -1,25 +1,53 @@
 mod arbitrum_data_feed;
+mod block_data_feed;
 pub use arbitrum_data_feed::ArbitrumDataFeed;
+pub use block_data_feed::BlockDataFeed;
 
 use tokio::sync::mpsc::Sender;
 use websocket_lite::Result;
 
 use crate::env::*;
-use crate::types::BalanceChange;
+use crate::types::MarketUpdate;
 
 use crate::networks::ARBITRUM_CHAIN_ID;
 #[async_trait::async_trait]
 pub trait MarketDataFeed {
     async fn init(
         &self,
-        sender: Sender<Vec<BalanceChange>>,
+        sender: Sender<MarketUpdate>,
         runtime_config: &'static RuntimeConfig,
         runtime_cache: &'static RuntimeCache,
     ) -> Result<()>;
 }
 
+// What drives the market updates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedMode {
+    // pending transactions of the network's sequencer feed
+    Sequencer,
+    // the logs of confirmed blocks, on any chain and without the debug namespace
+    Blocks,
+}
+
+impl FeedMode {
+    pub fn from_name(name: &str) -> Option<FeedMode> {
+        match name {
+            "sequencer" => return Some(FeedMode::Sequencer),
+            "blocks" => return Some(FeedMode::Blocks),
+            _ => return None,
+        }
+    }
+}
+
 #[inline(always)]
-pub fn get_network_data_feed(chain_id: u32) -> Option<&'static (dyn MarketDataFeed + Send + Sync)> {
+pub fn get_network_data_feed(
+    chain_id: u32,
+    feed_mode: FeedMode,
+) -> Option<&'static (dyn MarketDataFeed + Send + Sync)> {
+    if feed_mode == FeedMode::Blocks {
+        return Some(&BlockDataFeed);
+    }
+
     match chain_id {
         ARBITRUM_CHAIN_ID => return Some(&ArbitrumDataFeed),
         0_u32..=42160_u32 | 42162_u32..=u32::MAX => {
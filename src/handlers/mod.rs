@@ -1,5 +1,6 @@
 mod network_handler;
 mod market_data_feed;
 
+pub use market_data_feed::FeedMode;
 pub use market_data_feed::MarketDataFeed;
 pub use network_handler::NetworkHandler;
@@ -1,26 +1,46 @@
-use std::thread;
+use std::{thread, time::Duration};
 
 use ethers::{
     prelude::AbiError,
-    types::{Address, Bytes},
+    types::{Address, Bytes, U256},
+    utils::format_units,
 };
+use itertools::Itertools;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
 use crate::{
-    env::{RuntimeCache, RuntimeConfig, EXECUTE_TX_BUNDLE_FUNCTION},
-    exchanges::{init_exchange_handlers, populate_swap},
+    env::{RuntimeCache, RuntimeConfig},
+    exchanges::{
+        erc20::{populate_approve, populate_transfer},
+        get_pair_balance, get_swap_mode, init_exchange_handlers, populate_swap,
+        types::{PairBalance, SwapMode},
+    },
+    flash_liquidity::{encode_batch_bundle, encode_bundle, FlashLiquiditySource},
+    local_evm,
+    log_tracer::TracerBackend,
     networks::Network,
-    price_oracle::PriceOracle,
-    types::{BalanceChange, BundleExecutionCall, RouteResult},
+    prefilter,
+    price_oracle::{GasPrices, PriceOracle},
+    rpc_batching,
+    token_safety::denylist,
+    types::{
+        encode_packed_bundle, BundleGuards, MarketUpdate, Route, RouteBatch, RouteResult,
+    },
 };
 
 use super::{market_data_feed::get_network_data_feed, MarketDataFeed};
 
+const DENYLIST_RELOAD_INTERVAL: u64 = 5;
+const PREFILTER_REPORT_INTERVAL: u64 = 60;
+const RPC_REPORT_INTERVAL: u64 = 60;
+
 pub struct NetworkHandler {
     price_oracle: PriceOracle,
     runtime_config: &'static RuntimeConfig,
     runtime_cache: &'static RuntimeCache,
     data_feed: &'static (dyn MarketDataFeed + Send + Sync),
+    routes: &'static Vec<Route>,
 }
 
 impl NetworkHandler {
@@ -29,15 +49,18 @@ impl NetworkHandler {
         network: &'static Network,
         runtime_config: &'static RuntimeConfig,
         runtime_cache: &'static RuntimeCache,
+        routes: &'static Vec<Route>,
     ) -> Option<NetworkHandler> {
-        if let Some(data_feed) = get_network_data_feed(network.chain_id) {
+        if let Some(data_feed) = get_network_data_feed(network.chain_id, runtime_config.feed_mode) {
             let price_oracle = PriceOracle::new(network, runtime_cache, runtime_config);
+            prefilter::load(network, runtime_cache);
 
             return Some(NetworkHandler {
                 runtime_config,
                 runtime_cache,
                 price_oracle,
                 data_feed,
+                routes,
             });
         }
 
@@ -48,8 +71,17 @@ impl NetworkHandler {
     pub async fn init(&mut self) {
         init_exchange_handlers();
         self.price_oracle.initiate();
+        // routes of either feed are evaluated on the reserve table, blocks move it from here
+        PriceOracle::load_market_reserves(self.runtime_cache, self.runtime_config).await;
+        denylist::initiate_reloads(Duration::from_secs(DENYLIST_RELOAD_INTERVAL));
+        prefilter::initiate_reports(Duration::from_secs(PREFILTER_REPORT_INTERVAL));
+        rpc_batching::initiate_reports(Duration::from_secs(RPC_REPORT_INTERVAL));
+
+        if self.runtime_config.tracer_backend == TracerBackend::Revm {
+            local_evm::initiate_state_updates(self.runtime_cache, self.runtime_config);
+        }
 
-        let (sender, mut receiver): (Sender<Vec<BalanceChange>>, Receiver<_>) = channel(32);
+        let (sender, mut receiver): (Sender<MarketUpdate>, Receiver<_>) = channel(32);
 
         let data_feed = self.data_feed;
         let config_reference = self.runtime_config;
@@ -68,151 +100,155 @@ impl NetworkHandler {
         });
 
         let mut switch = true;
-        while let Some(balance_changes) = receiver.recv().await {
+        while let Some(market_update) = receiver.recv().await {
             if switch == true {
                 switch = false;
                 println!("Validation received...\n");
                 println!("Listening to market updates...\n")
             } else {
-                if balance_changes.len() > 0 {
-                    Self::handle_market_update(&balance_changes);
+                // opportunities on the state of an orphaned block are dropped
+                if let Some(block) = market_update.block {
+                    if !PriceOracle::is_canonical(&block) {
+                        continue;
+                    }
+                }
+
+                if market_update.balance_changes.len() > 0 {
+                    self.handle_market_update(&market_update).await;
+                }
+            }
+        }
+    }
+
+    // Evaluates the routes through the markets of the update, on the reserve table moved by its
+    // changes while they're pending. The table has to be complete and at the update's block,
+    // changes traced on one block aren't applied to the reserves of another. Routes through
+    // desynchronised pairs would revert and are skipped.
+    async fn handle_market_update(&self, market_update: &MarketUpdate) {
+        let reserve_snapshot = self.price_oracle.get_market_reserves().await;
+        if reserve_snapshot.is_empty() || reserve_snapshot.partial {
+            return;
+        }
+
+        if let Some(block) = market_update.block {
+            if block.number != reserve_snapshot.block_number {
+                return;
+            }
+        }
+
+        let mut reserve_table = reserve_snapshot.reserves;
+
+        if !market_update.confirmed {
+            for balance_change in &market_update.balance_changes {
+                let market_id = balance_change.market.id;
+                if let Ok(index) = reserve_table.binary_search_by_key(&market_id, |x| x.id) {
+                    let reserves = reserve_table[index].value;
+                    reserve_table[index].value = (
+                        (reserves.0 + balance_change.amount_0_in)
+                            .saturating_sub(balance_change.amount_0_out.into()),
+                        (reserves.1 + balance_change.amount_1_in)
+                            .saturating_sub(balance_change.amount_1_out.into()),
+                    );
                 }
             }
         }
+
+        let price_table = self.price_oracle.get_ref_price_table().await;
+        let gas_prices = PriceOracle::get_gas_prices().await;
+        let market_ids: Vec<usize> = market_update
+            .balance_changes
+            .iter()
+            .map(|x| x.market.id)
+            .unique()
+            .collect();
+
+        let mut route_results: Vec<RouteResult> = self
+            .routes
+            .par_iter()
+            .filter(|x| {
+                !x.markets
+                    .iter()
+                    .any(|y| get_pair_balance(y.id) == PairBalance::Desynchronised)
+            })
+            .filter_map(|x| {
+                x.calculate_result(&reserve_table, &price_table, &gas_prices, &market_ids)
+            })
+            .collect();
+
+        if route_results.is_empty() {
+            return;
+        }
+
+        // a route has to pay for its own transaction, L1 data included
+        route_results.retain_mut(|route_result| {
+            match self.get_route_cost(route_result, &gas_prices) {
+                Some(cost) => route_result.cost = cost,
+                None => return false,
+            }
+
+            return route_result.ref_profit_loss > route_result.cost;
+        });
+
+        if route_results.is_empty() {
+            return;
+        }
+
+        for route_result in route_results.iter_mut() {
+            route_result.tag_source(&market_update.balance_changes);
+        }
+
+        let route_count = route_results.len();
+        let route_batch = RouteBatch::select(
+            route_results,
+            &self.runtime_cache.markets,
+            &reserve_table,
+            &price_table,
+            &gas_prices,
+        );
+
+        let cost = match self.get_batch_cost(&route_batch, &gas_prices) {
+            Some(value) => value,
+            None => return,
+        };
+
+        if route_batch.get_ref_profit_loss() <= cost {
+            return;
+        }
+
+        println!(
+            "{} block {}: {} profitable routes, {} batched for {} ref net of costs",
+            if market_update.confirmed { "Confirmed" } else { "Pending" },
+            market_update
+                .block
+                .map_or(PriceOracle::get_block_number(), |x| x.number),
+            route_count,
+            route_batch.route_results.len(),
+            format_units(route_batch.get_ref_profit_loss() - cost, 18).unwrap_or_default()
+        );
     }
 
+    // Cost in wei of sending the route alone, in the packed format
     #[inline(always)]
-    fn handle_market_update(_balance_changes: &Vec<BalanceChange>) {
-        // let mut reserve_table = self.price_oracle.get_market_reserves().await;
-
-        // for balance_change in balance_changes {
-        //     let reserves = reserve_table[balance_change.market.id].value;
-        //     reserve_table[balance_change.market.id].value = (
-        //         (reserves.0 + balance_change.amount_0_in) - balance_change.amount_0_out,
-        //         (reserves.1 + balance_change.amount_1_in) - balance_change.amount_1_out,
-        //     )
-        // }
-
-        // let inst = Instant::now();
-        // let f: crate::types::OrganizedList<(ethers::types::U256, ethers::types::U256)> =
-        // get_market_reserves(
-        //     &self.runtime_cache.markets,
-        //     self.runtime_cache,
-        //     self.runtime_config,
-        // )
-        // .await;
-        // println!("{:?}", inst.elapsed());
-
-        // for i in 0..reserve_table.len() {
-        //     let old_reserves = reserve_table.to_raw_vec()[i];
-        //     let new_reserves = f.to_raw_vec()[i];
-
-        //     if !old_reserves.value.0.eq(&new_reserves.value.0)
-        //         || !old_reserves.value.1.eq(&new_reserves.value.1)
-        //     {
-        //         println!(
-        //             "{:?}",
-        //             self.runtime_cache.markets[old_reserves.id]
-        //                 .value
-        //                 .contract_address
-        //         );
-        //         println!("{:#?}", old_reserves);
-        //         println!("{:#?}", new_reserves);
-        //         println!(
-        //             "{:#?}",
-        //             balance_changes
-        //                 .iter()
-        //                 .find(|x| x.market.id == old_reserves.id)
-        //         );
-        //     }
-        // }
-        // if _reserve_table == f {
-        //     println!("t");
-        //     process::exit(1);
-        // }
-
-        // let _route_results: Vec<RouteResult> = RUNTIME_ROUTES
-        //     .par_iter()
-        //     .filter_map(|x| {
-        //         return x.calculate_result(&reserve_table, price_table, &market_ids);
-        //     })
-        //     .collect();
-
-        // for market_id in market_ids {
-        //     println!("id: {}", market_id);
-        //     println!("market {:#?}", self.runtime_cache.markets[*market_id]);
-        //     println!("{:#?}", f[*market_id]);
-        // }
-
-        // process::exit(0);
-
-        // if route_results.len() > 0 {
-        //     let mut best_route_result: &RouteResult = &route_results[0];
-
-        //     for i in 1..route_results.len() {
-        //         let current_route_result = &route_results[i];
-        //         if current_route_result.ref_profit_loss > best_route_result.ref_profit_loss {
-        //             best_route_result = current_route_result;
-        //         }
-        //     }
-
-        //     // if let Ok(transaction_data) =
-        //     //     self.build_bundled_transaction(best_route_result, self.runtime_config)
-        //     // {
-        //     //     let raw_transaction: TransactionRequest = TransactionRequest {
-        //     //         data: Some(transaction_data),
-        //     //         gas_price: Some(*self.price_oracle.get_gas_price()),
-        //     //         ..self.base_transaction.clone()
-        //     //     };
-
-        //     //     // let s = self
-        //     //     //     .runtime_cache
-        //     //     //     .client
-        //     //     //     .estimate_gas(
-        //     //     //         &TypedTransaction::Legacy(raw_transaction.clone()),
-        //     //     //         Some(BlockId::Number(BlockNumber::Latest)),
-        //     //     //     )
-        //     //     //     .await;
-
-        //     //     // if (s.is_err()) {
-        // let market_reserves = get_market_reserves(
-        //     &self.runtime_cache.markets,
-        //     self.runtime_cache,
-        //     self.runtime_config,
-        // )
-        // .await;
-
-        // // let old_reserves: Vec<&Reserves> = best_route_result
-        // //     .route_reserves
-        // //     .iter()
-        // //     .map(|x| &x.0.value)
-        // //     .collect();
-        // let markets: Vec<&'static OrgValue<Market>> = best_route_result
-        //     .route_reserves
-        //     .iter()
-        //     .map(|x| x.1)
-        //     .collect();
-        // for (reserves, market) in &best_route_result.route_reserves {
-        //     println!("{:#?}", market.value.contract_address);
-        //     println!("{:#?}", reserves.value);
-        // }
-
-        //     //         process::exit(1);
-        //     //     // } else {
-        //     //         // println!("{:#?}", s);
-
-        //     //         // println!(
-        //     //         //     "calculated {} / {} routes in {:?} ({} WETH)",
-        //     //         //     route_results.len(),
-        //     //         //     RUNTIME_ROUTES.len(),
-        //     //         //     inst.elapsed(),
-        //     //         //     format_units(best_route_result.ref_profit_loss, 18).unwrap()
-        //     //         // );
-        //     //     // }
-
-        //     //     // println!("{:#?}", _transaction);
-        //     // }
-        // }
+    fn get_route_cost(&self, route_result: &RouteResult, gas_prices: &GasPrices) -> Option<U256> {
+        let calldata = self
+            .build_packed_transaction(route_result, self.runtime_config)
+            .ok()?;
+
+        return Some(gas_prices.cost_of(&calldata, route_result.get_gas_limit()));
+    }
+
+    // Cost in wei of the transaction sending the batch
+    #[inline(always)]
+    fn get_batch_cost(&self, route_batch: &RouteBatch, gas_prices: &GasPrices) -> Option<U256> {
+        if !route_batch.is_batched() {
+            return Some(route_batch.route_results.first()?.cost);
+        }
+
+        let calldata = self
+            .build_batched_transaction(route_batch, self.runtime_config)
+            .ok()?;
+
+        return Some(gas_prices.cost_of(&calldata, route_batch.get_gas_limit()));
     }
 
     #[inline(always)]
@@ -221,46 +257,147 @@ impl NetworkHandler {
         best_route_result: &RouteResult,
         runtime_config: &'static RuntimeConfig,
     ) -> Result<Bytes, AbiError> {
-        let transactions = &best_route_result.transactions;
+        let mut targets: Vec<Address> = vec![];
+        let mut payloads: Vec<Bytes> = vec![];
 
-        let volume = best_route_result.start_balance;
-        // let flash_loan_fee = price_oracle.get_flash_loan_fee() * volume / U256::from(10000);
+        Self::populate_route_calls(
+            best_route_result,
+            runtime_config.executor_address,
+            &mut targets,
+            &mut payloads,
+        )?;
+
+        let guards = BundleGuards::from_route_result(
+            best_route_result,
+            runtime_config.slippage_tolerance,
+            PriceOracle::get_block_number(),
+        );
+
+        return encode_bundle(best_route_result, targets, payloads, guards);
+    }
+
+    // The routes of a batch run one after the other on a shared Aave flash loan, a single route
+    // keeps its own flash liquidity source
+    #[inline(always)]
+    fn build_batched_transaction(
+        &self,
+        route_batch: &RouteBatch,
+        runtime_config: &'static RuntimeConfig,
+    ) -> Result<Bytes, AbiError> {
+        if !route_batch.is_batched() {
+            return self.build_bundled_transaction(&route_batch.route_results[0], runtime_config);
+        }
 
         let mut targets: Vec<Address> = vec![];
         let mut payloads: Vec<Bytes> = vec![];
 
-        for i in 0..transactions.len() {
-            let transaction = &transactions[i];
-            targets.push(transaction.value.market.value.contract_address);
-
-            if i < transactions.len() - 1 {
-                if let Ok(swap_data) = populate_swap(
-                    &transaction.value,
-                    &best_route_result.transactions[i + 1]
-                        .value
-                        .market
-                        .value
-                        .contract_address,
-                ) {
-                    payloads.push(swap_data);
+        for route_result in &route_batch.route_results {
+            Self::populate_route_calls(
+                route_result,
+                runtime_config.executor_address,
+                &mut targets,
+                &mut payloads,
+            )?;
+        }
+
+        let block_number = PriceOracle::get_block_number();
+        let guards = BundleGuards::from_route_result(
+            &route_batch.route_results[0],
+            runtime_config.slippage_tolerance,
+            block_number,
+        );
+        let loans = route_batch.get_loans(runtime_config.slippage_tolerance, block_number);
+
+        return encode_batch_bundle(loans, targets, payloads, guards);
+    }
+
+    // Same bundle as `build_bundled_transaction` in the executor's packed format, sent as raw
+    // calldata it costs a fraction of the L1 data
+    #[inline(always)]
+    fn build_packed_transaction(
+        &self,
+        best_route_result: &RouteResult,
+        runtime_config: &'static RuntimeConfig,
+    ) -> Result<Bytes, AbiError> {
+        let guards = BundleGuards::from_route_result(
+            best_route_result,
+            runtime_config.slippage_tolerance,
+            PriceOracle::get_block_number(),
+        );
+
+        return encode_packed_bundle(best_route_result, runtime_config.executor_address, guards);
+    }
+
+    // Appends the executor calls of every hop of the route. The flash liquidity is received by
+    // the executor, every hop either gets its input pushed by the previous market or from the
+    // executor's balance. A flash swap pair is called by the executor and sends its output to it
+    // before the bundle runs.
+    #[inline(always)]
+    fn populate_route_calls(
+        route_result: &RouteResult,
+        executor_address: Address,
+        targets: &mut Vec<Address>,
+        payloads: &mut Vec<Bytes>,
+    ) -> Result<(), AbiError> {
+        let transactions = &route_result.transactions;
+
+        let mut held_by_executor = true;
+        let first_index = match route_result.flash_liquidity.source {
+            FlashLiquiditySource::FlashSwap => 1,
+            _ => 0,
+        };
+
+        // the output goes straight to the next market when it expects pushed tokens
+        let next_recipient = |i: usize| match transactions.get(i + 1) {
+            Some(next) if get_swap_mode(&next.value.market.value) == SwapMode::Push => {
+                next.value.market.value.contract_address
+            }
+            _ => executor_address,
+        };
+
+        for i in first_index..transactions.len() {
+            let transaction = &transactions[i].value;
+            let market_address = transaction.market.value.contract_address;
+            let token_in = transaction.token_in().contract_address;
+
+            match get_swap_mode(&transaction.market.value) {
+                SwapMode::Push => {
+                    if held_by_executor {
+                        targets.push(token_in);
+                        payloads.push(populate_transfer(&market_address, transaction.amount_in)?);
+                    }
+
+                    let recipient = next_recipient(i);
+
+                    held_by_executor = recipient == executor_address;
+                    targets.push(market_address);
+                    payloads.push(populate_swap(transaction, &recipient, &executor_address)?);
                 }
-            } else {
-                if let Ok(swap_data) =
-                    populate_swap(&transaction.value, &runtime_config.executor_address)
-                {
-                    payloads.push(swap_data);
+                SwapMode::Pull => {
+                    targets.push(token_in);
+                    payloads.push(populate_approve(&market_address, transaction.amount_in)?);
+
+                    held_by_executor = true;
+                    targets.push(market_address);
+                    payloads.push(populate_swap(
+                        transaction,
+                        &executor_address,
+                        &executor_address,
+                    )?);
+                }
+                SwapMode::Routed(router_address) => {
+                    targets.push(token_in);
+                    payloads.push(populate_approve(&router_address, transaction.amount_in)?);
+
+                    let recipient = next_recipient(i);
+
+                    held_by_executor = recipient == executor_address;
+                    targets.push(router_address);
+                    payloads.push(populate_swap(transaction, &recipient, &executor_address)?);
                 }
             }
         }
 
-        return ethers::contract::encode_function_data::<BundleExecutionCall>(
-            &EXECUTE_TX_BUNDLE_FUNCTION,
-            BundleExecutionCall {
-                token: best_route_result.base_token.contract_address,
-                amount_to_first_market: volume,
-                targets,
-                payloads,
-            },
-        );
+        return Ok(());
     }
 }
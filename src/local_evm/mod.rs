@@ -0,0 +1,675 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{
+        BlockNumber, DiffMode, GethDebugBuiltInTracerConfig, GethDebugBuiltInTracerType,
+        GethDebugTracerConfig, GethDebugTracerType, GethDebugTracingOptions, GethTrace,
+        GethTraceFrame, PreStateConfig, PreStateFrame, Transaction, H160, H256, U64,
+    },
+};
+use futures::future::join_all;
+use itertools::Itertools;
+use revm::{
+    db::CacheDB,
+    primitives::{
+        AccountInfo, Address, BlockEnv, Bytecode, Bytes, ExecutionResult, Log, TransactTo, TxEnv,
+        B256, U256,
+    },
+    Database, DatabaseCommit, DatabaseRef, Evm,
+};
+use tokio::runtime::Handle;
+
+use crate::{
+    env::{RuntimeCache, RuntimeConfig},
+    price_oracle::PriceOracle,
+};
+
+use self::rpc_state::RpcState;
+
+mod rpc_state;
+#[cfg(test)]
+mod tests;
+
+const STATE_UPDATE_INTERVAL: u64 = 50;
+// blocks a state is moved through by their diffs, past that it's built again
+const MAX_DIFF_BLOCKS: u64 = 8;
+const ARB_SYS_ADDRESS: &str = "0x0000000000000000000000000000000000000064";
+// returns NUMBER for any call: `arbBlockNumber` without the rest of ArbSys
+const ARB_SYS_CODE: [u8; 9] = [0x43, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3];
+// slots kept warm per account, the ones read the longest ago are dropped past it
+const MAX_WARM_SLOTS: usize = 256;
+// blocks a warm slot is kept without being read again
+const WARM_SLOT_EXPIRY: u64 = 7200;
+
+// Accounts and storage of the tracked markets, their tokens and the executor at one block.
+// Everything else is read from the node at that block.
+struct LocalState<ExtDB = RpcState> {
+    block_number: U64,
+    // the next block's diff only applies on top of this one
+    block_hash: Option<H256>,
+    // of the block, executions run as if they were in it
+    timestamp: u64,
+    chain_id: u64,
+    db: CacheDB<ExtDB>,
+}
+
+lazy_static! {
+    static ref LOCAL_STATE: RwLock<Option<Arc<LocalState>>> = RwLock::new(None);
+    // storage slots of tracked accounts read by past executions, loaded ahead on every block,
+    // with the block of the state they were last read on
+    static ref WARM_SLOTS: RwLock<HashMap<Address, HashMap<U256, U64>>> =
+        RwLock::new(HashMap::new());
+}
+
+#[inline(always)]
+fn to_address(address: H160) -> Address {
+    return Address::from(address.to_fixed_bytes());
+}
+
+#[inline(always)]
+fn to_u256(value: ethers::types::U256) -> U256 {
+    return U256::from_limbs(value.0);
+}
+
+#[inline(always)]
+fn get_tracked_addresses(
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> Vec<Address> {
+    let mut addresses: Vec<H160> = vec![runtime_config.executor_address];
+
+    for market in runtime_cache.markets.iter() {
+        addresses.push(market.value.contract_address);
+        addresses.extend(market.value.tokens.iter().map(|x| x.contract_address));
+    }
+
+    return addresses.into_iter().unique().map(to_address).collect();
+}
+
+// Account and warm slots of a tracked address, the code is kept from the previous block
+#[inline(always)]
+async fn load_account(
+    address: Address,
+    code: Option<Bytecode>,
+    slots: Vec<U256>,
+    block_number: U64,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<(Address, AccountInfo, Vec<(U256, U256)>)> {
+    let provider = runtime_cache.client.provider();
+    let eth_address = H160::from(address.into_array());
+    let block_id = Some(block_number.into());
+
+    let code = match code {
+        Some(value) => value,
+        None => Bytecode::new_raw(
+            provider
+                .get_code(eth_address, block_id)
+                .await
+                .ok()?
+                .0
+                .into(),
+        ),
+    };
+
+    let (nonce, balance, values) = tokio::join!(
+        provider.get_transaction_count(eth_address, block_id),
+        provider.get_balance(eth_address, block_id),
+        join_all(slots.iter().map(|slot| {
+            provider.get_storage_at(eth_address, H256::from(slot.to_be_bytes()), block_id)
+        }))
+    );
+
+    let mut storage: Vec<(U256, U256)> = vec![];
+    for (slot, value) in slots.into_iter().zip(values) {
+        storage.push((slot, U256::from_be_bytes(value.ok()?.to_fixed_bytes())));
+    }
+
+    let info = AccountInfo::new(
+        to_u256(balance.ok()?),
+        nonce.ok()?.as_u64(),
+        code.hash_slow(),
+        code,
+    );
+
+    return Some((address, info, storage));
+}
+
+#[inline(always)]
+fn get_warm_slots(address: &Address) -> Vec<U256> {
+    return WARM_SLOTS
+        .read()
+        .unwrap()
+        .get(address)
+        .map_or(vec![], |x| x.keys().copied().collect());
+}
+
+// Slots an execution on the state of `block_number` read from a tracked account
+fn record_warm_slots(address: Address, slots: impl Iterator<Item = U256>, block_number: U64) {
+    let mut warm_slots = WARM_SLOTS.write().unwrap();
+    let account_slots = warm_slots.entry(address).or_default();
+
+    for slot in slots {
+        account_slots.insert(slot, block_number);
+    }
+
+    if account_slots.len() > MAX_WARM_SLOTS {
+        let excess = account_slots.len() - MAX_WARM_SLOTS;
+        let oldest: Vec<U256> = account_slots
+            .iter()
+            .sorted_by_key(|x| x.1)
+            .take(excess)
+            .map(|x| *x.0)
+            .collect();
+
+        for slot in oldest {
+            account_slots.remove(&slot);
+        }
+    }
+}
+
+// Drops the slots no execution read in the last `WARM_SLOT_EXPIRY` blocks
+fn expire_warm_slots(block_number: U64) {
+    let mut warm_slots = WARM_SLOTS.write().unwrap();
+
+    for account_slots in warm_slots.values_mut() {
+        account_slots.retain(|_, x| *x + WARM_SLOT_EXPIRY >= block_number);
+    }
+    warm_slots.retain(|_, x| !x.is_empty());
+}
+
+#[inline(always)]
+fn get_local_state() -> Option<Arc<LocalState>> {
+    return LOCAL_STATE.read().unwrap().clone();
+}
+
+async fn build_state(
+    block_number: U64,
+    tracked_addresses: &Vec<Address>,
+    previous: Option<&LocalState>,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> LocalState {
+    let mut db = CacheDB::new(RpcState::new(runtime_cache.client.clone(), block_number));
+
+    let previous_code: HashMap<Address, Bytecode> = match previous {
+        Some(state) => tracked_addresses
+            .iter()
+            .filter_map(|x| Some((*x, state.db.accounts.get(x)?.info.code.clone()?)))
+            .collect(),
+        None => HashMap::new(),
+    };
+
+    let block = runtime_cache
+        .client
+        .provider()
+        .get_block(block_number)
+        .await
+        .ok()
+        .flatten();
+    let block_hash = block.as_ref().and_then(|x| x.hash);
+    // a block that can't be read leaves the state unchained, it's built again on the next one
+    let timestamp = match block {
+        Some(block) => block.timestamp.as_u64(),
+        None => previous.map_or(0, |x| x.timestamp),
+    };
+
+    for chunk in tracked_addresses.chunks(runtime_config.small_chunk_size) {
+        let accounts = join_all(chunk.iter().map(|address| {
+            load_account(
+                *address,
+                previous_code.get(address).cloned(),
+                get_warm_slots(address),
+                block_number,
+                runtime_cache,
+            )
+        }))
+        .await;
+
+        // accounts that failed to load are read from the node when used
+        for (address, info, storage) in accounts.into_iter().flatten() {
+            db.insert_account_info(address, info);
+
+            let account = db.accounts.entry(address).or_default();
+            account.storage.extend(storage);
+        }
+    }
+
+    let arb_sys_code = Bytecode::new_raw(Bytes::from_static(&ARB_SYS_CODE));
+    db.insert_account_info(
+        ARB_SYS_ADDRESS.parse().unwrap(),
+        AccountInfo::new(U256::ZERO, 0, arb_sys_code.hash_slow(), arb_sys_code),
+    );
+
+    return LocalState {
+        block_number,
+        block_hash,
+        timestamp,
+        chain_id: runtime_config.chain_id as u64,
+        db,
+    };
+}
+
+// Moves the held accounts by the post-state of a transaction. Accounts the node no longer has,
+// e.g. after a self-destruct, are dropped and read from the node when used.
+fn apply_diff<ExtDB>(db: &mut CacheDB<ExtDB>, diff: DiffMode) {
+    for (address, pre) in &diff.pre {
+        let account = match db.accounts.get_mut(&to_address(*address)) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let post_storage = match diff.post.get(address) {
+            Some(post) => post.storage.as_ref(),
+            None => {
+                db.accounts.remove(&to_address(*address));
+                continue;
+            }
+        };
+
+        // slots missing from the post-state were cleared
+        for slot in pre.storage.iter().flat_map(|x| x.keys()) {
+            if post_storage.map_or(true, |x| !x.contains_key(slot)) {
+                account
+                    .storage
+                    .insert(U256::from_be_bytes(slot.0), U256::ZERO);
+            }
+        }
+    }
+
+    for (address, post) in diff.post {
+        let account = match db.accounts.get_mut(&to_address(address)) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if let Some(balance) = post.balance {
+            account.info.balance = to_u256(balance);
+        }
+
+        if let Some(nonce) = post.nonce {
+            account.info.nonce = nonce.as_u64();
+        }
+
+        for (slot, value) in post.storage.unwrap_or_default() {
+            account
+                .storage
+                .insert(U256::from_be_bytes(slot.0), U256::from_be_bytes(value.0));
+        }
+
+        if let Some(code) = post
+            .code
+            .and_then(|x| x.parse::<ethers::types::Bytes>().ok())
+        {
+            let code = Bytecode::new_raw(code.0.into());
+            let mut info = account.info.clone();
+
+            info.code_hash = code.hash_slow();
+            info.code = Some(code);
+            db.insert_account_info(to_address(address), info);
+        }
+    }
+}
+
+// Moves a state to `block_number` by the diffs of the blocks in between and loads the slots
+// executions first read since. `None` when a block can't be traced or doesn't follow the state's
+// block, e.g. after a reorg.
+async fn update_state(
+    previous: &LocalState,
+    block_number: U64,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) -> Option<LocalState> {
+    if block_number <= previous.block_number
+        || block_number - previous.block_number > U64::from(MAX_DIFF_BLOCKS)
+    {
+        return None;
+    }
+
+    let provider = runtime_cache.client.provider();
+    let tracing_options = GethDebugTracingOptions {
+        tracer: Some(GethDebugTracerType::BuiltInTracer(
+            GethDebugBuiltInTracerType::PreStateTracer,
+        )),
+        tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
+            GethDebugBuiltInTracerConfig::PreStateTracer(PreStateConfig {
+                diff_mode: Some(true),
+            }),
+        )),
+        ..Default::default()
+    };
+
+    let mut db = previous.db.clone();
+    db.db = RpcState::new(runtime_cache.client.clone(), block_number);
+
+    let mut block_hash = previous.block_hash?;
+    let mut timestamp = previous.timestamp;
+    for number in previous.block_number.as_u64() + 1..=block_number.as_u64() {
+        let block = provider
+            .get_block(BlockNumber::Number(number.into()))
+            .await
+            .ok()??;
+
+        if block.parent_hash != block_hash {
+            return None;
+        }
+
+        block_hash = block.hash?;
+        timestamp = block.timestamp.as_u64();
+        let traces = provider
+            .debug_trace_block_by_hash(block_hash, tracing_options.clone())
+            .await
+            .ok()?;
+
+        for trace in traces {
+            match trace {
+                GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Diff(diff))) => {
+                    apply_diff(&mut db, diff)
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    let missing_slots: Vec<(Address, U256)> = WARM_SLOTS
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|(address, slots)| {
+            let account = db.accounts.get(address)?;
+            let slots: Vec<U256> = slots
+                .keys()
+                .filter(|x| !account.storage.contains_key(*x))
+                .copied()
+                .collect();
+
+            Some(slots.into_iter().map(move |slot| (*address, slot)))
+        })
+        .flatten()
+        .collect();
+
+    for chunk in missing_slots.chunks(runtime_config.small_chunk_size) {
+        let values = join_all(chunk.iter().map(|(address, slot)| {
+            provider.get_storage_at(
+                H160::from(address.into_array()),
+                H256::from(slot.to_be_bytes()),
+                Some(block_number.into()),
+            )
+        }))
+        .await;
+
+        // slots that failed to load are read from the node when used
+        for ((address, slot), value) in chunk.iter().zip(values) {
+            if let Ok(value) = value {
+                db.accounts
+                    .entry(*address)
+                    .or_default()
+                    .storage
+                    .insert(*slot, U256::from_be_bytes(value.to_fixed_bytes()));
+            }
+        }
+    }
+
+    return Some(LocalState {
+        block_number,
+        block_hash: Some(block_hash),
+        timestamp,
+        chain_id: previous.chain_id,
+        db,
+    });
+}
+
+/// Moves the local state to every new block in the background, executions use the latest
+/// state. It is built again from the node when the block diffs can't be applied.
+pub fn initiate_state_updates(
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    let handle = Handle::current();
+
+    thread::spawn(move || {
+        handle.spawn(async move {
+            let tracked_addresses = get_tracked_addresses(runtime_cache, runtime_config);
+            let mut interval = tokio::time::interval(Duration::from_millis(STATE_UPDATE_INTERVAL));
+
+            loop {
+                interval.tick().await;
+
+                let block_number = PriceOracle::get_block_number();
+                let previous = get_local_state();
+
+                if block_number.is_zero()
+                    || previous
+                        .as_ref()
+                        .map_or(false, |x| x.block_number == block_number)
+                {
+                    continue;
+                }
+
+                let state = match previous.as_deref() {
+                    Some(previous) => {
+                        update_state(previous, block_number, runtime_cache, runtime_config).await
+                    }
+                    None => None,
+                };
+
+                let state = match state {
+                    Some(value) => value,
+                    None => {
+                        build_state(
+                            block_number,
+                            &tracked_addresses,
+                            previous.as_deref(),
+                            runtime_cache,
+                            runtime_config,
+                        )
+                        .await
+                    }
+                };
+                *LOCAL_STATE.write().unwrap() = Some(Arc::new(state));
+                expire_warm_slots(block_number);
+            }
+        });
+    });
+}
+
+#[inline(always)]
+fn get_block_env<ExtDB>(state: &LocalState<ExtDB>) -> BlockEnv {
+    // the base fee stays zero, gas isn't paid by local executions
+    return BlockEnv {
+        number: U256::from(state.block_number.as_u64()),
+        timestamp: U256::from(state.timestamp),
+        ..Default::default()
+    };
+}
+
+#[inline(always)]
+fn get_tx_env(from: H160, to: H160, value: ethers::types::U256, data: Bytes, gas: u64) -> TxEnv {
+    return TxEnv {
+        caller: to_address(from),
+        gas_limit: gas,
+        gas_price: U256::ZERO,
+        transact_to: TransactTo::Call(to_address(to)),
+        value: to_u256(value),
+        data,
+        nonce: None,
+        chain_id: None,
+        ..Default::default()
+    };
+}
+
+// Executes on `db`, keeping the changes in it when `commit` is set
+#[inline(always)]
+fn execute<DB: Database + DatabaseCommit, ExtDB>(
+    db: DB,
+    state: &LocalState<ExtDB>,
+    tx_env: TxEnv,
+    commit: bool,
+) -> Option<ExecutionResult> {
+    let mut evm = Evm::builder()
+        .with_db(db)
+        .with_block_env(get_block_env(state))
+        .with_tx_env(tx_env)
+        .modify_cfg_env(|x| x.chain_id = state.chain_id)
+        .build();
+
+    let result = evm.transact().ok()?;
+
+    // slots of tracked accounts are loaded ahead from the next block on
+    for (address, account) in &result.state {
+        if state.db.accounts.contains_key(address) {
+            record_warm_slots(
+                *address,
+                account.storage.keys().copied(),
+                state.block_number,
+            );
+        }
+    }
+
+    if commit {
+        evm.db_mut().commit(result.state);
+    }
+
+    return Some(result.result);
+}
+
+#[inline(always)]
+fn get_transaction_env(tx: &Transaction) -> Option<TxEnv> {
+    return Some(get_tx_env(
+        tx.from,
+        tx.to?,
+        tx.value,
+        tx.input.0.clone().into(),
+        tx.gas.as_u64(),
+    ));
+}
+
+#[inline(always)]
+fn into_logs(result: ExecutionResult) -> Option<Vec<Log>> {
+    return match result {
+        ExecutionResult::Success { logs, .. } => Some(logs),
+        _ => None,
+    };
+}
+
+// The EVM reads what the local state doesn't hold from the node, blocking the thread it runs on.
+// Executions run on the blocking pool on a handle to the latest state, the lock isn't held.
+#[inline(always)]
+async fn run_on_state<T, F>(f: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&LocalState) -> Option<T> + Send + 'static,
+{
+    let state = get_local_state()?;
+    return tokio::task::spawn_blocking(move || f(&state)).await.ok()?;
+}
+
+#[inline(always)]
+fn execute_on_state<ExtDB: DatabaseRef>(
+    state: &LocalState<ExtDB>,
+    tx: &Transaction,
+) -> Option<Vec<Log>> {
+    let result = execute(
+        CacheDB::new(&state.db),
+        state,
+        get_transaction_env(tx)?,
+        false,
+    )?;
+    return into_logs(result);
+}
+
+/// Logs of a pending transaction executed on the local state, `None` when it reverts or before
+/// the first state is built
+pub async fn execute_transaction(tx: Transaction) -> Option<Vec<Log>> {
+    return run_on_state(move |state| execute_on_state(state, &tx)).await;
+}
+
+// Reads of an execution sequence go to the state it started on
+struct SharedState<ExtDB>(Arc<LocalState<ExtDB>>);
+
+impl<ExtDB: DatabaseRef> DatabaseRef for SharedState<ExtDB> {
+    type Error = ExtDB::Error;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        return self.0.db.basic_ref(address);
+    }
+
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        return self.0.db.code_by_hash_ref(code_hash);
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        return self.0.db.storage_ref(address, index);
+    }
+
+    fn block_hash_ref(&self, number: U256) -> Result<B256, Self::Error> {
+        return self.0.db.block_hash_ref(number);
+    }
+}
+
+/// Pending transactions executed one after the other on the latest local state, each on the
+/// state left by the ones before it
+pub struct LocalExecution<ExtDB = RpcState> {
+    state: Arc<LocalState<ExtDB>>,
+    // held by the blocking pool while a transaction executes
+    db: Option<CacheDB<SharedState<ExtDB>>>,
+}
+
+impl LocalExecution {
+    /// `None` before the first state is built
+    pub fn new() -> Option<LocalExecution> {
+        return Some(LocalExecution::from_state(get_local_state()?));
+    }
+}
+
+impl<ExtDB> LocalExecution<ExtDB>
+where
+    ExtDB: DatabaseRef + Send + Sync + 'static,
+{
+    fn from_state(state: Arc<LocalState<ExtDB>>) -> LocalExecution<ExtDB> {
+        return LocalExecution {
+            db: Some(CacheDB::new(SharedState(state.clone()))),
+            state,
+        };
+    }
+
+    /// Logs of the next transaction, `None` when it reverts
+    pub async fn execute(&mut self, tx: Transaction) -> Option<Vec<Log>> {
+        let mut db = self.db.take()?;
+        let state = self.state.clone();
+
+        let (db, result) = tokio::task::spawn_blocking(move || {
+            let result =
+                get_transaction_env(&tx).and_then(|tx_env| execute(&mut db, &state, tx_env, true));
+            (db, result)
+        })
+        .await
+        .ok()?;
+        self.db = Some(db);
+
+        return into_logs(result?);
+    }
+}
+
+/// Runs a `BundleExecutor` call on the local state as sent by `from`
+pub async fn simulate_bundle(
+    from: H160,
+    executor_address: H160,
+    calldata: ethers::types::Bytes,
+    gas_limit: u64,
+) -> Option<ExecutionResult> {
+    let tx_env = get_tx_env(
+        from,
+        executor_address,
+        ethers::types::U256::zero(),
+        calldata.0.into(),
+        gas_limit,
+    );
+
+    return run_on_state(move |state| execute(CacheDB::new(&state.db), state, tx_env, false)).await;
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use ethers::{
+    providers::{Middleware, ProviderError},
+    types::{BlockId, BlockNumber, H160, H256, U64},
+};
+use revm::{
+    primitives::{AccountInfo, Address, Bytecode, B256, KECCAK_EMPTY, U256},
+    DatabaseRef,
+};
+use tokio::runtime::Handle;
+
+use crate::env::types::RuntimeClient;
+
+// State the warm cache doesn't hold, read from the node at a fixed block
+#[derive(Clone)]
+pub struct RpcState {
+    client: Arc<RuntimeClient>,
+    block_id: BlockId,
+    // code of the accounts read so far, by hash
+    codes: Arc<RwLock<HashMap<B256, Bytecode>>>,
+}
+
+impl RpcState {
+    pub fn new(client: Arc<RuntimeClient>, block_number: U64) -> RpcState {
+        return RpcState {
+            client,
+            block_id: BlockId::Number(BlockNumber::Number(block_number)),
+            codes: Arc::new(RwLock::new(HashMap::new())),
+        };
+    }
+}
+
+// The EVM reads state synchronously, executions run on the blocking pool where requests can
+// block the thread whatever the runtime's flavor
+#[inline(always)]
+fn block_on<F: Future>(future: F) -> F::Output {
+    return match Handle::try_current() {
+        Ok(handle) => handle.block_on(future),
+        Err(_) => futures::executor::block_on(future),
+    };
+}
+
+impl DatabaseRef for RpcState {
+    type Error = ProviderError;
+
+    fn basic_ref(&self, address: Address) -> Result<Option<AccountInfo>, Self::Error> {
+        let address = H160::from(address.into_array());
+        let provider = self.client.provider();
+
+        let (nonce, balance, code) = block_on(async {
+            tokio::join!(
+                provider.get_transaction_count(address, Some(self.block_id)),
+                provider.get_balance(address, Some(self.block_id)),
+                provider.get_code(address, Some(self.block_id))
+            )
+        });
+
+        let bytecode = Bytecode::new_raw(code?.0.into());
+        let code_hash = bytecode.hash_slow();
+        self.codes
+            .write()
+            .unwrap()
+            .insert(code_hash, bytecode.clone());
+
+        return Ok(Some(AccountInfo::new(
+            U256::from_limbs(balance?.0),
+            nonce?.as_u64(),
+            code_hash,
+            bytecode,
+        )));
+    }
+
+    // code is loaded with the account, a hash no account was read with has no code to look up
+    fn code_by_hash_ref(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        if code_hash == KECCAK_EMPTY {
+            return Ok(Bytecode::new());
+        }
+
+        return match self.codes.read().unwrap().get(&code_hash) {
+            Some(code) => Ok(code.clone()),
+            None => Err(ProviderError::CustomError(format!(
+                "code {} wasn't loaded",
+                code_hash
+            ))),
+        };
+    }
+
+    fn storage_ref(&self, address: Address, index: U256) -> Result<U256, Self::Error> {
+        let value = block_on(self.client.provider().get_storage_at(
+            H160::from(address.into_array()),
+            H256::from(index.to_be_bytes()),
+            Some(self.block_id),
+        ))?;
+
+        return Ok(U256::from_be_bytes(value.to_fixed_bytes()));
+    }
+
+    fn block_hash_ref(&self, number: U256) -> Result<B256, Self::Error> {
+        let number = U64::from(number.saturating_to::<u64>());
+        let block = block_on(self.client.provider().get_block(number))?;
+
+        return Ok(block
+            .and_then(|x| x.hash)
+            .map_or(B256::ZERO, |x| B256::from(x.to_fixed_bytes())));
+    }
+}
@@ -0,0 +1,603 @@
+use ethers::{
+    abi::{AbiParser, ParamType, Token},
+    types::{AccountState, H256},
+    utils::keccak256,
+};
+use revm::{
+    db::EmptyDB,
+    primitives::{Output, TransactTo},
+};
+
+use super::*;
+
+// Hand assembled mocks. The ERC20 keeps the balance of a holder in the slot of its address, the
+// pair keeps token0, token1 and its reserves in slots 0 to 3 and checks the 0.3% fee invariant.
+const ERC20_CODE: &str = "60003560e01c806370a0823114610037578063a9059cbb1461004457806323b872dd14610059578063095ea7b3146100e4575b600080fd5b6004355460005260206000f35b3360805260043560a05260243560c052610070565b60043560805260243560a05260443560c052610070565b60005460a051146000541515166100325760c05160805154106100325760c05160805154036080515561271060015460c051020460c0510360e05260e05160a051540160a0515560a0516080517fddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef602060e0a35b600160005260206000f3";
+const PAIR_CODE: &str = "60003560e01c80630902f1ac146100375780630dfe16811461004f578063d21220a71461005b578063022c0d9f14610067575b600080fd5b60025460005260035460205260045460405260606000f35b60005460005260206000f35b60015460005260206000f35b60043560805260243560a05260443560c052608051156100cb577fa9059cbb000000000000000000000000000000000000000000000000000000006102005260c05161020452608051610224526020610300604461020060006000545af115610032575b60a0511561011d577fa9059cbb000000000000000000000000000000000000000000000000000000006102005260c0516102045260a051610224526020610300604461020060006001545af115610032575b6064356004013515610199577f10d1e85c000000000000000000000000000000000000000000000000000000006102005233610204526080516102245260a051610244526080610264526064356004013560200160643560040161028437600060006064356004013560a401610200600060c0515af115610032575b7f70a0823100000000000000000000000000000000000000000000000000000000610200523061020452602061030060246102006000545afa15610032576103005160e0527f70a0823100000000000000000000000000000000000000000000000000000000610200523061020452602061030060246102006001545afa15610032576103005161010052608051600254038060e051119060e05103026103005260a0516003540380610100511190610100510302610320526080516103405260a051610360526103005161032051171561003257620f42406003546002540202600361032051026103e8610100510203600361030051026103e860e051020302106100325760e05160025561010051600355426004557f1c411e9a96e071241c2f21f7726b17ae89e3cab4c78be50e062b03a9fffbbad1604060e0a160c051337fd78ad95fa46c994b6551d0da85fc275fe613ce37657fb8d5e3d130840159d8226080610300a300";
+// Aave pool lending from its own balance without a premium, and the provider returning it
+const AAVE_POOL_CODE: &str = "60003560e01c806342b0b77c14610016575b600080fd5b7fa9059cbb000000000000000000000000000000000000000000000000000000006102005260043561020452604435610224526020610300604461020060006024355af115610011577f1b11d0ff00000000000000000000000000000000000000000000000000000000610400526024356104045260443561042452600061044452336104645260a061048452606435600401356020016064356004016104a43760206103006064356004013560c40161040060006004355af115610011576103005115610011577f23b872dd0000000000000000000000000000000000000000000000000000000061020052600435610204523061022452604435610244526020610300606461020060006024355af1156100115700";
+const AAVE_PROVIDER_CODE: &str =
+    "60003560e01c8063026b1d5f14610016575b600080fd5b60005460005260206000f3";
+
+type LogParts = (H160, Vec<H256>, Vec<u8>);
+
+fn get_address(value: u64) -> H160 {
+    return H160::from_low_u64_be(value);
+}
+
+fn get_units(value: u64) -> ethers::types::U256 {
+    return ethers::types::U256::exp10(18) * value;
+}
+
+fn to_slot(address: H160) -> U256 {
+    return U256::from_be_slice(address.as_bytes());
+}
+
+fn get_state() -> LocalState<EmptyDB> {
+    return LocalState {
+        block_number: U64::from(1),
+        block_hash: None,
+        timestamp: 1_700_000_000,
+        chain_id: 42161,
+        db: CacheDB::new(EmptyDB::default()),
+    };
+}
+
+fn insert_contract(
+    state: &mut LocalState<EmptyDB>,
+    address: H160,
+    code: &str,
+    storage: Vec<(U256, U256)>,
+) {
+    let code = Bytecode::new_raw(hex::decode(code).unwrap().into());
+    state.db.insert_account_info(
+        to_address(address),
+        AccountInfo::new(U256::ZERO, 0, code.hash_slow(), code),
+    );
+
+    for (slot, value) in storage {
+        state
+            .db
+            .insert_account_storage(to_address(address), slot, value)
+            .unwrap();
+    }
+}
+
+fn insert_token(state: &mut LocalState<EmptyDB>, token: H160, balances: Vec<(H160, u64)>) {
+    let storage = balances
+        .into_iter()
+        .map(|(holder, amount)| (to_slot(holder), to_u256(get_units(amount))))
+        .collect();
+
+    insert_contract(state, token, ERC20_CODE, storage);
+}
+
+fn insert_pair(state: &mut LocalState<EmptyDB>, pair: H160, tokens: [H160; 2], reserves: [u64; 2]) {
+    insert_contract(
+        state,
+        pair,
+        PAIR_CODE,
+        vec![
+            (U256::from(0), to_slot(tokens[0])),
+            (U256::from(1), to_slot(tokens[1])),
+            (U256::from(2), to_u256(get_units(reserves[0]))),
+            (U256::from(3), to_u256(get_units(reserves[1]))),
+        ],
+    );
+}
+
+fn encode_call(signature: &str, tokens: &[Token]) -> ethers::types::Bytes {
+    let function = AbiParser::default().parse_function(signature).unwrap();
+    return function.encode_input(tokens).unwrap().into();
+}
+
+fn get_transaction(from: H160, to: H160, input: ethers::types::Bytes) -> Transaction {
+    return Transaction {
+        from,
+        to: Some(to),
+        input,
+        gas: 10_000_000.into(),
+        ..Default::default()
+    };
+}
+
+fn get_amount_out(
+    amount_in: ethers::types::U256,
+    reserve_in: ethers::types::U256,
+    reserve_out: ethers::types::U256,
+) -> ethers::types::U256 {
+    let amount_in = amount_in * 997;
+    return amount_in * reserve_out / (reserve_in * 1000 + amount_in);
+}
+
+fn into_parts(logs: Vec<Log>) -> Vec<LogParts> {
+    return logs
+        .into_iter()
+        .map(|log| {
+            (
+                H160::from(log.address.into_array()),
+                log.data.topics().iter().map(|x| H256::from(x.0)).collect(),
+                log.data.data.to_vec(),
+            )
+        })
+        .collect();
+}
+
+fn transfer_log(token: H160, from: H160, to: H160, amount: ethers::types::U256) -> LogParts {
+    return (
+        token,
+        vec![
+            H256::from(keccak256("Transfer(address,address,uint256)")),
+            H256::from(from),
+            H256::from(to),
+        ],
+        ethers::abi::encode(&[Token::Uint(amount)]),
+    );
+}
+
+fn sync_log(pair: H160, reserves: [ethers::types::U256; 2]) -> LogParts {
+    return (
+        pair,
+        vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+        ethers::abi::encode(&[Token::Uint(reserves[0]), Token::Uint(reserves[1])]),
+    );
+}
+
+fn swap_log(pair: H160, sender: H160, amounts: [ethers::types::U256; 4], to: H160) -> LogParts {
+    return (
+        pair,
+        vec![
+            H256::from(keccak256(
+                "Swap(address,uint256,uint256,uint256,uint256,address)",
+            )),
+            H256::from(sender),
+            H256::from(to),
+        ],
+        ethers::abi::encode(&amounts.map(Token::Uint)),
+    );
+}
+
+#[tokio::test]
+async fn v2_swaps_log_on_the_state_left_by_earlier_transactions() {
+    let (weth, token, pair, user) = (
+        get_address(0x1000),
+        get_address(0x2000),
+        get_address(0x3000),
+        get_address(0xa11ce),
+    );
+
+    let mut state = get_state();
+    insert_token(&mut state, weth, vec![(pair, 100), (user, 10)]);
+    insert_token(&mut state, token, vec![(pair, 200_000)]);
+    insert_pair(&mut state, pair, [weth, token], [100, 200_000]);
+
+    let zero = ethers::types::U256::zero();
+    let amount_in = get_units(1);
+    let amount_out = get_amount_out(amount_in, get_units(100), get_units(200_000));
+    let get_swap = |amount_out| {
+        get_transaction(
+            user,
+            pair,
+            encode_call(
+                "swap(uint256,uint256,address,bytes)",
+                &[
+                    Token::Uint(zero),
+                    Token::Uint(amount_out),
+                    Token::Address(user),
+                    Token::Bytes(vec![]),
+                ],
+            ),
+        )
+    };
+
+    let push = get_transaction(
+        user,
+        weth,
+        encode_call(
+            "transfer(address,uint256)",
+            &[Token::Address(pair), Token::Uint(amount_in)],
+        ),
+    );
+
+    // without the pushed input the swap breaks the invariant
+    assert_eq!(execute_on_state(&state, &get_swap(amount_out)), None);
+
+    let mut execution = LocalExecution::from_state(Arc::new(state));
+    let mut results = vec![];
+    for tx in [
+        push,
+        get_swap(amount_out),
+        get_swap(ethers::types::U256::one()),
+    ] {
+        results.push(execution.execute(tx).await);
+    }
+
+    assert_eq!(
+        into_parts(results[0].clone().unwrap()),
+        vec![transfer_log(weth, user, pair, amount_in)]
+    );
+    assert_eq!(
+        into_parts(results[1].clone().unwrap()),
+        vec![
+            transfer_log(token, pair, user, amount_out),
+            sync_log(pair, [get_units(101), get_units(200_000) - amount_out]),
+            swap_log(pair, user, [amount_in, zero, zero, amount_out], user),
+        ]
+    );
+    assert_eq!(results[2], None);
+}
+
+#[test]
+fn execute_tx_bundle_logs_the_flash_loan_and_every_swap() {
+    let (weth, token, first_pair, second_pair, aave_pool, aave_provider, sender) = (
+        get_address(0x1000),
+        get_address(0x2000),
+        get_address(0x3000),
+        get_address(0x4000),
+        get_address(0x5000),
+        get_address(0x6000),
+        get_address(0xe0a),
+    );
+
+    let mut state = get_state();
+    insert_token(
+        &mut state,
+        weth,
+        vec![(first_pair, 100), (second_pair, 100), (aave_pool, 1000)],
+    );
+    insert_token(
+        &mut state,
+        token,
+        vec![(first_pair, 200_000), (second_pair, 180_000)],
+    );
+    insert_pair(&mut state, first_pair, [weth, token], [100, 200_000]);
+    insert_pair(&mut state, second_pair, [weth, token], [100, 180_000]);
+    insert_contract(&mut state, aave_pool, AAVE_POOL_CODE, vec![]);
+    insert_contract(
+        &mut state,
+        aave_provider,
+        AAVE_PROVIDER_CODE,
+        vec![(U256::ZERO, to_slot(aave_pool))],
+    );
+
+    // the artifact's bytecode predates the guarded entry points, it's deployed and called
+    // through the plain `executeTxBundle` it was built with
+    let artifact: serde_json::Value =
+        serde_json::from_str(include_str!("../contracts/abi/BundleExecutor.json")).unwrap();
+    let mut init_code = hex::decode(artifact["bytecode"].as_str().unwrap()).unwrap();
+    init_code.extend(ethers::abi::encode(&[
+        Token::Address(sender),
+        Token::Address(aave_provider),
+    ]));
+
+    let deployment = TxEnv {
+        caller: to_address(sender),
+        gas_limit: 10_000_000,
+        gas_price: U256::ZERO,
+        transact_to: TransactTo::create(),
+        data: init_code.into(),
+        ..Default::default()
+    };
+
+    let mut db = state.db.clone();
+    let executor = match execute(&mut db, &state, deployment, true) {
+        Some(ExecutionResult::Success {
+            output: Output::Create(_, Some(address)),
+            ..
+        }) => H160::from(address.into_array()),
+        result => panic!("deployment failed: {:?}", result),
+    };
+    state.db = db;
+
+    let zero = ethers::types::U256::zero();
+    let amount_in = get_units(1);
+    let first_amount_out = get_amount_out(amount_in, get_units(100), get_units(200_000));
+    let second_amount_out = get_amount_out(first_amount_out, get_units(180_000), get_units(100));
+    let get_swap = |amount_0_out, amount_1_out, recipient| {
+        Token::Bytes(
+            encode_call(
+                "swap(uint256,uint256,address,bytes)",
+                &[
+                    Token::Uint(amount_0_out),
+                    Token::Uint(amount_1_out),
+                    Token::Address(recipient),
+                    Token::Bytes(vec![]),
+                ],
+            )
+            .to_vec(),
+        )
+    };
+
+    let bundle = get_transaction(
+        sender,
+        executor,
+        encode_call(
+            "executeTxBundle(address,uint256,address[],bytes[])",
+            &[
+                Token::Address(weth),
+                Token::Uint(amount_in),
+                Token::Array(vec![
+                    Token::Address(first_pair),
+                    Token::Address(second_pair),
+                ]),
+                Token::Array(vec![
+                    get_swap(zero, first_amount_out, second_pair),
+                    get_swap(second_amount_out, zero, executor),
+                ]),
+            ],
+        ),
+    );
+
+    assert_eq!(
+        into_parts(execute_on_state(&state, &bundle).unwrap()),
+        vec![
+            transfer_log(weth, aave_pool, executor, amount_in),
+            transfer_log(weth, executor, first_pair, amount_in),
+            transfer_log(token, first_pair, second_pair, first_amount_out),
+            sync_log(
+                first_pair,
+                [get_units(101), get_units(200_000) - first_amount_out]
+            ),
+            swap_log(
+                first_pair,
+                executor,
+                [amount_in, zero, zero, first_amount_out],
+                second_pair
+            ),
+            transfer_log(weth, second_pair, executor, second_amount_out),
+            sync_log(
+                second_pair,
+                [
+                    get_units(100) - second_amount_out,
+                    get_units(180_000) + first_amount_out
+                ]
+            ),
+            swap_log(
+                second_pair,
+                executor,
+                [zero, first_amount_out, second_amount_out, zero],
+                executor
+            ),
+            transfer_log(weth, executor, sender, second_amount_out - amount_in),
+            transfer_log(weth, executor, aave_pool, amount_in),
+        ]
+    );
+}
+
+#[test]
+fn block_diffs_move_the_held_accounts() {
+    let (token, holder, removed, untracked) = (
+        get_address(0x1000),
+        get_address(0xa11ce),
+        get_address(0xdead),
+        get_address(0xbeef),
+    );
+
+    let mut state = get_state();
+    insert_token(&mut state, token, vec![(holder, 10), (removed, 1)]);
+    insert_contract(&mut state, removed, ERC20_CODE, vec![]);
+
+    let slot = |address: H160| H256::from(address);
+    let value = |amount: u64| H256::from(<[u8; 32]>::from(get_units(amount)));
+
+    let diff = DiffMode {
+        pre: [
+            (
+                token,
+                AccountState {
+                    storage: Some([(slot(holder), value(10)), (slot(removed), value(1))].into()),
+                    ..Default::default()
+                },
+            ),
+            (removed, AccountState::default()),
+        ]
+        .into(),
+        post: [
+            (
+                token,
+                AccountState {
+                    balance: Some(7.into()),
+                    storage: Some([(slot(holder), value(11))].into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                untracked,
+                AccountState {
+                    balance: Some(1.into()),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into(),
+    };
+    apply_diff(&mut state.db, diff);
+
+    let account = &state.db.accounts[&to_address(token)];
+    assert_eq!(account.info.balance, U256::from(7));
+    assert_eq!(account.storage[&to_slot(holder)], to_u256(get_units(11)));
+    assert_eq!(account.storage[&to_slot(removed)], U256::ZERO);
+    assert!(account.info.code.is_some());
+
+    assert!(!state.db.accounts.contains_key(&to_address(removed)));
+    assert!(!state.db.accounts.contains_key(&to_address(untracked)));
+}
+
+#[test]
+fn executions_run_at_the_timestamp_of_the_state() {
+    let (caller, clock) = (get_address(0xa11ce), get_address(0x1000));
+
+    // TIMESTAMP, returned as a word
+    let mut state = get_state();
+    insert_contract(&mut state, clock, "4260005260206000f3", vec![]);
+
+    let tx_env = get_tx_env(caller, clock, 0.into(), Bytes::new(), 100_000);
+    let output = match execute(CacheDB::new(&state.db), &state, tx_env, false) {
+        Some(ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        }) => output,
+        result => panic!("unexpected result {:?}", result),
+    };
+
+    assert_eq!(U256::from_be_slice(&output), U256::from(state.timestamp));
+}
+
+#[test]
+fn warm_slots_are_bounded_and_expire() {
+    let account = to_address(get_address(0x5157));
+
+    record_warm_slots(account, (0..200).map(U256::from), U64::from(1));
+    record_warm_slots(account, (200..400).map(U256::from), U64::from(2));
+
+    // the slots read last are kept
+    let slots = get_warm_slots(&account);
+    assert_eq!(slots.len(), MAX_WARM_SLOTS);
+    assert!((200..400).all(|x| slots.contains(&U256::from(x))));
+
+    expire_warm_slots(U64::from(2 + WARM_SLOT_EXPIRY));
+    assert_eq!(get_warm_slots(&account).len(), 200);
+
+    expire_warm_slots(U64::from(3 + WARM_SLOT_EXPIRY));
+    assert!(!WARM_SLOTS.read().unwrap().contains_key(&account));
+}
+
+#[test]
+fn uniswap_query_reads_pair_states_and_reserves() {
+    let (weth, token, first_pair, second_pair, query) = (
+        get_address(0x1000),
+        get_address(0x2000),
+        get_address(0x3000),
+        get_address(0x4000),
+        get_address(0x5000),
+    );
+    // its token has no code, the balance read comes back empty
+    let (broken_token, broken_pair) = (get_address(0x6000), get_address(0x7000));
+
+    let mut state = get_state();
+    insert_token(
+        &mut state,
+        weth,
+        vec![(first_pair, 100), (second_pair, 101)],
+    );
+    insert_token(
+        &mut state,
+        token,
+        vec![(first_pair, 200_000), (second_pair, 180_000)],
+    );
+    insert_pair(&mut state, first_pair, [weth, token], [100, 200_000]);
+    insert_pair(&mut state, second_pair, [weth, token], [100, 180_000]);
+    insert_pair(&mut state, broken_pair, [weth, broken_token], [5, 10]);
+    insert_pair(&mut state, broken_pair, [weth, broken_token], [5, 10]);
+    state
+        .db
+        .insert_account_storage(to_address(second_pair), U256::from(4), U256::from(7))
+        .unwrap();
+
+    // the views are called on the runtime code, as the state override injects it
+    let artifact: serde_json::Value =
+        serde_json::from_str(include_str!("../contracts/abi/UniswapQuery.json")).unwrap();
+    insert_contract(
+        &mut state,
+        query,
+        artifact["deployedBytecode"].as_str().unwrap(),
+        vec![],
+    );
+
+    let pairs = Token::Array(vec![
+        Token::Address(first_pair),
+        Token::Address(second_pair),
+        Token::Address(broken_pair),
+    ]);
+    let call = |signature: &str, outputs: &[ParamType]| {
+        let tx = get_transaction(
+            get_address(0xa11ce),
+            query,
+            encode_call(signature, &[pairs.clone()]),
+        );
+        let output = match execute(
+            CacheDB::new(&state.db),
+            &state,
+            get_transaction_env(&tx).unwrap(),
+            false,
+        ) {
+            Some(ExecutionResult::Success {
+                output: Output::Call(output),
+                ..
+            }) => output,
+            result => panic!("{} failed: {:?}", signature, result),
+        };
+
+        return ethers::abi::decode(outputs, &output).unwrap();
+    };
+
+    let units = |amount: u64| Token::Uint(get_units(amount));
+    let zero = Token::Uint(ethers::types::U256::zero());
+    let pair_state = ParamType::Tuple(vec![
+        ParamType::Bool,
+        ParamType::Uint(112),
+        ParamType::Uint(112),
+        ParamType::Uint(32),
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+    ]);
+
+    assert_eq!(
+        call(
+            "getPairStates(address[])",
+            &[
+                ParamType::Uint(256),
+                ParamType::FixedBytes(32),
+                ParamType::Array(Box::new(pair_state)),
+            ],
+        ),
+        vec![
+            Token::Uint(state.block_number.as_u64().into()),
+            // the empty database hashes the decimal block number
+            Token::FixedBytes(keccak256("0").to_vec()),
+            Token::Array(vec![
+                Token::Tuple(vec![
+                    Token::Bool(true),
+                    units(100),
+                    units(200_000),
+                    zero.clone(),
+                    units(100),
+                    units(200_000),
+                ]),
+                Token::Tuple(vec![
+                    Token::Bool(true),
+                    units(100),
+                    units(180_000),
+                    Token::Uint(7.into()),
+                    units(101),
+                    units(180_000),
+                ]),
+                // the reserves are still read, the balances stop at the failing token
+                Token::Tuple(vec![
+                    Token::Bool(false),
+                    units(5),
+                    units(10),
+                    zero.clone(),
+                    zero.clone(),
+                    zero.clone(),
+                ]),
+            ]),
+        ]
+    );
+
+    // the selectors the artifact was built with still dispatch
+    assert_eq!(
+        call(
+            "getReservesByPairs(address[])",
+            &[ParamType::Array(Box::new(ParamType::FixedArray(
+                Box::new(ParamType::Uint(112)),
+                3
+            )))],
+        ),
+        vec![Token::Array(vec![
+            Token::FixedArray(vec![units(100), units(200_000), zero.clone()]),
+            Token::FixedArray(vec![units(100), units(180_000), Token::Uint(7.into())]),
+            Token::FixedArray(vec![units(5), units(10), zero.clone()]),
+        ])]
+    );
+}
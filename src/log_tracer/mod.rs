@@ -1,37 +1,48 @@
 use crate::{
     env::RuntimeCache,
+    exchanges::get_router_protocol,
+    local_evm,
     types::{market::Market, TransactionLog},
 };
 use ethers::{
     abi::RawLog,
     providers::Middleware,
     types::{
-        BlockId, BlockNumber, GethDebugTracerType, GethDebugTracingCallOptions,
-        GethDebugTracingOptions, GethTrace, NameOrAddress, Transaction, TransactionRequest,
+        spoof, BlockId, BlockNumber, CallConfig, GethDebugBuiltInTracerConfig,
+        GethDebugBuiltInTracerType, GethDebugTracerConfig, GethDebugTracerType,
+        GethDebugTracingCallOptions, GethDebugTracingOptions, GethTrace, GethTraceFrame,
+        NameOrAddress, PreStateConfig, PreStateFrame, Transaction, TransactionRequest,
     },
 };
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use serde_json::Value;
 
-use self::utils::{parse_address, parse_buffer, parse_topic_buffer};
+use self::types::{CallTraceFrame, LogFrame};
 
 mod types;
-mod utils;
 
+// Logs are emitted as hex strings, topics padded to 32 bytes
 const JS_CONTENT: &str = "{
     data: [],
     fault: function (log) {
     },
     step: function (log) {
-       var topicCount = (log.op.toString().match(/LOG(\\d)/) || [])[1];
+        var topicCount = (log.op.toString().match(/LOG(\\d)/) || [])[1];
         if (topicCount) {
-            const peek_0 = parseInt(log.stack.peek(0));
-            const res = {
-                address: log.contract.getAddress(),
-                data: log.memory.slice(peek_0, peek_0 + parseInt(log.stack.peek(1))),
+            var offset = parseInt(log.stack.peek(0));
+            var res = {
+                address: toHex(log.contract.getAddress()),
+                data: toHex(log.memory.slice(offset, offset + parseInt(log.stack.peek(1)))),
+                topics: [],
             };
-            
-            for (var i = 0; i < topicCount; i++){
-                res[i.toString()] = log.stack.peek(i + 2);
+
+            for (var i = 0; i < topicCount; i++) {
+                var topic = log.stack.peek(i + 2).toString(16);
+                while (topic.length < 64) {
+                    topic = '0' + topic;
+                }
+
+                res.topics.push('0x' + topic);
             }
 
             this.data.push(res);
@@ -53,93 +64,339 @@ const GETH_DEBUG_TRACING_OPTIONS:ethers::types::GethDebugTracingOptions = ethers
     disable_stack: Some(false),
 };
 
+// How the logs of a pending transaction are collected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TracerBackend {
+    // the JS tracer above, stepping through every opcode
+    Js,
+    // geth's native `callTracer` with `withLog`, logs of reverted calls are dropped
+    CallTracer,
+    // the in-process EVM of `local_evm`, without a node round-trip for the tracked state
+    Revm,
+}
+
+impl TracerBackend {
+    pub fn from_name(name: &str) -> Option<TracerBackend> {
+        match name {
+            "js" => return Some(TracerBackend::Js),
+            "callTracer" => return Some(TracerBackend::CallTracer),
+            "revm" => return Some(TracerBackend::Revm),
+            _ => return None,
+        }
+    }
+}
+
 #[inline(always)]
-pub async fn trace_transaction(
-    tx: Transaction,
-    runtime_cache: &'static RuntimeCache,
-) -> Option<Vec<TransactionLog>> {
-    // get the transaction traces
-    let request = TransactionRequest {
+fn get_transaction_request(tx: &Transaction) -> TransactionRequest {
+    return TransactionRequest {
         from: Some(tx.from),
         to: Some(NameOrAddress::Address(tx.to.unwrap())),
         gas: Some(tx.gas),
         gas_price: tx.gas_price,
         value: Some(tx.value),
-        data: Some(tx.input),
+        data: Some(tx.input.clone()),
         nonce: None,
         chain_id: None,
     };
+}
+
+#[inline(always)]
+pub async fn trace_transaction(
+    tx: Transaction,
+    tracer_backend: TracerBackend,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<Vec<TransactionLog>> {
+    return trace_transaction_on_state(tx, None, tracer_backend, runtime_cache).await;
+}
+
+/// Traces transactions in sequencer order, every trace runs on the state left by the ones
+/// before it. Transactions whose logs aren't needed are applied to that state only once a later
+/// transaction is traced.
+pub struct SequentialTracer {
+    tracer_backend: TracerBackend,
+    runtime_cache: &'static RuntimeCache,
+    // state overrides of the node tracers
+    state: spoof::State,
+    // the in-process equivalent, started on the first trace
+    local_execution: Option<local_evm::LocalExecution>,
+    applied_transactions: Vec<Transaction>,
+}
+
+impl SequentialTracer {
+    pub fn new(
+        tracer_backend: TracerBackend,
+        runtime_cache: &'static RuntimeCache,
+    ) -> SequentialTracer {
+        return SequentialTracer {
+            tracer_backend,
+            runtime_cache,
+            state: spoof::State::default(),
+            local_execution: None,
+            applied_transactions: vec![],
+        };
+    }
+
+    /// Moves the state by the transaction without collecting its logs
+    pub fn apply(&mut self, tx: Transaction) {
+        if tx.to.is_some() {
+            self.applied_transactions.push(tx);
+        }
+    }
 
-    let tracing_options = GethDebugTracingCallOptions{
-        state_overrides: None,
+    /// Logs of the transaction on the state left by the ones before it, the state then moves by
+    /// the transaction
+    pub async fn trace(&mut self, tx: Transaction) -> Option<Vec<TransactionLog>> {
+        if tx.to.is_none() {
+            return None;
+        }
+
+        if self.tracer_backend == TracerBackend::Revm {
+            if self.local_execution.is_none() {
+                self.local_execution = Some(local_evm::LocalExecution::new()?);
+            }
+
+            let local_execution = self.local_execution.as_mut()?;
+            for applied in std::mem::take(&mut self.applied_transactions) {
+                local_execution.execute(applied).await;
+            }
+
+            let logs = local_execution.execute(tx).await?;
+            return Some(into_transaction_logs(into_log_frames(logs), self.runtime_cache));
+        }
+
+        for applied in std::mem::take(&mut self.applied_transactions) {
+            // a reverted transaction leaves no diff
+            let state_diff = get_state_diff(&applied, Some(self.state.clone()), self.runtime_cache);
+            if let Some(state_diff) = state_diff.await {
+                apply_state_diff(&mut self.state, state_diff);
+            }
+        }
+
+        let overrides = Some(self.state.clone());
+        let (transaction_logs, state_diff) = tokio::join!(
+            trace_transaction_on_state(
+                tx.clone(),
+                overrides.clone(),
+                self.tracer_backend,
+                self.runtime_cache
+            ),
+            get_state_diff(&tx, overrides, self.runtime_cache)
+        );
+
+        if let Some(state_diff) = state_diff {
+            apply_state_diff(&mut self.state, state_diff);
+        }
+
+        return transaction_logs;
+    }
+}
+
+// Post-transaction values of every account the transaction touched. Slots the transaction
+// cleared are only listed in the pre-state.
+#[inline(always)]
+async fn get_state_diff(
+    tx: &Transaction,
+    state_overrides: Option<spoof::State>,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<PreStateFrame> {
+    let tracing_options = GethDebugTracingCallOptions {
+        state_overrides,
         tracing_options: GethDebugTracingOptions {
-            tracer: Some(GethDebugTracerType::JsTracer(JS_CONTENT.to_string())) ,
+            tracer: Some(GethDebugTracerType::BuiltInTracer(
+                GethDebugBuiltInTracerType::PreStateTracer,
+            )),
+            tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
+                GethDebugBuiltInTracerConfig::PreStateTracer(PreStateConfig {
+                    diff_mode: Some(true),
+                }),
+            )),
             ..GETH_DEBUG_TRACING_OPTIONS
-        }
+        },
     };
 
-    if let Ok(geth_trace) = runtime_cache
+    let geth_trace = runtime_cache
         .client
         .debug_trace_call(
-            request,
-            Some(BlockId::Number(BlockNumber::Number(
-                tx.block_number.unwrap() - 1,
-            ))),
+            get_transaction_request(tx),
+            Some(BlockId::Number(BlockNumber::Number(tx.block_number? - 1))),
             tracing_options,
         )
         .await
-    {
-        // Check if the result is valid
-        if let GethTrace::Unknown(trace_container) = geth_trace {
-            // All raw traces should be contained in an array
-            if let Some(raw_traces) = trace_container.as_array() {
-                // Only itterate if length > 0
-                if raw_traces.len() > 0 {
-                    let res: Vec<TransactionLog> = raw_traces
-                        .par_iter()
-                        .filter_map(|trace_object| {
-                            if let Some(trace) = trace_object.as_object() {
-                                let call_address = parse_address(&trace["address"]);
-
-                                // If this a tracked market, decode the transaction log details
-                                if let Some(market) =
-                                    Market::from_address(&call_address, &runtime_cache)
-                                {
-                                    let mut raw_log: RawLog = RawLog {
-                                        topics: vec![],
-                                        data: parse_buffer(&trace["data"]),
-                                    };
-
-                                    let topic_count: usize = trace.len() - 2;
-                                    if topic_count >= 1 {
-                                        for i in 0..topic_count {
-                                            if let Some(topic) =
-                                                parse_topic_buffer(&trace[&i.to_string()])
-                                            {
-                                                raw_log.topics.push(topic);
-                                            }
-                                        }
-                                    }
-
-                                    Some(TransactionLog {
-                                        address: call_address,
-                                        protocol: market.value.protocol,
-                                        raw: raw_log,
-                                    })
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                        .collect();
-
-                    return Some(res);
-                }
+        .ok()?;
+
+    if let GethTrace::Known(GethTraceFrame::PreStateTracer(frame)) = geth_trace {
+        return Some(frame);
+    }
+
+    return None;
+}
+
+#[inline(always)]
+fn apply_state_diff(state: &mut spoof::State, state_diff: PreStateFrame) {
+    let diff = match state_diff {
+        PreStateFrame::Diff(value) => value,
+        PreStateFrame::Default(_) => return,
+    };
+
+    for (address, pre) in diff.pre {
+        let post_storage = diff.post.get(&address).and_then(|x| x.storage.as_ref());
+        let account = state.account(address);
+
+        // slots missing from the post-state were cleared
+        for slot in pre.storage.unwrap_or_default().into_keys() {
+            if post_storage.map_or(true, |x| !x.contains_key(&slot)) {
+                account.store(slot, Default::default());
             }
         }
     }
 
+    for (address, post) in diff.post {
+        let account = state.account(address);
+
+        if let Some(balance) = post.balance {
+            account.balance(balance);
+        }
+
+        if let Some(nonce) = post.nonce {
+            account.nonce(nonce.as_u64().into());
+        }
+
+        if let Some(code) = post.code.and_then(|x| x.parse().ok()) {
+            account.code(code);
+        }
+
+        for (slot, value) in post.storage.unwrap_or_default() {
+            account.store(slot, value);
+        }
+    }
+}
+
+#[inline(always)]
+async fn trace_transaction_on_state(
+    tx: Transaction,
+    state_overrides: Option<spoof::State>,
+    tracer_backend: TracerBackend,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<Vec<TransactionLog>> {
+    let log_frames = match tracer_backend {
+        TracerBackend::Js => trace_js_logs(&tx, state_overrides, runtime_cache).await,
+        TracerBackend::CallTracer => trace_call_logs(&tx, state_overrides, runtime_cache).await,
+        TracerBackend::Revm => local_evm::execute_transaction(tx).await.map(into_log_frames),
+    }?;
+
+    if log_frames.len() > 0 {
+        return Some(into_transaction_logs(log_frames, runtime_cache));
+    }
+
     return None;
 }
+
+#[inline(always)]
+async fn trace_js_logs(
+    tx: &Transaction,
+    state_overrides: Option<spoof::State>,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<Vec<LogFrame>> {
+    let tracing_options = GethDebugTracingCallOptions {
+        state_overrides,
+        tracing_options: GethDebugTracingOptions {
+            tracer: Some(GethDebugTracerType::JsTracer(JS_CONTENT.to_string())),
+            ..GETH_DEBUG_TRACING_OPTIONS
+        },
+    };
+
+    let geth_trace = runtime_cache
+        .client
+        .debug_trace_call(
+            get_transaction_request(tx),
+            Some(BlockId::Number(BlockNumber::Number(tx.block_number? - 1))),
+            tracing_options,
+        )
+        .await
+        .ok()?;
+
+    // the tracer's result doesn't match any of the known frames
+    if let GethTrace::Unknown(trace_container) = geth_trace {
+        return serde_json::from_value(trace_container).ok();
+    }
+
+    return None;
+}
+
+#[inline(always)]
+async fn trace_call_logs(
+    tx: &Transaction,
+    state_overrides: Option<spoof::State>,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<Vec<LogFrame>> {
+    let tracing_options = GethDebugTracingCallOptions {
+        state_overrides,
+        tracing_options: GethDebugTracingOptions {
+            tracer: Some(GethDebugTracerType::BuiltInTracer(
+                GethDebugBuiltInTracerType::CallTracer,
+            )),
+            tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
+                GethDebugBuiltInTracerConfig::CallTracer(CallConfig {
+                    only_top_call: Some(false),
+                    with_log: Some(true),
+                }),
+            )),
+            ..GETH_DEBUG_TRACING_OPTIONS
+        },
+    };
+
+    let block = BlockId::Number(BlockNumber::Number(tx.block_number? - 1));
+    let params = [
+        serde_json::to_value(get_transaction_request(tx)).ok()?,
+        serde_json::to_value(block).ok()?,
+        serde_json::to_value(tracing_options).ok()?,
+    ];
+
+    // requested raw, ethers' `CallFrame` drops the position of the logs between subcalls
+    let trace: Value = runtime_cache
+        .client
+        .provider()
+        .request("debug_traceCall", params)
+        .await
+        .ok()?;
+
+    let call_frame: CallTraceFrame = serde_json::from_value(trace).ok()?;
+    return Some(call_frame.into_log_frames());
+}
+
+#[inline(always)]
+fn into_log_frames(logs: Vec<revm::primitives::Log>) -> Vec<LogFrame> {
+    return logs
+        .into_iter()
+        .map(|log| LogFrame {
+            address: log.address.into_array().into(),
+            data: log.data.data.to_vec().into(),
+            topics: log.data.topics().iter().map(|x| x.0.into()).collect(),
+        })
+        .collect();
+}
+
+// Logs of tracked markets, or of routers emitting on their behalf, in emission order
+#[inline(always)]
+fn into_transaction_logs(
+    log_frames: Vec<LogFrame>,
+    runtime_cache: &'static RuntimeCache,
+) -> Vec<TransactionLog> {
+    return log_frames
+        .into_par_iter()
+        .filter_map(|log_frame| {
+            let protocol = Market::from_address(&log_frame.address, &runtime_cache)
+                .map(|market| market.value.protocol)
+                .or_else(|| get_router_protocol(&log_frame.address))?;
+
+            Some(TransactionLog {
+                address: log_frame.address,
+                protocol,
+                raw: RawLog {
+                    topics: log_frame.topics,
+                    data: log_frame.data.to_vec(),
+                },
+            })
+        })
+        .collect();
+}
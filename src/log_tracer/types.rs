@@ -1,8 +1,60 @@
 use ethers::types::*;
+use serde::Deserialize;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct LogFrame{
     pub address: H160,
     pub data: Bytes,
     pub topics: Vec<H256>
-}
\ No newline at end of file
+}
+
+// The parts of a `callTracer` frame holding logs
+#[derive(Deserialize)]
+pub struct CallTraceFrame {
+    #[serde(default)]
+    pub calls: Vec<CallTraceFrame>,
+    #[serde(default)]
+    pub logs: Vec<CallTraceLog>,
+}
+
+#[derive(Deserialize)]
+pub struct CallTraceLog {
+    pub address: H160,
+    #[serde(default)]
+    pub topics: Vec<H256>,
+    #[serde(default)]
+    pub data: Bytes,
+    // number of subcalls made before the log, missing on older nodes
+    pub position: Option<usize>,
+}
+
+impl CallTraceFrame {
+    /// Flattens the logs of the frame and its subcalls in emission order. Without positions a
+    /// frame's own logs are taken after its subcalls.
+    pub fn into_log_frames(self) -> Vec<LogFrame> {
+        let mut result: Vec<LogFrame> = vec![];
+        self.append_log_frames(&mut result);
+
+        return result;
+    }
+
+    fn append_log_frames(self, result: &mut Vec<LogFrame>) {
+        let call_count = self.calls.len();
+        let mut logs = self.logs.into_iter().peekable();
+        let mut calls = self.calls.into_iter();
+
+        for i in 0..=call_count {
+            while let Some(log) = logs.next_if(|x| x.position.unwrap_or(call_count) <= i) {
+                result.push(LogFrame {
+                    address: log.address,
+                    data: log.data,
+                    topics: log.topics,
+                });
+            }
+
+            if let Some(call) = calls.next() {
+                call.append_log_frames(result);
+            }
+        }
+    }
+}
@@ -4,6 +4,8 @@ use env::{RuntimeCache, RuntimeConfig};
 use networks::Network;
 use types::Route;
 
+use ethers::providers::Middleware;
+
 use crate::{exchanges::init_exchange_handlers, handlers::NetworkHandler};
 
 #[macro_use]
@@ -11,12 +13,19 @@ extern crate lazy_static;
 extern crate async_trait;
 extern crate base64;
 
+pub mod calldata_decoder;
 pub mod env;
 pub mod exchanges;
+pub mod flash_liquidity;
 mod handlers;
+pub mod local_evm;
 pub mod log_tracer;
 pub mod networks;
+pub mod prefilter;
 pub mod price_oracle;
+pub mod provider_pool;
+pub mod rpc_batching;
+pub mod token_safety;
 pub mod types;
 pub mod utils;
 
@@ -39,7 +48,16 @@ async fn main() {
         Ok(runtime_cache) => {
             init_exchange_handlers();
 
-            println!("Query: {}", runtime_cache.uniswap_query.address());
+            match env::query::get_deployed_address() {
+                Some(address) => println!("Query: state override (fallback {:?})", address),
+                None => println!("Query: state override"),
+            }
+            let endpoint_health = runtime_cache.client.provider().as_ref().get_health();
+            println!(
+                "RPC: {} endpoints, {} healthy",
+                endpoint_health.len(),
+                endpoint_health.iter().filter(|x| x.1.healthy).count()
+            );
             println!("Wallet: {}", runtime_cache.client.address());
             println!("Executor: {}\n", runtime_cache.bundle_executor.address());
 
@@ -49,9 +67,12 @@ async fn main() {
 
             println!("Waiting for validation, this might take a while...");
 
-            if let Some(mut network_handler) =
-                NetworkHandler::from_network(&RUNTIME_NETWORK, &RUNTIME_CONFIG, runtime_cache)
-            {
+            if let Some(mut network_handler) = NetworkHandler::from_network(
+                &RUNTIME_NETWORK,
+                &RUNTIME_CONFIG,
+                runtime_cache,
+                &RUNTIME_ROUTES,
+            ) {
                 network_handler.init().await;
             }
         }
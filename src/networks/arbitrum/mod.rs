@@ -5,6 +5,9 @@ pub const CHAIN_ID: u32 = 42161;
 const NAME: &str = "arbitrum";
 const FLASHLOAN_POOL_ADDRESS_PROVIDER: &str = "0x9af2925C7b97b9418c3C0eb759c0E644701b9714";
 const UNISWAP_QUERY_ADDRESS: &str = "0x70FeDD23788d69FDB2B24fcbf2e49eD3b80Ec1F9";
+const WETH_ADDRESS: &str = "0x82aF49447D8a07e3bd95BD0d56f35241523fBab1";
+// native USDC, the bridged USDC.e is thinner
+const USDC_ADDRESS: &str = "0xaf88d065e77c8cC2239327C5EDb3A432268e5831";
 
 pub fn get_instance() -> Network {
     let exchanges: Vec<crate::exchanges::types::Exchange> = super::load_exchanges_from_file(NAME);
@@ -15,7 +18,9 @@ pub fn get_instance() -> Network {
         name: NAME.to_string(),
         exchanges,
         tokens,
-        uniswap_query_address: UNISWAP_QUERY_ADDRESS.parse().unwrap(),
+        uniswap_query_address: Some(UNISWAP_QUERY_ADDRESS.parse().unwrap()),
         flashloan_pool_address_provider: FLASHLOAN_POOL_ADDRESS_PROVIDER.parse().unwrap(),
+        weth_address: WETH_ADDRESS.parse().unwrap(),
+        usdc_address: USDC_ADDRESS.parse().unwrap(),
     };
 }
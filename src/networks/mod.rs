@@ -12,10 +12,19 @@ pub struct Network {
     pub exchanges: Vec<Exchange>,
     pub tokens: Vec<Token>,
     pub flashloan_pool_address_provider: Address,
-    pub uniswap_query_address: Address,
+    // deployed query contract, for nodes without state overrides
+    pub uniswap_query_address: Option<Address>,
+    // reference prices are taken from these two
+    pub weth_address: Address,
+    pub usdc_address: Address,
 }
 
 impl Network {
+    #[inline(always)]
+    pub fn get_token(&self, address: &Address) -> Option<&Token> {
+        return self.tokens.iter().find(|x| x.contract_address == *address);
+    }
+
     pub fn from_chain_id(chain_id: &u32) -> Network {
         if *chain_id == arbitrum::CHAIN_ID {
             return arbitrum::get_instance();
@@ -0,0 +1,165 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::RwLock,
+    time::Duration,
+};
+
+use ethers::types::{Transaction, H160};
+use itertools::Itertools;
+
+use crate::{
+    env::{RuntimeCache, RuntimeConfig},
+    exchanges::{erc20::get_transfer_fee, get_router_protocol, is_pair_router},
+    networks::Network,
+    types::{market::Market, OrganizedList, Token},
+};
+
+#[cfg(test)]
+mod tests;
+
+// approve, permit, setApprovalForAll, WETH deposit and withdraw
+const IRRELEVANT_SELECTORS: [[u8; 4]; 5] = [
+    [0x09, 0x5e, 0xa7, 0xb3],
+    [0xd5, 0x05, 0xac, 0xcf],
+    [0xa2, 0x2c, 0xb4, 0x65],
+    [0xd0, 0xe3, 0x0d, 0xb0],
+    [0x2e, 0x1a, 0x7d, 0x4d],
+];
+// transfer and transferFrom, relevant for tokens swapping their taxes on transfer
+const TRANSFER_SELECTORS: [[u8; 4]; 2] = [[0xa9, 0x05, 0x9c, 0xbb], [0x23, 0xb8, 0x72, 0xdd]];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    ContractCreation,
+    PlainTransfer,
+    IrrelevantSelector,
+    TokenTransfer,
+    UnknownContract,
+}
+
+// Contracts a relevant transaction calls directly
+#[derive(Default)]
+struct KnownContracts {
+    aggregators: HashSet<H160>,
+    markets: HashSet<H160>,
+    tokens: HashMap<H160, &'static Token>,
+}
+
+lazy_static! {
+    static ref KNOWN_CONTRACTS: RwLock<KnownContracts> = RwLock::new(KnownContracts::default());
+    static ref SKIP_COUNTS: RwLock<HashMap<SkipReason, u64>> = RwLock::new(HashMap::new());
+    static ref CHECKED_COUNT: RwLock<u64> = RwLock::new(0);
+}
+
+// Aggregators are listed next to the network's token file, the tracked markets and their
+// tokens come from the runtime cache
+pub fn load(network: &Network, runtime_cache: &'static RuntimeCache) {
+    let path = format!("src/networks/{}/_aggregators.json", network.name);
+    let aggregators: Vec<H160> = fs::read_to_string(path)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default();
+
+    load_contracts(aggregators, &runtime_cache.markets);
+}
+
+#[inline(always)]
+fn load_contracts(aggregators: Vec<H160>, markets: &OrganizedList<Market>) {
+    let mut known_contracts = KNOWN_CONTRACTS.write().unwrap();
+    known_contracts.aggregators = aggregators.into_iter().collect();
+
+    for market in markets.iter() {
+        known_contracts
+            .markets
+            .insert(market.value.contract_address);
+
+        for token in market.value.tokens {
+            known_contracts.tokens.insert(token.contract_address, token);
+        }
+    }
+}
+
+#[inline(always)]
+fn get_skip_reason(tx: &Transaction, prefilter_unknown_contracts: bool) -> Option<SkipReason> {
+    let to = match tx.to {
+        Some(value) => value,
+        None => return Some(SkipReason::ContractCreation),
+    };
+
+    if tx.input.is_empty() {
+        return Some(SkipReason::PlainTransfer);
+    }
+
+    let selector = tx.input.get(..4).unwrap_or_default();
+    if IRRELEVANT_SELECTORS.iter().any(|x| x == selector) {
+        return Some(SkipReason::IrrelevantSelector);
+    }
+
+    let known_contracts = KNOWN_CONTRACTS.read().unwrap();
+    if TRANSFER_SELECTORS.iter().any(|x| x == selector) {
+        let is_taxed = known_contracts
+            .tokens
+            .get(&to)
+            .map_or(false, |x| get_transfer_fee(x) > 0);
+
+        if !is_taxed {
+            return Some(SkipReason::TokenTransfer);
+        }
+    }
+
+    if prefilter_unknown_contracts
+        && !known_contracts.aggregators.contains(&to)
+        && !known_contracts.markets.contains(&to)
+        && !known_contracts.tokens.contains_key(&to)
+        && !is_pair_router(&to)
+        && get_router_protocol(&to).is_none()
+    {
+        return Some(SkipReason::UnknownContract);
+    }
+
+    return None;
+}
+
+/// Whether the transaction may touch a tracked market and has to be traced, skipped
+/// transactions are counted by reason
+pub fn is_relevant(tx: &Transaction, runtime_config: &RuntimeConfig) -> bool {
+    *CHECKED_COUNT.write().unwrap() += 1;
+
+    return match get_skip_reason(tx, runtime_config.prefilter_unknown_contracts) {
+        Some(reason) => {
+            *SKIP_COUNTS.write().unwrap().entry(reason).or_default() += 1;
+            false
+        }
+        None => true,
+    };
+}
+
+/// Prints the skip counts since startup every `interval`
+pub fn initiate_reports(interval: Duration) {
+    tokio::spawn(async move {
+        let mut run_interval = tokio::time::interval(interval);
+
+        loop {
+            run_interval.tick().await;
+
+            let checked_count = *CHECKED_COUNT.read().unwrap();
+            if checked_count == 0 {
+                continue;
+            }
+
+            let skip_counts = SKIP_COUNTS.read().unwrap();
+            let skipped_count: u64 = skip_counts.values().sum();
+            let reasons = skip_counts
+                .iter()
+                .sorted_by_key(|x| std::cmp::Reverse(*x.1))
+                .map(|(reason, count)| format!("{:?}: {}", reason, count))
+                .join(", ");
+
+            println!(
+                "Prefilter skipped {} / {} transactions ({})\n",
+                skipped_count, checked_count, reasons
+            );
+        }
+    });
+}
@@ -0,0 +1,148 @@
+use ethers::types::Bytes;
+
+use crate::exchanges::{erc20::set_transfer_fee, register_router, types::Protocol};
+
+use super::*;
+
+const TOKEN: u64 = 0x1000;
+// transfer taxes declared in the token file and detected at startup
+const DECLARED_TAX_TOKEN: u64 = 0x4000;
+const DETECTED_TAX_TOKEN: u64 = 0x4100;
+const MARKET: u64 = 0xa0;
+const AGGREGATOR: u64 = 0x7000;
+const ROUTER: u64 = 0x9100;
+const UNKNOWN: u64 = 0x8000;
+
+const APPROVE: &str = "095ea7b3";
+const DEPOSIT: &str = "d0e30db0";
+const TRANSFER: &str = "a9059cbb";
+const TRANSFER_FROM: &str = "23b872dd";
+const SWAP: &str = "38ed1739";
+
+fn get_address(value: u64) -> H160 {
+    return H160::from_low_u64_be(value);
+}
+
+fn get_token(address: u64, transfer_fee: Option<u32>) -> &'static Token {
+    return Box::leak(Box::new(Token {
+        contract_address: get_address(address),
+        flash_loan_enabled: false,
+        decimals: 18,
+        ref_symbol: None,
+        transfer_fee,
+        rebasing: false,
+    }));
+}
+
+fn load_fixture() {
+    let token = get_token(TOKEN, None);
+    let detected_tax_token = get_token(DETECTED_TAX_TOKEN, None);
+    set_transfer_fee(detected_tax_token, 500);
+
+    let mut markets = OrganizedList::new();
+    for (pair, tokens) in [
+        (MARKET, [token, get_token(DECLARED_TAX_TOKEN, Some(100))]),
+        (MARKET + 1, [token, detected_tax_token]),
+    ] {
+        markets.add_value(Market::new(
+            get_address(pair),
+            tokens,
+            30,
+            false,
+            Protocol::UniswapV2,
+        ));
+    }
+
+    load_contracts(vec![get_address(AGGREGATOR)], &markets);
+    register_router(get_address(ROUTER), None);
+}
+
+fn get_transaction(to: Option<u64>, input: &str) -> Transaction {
+    return Transaction {
+        to: to.map(get_address),
+        input: input.parse::<Bytes>().unwrap(),
+        ..Default::default()
+    };
+}
+
+#[test]
+fn transactions_are_skipped_by_reason() {
+    load_fixture();
+
+    let arguments = "00".repeat(64);
+    let transfer = format!("{}{}", TRANSFER, arguments);
+    let transfer_from = format!("{}{}", TRANSFER_FROM, arguments);
+    let swap = format!("{}{}", SWAP, arguments);
+
+    // transaction, whether unknown contracts are skipped, and the expected reason
+    let cases = [
+        (
+            get_transaction(None, &swap),
+            false,
+            Some(SkipReason::ContractCreation),
+        ),
+        (
+            get_transaction(Some(MARKET), ""),
+            true,
+            Some(SkipReason::PlainTransfer),
+        ),
+        (
+            get_transaction(Some(TOKEN), APPROVE),
+            true,
+            Some(SkipReason::IrrelevantSelector),
+        ),
+        (
+            get_transaction(Some(TOKEN), DEPOSIT),
+            false,
+            Some(SkipReason::IrrelevantSelector),
+        ),
+        (
+            get_transaction(Some(TOKEN), &transfer),
+            true,
+            Some(SkipReason::TokenTransfer),
+        ),
+        (
+            get_transaction(Some(UNKNOWN), &transfer_from),
+            false,
+            Some(SkipReason::TokenTransfer),
+        ),
+        (
+            get_transaction(Some(UNKNOWN), &swap),
+            true,
+            Some(SkipReason::UnknownContract),
+        ),
+        (get_transaction(Some(UNKNOWN), &swap), false, None),
+        (get_transaction(Some(MARKET), &swap), true, None),
+        (get_transaction(Some(AGGREGATOR), &swap), true, None),
+        (get_transaction(Some(ROUTER), &swap), true, None),
+    ];
+
+    for (i, (tx, prefilter_unknown_contracts, expected)) in cases.iter().enumerate() {
+        assert_eq!(
+            get_skip_reason(tx, *prefilter_unknown_contracts),
+            *expected,
+            "case {}",
+            i
+        );
+    }
+}
+
+#[test]
+fn taxed_token_transfers_are_traced() {
+    load_fixture();
+
+    let arguments = "00".repeat(64);
+    for token in [DECLARED_TAX_TOKEN, DETECTED_TAX_TOKEN] {
+        for selector in [TRANSFER, TRANSFER_FROM] {
+            let tx = get_transaction(Some(token), &format!("{}{}", selector, arguments));
+            assert_eq!(get_skip_reason(&tx, true), None);
+        }
+
+        // approvals don't move balances, taxed or not
+        let tx = get_transaction(Some(token), APPROVE);
+        assert_eq!(
+            get_skip_reason(&tx, true),
+            Some(SkipReason::IrrelevantSelector)
+        );
+    }
+}
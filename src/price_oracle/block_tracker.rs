@@ -0,0 +1,765 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::RwLock,
+};
+
+use tokio::sync::broadcast;
+
+use ethers::{
+    providers::Middleware,
+    types::{Block, BlockId, H256, U64},
+};
+
+use crate::{
+    env::{RuntimeCache, RuntimeConfig},
+    exchanges::{
+        apply_confirmed_logs, get_block_logs, get_market_reserves, get_market_reserves_where,
+        parse_balance_changes, parse_synced_reserves,
+    },
+    types::{MarketUpdate, Reserves},
+};
+
+use super::MARKET_RESERVE_TABLE;
+
+// deepest reorg rolled back, the reserve table is reloaded past it
+const MAX_REORG_DEPTH: usize = 64;
+const BLOCK_UPDATE_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub number: U64,
+    pub hash: H256,
+    pub parent_hash: H256,
+}
+
+impl BlockHeader {
+    #[inline(always)]
+    fn from_block<T>(block: &Block<T>) -> Option<BlockHeader> {
+        return Some(BlockHeader {
+            number: block.number?,
+            hash: block.hash?,
+            parent_hash: block.parent_hash,
+        });
+    }
+}
+
+// A canonical block and the reserves its `Sync` events replaced. Blocks the table was loaded at
+// or past weren't applied and can't be undone.
+struct TrackedBlock {
+    header: BlockHeader,
+    applied: bool,
+    reserve_undo: Vec<(usize, Reserves)>,
+}
+
+lazy_static! {
+    static ref BLOCK_HISTORY: RwLock<VecDeque<TrackedBlock>> = RwLock::new(VecDeque::new());
+    // balance changes of every canonical block applied, replays included
+    static ref BLOCK_UPDATES: broadcast::Sender<MarketUpdate> =
+        broadcast::channel(BLOCK_UPDATE_CAPACITY).0;
+}
+
+#[inline(always)]
+pub fn subscribe() -> broadcast::Receiver<MarketUpdate> {
+    return BLOCK_UPDATES.subscribe();
+}
+
+#[inline(always)]
+pub fn get_head() -> Option<BlockHeader> {
+    return BLOCK_HISTORY.read().unwrap().back().map(|x| x.header);
+}
+
+/// Whether the block is still part of the canonical chain, blocks older than the history are
+/// assumed final
+#[inline(always)]
+pub fn is_canonical(header: &BlockHeader) -> bool {
+    let history = BLOCK_HISTORY.read().unwrap();
+    let oldest = match history.front() {
+        Some(value) => value.header.number,
+        None => return true,
+    };
+
+    if header.number < oldest {
+        return true;
+    }
+
+    return history
+        .iter()
+        .any(|x| x.header.number == header.number && x.header.hash == header.hash);
+}
+
+// Moves the reserve table by the block's logs, keeping the replaced reserves to undo it. Pairs
+// are set to their `Sync` reserves, pool markets are moved by their balance changes; pool
+// registries, e.g. the Liquidity Book bins, follow the logs but aren't rolled back.
+async fn apply_block(
+    header: BlockHeader,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<TrackedBlock> {
+    let logs = get_block_logs(header.hash, runtime_cache).await.ok()?;
+    let synced_reserves = parse_synced_reserves(&logs, runtime_cache);
+    let balance_changes = parse_balance_changes(&logs, runtime_cache);
+
+    let mut reserve_snapshot = MARKET_RESERVE_TABLE.write().await;
+    let mut reserve_undo: Vec<(usize, Reserves)> = vec![];
+
+    // a snapshot read at or past the block already holds its changes
+    let applied = reserve_snapshot.block_number < header.number;
+    if applied {
+        reserve_snapshot.block_number = header.number;
+        apply_confirmed_logs(&logs, runtime_cache);
+        let reserve_table = &mut reserve_snapshot.reserves;
+
+        for (market_id, reserves) in &synced_reserves {
+            if let Ok(index) = reserve_table.binary_search_by_key(market_id, |x| x.id) {
+                reserve_undo.push((*market_id, reserve_table[index].value));
+                reserve_table[index].value = *reserves;
+            }
+        }
+
+        for balance_change in &balance_changes {
+            let market_id = balance_change.market.id;
+            if synced_reserves.iter().any(|x| x.0 == market_id) {
+                continue;
+            }
+
+            if let Ok(index) = reserve_table.binary_search_by_key(&market_id, |x| x.id) {
+                let reserves = reserve_table[index].value;
+                reserve_undo.push((market_id, reserves));
+                reserve_table[index].value = (
+                    (reserves.0 + balance_change.amount_0_in)
+                        .saturating_sub(balance_change.amount_0_out.into()),
+                    (reserves.1 + balance_change.amount_1_in)
+                        .saturating_sub(balance_change.amount_1_out.into()),
+                );
+            }
+        }
+    }
+    drop(reserve_snapshot);
+
+    // nobody listens outside of the confirmed block mode
+    _ = BLOCK_UPDATES.send(MarketUpdate {
+        block: Some(header),
+        confirmed: true,
+        balance_changes,
+    });
+
+    return Some(TrackedBlock {
+        header,
+        applied,
+        reserve_undo,
+    });
+}
+
+#[inline(always)]
+async fn undo_block(tracked_block: &TrackedBlock) {
+    let mut reserve_snapshot = MARKET_RESERVE_TABLE.write().await;
+    reserve_snapshot.block_number = tracked_block.header.number - 1;
+
+    let reserve_table = &mut reserve_snapshot.reserves;
+    for (market_id, reserves) in tracked_block.reserve_undo.iter().rev() {
+        if let Ok(index) = reserve_table.binary_search_by_key(market_id, |x| x.id) {
+            reserve_table[index].value = *reserves;
+        }
+    }
+}
+
+// Blocks of the new branch from the common ancestor with the history (excluded) to `header`,
+// `None` when the ancestor is deeper than the history or a parent can't be read
+async fn get_branch(
+    header: BlockHeader,
+    runtime_cache: &'static RuntimeCache,
+) -> Option<(U64, Vec<BlockHeader>)> {
+    let mut branch: Vec<BlockHeader> = vec![header];
+
+    while branch.len() <= MAX_REORG_DEPTH {
+        let parent_hash = branch.last().unwrap().parent_hash;
+        let ancestor = BLOCK_HISTORY
+            .read()
+            .unwrap()
+            .iter()
+            .find(|x| x.header.hash == parent_hash)
+            .map(|x| x.header.number);
+
+        if let Some(ancestor) = ancestor {
+            branch.reverse();
+            return Some((ancestor, branch));
+        }
+
+        let parent = runtime_cache
+            .client
+            .get_block(BlockId::Hash(parent_hash))
+            .await
+            .ok()??;
+        branch.push(BlockHeader::from_block(&parent)?);
+    }
+
+    return None;
+}
+
+// Replaces the reserve table with a snapshot at `block_number`, blocks move it from there
+async fn load_reserves_at(
+    block_number: U64,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    let reserve_snapshot = get_market_reserves(
+        &runtime_cache.markets,
+        block_number,
+        runtime_cache,
+        runtime_config,
+    )
+    .await;
+
+    // routes index the table by market, they aren't evaluated on it
+    if reserve_snapshot.partial {
+        println!(
+            "Reserve snapshot at block {} is partial, retrying on the next block\n",
+            block_number
+        );
+    }
+
+    *MARKET_RESERVE_TABLE.write().await = reserve_snapshot;
+}
+
+// Reads the markets a partial table misses at the block it was moved to, until none is missing
+async fn complete_reserves(
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    let (block_number, missing) = {
+        let reserve_snapshot = MARKET_RESERVE_TABLE.read().await;
+        if !reserve_snapshot.partial {
+            return;
+        }
+
+        let missing: HashSet<usize> = runtime_cache
+            .markets
+            .iter()
+            .map(|x| x.id)
+            .filter(|x| reserve_snapshot.get_reserves(*x).is_none())
+            .collect();
+
+        (reserve_snapshot.block_number, missing)
+    };
+
+    let retried = get_market_reserves_where(
+        &runtime_cache.markets,
+        |x| missing.contains(&x),
+        block_number,
+        runtime_cache,
+        runtime_config,
+    )
+    .await;
+
+    let mut reserve_snapshot = MARKET_RESERVE_TABLE.write().await;
+    // moved or reloaded meanwhile, the reads are of another block
+    if !reserve_snapshot.partial || reserve_snapshot.block_number != block_number {
+        return;
+    }
+
+    for reserves in retried.reserves {
+        reserve_snapshot.reserves.add_pair(reserves);
+    }
+    reserve_snapshot.reserves.sort();
+    reserve_snapshot.partial = retried.partial;
+
+    if !reserve_snapshot.partial {
+        println!("Reserve snapshot completed at block {}\n", block_number);
+    }
+}
+
+// Loads the reserve table at the head, or at the latest block before the first one arrives
+pub async fn load_reserves(
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    let block_number = match get_head() {
+        Some(value) => value.number,
+        None => match runtime_cache.client.get_block_number().await {
+            Ok(value) => value,
+            Err(_) => return,
+        },
+    };
+
+    load_reserves_at(block_number, runtime_cache, runtime_config).await;
+}
+
+// Starts the history over from `header` on a reloaded reserve table
+async fn reset(
+    header: BlockHeader,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    // an empty table isn't maintained, there's nothing to reload
+    if !MARKET_RESERVE_TABLE.read().await.is_empty() {
+        load_reserves_at(header.number, runtime_cache, runtime_config).await;
+    }
+
+    let mut history = BLOCK_HISTORY.write().unwrap();
+    history.clear();
+    history.push_back(TrackedBlock {
+        header,
+        applied: false,
+        reserve_undo: vec![],
+    });
+}
+
+/// Follows the chain to a new head. Skipped blocks are filled in, a reorg rolls the reserve
+/// table back to the common ancestor and replays the `Sync` events of the canonical blocks.
+/// Markets missing from a partial table are read again.
+pub async fn handle_block<T>(
+    block: &Block<T>,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    follow_block(block, runtime_cache, runtime_config).await;
+    complete_reserves(runtime_cache, runtime_config).await;
+}
+
+async fn follow_block<T>(
+    block: &Block<T>,
+    runtime_cache: &'static RuntimeCache,
+    runtime_config: &'static RuntimeConfig,
+) {
+    let header = match BlockHeader::from_block(block) {
+        Some(value) => value,
+        None => return,
+    };
+
+    // the table may have been loaded before the first block
+    if get_head().is_none() {
+        let tracked_block = match apply_block(header, runtime_cache).await {
+            Some(value) => value,
+            None => TrackedBlock {
+                header,
+                applied: false,
+                reserve_undo: vec![],
+            },
+        };
+
+        BLOCK_HISTORY.write().unwrap().push_back(tracked_block);
+        return;
+    }
+
+    // blocks may be announced again
+    if is_canonical(&header) {
+        return;
+    }
+
+    let (ancestor, branch) = match get_branch(header, runtime_cache).await {
+        Some(value) => value,
+        None => {
+            println!("No known ancestor of block {}\n", header.number);
+            return reset(header, runtime_cache, runtime_config).await;
+        }
+    };
+
+    let head = header;
+    let orphaned: Vec<TrackedBlock> = {
+        let mut history = BLOCK_HISTORY.write().unwrap();
+        let position = history
+            .iter()
+            .position(|x| x.header.number > ancestor)
+            .unwrap_or(history.len());
+
+        history.drain(position..).collect()
+    };
+
+    // the snapshot holds changes of orphaned blocks it wasn't moved by, it's reloaded at the
+    // ancestor instead
+    if orphaned.iter().any(|x| !x.applied) && !MARKET_RESERVE_TABLE.read().await.is_empty() {
+        load_reserves_at(ancestor, runtime_cache, runtime_config).await;
+    } else {
+        for tracked_block in orphaned.iter().rev() {
+            undo_block(tracked_block).await;
+        }
+    }
+
+    if orphaned.len() > 0 {
+        println!(
+            "Reorg at {}: rolled back {} blocks, replaying {}\n",
+            ancestor + 1,
+            orphaned.len(),
+            branch.len()
+        );
+    }
+
+    for header in branch {
+        // a block whose events can't be read leaves the table behind, it's reloaded at the head
+        let tracked_block = match apply_block(header, runtime_cache).await {
+            Some(value) => value,
+            None => return reset(head, runtime_cache, runtime_config).await,
+        };
+
+        let mut history = BLOCK_HISTORY.write().unwrap();
+        history.push_back(tracked_block);
+
+        while history.len() > MAX_REORG_DEPTH {
+            history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ethers::{
+        middleware::SignerMiddleware,
+        providers::Provider,
+        signers::LocalWallet,
+        types::{Address, Log, H160, U256},
+        utils::keccak256,
+    };
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+    };
+    use url::Url;
+
+    use crate::{
+        env::{
+            query::QUERY_SCRATCH_ADDRESS,
+            types::{BundleExecutorContract, UniswapQueryContract},
+        },
+        exchanges::types::Protocol,
+        handlers::FeedMode,
+        log_tracer::TracerBackend,
+        provider_pool::ProviderPool,
+        types::{market::Market, OrganizedList, ReserveSnapshot, Token},
+    };
+
+    use super::*;
+
+    const LOADED_BLOCK: u64 = 100;
+
+    lazy_static! {
+        // the history and the reserve table are shared by the tests
+        static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    // Blocks of the canonical chain are of fork 0, those of another fork branch off it after
+    // block `base`. The hash encodes all three.
+    fn get_hash(fork: u64, base: u64, number: u64) -> H256 {
+        if fork == 0 || number <= base {
+            return H256::from_low_u64_be(number);
+        }
+
+        let mut hash = H256::from_low_u64_be(number);
+        hash.0[8..16].copy_from_slice(&base.to_be_bytes());
+        hash.0[16..24].copy_from_slice(&fork.to_be_bytes());
+        return hash;
+    }
+
+    fn read_hash(hash: &H256) -> (u64, u64, u64) {
+        let read = |range: std::ops::Range<usize>| {
+            return u64::from_be_bytes(hash.0[range].try_into().unwrap());
+        };
+
+        return (read(16..24), read(8..16), read(24..32));
+    }
+
+    fn get_block(hash: H256) -> Block<H256> {
+        let (fork, base, number) = read_hash(&hash);
+
+        return Block {
+            hash: Some(hash),
+            parent_hash: get_hash(fork, base, number - 1),
+            number: Some(U64::from(number)),
+            ..Default::default()
+        };
+    }
+
+    // Every block syncs the pair of its parity, blocks of the canonical chain sync the third
+    // pair twice as well
+    fn get_syncs(hash: &H256) -> Vec<(usize, Reserves)> {
+        let (fork, _, number) = read_hash(hash);
+        let mut result = vec![(
+            (number % 2) as usize,
+            (U256::from(number), U256::from(fork)),
+        )];
+
+        if fork == 0 {
+            result.push((2, (U256::from(number), U256::zero())));
+            result.push((2, (U256::from(number), U256::one())));
+        }
+
+        return result;
+    }
+
+    fn get_pair_address(market_id: usize) -> H160 {
+        return H160::from_low_u64_be(0x7100 + market_id as u64);
+    }
+
+    fn get_logs(hash: &H256) -> Vec<Log> {
+        let topic = H256::from(keccak256("Sync(uint112,uint112)"));
+
+        return get_syncs(hash)
+            .into_iter()
+            .map(|(market_id, reserves)| Log {
+                address: get_pair_address(market_id),
+                topics: vec![topic],
+                data: ethers::abi::encode(&[
+                    ethers::abi::Token::Uint(reserves.0),
+                    ethers::abi::Token::Uint(reserves.1),
+                ])
+                .into(),
+                block_hash: Some(*hash),
+                ..Default::default()
+            })
+            .collect();
+    }
+
+    // Reserve reads revert, a reloaded table is left partial
+    fn answer(request: &Value) -> Value {
+        let result = match request["method"].as_str().unwrap() {
+            "eth_blockNumber" => json!(format!("{:#x}", LOADED_BLOCK)),
+            "eth_getBlockByHash" => {
+                let hash: H256 = serde_json::from_value(request["params"][0].clone()).unwrap();
+                serde_json::to_value(get_block(hash)).unwrap()
+            }
+            "eth_getLogs" => {
+                let hash: H256 =
+                    serde_json::from_value(request["params"][0]["blockHash"].clone()).unwrap();
+                serde_json::to_value(get_logs(&hash)).unwrap()
+            }
+            _ => {
+                return json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": {"code": 3, "message": "execution reverted"},
+                })
+            }
+        };
+
+        return json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+    }
+
+    async fn serve_http_connection(stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+
+                if line == "\r\n" {
+                    break;
+                }
+
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+            let response = answer(&serde_json::from_slice(&body).unwrap()).to_string();
+
+            let message = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            _ = reader.get_mut().write_all(message.as_bytes()).await;
+        }
+    }
+
+    fn get_markets() -> OrganizedList<Market> {
+        let tokens: Vec<&'static Token> = (0..2)
+            .map(|x| {
+                return &*Box::leak(Box::new(Token {
+                    contract_address: H160::from_low_u64_be(0x7000 + x),
+                    flash_loan_enabled: false,
+                    decimals: 18,
+                    ref_symbol: None,
+                    transfer_fee: None,
+                    rebasing: false,
+                }));
+            })
+            .collect();
+
+        let mut result = OrganizedList::new();
+        for market_id in 0..3 {
+            result.add_value(Market::new(
+                get_pair_address(market_id),
+                [tokens[0], tokens[1]],
+                30,
+                false,
+                Protocol::UniswapV2,
+            ));
+        }
+
+        return result;
+    }
+
+    async fn get_runtime() -> (&'static RuntimeCache, &'static RuntimeConfig) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_http_connection(stream));
+            }
+        });
+
+        let provider = Provider::new(ProviderPool::connect_http(std::slice::from_ref(&url)).await);
+        let wallet: LocalWallet = format!("{:064x}", 1).parse().unwrap();
+        let client = Arc::new(SignerMiddleware::new(provider, wallet));
+
+        let runtime_cache = RuntimeCache {
+            uniswap_query: UniswapQueryContract::new(QUERY_SCRATCH_ADDRESS, client.clone()),
+            bundle_executor: BundleExecutorContract::new(Address::zero(), client.clone()),
+            client,
+            markets: get_markets(),
+            routes: vec![],
+        };
+        let runtime_config = RuntimeConfig {
+            chain_id: 42161,
+            rpc_endpoint: url.clone(),
+            rpc_endpoints: vec![],
+            debug_rpc_endpoints: vec![],
+            feed_endpoint: url,
+            executor_address: Address::zero(),
+            private_key: String::new(),
+            route_restraints: (2, 3),
+            small_chunk_size: 10,
+            large_chunk_size: 100,
+            min_market_reserves: String::from("0"),
+            price_cross_check: false,
+            slippage_tolerance: 0,
+            sequential_tracing: false,
+            tracer_backend: TracerBackend::Revm,
+            prefilter_unknown_contracts: false,
+            feed_mode: FeedMode::Blocks,
+            denylist_path: String::new(),
+        };
+
+        return (
+            Box::leak(Box::new(runtime_cache)),
+            Box::leak(Box::new(runtime_config)),
+        );
+    }
+
+    // Reserves of the table loaded at `LOADED_BLOCK`, moved by the syncs of `blocks` in order
+    fn replay(blocks: &[H256]) -> Vec<Reserves> {
+        let mut result = vec![(U256::from(1000), U256::from(1000)); 3];
+        for hash in blocks {
+            for (market_id, reserves) in get_syncs(hash) {
+                result[market_id] = reserves;
+            }
+        }
+
+        return result;
+    }
+
+    async fn reset_state() {
+        BLOCK_HISTORY.write().unwrap().clear();
+
+        let mut reserve_snapshot = ReserveSnapshot::new();
+        reserve_snapshot.block_number = U64::from(LOADED_BLOCK);
+        for reserves in replay(&[]) {
+            reserve_snapshot.reserves.add_value(reserves);
+        }
+        *MARKET_RESERVE_TABLE.write().await = reserve_snapshot;
+    }
+
+    async fn follow(
+        hashes: &[H256],
+        runtime_cache: &'static RuntimeCache,
+        runtime_config: &'static RuntimeConfig,
+    ) {
+        for hash in hashes {
+            follow_block(&get_block(*hash), runtime_cache, runtime_config).await;
+        }
+    }
+
+    async fn get_reserve_table() -> (U64, Vec<Reserves>) {
+        let reserve_snapshot = MARKET_RESERVE_TABLE.read().await;
+
+        return (
+            reserve_snapshot.block_number,
+            reserve_snapshot.reserves.iter().map(|x| x.value).collect(),
+        );
+    }
+
+    #[tokio::test]
+    async fn one_block_reorgs_undo_the_orphaned_block() {
+        let _lock = TEST_LOCK.lock().await;
+        let (runtime_cache, runtime_config) = get_runtime().await;
+        reset_state().await;
+
+        let canonical = [get_hash(0, 0, 101), get_hash(0, 0, 102)];
+        follow(&canonical, runtime_cache, runtime_config).await;
+        assert_eq!(
+            get_reserve_table().await,
+            (U64::from(102), replay(&canonical))
+        );
+
+        let head = get_hash(1, 101, 102);
+        follow(&[head], runtime_cache, runtime_config).await;
+
+        assert_eq!(
+            get_reserve_table().await,
+            (U64::from(102), replay(&[canonical[0], head]))
+        );
+        assert_eq!(get_head().map(|x| x.hash), Some(head));
+    }
+
+    #[tokio::test]
+    async fn two_block_reorgs_undo_in_reverse_and_replay_in_order() {
+        let _lock = TEST_LOCK.lock().await;
+        let (runtime_cache, runtime_config) = get_runtime().await;
+        reset_state().await;
+
+        let canonical = [101, 102, 103].map(|x| get_hash(0, 0, x));
+        follow(&canonical, runtime_cache, runtime_config).await;
+
+        // the parent of the new head is read from the node
+        let branch = [102, 103].map(|x| get_hash(2, 101, x));
+        follow(&[branch[1]], runtime_cache, runtime_config).await;
+
+        // the third pair is back to its last sync in block 101, not to one in between
+        assert_eq!(
+            get_reserve_table().await,
+            (
+                U64::from(103),
+                replay(&[canonical[0], branch[0], branch[1]])
+            )
+        );
+        assert_eq!(get_head().map(|x| x.hash), Some(branch[1]));
+        assert!(!is_canonical(
+            &BlockHeader::from_block(&get_block(canonical[1])).unwrap()
+        ));
+        assert!(is_canonical(
+            &BlockHeader::from_block(&get_block(branch[0])).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn reorgs_past_the_history_reload_the_table_at_the_head() {
+        let _lock = TEST_LOCK.lock().await;
+        let (runtime_cache, runtime_config) = get_runtime().await;
+        reset_state().await;
+
+        follow(
+            &[get_hash(0, 0, 101), get_hash(0, 0, 102)],
+            runtime_cache,
+            runtime_config,
+        )
+        .await;
+
+        // no ancestor within `MAX_REORG_DEPTH` blocks is known
+        let head = get_hash(3, 102 - MAX_REORG_DEPTH as u64 - 2, 103);
+        follow(&[head], runtime_cache, runtime_config).await;
+
+        let reserve_snapshot = MARKET_RESERVE_TABLE.read().await;
+        assert_eq!(reserve_snapshot.block_number, U64::from(103));
+        assert!(reserve_snapshot.partial);
+        assert_eq!(BLOCK_HISTORY.read().unwrap().len(), 1);
+        assert_eq!(get_head().map(|x| x.hash), Some(head));
+    }
+}
@@ -0,0 +1,86 @@
+use ethers::{
+    abi::{AbiParser, Function, Token},
+    prelude::*,
+    types::transaction::eip2718::TypedTransaction,
+    utils::parse_units,
+};
+
+use crate::env::types::RuntimeClient;
+
+const ARB_GAS_INFO_ADDRESS: &str = "0x000000000000000000000000000000000000006C";
+// size ArbOS adds to every transaction's calldata for its fixed fields when pricing L1 data
+const TX_FIXED_BYTES: usize = 140;
+// L1 gas charged for a non-zero calldata byte, ArbOS prices every posted byte at this rate
+const L1_GAS_PER_BYTE: u64 = 16;
+
+lazy_static! {
+    static ref GET_PRICES_IN_WEI_METHOD: Function = AbiParser::default()
+        .parse_function("getPricesInWei()(uint256,uint256,uint256,uint256,uint256,uint256)")
+        .unwrap();
+    static ref GET_L1_BASE_FEE_ESTIMATE_METHOD: Function = AbiParser::default()
+        .parse_function("getL1BaseFeeEstimate()(uint256)")
+        .unwrap();
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GasPrices {
+    // base fee of the latest L2 block
+    pub l2_base_fee: U256,
+    // `getPricesInWei` per L1 calldata byte
+    pub l1_byte_price: U256,
+    // `getL1BaseFeeEstimate`
+    pub l1_base_fee: U256,
+}
+
+impl Default for GasPrices {
+    fn default() -> Self {
+        return GasPrices {
+            l2_base_fee: U256::from(parse_units("0.1", "gwei").unwrap()),
+            l1_byte_price: U256::zero(),
+            l1_base_fee: U256::zero(),
+        };
+    }
+}
+
+impl GasPrices {
+    /// Cost in wei of a transaction with `calldata` using up to `gas_limit` L2 gas. The L1 part
+    /// prices the uncompressed calldata, which bounds the compressed size ArbOS charges for.
+    #[inline(always)]
+    pub fn cost_of(&self, calldata: &Bytes, gas_limit: U256) -> U256 {
+        return self.cost_of_size(calldata.len(), gas_limit);
+    }
+
+    /// Same as `cost_of` for calldata of `calldata_size` bytes
+    #[inline(always)]
+    pub fn cost_of_size(&self, calldata_size: usize, gas_limit: U256) -> U256 {
+        let l1_byte_price = self.l1_byte_price.max(self.l1_base_fee * L1_GAS_PER_BYTE);
+        let l1_cost = l1_byte_price * (calldata_size + TX_FIXED_BYTES);
+
+        return self.l2_base_fee * gas_limit + l1_cost;
+    }
+}
+
+#[inline(always)]
+async fn call_arb_gas_info(method: &Function, client: &RuntimeClient) -> Option<Vec<Token>> {
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(ARB_GAS_INFO_ADDRESS.parse::<Address>().ok()?)
+        .data(method.short_signature().to_vec())
+        .into();
+
+    let response = client.call(&tx, None).await.ok()?;
+    return method.decode_output(&response).ok();
+}
+
+/// Reads the L1 data prices from the `ArbGasInfo` precompile, returns (price per L1 calldata
+/// byte, L1 base fee estimate)
+pub async fn get_l1_prices(client: &RuntimeClient) -> Option<(U256, U256)> {
+    let (prices, base_fee) = tokio::join!(
+        call_arb_gas_info(&GET_PRICES_IN_WEI_METHOD, client),
+        call_arb_gas_info(&GET_L1_BASE_FEE_ESTIMATE_METHOD, client)
+    );
+
+    let byte_price = prices?.get(1)?.clone().into_uint()?;
+    let base_fee = base_fee?.get(0)?.clone().into_uint()?;
+
+    return Some((byte_price, base_fee));
+}
@@ -1,39 +1,51 @@
 use ethers::providers::Middleware;
+use ethers::types::Address;
+use ethers::types::Bytes;
 use ethers::types::U256;
 use ethers::types::U64;
-use ethers::utils::parse_units;
-use ethers::utils::ParseUnits;
 use futures::executor::block_on;
 use futures_util::StreamExt;
-use serde_json::Value;
 use std::sync::atomic;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 use tokio::runtime::Handle;
+use tokio::sync::broadcast;
 use tokio::sync::RwLock;
 
 use crate::env::RuntimeConfig;
 use crate::exchanges::get_market_reserves;
-use crate::types::OrganizedList;
+use crate::flash_liquidity::{aave, update_sources};
+use crate::types::MarketUpdate;
 use crate::types::PriceTable;
+use crate::types::ReserveSnapshot;
 use crate::types::Reserves;
-use crate::types::Token;
-use crate::{env::RuntimeCache, networks::Network};
+use crate::{
+    env::RuntimeCache,
+    networks::{Network, ARBITRUM_CHAIN_ID},
+};
 
-use self::base_table::get_base_price_table;
-mod base_table;
+pub use self::block_tracker::BlockHeader;
+pub use self::gas_prices::GasPrices;
+
+use self::gas_prices::get_l1_prices;
+use self::reference_prices::{calculate_price_table, cross_check_prices};
+mod block_tracker;
+mod gas_prices;
+mod reference_prices;
+
+const PRICE_UPDATE_INTERVAL: u64 = 60;
+const CROSS_CHECK_INTERVAL: u64 = 60 * 60 * 24;
+const FLASH_LOAN_UPDATE_INTERVAL: u64 = 10;
 
 lazy_static! {
-    static ref MARKET_RESERVE_TABLE: RwLock<OrganizedList<Reserves>> =
-        RwLock::new(OrganizedList::new());
+    static ref MARKET_RESERVE_TABLE: RwLock<ReserveSnapshot> = RwLock::new(ReserveSnapshot::new());
     static ref REF_PRICE_TABLE: RwLock<PriceTable> = RwLock::new(PriceTable::new());
     static ref NEW_BLOCK_NUMBER: atomic::AtomicU64 = AtomicU64::new(0);
-    static ref FLASH_LOAN_FEE: RwLock<U256> = RwLock::new(U256::zero());
     static ref WALLET_BALANCE: RwLock<U256> = RwLock::new(U256::zero());
-    static ref GAS_PRICE: RwLock<U256> =
-        RwLock::new(U256::from(parse_units("0.1", "gwei").unwrap()));
+    static ref GAS_PRICES: RwLock<GasPrices> = RwLock::new(GasPrices::default());
 }
 
 pub struct PriceOracle {
@@ -42,6 +54,8 @@ pub struct PriceOracle {
     runtime_config: &'static RuntimeConfig,
     market_join_handle: Option<thread::JoinHandle<()>>,
     daily_join_handle: Option<thread::JoinHandle<()>>,
+    price_join_handle: Option<thread::JoinHandle<()>>,
+    flash_loan_join_handle: Option<thread::JoinHandle<()>>,
     block_join_handle: Option<thread::JoinHandle<()>>,
 }
 unsafe impl Send for PriceOracle {}
@@ -58,6 +72,8 @@ impl PriceOracle {
             runtime_config,
             market_join_handle: None,
             daily_join_handle: None,
+            price_join_handle: None,
+            flash_loan_join_handle: None,
             block_join_handle: None,
         };
 
@@ -68,18 +84,44 @@ impl PriceOracle {
     pub fn initiate(&mut self) {
         self.initiate_block_updates();
         self.initiate_daily_updates(Duration::from_secs(60 * 60 * 24));
+        self.initiate_price_updates(Duration::from_secs(PRICE_UPDATE_INTERVAL));
+        self.initiate_flash_loan_updates(Duration::from_secs(FLASH_LOAN_UPDATE_INTERVAL));
         // self.initiate_market_updates(Duration::from_millis(1000));
     }
     #[inline(always)]
     fn initiate_block_updates(&mut self) {
         let cache_reference = self.runtime_cache;
+        let config_reference = self.runtime_config;
+        let is_arbitrum = self.network.chain_id == ARBITRUM_CHAIN_ID;
+        let handle = Handle::current();
 
         self.block_join_handle = Some(thread::spawn(move || {
+            // a reserve reload after a reorg spawns its calls on the runtime
+            let _guard = handle.enter();
+
             if let Ok(mut subscription) = block_on(cache_reference.client.subscribe_blocks()) {
                 loop {
                     block_on(async {
                         if let Some(block) = subscription.next().await {
-                            NEW_BLOCK_NUMBER.store(block.number.unwrap().as_u64(), Ordering::SeqCst)
+                            block_tracker::handle_block(&block, cache_reference, config_reference)
+                                .await;
+                            NEW_BLOCK_NUMBER.store(block.number.unwrap().as_u64(), Ordering::SeqCst);
+
+                            let l1_prices = if is_arbitrum {
+                                get_l1_prices(&cache_reference.client).await
+                            } else {
+                                None
+                            };
+
+                            let mut w_refrence = GAS_PRICES.write().await;
+                            if let Some(base_fee) = block.base_fee_per_gas {
+                                w_refrence.l2_base_fee = base_fee;
+                            }
+
+                            if let Some((byte_price, base_fee)) = l1_prices {
+                                w_refrence.l1_byte_price = byte_price;
+                                w_refrence.l1_base_fee = base_fee;
+                            }
                         }
                     });
                 }
@@ -99,20 +141,20 @@ impl PriceOracle {
             handle.spawn(async move {
                 loop {
                     // // let inst = Instant::now();
-                    let mut reserve_table: crate::types::OrganizedList<(U256, U256)> =
-                        get_market_reserves(
+                    if let Ok(block_number) = cache_reference.client.get_block_number().await {
+                        let reserve_snapshot = get_market_reserves(
                             &cache_reference.markets,
+                            block_number,
                             &cache_reference,
                             &config_reference,
                         )
                         .await;
 
-                    {
-                        let mut w_refrence = MARKET_RESERVE_TABLE.write().await;
-                        w_refrence.update_all(&mut reserve_table);
-                    }
+                        {
+                            let mut w_refrence = MARKET_RESERVE_TABLE.write().await;
+                            *w_refrence = reserve_snapshot;
+                        }
 
-                    if let Ok(block_number) = cache_reference.client.get_block_number().await {
                         NEW_BLOCK_NUMBER.store(block_number.as_u64(), Ordering::SeqCst)
                     }
 
@@ -124,51 +166,46 @@ impl PriceOracle {
         }));
     }
 
-    fn initiate_daily_updates(&mut self, interval: Duration) {
+    // Reference prices follow the reserve table kept by market updates, the reserves are
+    // fetched here while market updates are disabled
+    fn initiate_price_updates(&mut self, interval: Duration) {
         let cache_reference = self.runtime_cache;
+        let config_reference = self.runtime_config;
         let network_reference = self.network;
 
         let handle = Handle::current();
         let mut run_interval = tokio::time::interval(interval);
 
-        self.daily_join_handle = Some(thread::spawn(move || {
+        self.price_join_handle = Some(thread::spawn(move || {
             handle.spawn(async move {
+                let mut last_cross_check: Option<Instant> = None;
+
                 loop {
-                    let json_response = {
-                        if let Ok(res) =
-                            ureq::get("http://api.coinbase.com/v2/exchange-rates?currency=ETH")
-                                .call()
-                        {
-                            res.into_string().unwrap()
-                        } else {
-                            get_base_price_table()
+                    let mut reserve_snapshot = MARKET_RESERVE_TABLE.read().await.clone();
+                    if reserve_snapshot.is_empty() {
+                        if let Ok(block_number) = cache_reference.client.get_block_number().await {
+                            reserve_snapshot = get_market_reserves(
+                                &cache_reference.markets,
+                                block_number,
+                                &cache_reference,
+                                &config_reference,
+                            )
+                            .await;
                         }
-                    };
-
-                    let s: Value = serde_json::from_str(json_response.as_str()).unwrap();
-                    let value_map = s.as_object().unwrap()["data"].as_object().unwrap()["rates"]
-                        .as_object()
-                        .unwrap();
-
-                    let base_tokens: Vec<&'static Token> = network_reference
-                        .tokens
-                        .iter()
-                        .filter(|x| x.ref_symbol.is_some())
-                        .collect();
-
-                    let weth_token = &network_reference.tokens[0];
+                    }
 
-                    let mut new_price_table = PriceTable::new();
-                    for token in base_tokens {
-                        let symbol: &String = token.ref_symbol.as_ref().unwrap();
-                        let token_ref_price =
-                            1f64 / value_map[symbol].as_str().unwrap().parse::<f64>().unwrap();
+                    let new_price_table = calculate_price_table(
+                        network_reference,
+                        &cache_reference.markets,
+                        &reserve_snapshot.reserves,
+                    );
 
-                        if let Ok(ParseUnits::U256(ref_price)) =
-                            parse_units(token_ref_price.to_string(), weth_token.decimals)
-                        {
-                            new_price_table.update_value(&token, ref_price);
-                        }
+                    if config_reference.price_cross_check
+                        && last_cross_check
+                            .map_or(true, |x| x.elapsed().as_secs() >= CROSS_CHECK_INTERVAL)
+                    {
+                        cross_check_prices(network_reference, &new_price_table);
+                        last_cross_check = Some(Instant::now());
                     }
 
                     {
@@ -176,15 +213,55 @@ impl PriceOracle {
                         *w_refrence = new_price_table;
                     }
 
-                    if let Ok(flash_loan_response) =
-                        cache_reference.bundle_executor.get_flash_loan_fees().await
-                    {
-                        {
-                            let mut w_refrence = FLASH_LOAN_FEE.write().await;
-                            *w_refrence = U256::from(flash_loan_response);
-                        }
+                    run_interval.tick().await;
+                }
+            });
+
+            let _guard = handle.enter();
+        }));
+    }
+
+    // The Aave pool is resolved from the addresses provider until it succeeds, routes pick their
+    // flash liquidity source from what's read here
+    fn initiate_flash_loan_updates(&mut self, interval: Duration) {
+        let cache_reference = self.runtime_cache;
+        let network_reference = self.network;
+
+        let handle = Handle::current();
+        let mut run_interval = tokio::time::interval(interval);
+
+        self.flash_loan_join_handle = Some(thread::spawn(move || {
+            handle.spawn(async move {
+                let mut pool_address: Option<Address> = None;
+
+                loop {
+                    if pool_address.is_none() {
+                        pool_address = aave::get_pool_address(
+                            network_reference,
+                            &cache_reference.client,
+                        )
+                        .await;
                     }
 
+                    update_sources(network_reference, pool_address, cache_reference).await;
+
+                    run_interval.tick().await;
+                }
+            });
+
+            let _guard = handle.enter();
+        }));
+    }
+
+    fn initiate_daily_updates(&mut self, interval: Duration) {
+        let cache_reference = self.runtime_cache;
+
+        let handle = Handle::current();
+        let mut run_interval = tokio::time::interval(interval);
+
+        self.daily_join_handle = Some(thread::spawn(move || {
+            handle.spawn(async move {
+                loop {
                     if let Ok(balance_response) = cache_reference
                         .client
                         .get_balance(cache_reference.client.address(), None)
@@ -205,10 +282,21 @@ impl PriceOracle {
     }
 
     #[inline(always)]
-    pub async fn get_market_reserves(&self) -> OrganizedList<Reserves> {
+    pub async fn get_market_reserves(&self) -> ReserveSnapshot {
         return MARKET_RESERVE_TABLE.read().await.clone();
     }
 
+    // `None` until market updates fill the reserve table
+    #[inline(always)]
+    pub async fn get_market_reserve(market_id: usize) -> Option<Reserves> {
+        return MARKET_RESERVE_TABLE.read().await.get_reserves(market_id);
+    }
+
+    #[inline(always)]
+    pub async fn get_ref_price_table(&self) -> PriceTable {
+        return REF_PRICE_TABLE.read().await.clone();
+    }
+
     #[inline(always)]
     pub async fn get_wallet_balance(&self) -> U256 {
         return WALLET_BALANCE.read().await.clone();
@@ -216,7 +304,7 @@ impl PriceOracle {
 
     #[inline(always)]
     pub async fn get_flash_loan_fee(&self) -> U256 {
-        return FLASH_LOAN_FEE.read().await.clone();
+        return aave::get_premium();
     }
 
     #[inline(always)]
@@ -224,8 +312,46 @@ impl PriceOracle {
         return U64::from(NEW_BLOCK_NUMBER.load(Ordering::SeqCst));
     }
 
+    // Balance changes of every new canonical block, once the reserve table follows them
+    #[inline(always)]
+    pub fn subscribe_block_updates() -> broadcast::Receiver<MarketUpdate> {
+        return block_tracker::subscribe();
+    }
+
+    // Starts maintaining the reserve table from new blocks
+    #[inline(always)]
+    pub async fn load_market_reserves(
+        runtime_cache: &'static RuntimeCache,
+        runtime_config: &'static RuntimeConfig,
+    ) {
+        block_tracker::load_reserves(runtime_cache, runtime_config).await;
+    }
+
+    // Head of the canonical chain, `None` before the first block
+    #[inline(always)]
+    pub fn get_block_header() -> Option<BlockHeader> {
+        return block_tracker::get_head();
+    }
+
+    // State read at an orphaned block is invalid
+    #[inline(always)]
+    pub fn is_canonical(header: &BlockHeader) -> bool {
+        return block_tracker::is_canonical(header);
+    }
+
     #[inline(always)]
     pub async fn get_gas_price(&self) -> U256 {
-        return GAS_PRICE.read().await.clone();
+        return GAS_PRICES.read().await.l2_base_fee;
+    }
+
+    #[inline(always)]
+    pub async fn get_gas_prices() -> GasPrices {
+        return *GAS_PRICES.read().await;
+    }
+
+    /// Estimated cost in wei of sending `calldata` with `gas_limit`, L1 data included
+    #[inline(always)]
+    pub async fn cost_of(calldata: &Bytes, gas_limit: U256) -> U256 {
+        return GAS_PRICES.read().await.cost_of(calldata, gas_limit);
     }
 }
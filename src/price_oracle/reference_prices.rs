@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use ethers::{
+    types::{H160, U256},
+    utils::{parse_units, ParseUnits},
+};
+use serde_json::Value;
+
+use crate::{
+    exchanges::calculate_amount_out,
+    networks::Network,
+    types::{market::Market, OrganizedList, PriceTable, Reserves, Token},
+};
+
+// fraction of the input side reserve quoted to read a pool's marginal price
+const PROBE_DIVISOR: u64 = 10_000;
+// least value held on the priced side of a pool for it to take part in pricing, in WETH
+const MIN_PRICING_LIQUIDITY: &str = "1.0";
+// samples further than this from the weighted median are dropped, in 1/10000
+const MAX_SAMPLE_DEVIATION: u64 = 500;
+// reference prices further than this from the external rate are reported, in 1/10000
+const MAX_EXTERNAL_DEVIATION: u64 = 300;
+const EXTERNAL_RATES_URL: &str = "http://api.coinbase.com/v2/exchange-rates?currency=ETH";
+
+// (price, weight) where the price is the anchor value of one whole token and the weight the
+// anchor value held on the priced side of the pool
+type PriceSample = (U256, U256);
+
+#[inline(always)]
+fn get_decimal_power(token: &Token) -> U256 {
+    return U256::exp10(token.decimals as usize);
+}
+
+#[inline(always)]
+fn get_weighted_median(samples: &mut Vec<PriceSample>) -> Option<U256> {
+    samples.sort_by_key(|x| x.0);
+
+    let total_weight = samples.iter().fold(U256::zero(), |sum, x| sum + x.1);
+    if total_weight.is_zero() {
+        return None;
+    }
+
+    let mut weight = U256::zero();
+    for (price, sample_weight) in samples.iter() {
+        weight += *sample_weight;
+        if weight + weight >= total_weight {
+            return Some(*price);
+        }
+    }
+
+    return None;
+}
+
+// Weighted median of the samples that agree with the first weighted median, with their total
+// weight. A single thin or manipulated pool can't move the price while deeper pools disagree with
+// it.
+#[inline(always)]
+fn aggregate_samples(mut samples: Vec<PriceSample>) -> Option<PriceSample> {
+    let median = get_weighted_median(&mut samples)?;
+    if median.is_zero() {
+        return None;
+    }
+
+    let mut accepted: Vec<PriceSample> = samples
+        .into_iter()
+        .filter(|(price, _)| {
+            let deviation = if *price > median {
+                *price - median
+            } else {
+                median - *price
+            };
+
+            deviation * 10000 / median <= U256::from(MAX_SAMPLE_DEVIATION)
+        })
+        .collect();
+
+    let weight = accepted.iter().fold(U256::zero(), |sum, x| sum + x.1);
+    return Some((get_weighted_median(&mut accepted)?, weight));
+}
+
+// Prices `token_out` from a quote of `token_in` against a pool holding `token_out` priced at
+// `price_out`, both reserves are in (in, out) order
+#[inline(always)]
+fn get_price_sample(
+    market: &Market,
+    reserves: &Reserves,
+    zero_for_one: bool,
+    token_in: &Token,
+    token_out: &Token,
+    price_out: U256,
+) -> Option<PriceSample> {
+    let input_amount = reserves.0 / PROBE_DIVISOR;
+    if input_amount.is_zero() {
+        return None;
+    }
+
+    let output_amount = calculate_amount_out(reserves, &input_amount, market, zero_for_one);
+    if output_amount.is_zero() {
+        return None;
+    }
+
+    let output_value = price_out.checked_mul(output_amount)? / get_decimal_power(token_out);
+    let price = output_value.checked_mul(get_decimal_power(token_in))? / input_amount;
+    let weight = price_out.checked_mul(reserves.1)? / get_decimal_power(token_out);
+
+    return Some((price, weight));
+}
+
+// Prices every token reachable from `anchor` through pools of the reserve table, level by level:
+// tokens paired with the anchor first, then tokens paired with those, so every price is taken
+// from the shortest path of liquid pools
+fn propagate_prices(
+    anchor: &Token,
+    min_liquidity: U256,
+    markets: &OrganizedList<Market>,
+    reserves: &HashMap<usize, Reserves>,
+) -> HashMap<H160, PriceSample> {
+    let mut prices: HashMap<H160, PriceSample> = HashMap::new();
+    prices.insert(
+        anchor.contract_address,
+        (get_decimal_power(anchor), U256::zero()),
+    );
+
+    loop {
+        let mut samples: HashMap<H160, Vec<PriceSample>> = HashMap::new();
+
+        for market in markets.iter() {
+            let market_reserves = match reserves.get(&market.id) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let [token_0, token_1] = market.value.tokens;
+            let price_0 = prices.get(&token_0.contract_address);
+            let price_1 = prices.get(&token_1.contract_address);
+
+            let sample = match (price_0, price_1) {
+                (Some(&(price_0, _)), None) => get_price_sample(
+                    &market.value,
+                    &(market_reserves.1, market_reserves.0),
+                    false,
+                    token_1,
+                    token_0,
+                    price_0,
+                )
+                .map(|x| (token_1, x)),
+                (None, Some(&(price_1, _))) => get_price_sample(
+                    &market.value,
+                    market_reserves,
+                    true,
+                    token_0,
+                    token_1,
+                    price_1,
+                )
+                .map(|x| (token_0, x)),
+                _ => None,
+            };
+
+            if let Some((token, sample)) = sample {
+                if sample.1 >= min_liquidity {
+                    samples
+                        .entry(token.contract_address)
+                        .or_insert_with(Vec::new)
+                        .push(sample);
+                }
+            }
+        }
+
+        let priced_count = prices.len();
+        for (token, token_samples) in samples {
+            if let Some(price) = aggregate_samples(token_samples) {
+                prices.insert(token, price);
+            }
+        }
+
+        if prices.len() == priced_count {
+            return prices;
+        }
+    }
+}
+
+/// Prices every token reachable from WETH or USDC through pools of the reserve table. Prices
+/// taken from USDC are converted at the WETH price of USDC, a token priced from both anchors
+/// takes the liquidity-weighted median of the two. Prices are WETH values of one whole token, in
+/// WETH decimals.
+pub fn calculate_price_table(
+    network: &'static Network,
+    markets: &OrganizedList<Market>,
+    reserve_table: &OrganizedList<Reserves>,
+) -> PriceTable {
+    let weth_token = match network.get_token(&network.weth_address) {
+        Some(value) => value,
+        None => return PriceTable::new(),
+    };
+    let min_liquidity = match parse_units(MIN_PRICING_LIQUIDITY, weth_token.decimals) {
+        Ok(ParseUnits::U256(value)) => value,
+        _ => get_decimal_power(weth_token),
+    };
+
+    let reserves: HashMap<usize, Reserves> =
+        reserve_table.iter().map(|x| (x.id, x.value)).collect();
+
+    let mut prices = propagate_prices(weth_token, min_liquidity, markets, &reserves);
+
+    let usdc_token = network.get_token(&network.usdc_address);
+    let usdc_price = usdc_token.and_then(|x| prices.get(&x.contract_address));
+
+    if let (Some(usdc_token), Some(&(usdc_price, _))) = (usdc_token, usdc_price) {
+        let usdc_power = get_decimal_power(usdc_token);
+        let to_weth = |value: U256| value.checked_mul(usdc_price).map(|x| x / usdc_power);
+
+        let usdc_min_liquidity = min_liquidity * usdc_power / usdc_price;
+        let usdc_prices = propagate_prices(usdc_token, usdc_min_liquidity, markets, &reserves);
+
+        for (token, (price, weight)) in usdc_prices {
+            // the anchors are priced exactly by the first pass
+            if token == weth_token.contract_address || token == usdc_token.contract_address {
+                continue;
+            }
+
+            let sample = match (to_weth(price), to_weth(weight)) {
+                (Some(price), Some(weight)) => (price, weight),
+                _ => continue,
+            };
+
+            let price = match prices.get(&token) {
+                Some(&weth_sample) => {
+                    let median = get_weighted_median(&mut vec![weth_sample, sample]);
+                    (median.unwrap_or(weth_sample.0), weth_sample.1 + sample.1)
+                }
+                None => sample,
+            };
+            prices.insert(token, price);
+        }
+    }
+
+    let mut price_table = PriceTable::new();
+    for token in &network.tokens {
+        if let Some((price, _)) = prices.get(&token.contract_address) {
+            price_table.update_value(token, *price);
+        }
+    }
+
+    return price_table;
+}
+
+/// Compares the reference prices of tokens with a `ref_symbol` against an external exchange rate
+/// feed, deviations are only reported
+pub fn cross_check_prices(network: &'static Network, price_table: &PriceTable) {
+    let rates = match ureq::get(EXTERNAL_RATES_URL)
+        .call()
+        .ok()
+        .and_then(|x| x.into_string().ok())
+        .and_then(|x| serde_json::from_str::<Value>(x.as_str()).ok())
+    {
+        Some(value) => value,
+        None => {
+            println!("External rates unavailable, skipping the price cross-check");
+            return;
+        }
+    };
+
+    let weth_token = match network.get_token(&network.weth_address) {
+        Some(value) => value,
+        None => return,
+    };
+
+    for token in &network.tokens {
+        let symbol = match &token.ref_symbol {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let external_price = rates["data"]["rates"][symbol]
+            .as_str()
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|x| *x > 0f64)
+            .and_then(|x| parse_units((1f64 / x).to_string(), weth_token.decimals).ok());
+
+        let external_price = match external_price {
+            Some(ParseUnits::U256(value)) if !value.is_zero() => value,
+            _ => continue,
+        };
+
+        if !price_table.contains_key(&token.contract_address) {
+            println!("No reference price for {:?} ({})", token.contract_address, symbol);
+            continue;
+        }
+
+        let price = *price_table.get_value(&token.contract_address);
+        let deviation = if price > external_price {
+            price - external_price
+        } else {
+            external_price - price
+        };
+
+        if deviation * 10000 / external_price > U256::from(MAX_EXTERNAL_DEVIATION) {
+            println!(
+                "Reference price of {:?} deviates from the external rate: {} / {}",
+                token.contract_address, price, external_price
+            );
+        }
+    }
+}
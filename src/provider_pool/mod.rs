@@ -0,0 +1,453 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use ethers::{
+    providers::{Http, JsonRpcClient, PubsubClient, RpcError, Ws},
+    types::{U256, U64},
+};
+use futures::{
+    future::join_all,
+    stream::{self, FuturesUnordered, Stream, StreamExt},
+};
+use itertools::Itertools;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{value::RawValue, Value};
+use url::Url;
+
+use self::types::{PoolEndpoint, Subscription, Transport};
+
+mod types;
+
+#[cfg(test)]
+mod tests;
+
+pub use types::{EndpointHealth, MethodRoute, PoolError};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+// blocks an endpoint may trail the highest head of the pool
+const MAX_HEAD_LAG: u64 = 8;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A JSON-RPC transport over several HTTP and websocket endpoints. Reads go to the fastest
+/// healthy endpoint and fail over to the next one, debug traces to the endpoints serving them
+/// and transactions to every endpoint. Subscriptions are made on the fastest websocket endpoint
+/// and made again on another one when it drops them, or on the same one once it reconnects.
+#[derive(Debug, Clone)]
+pub struct ProviderPool {
+    endpoints: Arc<Vec<PoolEndpoint>>,
+    // by the id they were first made with, the one the provider keeps using
+    subscriptions: Arc<Mutex<HashMap<U256, Subscription>>>,
+}
+
+// Keeps credentials in the path of the url out of the console
+#[inline(always)]
+fn get_endpoint_name(url: &Url) -> String {
+    return url.origin().ascii_serialization();
+}
+
+// Error responses come from a working node, only methods it doesn't serve and rate limits are
+// worth another endpoint
+#[inline(always)]
+fn is_endpoint_error(error: &PoolError) -> bool {
+    return match error.as_error_response() {
+        Some(response) => {
+            response.code == -32601 || response.code == -32005 || response.code == 429
+        }
+        None => true,
+    };
+}
+
+fn record_failure(endpoint: &PoolEndpoint) {
+    let mut health = endpoint.health.write().unwrap();
+    health.failures += 1;
+
+    // until the next health check
+    if health.healthy {
+        health.healthy = false;
+        println!("RPC endpoint {} failing", get_endpoint_name(&endpoint.url));
+    }
+}
+
+async fn get_head(transport: &Transport) -> Option<(u64, Duration)> {
+    let params = Value::Array(vec![]);
+    let start = Instant::now();
+    let request = transport.request("eth_blockNumber", &params);
+
+    return match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, request).await {
+        Ok(Ok(value)) => serde_json::from_value::<U64>(value)
+            .ok()
+            .map(|x| (x.as_u64(), start.elapsed())),
+        _ => None,
+    };
+}
+
+// A websocket stays closed once dropped, the endpoint failing a check is connected again and
+// checked on the new connection. Subscriptions on the old one are made again by `resubscribe`.
+async fn check_endpoint(endpoint: &PoolEndpoint) -> Option<(u64, Duration)> {
+    let transport = endpoint.get_transport();
+    let result = get_head(&transport).await;
+
+    if result.is_some() || matches!(transport, Transport::Http(_)) {
+        return result;
+    }
+
+    let connect = Ws::connect(endpoint.url.as_str());
+    let client = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, connect).await {
+        Ok(Ok(client)) => client,
+        _ => return None,
+    };
+
+    let transport = Transport::Ws(client);
+    let result = get_head(&transport).await;
+    if result.is_some() {
+        *endpoint.transport.write().unwrap() = transport;
+        println!(
+            "RPC endpoint {} reconnected",
+            get_endpoint_name(&endpoint.url)
+        );
+    }
+
+    return result;
+}
+
+impl ProviderPool {
+    /// Connects to `endpoints` and `debug_endpoints`, only the latter serve debug traces unless
+    /// there are none. Endpoints that can't be reached are left out, at least one websocket
+    /// endpoint has to connect. Starts the health checks ranking the endpoints.
+    pub async fn connect(
+        endpoints: &[Url],
+        debug_endpoints: &[Url],
+    ) -> Result<ProviderPool, PoolError> {
+        let mut pool_endpoints: Vec<PoolEndpoint> = vec![];
+
+        for url in endpoints.iter().chain(debug_endpoints).unique() {
+            let transport = match url.scheme() {
+                "ws" | "wss" => match Ws::connect(url.as_str()).await {
+                    Ok(client) => Transport::Ws(client),
+                    Err(err) => {
+                        println!("RPC endpoint {} skipped: {}", get_endpoint_name(url), err);
+                        continue;
+                    }
+                },
+                _ => Transport::Http(Http::new(url.clone())),
+            };
+
+            pool_endpoints.push(PoolEndpoint {
+                url: url.clone(),
+                debug: debug_endpoints.is_empty() || debug_endpoints.contains(url),
+                transport: RwLock::new(transport),
+                health: RwLock::new(EndpointHealth::default()),
+            });
+        }
+
+        if !pool_endpoints
+            .iter()
+            .any(|x| matches!(x.get_transport(), Transport::Ws(_)))
+        {
+            return Err(PoolError::NoEndpoint("eth_subscribe".to_string()));
+        }
+
+        return Ok(ProviderPool::from_endpoints(pool_endpoints).await);
+    }
+
+    // A pool without subscriptions, for the tests of modules reading through the client
+    #[cfg(test)]
+    pub async fn connect_http(endpoints: &[Url]) -> ProviderPool {
+        let pool_endpoints = endpoints
+            .iter()
+            .map(|url| PoolEndpoint {
+                url: url.clone(),
+                debug: true,
+                transport: RwLock::new(Transport::Http(Http::new(url.clone()))),
+                health: RwLock::new(EndpointHealth::default()),
+            })
+            .collect();
+
+        return ProviderPool::from_endpoints(pool_endpoints).await;
+    }
+
+    async fn from_endpoints(pool_endpoints: Vec<PoolEndpoint>) -> ProviderPool {
+        let pool = ProviderPool {
+            endpoints: Arc::new(pool_endpoints),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        pool.check_health().await;
+        pool.initiate_health_checks();
+
+        return pool;
+    }
+
+    /// Polls the head of every endpoint, those trailing the highest head by more than
+    /// `MAX_HEAD_LAG` blocks or not answering are unhealthy until the next check
+    pub async fn check_health(&self) {
+        let results = join_all(self.endpoints.iter().map(check_endpoint)).await;
+        let best_head = results.iter().flatten().map(|x| x.0).max().unwrap_or(0);
+
+        for (endpoint, result) in self.endpoints.iter().zip(results) {
+            let mut health = endpoint.health.write().unwrap();
+            let was_healthy = health.healthy;
+
+            health.checks += 1;
+            match result {
+                Some((head, latency)) => {
+                    health.head = head;
+                    health.head_lag = best_head - head;
+                    health.latency = if health.latency.is_zero() {
+                        latency
+                    } else {
+                        (health.latency * 3 + latency) / 4
+                    };
+                    health.healthy = health.head_lag <= MAX_HEAD_LAG;
+                }
+                None => {
+                    health.failures += 1;
+                    health.healthy = false;
+                }
+            }
+
+            if health.checks == 1 || was_healthy == health.healthy {
+                continue;
+            }
+
+            let name = get_endpoint_name(&endpoint.url);
+            match (health.healthy, result) {
+                (true, _) => println!("RPC endpoint {} recovered", name),
+                (false, Some(_)) => {
+                    println!("RPC endpoint {} {} blocks behind", name, health.head_lag)
+                }
+                (false, None) => println!("RPC endpoint {} unreachable", name),
+            }
+        }
+    }
+
+    fn initiate_health_checks(&self) {
+        let pool = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+                pool.check_health().await;
+            }
+        });
+    }
+
+    pub fn get_health(&self) -> Vec<(String, EndpointHealth)> {
+        return self
+            .endpoints
+            .iter()
+            .map(|x| (get_endpoint_name(&x.url), x.health.read().unwrap().clone()))
+            .collect();
+    }
+
+    // Healthy endpoints by latency, the others stay as a last resort
+    fn get_ranked_endpoints(&self, route: MethodRoute) -> Vec<&PoolEndpoint> {
+        return self
+            .endpoints
+            .iter()
+            .filter(|x| route != MethodRoute::Debug || x.debug)
+            .sorted_by_cached_key(|x| {
+                let health = x.health.read().unwrap();
+                (!health.healthy, health.latency)
+            })
+            .collect();
+    }
+
+    async fn request_with_failover(
+        &self,
+        method: &str,
+        route: MethodRoute,
+        params: &Value,
+    ) -> Result<Value, PoolError> {
+        let mut last_error = PoolError::NoEndpoint(method.to_string());
+
+        for endpoint in self.get_ranked_endpoints(route) {
+            let transport = endpoint.get_transport();
+            let request = transport.request(method, params);
+            let result = match tokio::time::timeout(REQUEST_TIMEOUT, request).await {
+                Ok(value) => value,
+                Err(_) => Err(PoolError::Timeout(REQUEST_TIMEOUT)),
+            };
+
+            match result {
+                Err(err) if is_endpoint_error(&err) => {
+                    if err.as_error_response().is_none() {
+                        record_failure(endpoint);
+                    }
+                    last_error = err;
+                }
+                _ => return result,
+            }
+        }
+
+        return Err(last_error);
+    }
+
+    // Makes the subscription on the first websocket endpoint accepting it
+    async fn subscribe_on_endpoints(&self, params: &Value) -> Result<(Ws, U256), PoolError> {
+        let mut last_error = PoolError::NoEndpoint("eth_subscribe".to_string());
+
+        for endpoint in self.get_ranked_endpoints(MethodRoute::Subscription) {
+            let client = match endpoint.get_transport() {
+                Transport::Ws(client) => client,
+                Transport::Http(_) => continue,
+            };
+
+            let result = client
+                .request::<_, U256>("eth_subscribe", params)
+                .await
+                .map_err(PoolError::Ws);
+
+            match result {
+                Ok(id) => return Ok((client, id)),
+                Err(err) => {
+                    if err.as_error_response().is_none() {
+                        record_failure(endpoint);
+                    }
+                    last_error = err;
+                }
+            }
+        }
+
+        return Err(last_error);
+    }
+
+    // Notifications of a subscription whose endpoint dropped it, from the endpoint it's made
+    // again on. Waits for the health checks to reconnect an endpoint when none accepts it. `None`
+    // once it's unsubscribed.
+    async fn resubscribe(&self, id: U256) -> Option<<Ws as PubsubClient>::NotificationStream> {
+        loop {
+            let params = self.subscriptions.lock().unwrap().get(&id)?.params.clone();
+
+            if let Ok((client, client_id)) = self.subscribe_on_endpoints(&params).await {
+                if let Ok(notifications) = client.subscribe(client_id) {
+                    let mut subscriptions = self.subscriptions.lock().unwrap();
+                    let subscription = subscriptions.get_mut(&id)?;
+                    subscription.client = client;
+                    subscription.id = client_id;
+
+                    println!("Subscription {} made again", id);
+                    return Some(notifications);
+                }
+            }
+
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        }
+    }
+
+    async fn request_subscription(&self, method: &str, params: Value) -> Result<Value, PoolError> {
+        if method == "eth_subscribe" {
+            let (client, id) = self.subscribe_on_endpoints(&params).await?;
+            self.subscriptions
+                .lock()
+                .unwrap()
+                .insert(id, Subscription { params, client, id });
+
+            return serde_json::to_value(id).map_err(PoolError::Serde);
+        }
+
+        // the provider unsubscribes with the first id
+        let id: U256 = serde_json::from_value(params[0].clone()).map_err(PoolError::Serde)?;
+        let (client, client_id) = match self.subscriptions.lock().unwrap().get(&id) {
+            Some(value) => (value.client.clone(), value.id),
+            None => return Err(PoolError::NoEndpoint(method.to_string())),
+        };
+
+        return client
+            .request(method, [client_id])
+            .await
+            .map_err(PoolError::Ws);
+    }
+
+    // Answers with the first endpoint accepting the transaction, the other sends aren't cancelled
+    async fn broadcast(&self, method: &str, params: Value) -> Result<Value, PoolError> {
+        let mut requests: FuturesUnordered<_> = self
+            .get_ranked_endpoints(MethodRoute::Broadcast)
+            .into_iter()
+            .map(|endpoint| {
+                let transport = endpoint.get_transport();
+                let method = method.to_string();
+                let params = params.clone();
+
+                tokio::spawn(async move { transport.request(&method, &params).await })
+            })
+            .collect();
+
+        let mut last_error = PoolError::NoEndpoint(method.to_string());
+        while let Some(result) = requests.next().await {
+            match result {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(err)) => last_error = err,
+                Err(_) => {}
+            }
+        }
+
+        return Err(last_error);
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for ProviderPool {
+    type Error = PoolError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, PoolError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params).map_err(PoolError::Serde)?;
+        let route = MethodRoute::from_method(method);
+
+        let response = match route {
+            MethodRoute::Subscription => self.request_subscription(method, params).await,
+            MethodRoute::Broadcast => self.broadcast(method, params).await,
+            MethodRoute::Debug | MethodRoute::Read => {
+                self.request_with_failover(method, route, &params).await
+            }
+        };
+
+        return serde_json::from_value(response?).map_err(PoolError::Serde);
+    }
+}
+
+impl PubsubClient for ProviderPool {
+    type NotificationStream = Pin<Box<dyn Stream<Item = Box<RawValue>> + Send>>;
+
+    fn subscribe<T: Into<U256>>(&self, id: T) -> Result<Self::NotificationStream, PoolError> {
+        let id = id.into();
+        let notifications = match self.subscriptions.lock().unwrap().get(&id) {
+            Some(value) => value.client.subscribe(value.id).map_err(PoolError::Ws)?,
+            None => return Err(PoolError::NoEndpoint("eth_subscribe".to_string())),
+        };
+
+        // the stream of a dropped subscription ends
+        let stream = stream::unfold(
+            (self.clone(), notifications),
+            move |(pool, mut notifications)| async move {
+                loop {
+                    if let Some(notification) = notifications.next().await {
+                        return Some((notification, (pool, notifications)));
+                    }
+
+                    notifications = pool.resubscribe(id).await?;
+                }
+            },
+        );
+
+        return Ok(Box::pin(stream));
+    }
+
+    fn unsubscribe<T: Into<U256>>(&self, id: T) -> Result<(), PoolError> {
+        return match self.subscriptions.lock().unwrap().remove(&id.into()) {
+            Some(value) => value.client.unsubscribe(value.id).map_err(PoolError::Ws),
+            None => Ok(()),
+        };
+    }
+}
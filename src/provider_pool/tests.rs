@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+
+use ethers::providers::{Middleware, Provider};
+use futures::SinkExt;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::*;
+
+const HEAD: u64 = 100;
+const REVERT: (i64, &str) = (3, "execution reverted");
+const RATE_LIMIT: (i64, &str) = (429, "too many requests");
+
+// A node answering every method after `delay`, methods without a response are unknown to it
+#[derive(Clone, Default)]
+struct MockNode {
+    delay: Duration,
+    results: HashMap<&'static str, Value>,
+    errors: HashMap<&'static str, (i64, &'static str)>,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockNode {
+    fn new(delay: u64) -> MockNode {
+        return MockNode {
+            delay: Duration::from_millis(delay),
+            ..Default::default()
+        }
+        .with_result("eth_blockNumber", json!(format!("{:#x}", HEAD)));
+    }
+
+    fn with_result(mut self, method: &'static str, result: Value) -> MockNode {
+        self.results.insert(method, result);
+        return self;
+    }
+
+    fn with_error(mut self, method: &'static str, error: (i64, &'static str)) -> MockNode {
+        self.errors.insert(method, error);
+        return self;
+    }
+
+    // calls other than the health checks
+    fn get_calls(&self) -> Vec<String> {
+        return self
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| *x != "eth_blockNumber")
+            .cloned()
+            .collect();
+    }
+
+    async fn answer(&self, request: &Value) -> Value {
+        let method = request["method"].as_str().unwrap();
+        self.calls.lock().unwrap().push(method.to_string());
+        tokio::time::sleep(self.delay).await;
+
+        if let Some(result) = self.results.get(method) {
+            return json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+        }
+
+        let (code, message) = self
+            .errors
+            .get(method)
+            .copied()
+            .unwrap_or((-32601, "method not found"));
+
+        return json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": {"code": code, "message": message},
+        });
+    }
+}
+
+async fn serve_http_connection(node: MockNode, stream: TcpStream) {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+
+            if line == "\r\n" {
+                break;
+            }
+
+            if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).await.unwrap();
+        let response = node.answer(&serde_json::from_slice(&body).unwrap()).await;
+
+        let response = response.to_string();
+        let message = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            response.len(),
+            response
+        );
+        reader
+            .get_mut()
+            .write_all(message.as_bytes())
+            .await
+            .unwrap();
+    }
+}
+
+async fn serve_http(node: &MockNode) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let node = node.clone();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_http_connection(node.clone(), stream));
+        }
+    });
+
+    return Url::parse(&url).unwrap();
+}
+
+async fn serve_ws(node: &MockNode, notification: &'static str) -> (Url, JoinHandle<()>) {
+    return serve_ws_at(node, notification, "127.0.0.1:0").await;
+}
+
+// Serves one connection at a time, subscriptions are sent `notification` right away. Aborting
+// the task closes the connection and the listener.
+async fn serve_ws_at(
+    node: &MockNode,
+    notification: &'static str,
+    address: &str,
+) -> (Url, JoinHandle<()>) {
+    let listener = TcpListener::bind(address).await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let node = node.clone();
+
+    let handle = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut socket = match tokio_tungstenite::accept_async(stream).await {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            while let Some(Ok(message)) = socket.next().await {
+                let request: Value = match message {
+                    Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                    _ => continue,
+                };
+
+                let response = node.answer(&request).await;
+                _ = socket.send(Message::Text(response.to_string())).await;
+
+                if request["method"] == "eth_subscribe" {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "eth_subscription",
+                        "params": {"subscription": response["result"], "result": notification},
+                    });
+                    _ = socket.send(Message::Text(notification.to_string())).await;
+                }
+            }
+        }
+    });
+
+    return (Url::parse(&url).unwrap(), handle);
+}
+
+async fn get_pool(nodes: &[&MockNode], debug_nodes: &[&MockNode]) -> ProviderPool {
+    let mut pool_endpoints = vec![];
+
+    for node in nodes.iter().chain(debug_nodes) {
+        let url = serve_http(node).await;
+        pool_endpoints.push(PoolEndpoint {
+            debug: debug_nodes.is_empty() || debug_nodes.iter().any(|x| std::ptr::eq(*x, *node)),
+            transport: RwLock::new(Transport::Http(Http::new(url.clone()))),
+            url,
+            health: RwLock::new(EndpointHealth::default()),
+        });
+    }
+
+    return ProviderPool::from_endpoints(pool_endpoints).await;
+}
+
+async fn request(pool: &ProviderPool, method: &str) -> Result<Value, PoolError> {
+    return pool.request(method, Value::Array(vec![])).await;
+}
+
+#[tokio::test]
+async fn reads_fail_over_on_endpoint_errors_only() {
+    let result = json!("0x1");
+    let fast = MockNode::new(0)
+        .with_error("eth_getBalance", RATE_LIMIT)
+        .with_error("eth_call", REVERT);
+    let slow = MockNode::new(50)
+        .with_result("eth_getBalance", result.clone())
+        .with_result("eth_call", result.clone());
+
+    let pool = get_pool(&[&fast, &slow], &[]).await;
+
+    // rate limits and unknown methods are worth another endpoint
+    assert_eq!(request(&pool, "eth_getBalance").await.unwrap(), result);
+    assert_eq!(fast.get_calls(), vec!["eth_getBalance"]);
+    assert_eq!(slow.get_calls(), vec!["eth_getBalance"]);
+
+    // a revert is the answer of a working node
+    let error = request(&pool, "eth_call").await.unwrap_err();
+    assert_eq!(error.as_error_response().unwrap().code, REVERT.0);
+    assert_eq!(fast.get_calls(), vec!["eth_getBalance", "eth_call"]);
+    assert_eq!(slow.get_calls(), vec!["eth_getBalance"]);
+
+    let error = request(&pool, "eth_chainId").await.unwrap_err();
+    assert_eq!(error.as_error_response().unwrap().code, -32601);
+    assert_eq!(slow.get_calls().last().unwrap(), "eth_chainId");
+}
+
+#[tokio::test]
+async fn unreachable_endpoints_are_ranked_last() {
+    let result = json!("0xa4b1");
+    let node = MockNode::new(0).with_result("eth_chainId", result.clone());
+    let mut pool_endpoints = vec![];
+
+    // nothing listens on the port once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+    drop(listener);
+
+    for url in [url, serve_http(&node).await] {
+        pool_endpoints.push(PoolEndpoint {
+            debug: true,
+            transport: RwLock::new(Transport::Http(Http::new(url.clone()))),
+            url,
+            health: RwLock::new(EndpointHealth::default()),
+        });
+    }
+
+    let pool = ProviderPool::from_endpoints(pool_endpoints).await;
+    let health = pool.get_health();
+    assert!(!health[0].1.healthy && health[1].1.healthy);
+
+    assert_eq!(request(&pool, "eth_chainId").await.unwrap(), result);
+}
+
+#[tokio::test]
+async fn debug_methods_go_to_the_debug_endpoints() {
+    let trace = json!({"calls": []});
+    let fast = MockNode::new(0).with_result("eth_chainId", json!("0xa4b1"));
+    let debug = MockNode::new(50)
+        .with_result("eth_chainId", json!("0xa4b1"))
+        .with_result("debug_traceCall", trace.clone());
+
+    let pool = get_pool(&[&fast], &[&debug]).await;
+
+    assert_eq!(request(&pool, "debug_traceCall").await.unwrap(), trace);
+    assert_eq!(
+        request(&pool, "eth_chainId").await.unwrap(),
+        json!("0xa4b1")
+    );
+
+    assert_eq!(fast.get_calls(), vec!["eth_chainId"]);
+    assert_eq!(debug.get_calls(), vec!["debug_traceCall"]);
+}
+
+#[tokio::test]
+async fn transactions_are_sent_to_every_endpoint() {
+    let hash = json!(format!("{:#066x}", 1));
+    let rejecting =
+        MockNode::new(0).with_error("eth_sendRawTransaction", (-32000, "nonce too low"));
+    let accepting = MockNode::new(50).with_result("eth_sendRawTransaction", hash.clone());
+    let debug = MockNode::new(0).with_result("eth_sendRawTransaction", hash.clone());
+
+    let pool = get_pool(&[&rejecting, &accepting], &[&debug]).await;
+
+    // debug endpoints take transactions as well
+    assert_eq!(
+        request(&pool, "eth_sendRawTransaction").await.unwrap(),
+        hash
+    );
+
+    for node in [&rejecting, &accepting, &debug] {
+        for _ in 0..50 {
+            if node.get_calls().len() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(node.get_calls(), vec!["eth_sendRawTransaction"]);
+    }
+
+    // rejected everywhere, the error of one of them is returned
+    let rejecting =
+        MockNode::new(0).with_error("eth_sendRawTransaction", (-32000, "nonce too low"));
+    let pool = get_pool(&[&rejecting], &[]).await;
+
+    let error = request(&pool, "eth_sendRawTransaction").await.unwrap_err();
+    assert_eq!(error.as_error_response().unwrap().message, "nonce too low");
+}
+
+#[tokio::test]
+async fn subscriptions_move_to_another_websocket_endpoint() {
+    let first = MockNode::new(0).with_result("eth_subscribe", json!("0x1"));
+    let second = MockNode::new(50)
+        .with_result("eth_subscribe", json!("0x2"))
+        .with_result("eth_unsubscribe", json!(true));
+
+    let (first_url, first_handle) = serve_ws(&first, "0xa").await;
+    let (second_url, _) = serve_ws(&second, "0xb").await;
+
+    let pool = ProviderPool::connect(&[first_url, second_url], &[])
+        .await
+        .unwrap();
+    let provider = Provider::new(pool);
+    let mut subscription = provider.subscribe::<_, String>(["newHeads"]).await.unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let next = tokio::time::timeout(timeout, subscription.next()).await;
+    assert_eq!(next.unwrap().unwrap(), "0xa");
+    assert_eq!(second.get_calls(), Vec::<String>::new());
+
+    first_handle.abort();
+
+    let next = tokio::time::timeout(timeout, subscription.next()).await;
+    assert_eq!(next.unwrap().unwrap(), "0xb");
+    assert_eq!(second.get_calls(), vec!["eth_subscribe"]);
+
+    // unsubscribing reaches the endpoint holding the subscription
+    assert!(provider.unsubscribe(subscription.id).await.unwrap());
+    assert_eq!(second.get_calls(), vec!["eth_subscribe", "eth_unsubscribe"]);
+}
+
+#[tokio::test]
+async fn dropped_websocket_endpoints_are_reconnected() {
+    let node = MockNode::new(0).with_result("eth_subscribe", json!("0x1"));
+    let (url, handle) = serve_ws(&node, "0xa").await;
+
+    let pool = ProviderPool::connect(&[url.clone()], &[]).await.unwrap();
+    let provider = Provider::new(pool.clone());
+    let mut subscription = provider.subscribe::<_, String>(["newHeads"]).await.unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let next = tokio::time::timeout(timeout, subscription.next()).await;
+    assert_eq!(next.unwrap().unwrap(), "0xa");
+
+    // the only endpoint goes down long enough for the client's own reconnects to run out, and
+    // comes back on the same address
+    handle.abort();
+    _ = handle.await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let address = format!("{}:{}", url.host_str().unwrap(), url.port().unwrap());
+    serve_ws_at(&node, "0xb", &address).await;
+
+    let next = tokio::time::timeout(timeout, subscription.next()).await;
+    assert_eq!(next.unwrap().unwrap(), "0xb");
+    assert_eq!(
+        request(&pool, "eth_blockNumber").await.unwrap(),
+        json!(format!("{:#x}", HEAD))
+    );
+    assert_eq!(node.get_calls(), vec!["eth_subscribe", "eth_subscribe"]);
+}
@@ -0,0 +1,140 @@
+use std::{fmt, sync::RwLock, time::Duration};
+
+use ethers::{
+    providers::{
+        Http, HttpClientError, JsonRpcClient, JsonRpcError, ProviderError, RpcError, Ws,
+        WsClientError,
+    },
+    types::U256,
+};
+use serde_json::Value;
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Http(Http),
+    Ws(Ws),
+}
+
+impl Transport {
+    pub async fn request(&self, method: &str, params: &Value) -> Result<Value, PoolError> {
+        return match self {
+            Transport::Http(client) => client
+                .request(method, params)
+                .await
+                .map_err(PoolError::Http),
+            Transport::Ws(client) => client.request(method, params).await.map_err(PoolError::Ws),
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    Http(HttpClientError),
+    Ws(WsClientError),
+    Serde(serde_json::Error),
+    Timeout(Duration),
+    NoEndpoint(String),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            PoolError::Http(err) => write!(f, "{}", err),
+            PoolError::Ws(err) => write!(f, "{}", err),
+            PoolError::Serde(err) => write!(f, "{}", err),
+            PoolError::Timeout(timeout) => write!(f, "request timed out after {:?}", timeout),
+            PoolError::NoEndpoint(method) => write!(f, "no endpoint serves {}", method),
+        };
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+impl RpcError for PoolError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        return match self {
+            PoolError::Http(err) => err.as_error_response(),
+            PoolError::Ws(err) => err.as_error_response(),
+            _ => None,
+        };
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        return match self {
+            PoolError::Http(err) => err.as_serde_error(),
+            PoolError::Ws(err) => err.as_serde_error(),
+            PoolError::Serde(err) => Some(err),
+            _ => None,
+        };
+    }
+}
+
+impl From<PoolError> for ProviderError {
+    fn from(value: PoolError) -> Self {
+        return ProviderError::JsonRpcClientError(Box::new(value));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodRoute {
+    // debug and trace namespaces, only some nodes serve them
+    Debug,
+    // transactions, sent to every endpoint
+    Broadcast,
+    // bound to the connection the subscription was made on
+    Subscription,
+    Read,
+}
+
+impl MethodRoute {
+    pub fn from_method(method: &str) -> MethodRoute {
+        if method.starts_with("debug_") || method.starts_with("trace_") {
+            return MethodRoute::Debug;
+        }
+
+        return match method {
+            "eth_sendRawTransaction" | "eth_sendRawTransactionConditional" => {
+                MethodRoute::Broadcast
+            }
+            "eth_subscribe" | "eth_unsubscribe" => MethodRoute::Subscription,
+            _ => MethodRoute::Read,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EndpointHealth {
+    pub healthy: bool,
+    pub head: u64,
+    pub head_lag: u64,
+    // moving average of the health check round trips
+    pub latency: Duration,
+    pub checks: u64,
+    // failed health checks and requests failing on the endpoint itself
+    pub failures: u64,
+}
+
+// Where a subscription currently lives, `id` is the one `client` knows it by
+#[derive(Debug)]
+pub struct Subscription {
+    pub params: Value,
+    pub client: Ws,
+    pub id: U256,
+}
+
+#[derive(Debug)]
+pub struct PoolEndpoint {
+    pub url: Url,
+    pub debug: bool,
+    // replaced when a dropped websocket connects again
+    pub transport: RwLock<Transport>,
+    pub health: RwLock<EndpointHealth>,
+}
+
+impl PoolEndpoint {
+    #[inline(always)]
+    pub fn get_transport(&self) -> Transport {
+        return self.transport.read().unwrap().clone();
+    }
+}
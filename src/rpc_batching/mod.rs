@@ -0,0 +1,366 @@
+use std::{
+    cmp::{max, min},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use itertools::Itertools;
+
+// attempts of a chunk, splits after gas or size errors aren't counted
+const MAX_ATTEMPTS: usize = 4;
+const MAX_IN_FLIGHT: usize = 8;
+// chunks grow up to this multiple of their initial size
+const MAX_GROWTH: usize = 16;
+const TARGET_LATENCY: Duration = Duration::from_millis(1500);
+// below the frame and response limits of common providers
+const MAX_RESPONSE_BYTES: usize = 1 << 20;
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CallFailure {
+    GasLimit,
+    ResponseSize,
+    RateLimit,
+    // the node answered from a block behind the requested one
+    Lagging,
+    Other,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallMetrics {
+    pub chunk_size: usize,
+    pub calls: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub splits: u64,
+    pub rate_limits: u64,
+    pub items: u64,
+    pub response_bytes: u64,
+    pub total_latency: Duration,
+}
+
+// What earlier calls of a method observed
+struct MethodState {
+    max_chunk_size: usize,
+    backoff: Duration,
+    backoff_until: Option<Instant>,
+    metrics: CallMetrics,
+}
+
+lazy_static! {
+    static ref METHOD_STATES: RwLock<HashMap<&'static str, MethodState>> =
+        RwLock::new(HashMap::new());
+}
+
+// Providers word these differently, the messages are matched loosely
+#[inline(always)]
+fn classify_failure(message: &str) -> CallFailure {
+    let message = message.to_lowercase();
+
+    if message.contains("out of gas")
+        || message.contains("gas required exceeds")
+        || message.contains("gas limit")
+    {
+        return CallFailure::GasLimit;
+    }
+
+    // log queries over too many blocks or with too many results are refused the same way
+    if message.contains("response size")
+        || message.contains("too large")
+        || message.contains("too big")
+        || message.contains("block range")
+        || message.contains("more than")
+        || message.contains("limited to")
+    {
+        return CallFailure::ResponseSize;
+    }
+
+    if message.contains("429")
+        || message.contains("rate limit")
+        || message.contains("too many requests")
+        || message.contains("limit exceeded")
+        || message.contains("-32005")
+    {
+        return CallFailure::RateLimit;
+    }
+
+    if message.contains("state of block")
+        || message.contains("header not found")
+        || message.contains("unknown block")
+    {
+        return CallFailure::Lagging;
+    }
+
+    return CallFailure::Other;
+}
+
+#[inline(always)]
+fn get_chunk_size(method: &'static str, initial_chunk_size: usize) -> usize {
+    let mut states = METHOD_STATES.write().unwrap();
+    let state = states.entry(method).or_insert_with(|| {
+        let initial_chunk_size = max(initial_chunk_size, 1);
+
+        MethodState {
+            max_chunk_size: initial_chunk_size * MAX_GROWTH,
+            backoff: Duration::ZERO,
+            backoff_until: None,
+            metrics: CallMetrics {
+                chunk_size: initial_chunk_size,
+                ..Default::default()
+            },
+        }
+    });
+
+    return state.metrics.chunk_size;
+}
+
+// Time left until a rate-limited or lagging method may be called again
+#[inline(always)]
+fn get_backoff(method: &'static str) -> Option<Duration> {
+    let states = METHOD_STATES.read().unwrap();
+    let backoff_until = states.get(method)?.backoff_until?;
+
+    return backoff_until.checked_duration_since(Instant::now());
+}
+
+// Slow calls shrink the next chunks, fast full chunks grow them while the responses stay below
+// the size limit
+fn record_success(
+    method: &'static str,
+    item_count: usize,
+    response_bytes: usize,
+    latency: Duration,
+) {
+    let mut states = METHOD_STATES.write().unwrap();
+    let state = match states.get_mut(method) {
+        Some(value) => value,
+        None => return,
+    };
+
+    state.backoff = Duration::ZERO;
+    state.backoff_until = None;
+
+    let metrics = &mut state.metrics;
+    metrics.calls += 1;
+    metrics.items += item_count as u64;
+    metrics.response_bytes += response_bytes as u64;
+    metrics.total_latency += latency;
+
+    let mut chunk_size = metrics.chunk_size;
+    if latency > TARGET_LATENCY {
+        chunk_size = chunk_size * 3 / 4;
+    } else if latency < TARGET_LATENCY / 2 && item_count >= chunk_size {
+        chunk_size += max(chunk_size / 4, 1);
+    }
+
+    if response_bytes > 0 && item_count > 0 {
+        let item_bytes = max(response_bytes / item_count, 1);
+        chunk_size = min(chunk_size, MAX_RESPONSE_BYTES / item_bytes);
+    }
+
+    metrics.chunk_size = chunk_size.clamp(1, state.max_chunk_size);
+}
+
+fn record_failure(method: &'static str, failure: CallFailure, item_count: usize) {
+    let mut states = METHOD_STATES.write().unwrap();
+    let state = match states.get_mut(method) {
+        Some(value) => value,
+        None => return,
+    };
+
+    state.metrics.calls += 1;
+    state.metrics.failures += 1;
+
+    match failure {
+        CallFailure::GasLimit | CallFailure::ResponseSize => {
+            state.metrics.chunk_size = max(min(state.metrics.chunk_size, item_count) / 2, 1);
+        }
+        CallFailure::RateLimit | CallFailure::Lagging => {
+            if failure == CallFailure::RateLimit {
+                state.metrics.rate_limits += 1;
+            }
+            // an immediate retry would reach the node before it caught up
+            state.backoff = max(state.backoff * 2, MIN_BACKOFF).min(MAX_BACKOFF);
+            state.backoff_until = Some(Instant::now() + state.backoff);
+        }
+        CallFailure::Other => {}
+    }
+}
+
+fn record_retry(method: &'static str, split: bool) {
+    if let Some(state) = METHOD_STATES.write().unwrap().get_mut(method) {
+        if split {
+            state.metrics.splits += 1;
+        } else {
+            state.metrics.retries += 1;
+        }
+    }
+}
+
+/// Calls `method` over `items` in chunks sized from what earlier calls of it observed. `call`
+/// returns one result per item of its chunk and the size of the response in bytes, 0 when
+/// unknown. Chunks failing on the gas limit or the response size are split, rate-limited calls
+/// and calls answered from a lagging node back off and other failures are retried. Returns
+/// every item with its result in the order of `items`, the items of chunks that fail every
+/// attempt are left out.
+pub async fn call_batched<I, T, E, F, Fut>(
+    method: &'static str,
+    initial_chunk_size: usize,
+    items: Vec<I>,
+    call: F,
+) -> Vec<(I, T)>
+where
+    I: Clone,
+    E: ToString,
+    F: Fn(Vec<I>) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, usize), E>>,
+{
+    let mut remaining: VecDeque<(usize, I)> = items.into_iter().enumerate().collect();
+    let mut retries: VecDeque<(Vec<(usize, I)>, usize)> = VecDeque::new();
+    let mut in_flight = FuturesUnordered::new();
+    let mut result: Vec<(usize, I, T)> = vec![];
+
+    loop {
+        while in_flight.len() < MAX_IN_FLIGHT {
+            let (chunk, attempt) = if let Some(retry) = retries.pop_front() {
+                retry
+            } else if !remaining.is_empty() {
+                let chunk_size = min(get_chunk_size(method, initial_chunk_size), remaining.len());
+                (remaining.drain(..chunk_size).collect_vec(), 0)
+            } else {
+                break;
+            };
+
+            let call = &call;
+            in_flight.push(async move {
+                if let Some(backoff) = get_backoff(method) {
+                    tokio::time::sleep(backoff).await;
+                }
+
+                let start = Instant::now();
+                let response = call(chunk.iter().map(|x| x.1.clone()).collect()).await;
+                return (chunk, attempt, response, start.elapsed());
+            });
+        }
+
+        let (chunk, attempt, response, latency) = match in_flight.next().await {
+            Some(value) => value,
+            None => break,
+        };
+
+        let failure = match response {
+            Ok((values, response_bytes)) if values.len() == chunk.len() => {
+                record_success(method, chunk.len(), response_bytes, latency);
+
+                for ((index, item), value) in chunk.into_iter().zip(values) {
+                    result.push((index, item, value));
+                }
+                continue;
+            }
+            Ok(_) => CallFailure::Other,
+            Err(err) => classify_failure(&err.to_string()),
+        };
+
+        record_failure(method, failure, chunk.len());
+
+        let is_size_failure =
+            failure == CallFailure::GasLimit || failure == CallFailure::ResponseSize;
+        if is_size_failure && chunk.len() > 1 {
+            record_retry(method, true);
+
+            let mut chunk = chunk;
+            let upper = chunk.split_off(chunk.len() / 2);
+            retries.push_back((chunk, attempt));
+            retries.push_back((upper, attempt));
+        } else if attempt + 1 < MAX_ATTEMPTS {
+            record_retry(method, false);
+            retries.push_back((chunk, attempt + 1));
+        }
+    }
+
+    return result
+        .into_iter()
+        .sorted_by_key(|x| x.0)
+        .map(|(_, item, value)| (item, value))
+        .collect();
+}
+
+/// A single call of `method` with the backoff and retries of batched calls
+pub async fn call_with_retries<T, E, F, Fut>(method: &'static str, call: F) -> Result<T, E>
+where
+    E: ToString,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    get_chunk_size(method, 1);
+    let mut attempt = 0;
+
+    loop {
+        if let Some(backoff) = get_backoff(method) {
+            tokio::time::sleep(backoff).await;
+        }
+
+        let start = Instant::now();
+        match call().await {
+            Ok(value) => {
+                record_success(method, 1, 0, start.elapsed());
+                return Ok(value);
+            }
+            Err(err) => {
+                record_failure(method, classify_failure(&err.to_string()), 1);
+
+                attempt += 1;
+                if attempt >= MAX_ATTEMPTS {
+                    return Err(err);
+                }
+                record_retry(method, false);
+            }
+        }
+    }
+}
+
+#[inline(always)]
+pub fn get_metrics() -> Vec<(&'static str, CallMetrics)> {
+    return METHOD_STATES
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(method, state)| (*method, state.metrics.clone()))
+        .sorted_by_key(|x| x.0)
+        .collect();
+}
+
+/// Prints the metrics of every method called since startup every `interval`
+pub fn initiate_reports(interval: Duration) {
+    tokio::spawn(async move {
+        let mut run_interval = tokio::time::interval(interval);
+
+        loop {
+            run_interval.tick().await;
+
+            for (method, metrics) in get_metrics() {
+                if metrics.calls == 0 {
+                    continue;
+                }
+
+                println!(
+                    "RPC {}: {} calls, {} failed, {} retried, {} split, {} rate limited, chunk {}, \
+                     {}ms avg, {} KiB",
+                    method,
+                    metrics.calls,
+                    metrics.failures,
+                    metrics.retries,
+                    metrics.splits,
+                    metrics.rate_limits,
+                    metrics.chunk_size,
+                    metrics.total_latency.as_millis() / metrics.calls as u128,
+                    metrics.response_bytes / 1024
+                );
+            }
+        }
+    });
+}
@@ -0,0 +1,180 @@
+use ethers::types::H160;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::networks::Network;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DenylistEntry {
+    pub contract_address: H160,
+    pub reason: String,
+}
+
+// Entries of the network's denylist, maintained with the network files, and those of the file the
+// token screening writes to. Tokens the screening couldn't run on are held in memory only.
+#[derive(Default)]
+struct Denylist {
+    network_path: String,
+    path: String,
+    modified: [Option<SystemTime>; 2],
+    listed: HashMap<H160, String>,
+    screened: HashMap<H160, String>,
+    held: HashSet<H160>,
+}
+
+lazy_static! {
+    static ref DENYLIST: RwLock<Denylist> = RwLock::new(Denylist::default());
+    // keeps writes of the screened entries in the order they were made
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+    // changes on every edit of the entries
+    static ref GENERATION: AtomicUsize = AtomicUsize::new(0);
+}
+
+thread_local! {
+    // denied addresses as of a generation, route evaluation checks them without locking
+    static DENIED: RefCell<(usize, HashSet<H160>)> = RefCell::new((usize::MAX, HashSet::new()));
+}
+
+#[inline(always)]
+fn get_modified(path: &str) -> Option<SystemTime> {
+    return fs::metadata(path).and_then(|x| x.modified()).ok();
+}
+
+#[inline(always)]
+fn read_entries(path: &str) -> HashMap<H160, String> {
+    let entries: Vec<DenylistEntry> = fs::read_to_string(path)
+        .ok()
+        .and_then(|x| serde_json::from_str(&x).ok())
+        .unwrap_or_default();
+
+    return entries
+        .into_iter()
+        .map(|x| (x.contract_address, x.reason))
+        .collect();
+}
+
+// same layout as the network files
+#[inline(always)]
+fn write_entries(path: &str, entries: &HashMap<H160, String>) -> bool {
+    let entries: Vec<DenylistEntry> = entries
+        .iter()
+        .sorted_by_key(|x| *x.0)
+        .map(|(contract_address, reason)| DenylistEntry {
+            contract_address: *contract_address,
+            reason: reason.clone(),
+        })
+        .collect();
+
+    let mut contents: Vec<u8> = vec![];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut contents, formatter);
+
+    return entries.serialize(&mut serializer).is_ok() && fs::write(path, contents).is_ok();
+}
+
+// The network's denylist lives next to its token file and is only read, tokens denied by the
+// screening go to `path`. Missing files are empty denylists.
+pub fn load(network: &Network, path: &str) {
+    let network_path = format!("src/networks/{}/_denylist.json", network.name);
+    let mut denylist = DENYLIST.write().unwrap();
+
+    denylist.modified = [get_modified(&network_path), get_modified(path)];
+    denylist.listed = read_entries(&network_path);
+    denylist.screened = read_entries(path);
+    denylist.network_path = network_path;
+    denylist.path = path.to_string();
+
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+// Picks up edits made to the files since they were last read
+pub fn reload() -> bool {
+    let mut denylist = DENYLIST.write().unwrap();
+    let modified = [get_modified(&denylist.network_path), get_modified(&denylist.path)];
+
+    if modified == denylist.modified {
+        return false;
+    }
+
+    if modified[0] != denylist.modified[0] {
+        denylist.listed = read_entries(&denylist.network_path);
+    }
+
+    if modified[1] != denylist.modified[1] {
+        denylist.screened = read_entries(&denylist.path);
+    }
+
+    denylist.modified = modified;
+    GENERATION.fetch_add(1, Ordering::Release);
+    return true;
+}
+
+#[inline(always)]
+pub fn is_denied(address: &H160) -> bool {
+    let generation = GENERATION.load(Ordering::Acquire);
+
+    return DENIED.with(|denied| {
+        let mut denied = denied.borrow_mut();
+
+        if denied.0 != generation {
+            let denylist = DENYLIST.read().unwrap();
+            denied.1 = denylist
+                .listed
+                .keys()
+                .chain(denylist.screened.keys())
+                .chain(denylist.held.iter())
+                .copied()
+                .collect();
+            denied.0 = generation;
+        }
+
+        return denied.1.contains(address);
+    });
+}
+
+pub fn deny(address: H160, reason: String) {
+    let _write_lock = WRITE_LOCK.lock().unwrap();
+
+    let (path, entries) = {
+        let mut denylist = DENYLIST.write().unwrap();
+        denylist.screened.insert(address, reason);
+
+        (denylist.path.clone(), denylist.screened.clone())
+    };
+    GENERATION.fetch_add(1, Ordering::Release);
+
+    if write_entries(&path, &entries) {
+        // written by us, no need to read it back
+        DENYLIST.write().unwrap().modified[1] = get_modified(&path);
+    }
+}
+
+// Keeps an unscreened token out of the routes until the next screening, without writing it down
+pub fn hold(address: H160) {
+    DENYLIST.write().unwrap().held.insert(address);
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+pub fn initiate_reloads(interval: Duration) {
+    tokio::spawn(async move {
+        let mut run_interval = tokio::time::interval(interval);
+
+        loop {
+            run_interval.tick().await;
+
+            if reload() {
+                println!("Denylist reloaded...\n");
+            }
+        }
+    });
+}
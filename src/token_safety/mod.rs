@@ -0,0 +1,297 @@
+use ethers::{
+    abi::{AbiParser, Function},
+    prelude::*,
+    types::transaction::eip2718::TypedTransaction,
+};
+use futures::future::join_all;
+use std::collections::HashMap;
+
+use crate::{
+    env::{types::RuntimeClient, RuntimeConfig},
+    exchanges::{
+        erc20::{simulate_round_trip, simulate_swap_round_trip, RoundTrip, SwapRoundTrip},
+        types::Protocol,
+    },
+    networks::Network,
+    rpc_batching::call_with_retries,
+    types::{market::Market, Reserves, Token},
+};
+
+pub mod denylist;
+
+// highest accepted loss of a round trip to the token and back, swap fees aside, in 1/10000
+const MAX_ROUND_TRIP_TAX: u32 = 1000;
+// PUSH4 pause(), the selector as it appears in a function dispatcher
+const PAUSE_SELECTOR_PUSH: [u8; 5] = [0x63, 0x84, 0x56, 0xcb, 0x59];
+// bytes32(uint256(keccak256("eip1967.proxy.implementation")) - 1)
+const IMPLEMENTATION_SLOT: &str =
+    "0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+
+lazy_static! {
+    static ref OWNER_METHOD: Function = AbiParser::default()
+        .parse_function("owner()(address)")
+        .unwrap();
+}
+
+// Code of the token, or of its implementation when it's an EIP-1967 proxy
+#[inline(always)]
+async fn get_token_code(token: &Token, client: &RuntimeClient) -> Result<Bytes, ProviderError> {
+    let slot: H256 = IMPLEMENTATION_SLOT.parse().unwrap();
+    let implementation = client
+        .provider()
+        .get_storage_at(token.contract_address, slot, None)
+        .await?;
+    let implementation = Address::from(implementation);
+
+    if implementation.is_zero() {
+        return client
+            .provider()
+            .get_code(token.contract_address, None)
+            .await;
+    }
+
+    return client.provider().get_code(implementation, None).await;
+}
+
+// A reverting `owner()` leaves the token without an owner
+#[inline(always)]
+async fn get_owner(token: &Token, client: &RuntimeClient) -> Result<Address, ProviderError> {
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(token.contract_address)
+        .data(OWNER_METHOD.short_signature().to_vec())
+        .into();
+
+    return match client.provider().call(&tx, None).await {
+        Ok(response) if response.len() == 32 => Ok(Address::from_slice(&response[12..])),
+        Ok(_) => Ok(Address::zero()),
+        Err(err) if RpcError::as_error_response(&err).is_some() => Ok(Address::zero()),
+        Err(err) => Err(err),
+    };
+}
+
+// A pause function is only a risk while someone owns the token
+#[inline(always)]
+async fn is_pausable(token: &Token, client: &RuntimeClient) -> Result<bool, ProviderError> {
+    let code = call_with_retries("token_code", || get_token_code(token, client)).await?;
+
+    if !code
+        .windows(PAUSE_SELECTOR_PUSH.len())
+        .any(|x| x == PAUSE_SELECTOR_PUSH)
+    {
+        return Ok(false);
+    }
+
+    let owner = call_with_retries("owner", || get_owner(token, client)).await?;
+    return Ok(!owner.is_zero());
+}
+
+/// Simulates buying the token through the UniswapV2 `market` with `amount` of `base` held by
+/// `holder` and selling everything bought, returns the reason the token is unsafe to route
+/// through, `None` when it passes. Fails when the simulation couldn't run.
+pub async fn screen_token_swaps(
+    token: &Token,
+    market: &Market,
+    base: &Token,
+    holder: H160,
+    amount: U256,
+    client: &RuntimeClient,
+) -> Result<Option<String>, ProviderError> {
+    let round_trip = call_with_retries("swap_round_trip", || {
+        simulate_swap_round_trip(market, base, holder, amount, client)
+    })
+    .await?;
+
+    return get_swap_risk(token, round_trip, client).await;
+}
+
+#[inline(always)]
+async fn get_swap_risk(
+    token: &Token,
+    round_trip: Option<SwapRoundTrip>,
+    client: &RuntimeClient,
+) -> Result<Option<String>, ProviderError> {
+    let round_trip = match round_trip {
+        Some(round_trip) => round_trip,
+        None => return Ok(Some(String::from("swap round trip reverts"))),
+    };
+
+    if round_trip.received.is_zero() || round_trip.returned.is_zero() {
+        return Ok(Some(String::from("swaps are blocked")));
+    }
+
+    if round_trip.received > round_trip.bought
+        || round_trip.sold > round_trip.received
+        || round_trip.returned != round_trip.sell_quote
+    {
+        return Ok(Some(String::from("balances change unexpectedly")));
+    }
+
+    let buy_tax = (round_trip.bought - round_trip.received) * 10000 / round_trip.bought;
+    let sell_tax = (round_trip.received - round_trip.sold) * 10000 / round_trip.received;
+    if buy_tax + sell_tax > U256::from(MAX_ROUND_TRIP_TAX) {
+        return Ok(Some(format!(
+            "buy tax of {} bps, sell tax of {} bps",
+            buy_tax, sell_tax
+        )));
+    }
+
+    if is_pausable(token, client).await? {
+        return Ok(Some(String::from("owner can pause transfers")));
+    }
+
+    return Ok(None);
+}
+
+/// Simulates a transfer of the token from `holder` to `recipient` and back, for tokens without a
+/// pair to swap through. Returns the reason the token is unsafe to route through, `None` when it
+/// passes. Fails when the simulation or the owner checks couldn't run.
+pub async fn screen_token(
+    token: &Token,
+    holder: H160,
+    recipient: H160,
+    amount: U256,
+    client: &RuntimeClient,
+) -> Result<Option<String>, ProviderError> {
+    let round_trip = call_with_retries("transfer_round_trip", || {
+        simulate_round_trip(token, holder, recipient, amount, client)
+    })
+    .await?;
+
+    return get_transfer_risk(token, amount, round_trip, client).await;
+}
+
+#[inline(always)]
+async fn get_transfer_risk(
+    token: &Token,
+    amount: U256,
+    round_trip: Option<RoundTrip>,
+    client: &RuntimeClient,
+) -> Result<Option<String>, ProviderError> {
+    let round_trip = match round_trip {
+        Some(round_trip) => round_trip,
+        None => return Ok(Some(String::from("transfer round trip reverts"))),
+    };
+
+    if round_trip.received.is_zero() || round_trip.returned.is_zero() {
+        return Ok(Some(String::from("transfers are blocked")));
+    }
+
+    if round_trip.spent != amount
+        || round_trip.returned_spent != round_trip.received
+        || round_trip.returned > amount
+    {
+        return Ok(Some(String::from("balances change unexpectedly")));
+    }
+
+    let tax = (amount - round_trip.returned) * 10000 / amount;
+    if tax > U256::from(MAX_ROUND_TRIP_TAX) {
+        return Ok(Some(format!("round trip tax of {} bps", tax)));
+    }
+
+    if is_pausable(token, client).await? {
+        return Ok(Some(String::from("owner can pause transfers")));
+    }
+
+    return Ok(None);
+}
+
+// The deepest UniswapV2 pair between the token and a flash loan token, with the largest other
+// holder of that base token to fund the buy
+fn get_swap_market<'a>(
+    token: &Token,
+    liquid_markets: &'a Vec<(Market, Reserves)>,
+) -> Option<(&'a Market, &'static Token, H160, U256)> {
+    let (market, base, base_reserve) = liquid_markets
+        .iter()
+        .filter(|(market, _)| market.protocol == Protocol::UniswapV2 && !market.stable)
+        .filter_map(|(market, reserves)| {
+            if market.tokens[0].contract_address == token.contract_address {
+                return Some((market, market.tokens[1], reserves.1));
+            }
+
+            if market.tokens[1].contract_address == token.contract_address {
+                return Some((market, market.tokens[0], reserves.0));
+            }
+
+            return None;
+        })
+        .filter(|x| x.1.flash_loan_enabled)
+        .max_by_key(|x| x.2)?;
+
+    // the vault holds the tokens of Balancer pools
+    let (holder, holder_reserve) = liquid_markets
+        .iter()
+        .filter(|(x, _)| {
+            x.protocol != Protocol::BalancerV2 && x.contract_address != market.contract_address
+        })
+        .filter_map(|(x, reserves)| {
+            x.tokens
+                .iter()
+                .zip([reserves.0, reserves.1])
+                .find(|(token, _)| token.contract_address == base.contract_address)
+                .map(|(_, reserve)| (x.contract_address, reserve))
+        })
+        .max_by_key(|x| x.1)?;
+
+    let amount = (base_reserve / 1000).min(holder_reserve);
+    if amount.is_zero() {
+        return None;
+    }
+
+    return Some((market, base, holder, amount));
+}
+
+// Flash loan tokens are the route bases and are trusted, every other token is screened with a
+// swap round trip through its deepest pair with a base token, or with a transfer round trip from
+// its largest holder when it has none, before markets holding it are cached. Tokens whose
+// screening can't run are held out of the routes rather than let through unscreened.
+pub async fn screen_tokens(
+    network: &'static Network,
+    liquid_markets: &Vec<(Market, Reserves)>,
+    holders: &HashMap<H160, (H160, U256)>,
+    config: &'static RuntimeConfig,
+    client: &RuntimeClient,
+) {
+    let screenings = network.tokens.iter().filter_map(|token| {
+        if token.flash_loan_enabled || denylist::is_denied(&token.contract_address) {
+            return None;
+        }
+
+        let swap_market = get_swap_market(token, liquid_markets);
+        let (holder, reserve) = *holders.get(&token.contract_address)?;
+
+        return Some(async move {
+            let reason = match swap_market {
+                Some((market, base, base_holder, amount)) => {
+                    screen_token_swaps(token, market, base, base_holder, amount, client).await
+                }
+                None => {
+                    screen_token(
+                        token,
+                        holder,
+                        config.executor_address,
+                        (reserve / 1000).max(U256::one()),
+                        client,
+                    )
+                    .await
+                }
+            };
+
+            (token, reason)
+        });
+    });
+
+    for (token, reason) in join_all(screenings).await {
+        match reason {
+            Ok(Some(reason)) => {
+                println!("Denied {:?}: {}", token.contract_address, reason);
+                denylist::deny(token.contract_address, reason);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("Held {:?} out, screening failed: {}", token.contract_address, err);
+                denylist::hold(token.contract_address);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::types::{OrgValue, market::Market};
+use crate::types::{OrgValue, market::Market, TransactionSource};
 
 #[derive(Debug, Clone)]
 pub struct BalanceChange {
@@ -7,4 +7,6 @@ pub struct BalanceChange {
     pub amount_1_in: u128,
     pub amount_0_out: u128,
     pub amount_1_out: u128,
+    // set by the data feed once the transaction's logs are parsed
+    pub source: Option<TransactionSource>,
 }
\ No newline at end of file
@@ -1,13 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use ethers::{
     abi::{Address, Token, Tokenize},
-    types::{Bytes, U256},
+    types::{Bytes, U256, U64},
 };
 
+use super::RouteResult;
+
+// blocks and seconds a bundle stays valid for after the block it was calculated on
+const MAX_BLOCK_DELAY: u64 = 8;
+const MAX_TIME_DELAY: u64 = 4;
+
+// The executor's `Guards`, checked on-chain before and after the bundle runs
+#[derive(Clone, Copy, Debug)]
+pub struct BundleGuards {
+    pub min_profit: U256,
+    pub max_block_number: U256,
+    pub deadline: U256,
+}
+
+impl BundleGuards {
+    /// Accepts the route's profit reduced by `slippage_tolerance` (in 1/10000), landing within
+    /// a few blocks of `block_number`
+    pub fn from_route_result(
+        route_result: &RouteResult,
+        slippage_tolerance: u32,
+        block_number: U64,
+    ) -> BundleGuards {
+        let slippage_tolerance = U256::from(slippage_tolerance.min(10000));
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+
+        return BundleGuards {
+            min_profit: route_result.profit_loss * (U256::from(10000u32) - slippage_tolerance)
+                / 10000u32,
+            max_block_number: U256::from(block_number.as_u64() + MAX_BLOCK_DELAY),
+            deadline: U256::from(timestamp + MAX_TIME_DELAY),
+        };
+    }
+
+    #[inline(always)]
+    fn into_token(self) -> Token {
+        return Token::Tuple(vec![
+            Token::Uint(self.min_profit),
+            Token::Uint(self.max_block_number),
+            Token::Uint(self.deadline),
+        ]);
+    }
+}
+
 pub struct BundleExecutionCall {
     pub token: Address,
     pub amount_to_first_market: U256,
     pub targets: Vec<Address>,
     pub payloads: Vec<Bytes>,
+    pub guards: BundleGuards,
 }
 
 impl BundleExecutionCall {
@@ -32,7 +80,74 @@ impl Tokenize for BundleExecutionCall {
             Token::Uint(self.amount_to_first_market),
             Token::Array(self.get_target_tokens()),
             Token::Array(self.get_payload_tokens()),
+            self.guards.into_token(),
         ]);
     }
 
 }
+
+pub struct FlashSwapExecutionCall {
+    pub pair: Address,
+    pub token: Address,
+    pub repay_amount: U256,
+    pub amount_0_out: U256,
+    pub amount_1_out: U256,
+    pub targets: Vec<Address>,
+    pub payloads: Vec<Bytes>,
+    pub guards: BundleGuards,
+}
+
+impl Tokenize for FlashSwapExecutionCall {
+    #[inline(always)]
+    fn into_tokens(self) -> Vec<ethers::abi::Token> {
+        return Vec::from([
+            Token::Address(self.pair),
+            Token::Address(self.token),
+            Token::Uint(self.repay_amount),
+            Token::Uint(self.amount_0_out),
+            Token::Uint(self.amount_1_out),
+            Token::Array(self.targets.iter().map(|x| Token::Address(*x)).collect()),
+            Token::Array(self.payloads.iter().map(|x| Token::Bytes(x.to_vec())).collect()),
+            self.guards.into_token(),
+        ]);
+    }
+}
+
+// One flash loaned token of a batch, shared by the routes starting with it
+#[derive(Clone, Copy, Debug)]
+pub struct BundleLoan {
+    pub token: Address,
+    pub amount: U256,
+    pub min_profit: U256,
+}
+
+pub struct BatchExecutionCall {
+    pub loans: Vec<BundleLoan>,
+    pub targets: Vec<Address>,
+    pub payloads: Vec<Bytes>,
+    pub guards: BundleGuards,
+}
+
+impl Tokenize for BatchExecutionCall {
+    #[inline(always)]
+    fn into_tokens(self) -> Vec<ethers::abi::Token> {
+        let loans = self
+            .loans
+            .iter()
+            .map(|x| {
+                Token::Tuple(vec![
+                    Token::Address(x.token),
+                    Token::Uint(x.amount),
+                    Token::Uint(x.min_profit),
+                ])
+            })
+            .collect();
+
+        return Vec::from([
+            Token::Array(loans),
+            Token::Array(self.targets.iter().map(|x| Token::Address(*x)).collect()),
+            Token::Array(self.payloads.iter().map(|x| Token::Bytes(x.to_vec())).collect()),
+            self.guards.into_token(),
+        ]);
+    }
+}
@@ -1,8 +1,15 @@
 use ethers::prelude::*;
 
-use crate::{env::RuntimeCache, exchanges::{types::Protocol, calculate_amount_out}};
+use crate::{
+    env::RuntimeCache,
+    exchanges::{
+        calculate_amount_out,
+        erc20::{apply_transfer_fee, get_transfer_fee},
+        types::{MarketPool, Protocol},
+    },
+};
 
-use super::{OrgValue, Token, Reserves};
+use super::{OrgValue, OrganizedList, Token, Reserves};
 
 lazy_static! {
     static ref BASE_FEE_MUL: U256 = U256::from(10000u128);
@@ -15,6 +22,9 @@ pub struct Market {
     pub fee: i32,
     pub stable: bool,
     pub protocol: Protocol,
+    pub pool: Option<MarketPool>,
+    // the executor can borrow the output of the market before paying for it
+    pub flash_swaps: bool,
     fee_mul: U256,
 }
 
@@ -40,16 +50,38 @@ impl Market {
             fee,
             stable,
             protocol,
+            pool: None,
+            flash_swaps: false,
             fee_mul: U256::from(10000u128 - fee as u128),
         };
     }
 
+    pub fn from_pool(
+        contract_address: Address,
+        tokens: [&'static Token; 2],
+        fee: i32,
+        protocol: Protocol,
+        pool: MarketPool,
+    ) -> Market {
+        return Market {
+            pool: Some(pool),
+            ..Market::new(contract_address, tokens, fee, true, protocol)
+        };
+    }
+
     #[inline(always)]
     pub fn from_address(
         address: &H160,
         runtime_cache: &'static RuntimeCache,
     ) -> Option<&'static OrgValue<Market>> {
-        for market in &runtime_cache.markets.to_vec() {
+        return Market::from_list(address, &runtime_cache.markets);
+    }
+
+    pub fn from_list(
+        address: &H160,
+        markets: &'static OrganizedList<Market>,
+    ) -> Option<&'static OrgValue<Market>> {
+        for market in markets.iter() {
             if market.value.contract_address.0 == address.0 {
                 return Some(market);
             }
@@ -67,7 +99,22 @@ impl Market {
     }
 
     #[inline(always)]
-    pub fn amount_out(&self, reserves: &Reserves, input_amount: &U256) -> U256{
-        return calculate_amount_out(reserves, input_amount, self);
+    pub fn is_constant_product(&self) -> bool {
+        // the closed form optimum doesn't account for transfer taxes
+        if self.tokens.iter().any(|x| get_transfer_fee(x) > 0) {
+            return false;
+        }
+
+        return self.protocol == Protocol::UniswapV2
+            || (self.protocol == Protocol::StableSwap && !self.stable);
+    }
+
+    // The market receives the input minus its transfer tax, the output is what the market sends
+    #[inline(always)]
+    pub fn amount_out(&self, reserves: &Reserves, input_amount: &U256, zero_for_one: bool) -> U256{
+        let token_in = if zero_for_one { self.tokens[0] } else { self.tokens[1] };
+        let input_amount = apply_transfer_fee(token_in, *input_amount);
+
+        return calculate_amount_out(reserves, &input_amount, self, zero_for_one);
     }
 }
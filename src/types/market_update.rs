@@ -0,0 +1,12 @@
+use crate::price_oracle::BlockHeader;
+
+use super::BalanceChange;
+
+// Balance changes of a feed message with the head its transactions were traced on, or of a
+// confirmed block, already part of the reserve table
+#[derive(Clone)]
+pub struct MarketUpdate {
+    pub block: Option<BlockHeader>,
+    pub confirmed: bool,
+    pub balance_changes: Vec<BalanceChange>,
+}
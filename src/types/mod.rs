@@ -3,19 +3,27 @@ mod token;
 mod transaction_log;
 
 mod route;
+mod route_batch;
 mod reserves;
 mod org_list;
 mod swap_log;
 mod price_table;
 mod bundle_execution_call;
+mod packed_bundle;
 mod balance_change;
+mod market_update;
+mod reserve_snapshot;
 mod relay_message;
 mod transaction_decode_result;
 
 pub use transaction_decode_result::TransactionDecodeResult;
+pub use transaction_decode_result::TransactionSource;
 pub use balance_change::BalanceChange;
+pub use market_update::MarketUpdate;
+pub use reserve_snapshot::ReserveSnapshot;
 pub use relay_message::RelayMessage;
 pub use self::bundle_execution_call::*;
+pub use self::packed_bundle::encode_packed_bundle;
 pub use self::price_table::PriceTable;
 pub use self::swap_log::SwapLog;
 pub use self::org_list::OrganizedList;
@@ -23,5 +31,7 @@ pub use self::org_list::OrgValue;
 pub use self::token::Token;
 pub use self::transaction_log::TransactionLog;
 pub use self::reserves::Reserves;
+pub use self::reserves::ReverseReserves;
 pub use self::route::Route;
 pub use self::route::RouteResult;
+pub use self::route_batch::RouteBatch;
@@ -0,0 +1,622 @@
+use ethers::{
+    abi::Error,
+    prelude::AbiError,
+    types::{Address, Bytes, U256},
+};
+
+use crate::{
+    exchanges::{
+        erc20::{populate_approve, populate_transfer},
+        get_swap_mode, populate_swap,
+        types::{Protocol, SwapMode},
+    },
+    flash_liquidity::FlashLiquiditySource,
+};
+
+use super::{market::Market, BundleGuards, RouteResult, SwapLog};
+
+// Packed bundles are raw calldata for the `BundleExecutor` fallback, see `BundleExecutor.sol`
+// for the layout
+const SOURCE_AAVE: u8 = 0;
+const SOURCE_BALANCER: u8 = 1;
+const SOURCE_INVENTORY: u8 = 2;
+const SOURCE_FLASH_SWAP: u8 = 3;
+const RECORD_CALL: u8 = 1;
+const ZERO_FOR_ONE: u8 = 2;
+const PUSH_TARGET: u8 = 4;
+
+// Amounts are a length byte followed by the big-endian bytes without leading zeros
+#[inline(always)]
+fn push_amount(data: &mut Vec<u8>, amount: U256) {
+    let mut bytes = [0u8; 32];
+    amount.to_big_endian(&mut bytes);
+
+    let length = amount.bits().div_ceil(8);
+    data.push(length as u8);
+    data.extend_from_slice(&bytes[32 - length..]);
+}
+
+#[inline(always)]
+fn push_pair_swap(data: &mut Vec<u8>, swap: &SwapLog) {
+    let flags = if swap.zero_for_one() { ZERO_FOR_ONE } else { 0 };
+
+    data.push(flags);
+    data.extend_from_slice(swap.market.value.contract_address.as_bytes());
+    push_amount(data, swap.amount_out());
+}
+
+#[inline(always)]
+fn push_call(
+    data: &mut Vec<u8>,
+    target: &Address,
+    payload: Bytes,
+    push_target: bool,
+) -> Result<(), AbiError> {
+    let length =
+        u16::try_from(payload.len()).map_err(|_| AbiError::DecodingError(Error::InvalidData))?;
+    let flags = if push_target {
+        RECORD_CALL | PUSH_TARGET
+    } else {
+        RECORD_CALL
+    };
+
+    data.push(flags);
+    data.extend_from_slice(target.as_bytes());
+    data.extend_from_slice(&length.to_be_bytes());
+    data.extend_from_slice(&payload);
+
+    return Ok(());
+}
+
+// Pair swaps are rebuilt by the executor, it only knows `swap(uint256,uint256,address,bytes)`
+#[inline(always)]
+fn is_packed_pair(market: &Market) -> bool {
+    return market.protocol == Protocol::UniswapV2;
+}
+
+/// Encodes the route like `build_bundled_transaction` in the packed format. Uniswap V2 swaps
+/// only carry their pair, direction and amount out, their recipient is implied by the next record.
+pub fn encode_packed_bundle(
+    route_result: &RouteResult,
+    executor_address: Address,
+    guards: BundleGuards,
+) -> Result<Bytes, AbiError> {
+    let transactions = &route_result.transactions;
+    let mut data: Vec<u8> = vec![];
+
+    let source = match route_result.flash_liquidity.source {
+        FlashLiquiditySource::Aave => SOURCE_AAVE,
+        FlashLiquiditySource::Balancer => SOURCE_BALANCER,
+        FlashLiquiditySource::Inventory => SOURCE_INVENTORY,
+        FlashLiquiditySource::FlashSwap => SOURCE_FLASH_SWAP,
+    };
+
+    data.push(source);
+    data.extend_from_slice(route_result.base_token.contract_address.as_bytes());
+
+    let mut first_index = 0;
+    if source == SOURCE_FLASH_SWAP {
+        let first_swap = &transactions[0].value;
+        first_index = 1;
+
+        push_amount(&mut data, first_swap.amount_in);
+        push_amount(&mut data, guards.min_profit);
+        push_amount(&mut data, guards.max_block_number);
+        push_amount(&mut data, guards.deadline);
+        push_pair_swap(&mut data, first_swap);
+    } else {
+        push_amount(&mut data, route_result.start_balance);
+        push_amount(&mut data, guards.min_profit);
+        push_amount(&mut data, guards.max_block_number);
+        push_amount(&mut data, guards.deadline);
+    }
+
+    let mut held_by_executor = true;
+
+    // the executor resolves pair swap recipients the same way
+    let next_recipient = |i: usize| match transactions.get(i + 1) {
+        Some(next) if get_swap_mode(&next.value.market.value) == SwapMode::Push => {
+            next.value.market.value.contract_address
+        }
+        _ => executor_address,
+    };
+
+    for i in first_index..transactions.len() {
+        let transaction = &transactions[i].value;
+        let market = &transaction.market.value;
+        let token_in = transaction.token_in().contract_address;
+
+        match get_swap_mode(market) {
+            SwapMode::Push => {
+                if held_by_executor {
+                    push_call(
+                        &mut data,
+                        &token_in,
+                        populate_transfer(&market.contract_address, transaction.amount_in)?,
+                        false,
+                    )?;
+                }
+
+                let recipient = next_recipient(i);
+                held_by_executor = recipient == executor_address;
+
+                if is_packed_pair(market) {
+                    push_pair_swap(&mut data, transaction);
+                } else {
+                    push_call(
+                        &mut data,
+                        &market.contract_address,
+                        populate_swap(transaction, &recipient, &executor_address)?,
+                        true,
+                    )?;
+                }
+            }
+            SwapMode::Pull => {
+                push_call(
+                    &mut data,
+                    &token_in,
+                    populate_approve(&market.contract_address, transaction.amount_in)?,
+                    false,
+                )?;
+
+                held_by_executor = true;
+                push_call(
+                    &mut data,
+                    &market.contract_address,
+                    populate_swap(transaction, &executor_address, &executor_address)?,
+                    false,
+                )?;
+            }
+            SwapMode::Routed(router_address) => {
+                push_call(
+                    &mut data,
+                    &token_in,
+                    populate_approve(&router_address, transaction.amount_in)?,
+                    false,
+                )?;
+
+                let recipient = next_recipient(i);
+                held_by_executor = recipient == executor_address;
+
+                push_call(
+                    &mut data,
+                    &router_address,
+                    populate_swap(transaction, &recipient, &executor_address)?,
+                    false,
+                )?;
+            }
+        }
+    }
+
+    return Ok(Bytes::from(data));
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Address, Bytes, U256};
+
+    use crate::{
+        exchanges::{erc20::populate_transfer, populate_swap, types::Protocol},
+        flash_liquidity::{FlashLiquidity, FlashLiquiditySource},
+        types::{
+            market::Market, BundleGuards, OrgValue, OrganizedList, RouteResult, SwapLog, Token,
+        },
+    };
+
+    use super::*;
+
+    // `PackedHeader` as read by the executor
+    #[derive(Debug, Default, PartialEq)]
+    struct PackedHeader {
+        source: u8,
+        token: Address,
+        amount: U256,
+        min_profit: U256,
+        max_block_number: U256,
+        deadline: U256,
+        flags: u8,
+        pair: Address,
+        amount_out: U256,
+    }
+
+    // readPackedAmount
+    fn read_packed_amount(data: &[u8], offset: usize) -> (U256, usize) {
+        let length = data[offset] as usize;
+        let next = offset + 1 + length;
+
+        return (U256::from_big_endian(&data[offset + 1..next]), next);
+    }
+
+    // readPackedHeader
+    fn read_packed_header(data: &[u8]) -> (PackedHeader, usize) {
+        let mut header = PackedHeader {
+            source: data[0],
+            token: Address::from_slice(&data[1..21]),
+            ..Default::default()
+        };
+
+        let mut offset;
+        (header.amount, offset) = read_packed_amount(data, 21);
+        (header.min_profit, offset) = read_packed_amount(data, offset);
+        (header.max_block_number, offset) = read_packed_amount(data, offset);
+        (header.deadline, offset) = read_packed_amount(data, offset);
+
+        if header.source == SOURCE_FLASH_SWAP {
+            header.flags = data[offset];
+            header.pair = Address::from_slice(&data[offset + 1..offset + 21]);
+            (header.amount_out, offset) = read_packed_amount(data, offset + 21);
+        }
+
+        return (header, offset);
+    }
+
+    // readPackedRecords, pair swaps are rebuilt with the executor as `address(this)`
+    fn read_packed_records(
+        data: &[u8],
+        mut offset: usize,
+        executor_address: Address,
+    ) -> (Vec<Address>, Vec<Bytes>) {
+        let mut flags: Vec<u8> = vec![];
+        let mut targets: Vec<Address> = vec![];
+        let mut payloads: Vec<Bytes> = vec![];
+        let mut amounts_out: Vec<U256> = vec![];
+
+        while offset < data.len() {
+            flags.push(data[offset]);
+            targets.push(Address::from_slice(&data[offset + 1..offset + 21]));
+            offset += 21;
+
+            if data[offset - 21] & RECORD_CALL != 0 {
+                let length = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
+                payloads.push(Bytes::from(data[offset + 2..offset + 2 + length].to_vec()));
+                amounts_out.push(U256::zero());
+                offset += 2 + length;
+            } else {
+                let (amount_out, next) = read_packed_amount(data, offset);
+                payloads.push(Bytes::new());
+                amounts_out.push(amount_out);
+                offset = next;
+            }
+        }
+
+        assert_eq!(offset, data.len());
+
+        for i in 0..flags.len() {
+            if flags[i] & RECORD_CALL != 0 {
+                continue;
+            }
+
+            let recipient = match flags.get(i + 1) {
+                Some(next) if next & RECORD_CALL == 0 || next & PUSH_TARGET != 0 => targets[i + 1],
+                _ => executor_address,
+            };
+
+            let (amount_0_out, amount_1_out) = if flags[i] & ZERO_FOR_ONE != 0 {
+                (U256::zero(), amounts_out[i])
+            } else {
+                (amounts_out[i], U256::zero())
+            };
+
+            payloads[i] = ethers::abi::encode(&[
+                ethers::abi::Token::Uint(amount_0_out),
+                ethers::abi::Token::Uint(amount_1_out),
+                ethers::abi::Token::Address(recipient),
+                ethers::abi::Token::Bytes(vec![]),
+            ])
+            .into();
+            payloads[i] = [&[0x02, 0x2c, 0x0d, 0x9f][..], &payloads[i]]
+                .concat()
+                .into();
+        }
+
+        return (targets, payloads);
+    }
+
+    fn get_token(address: u64) -> &'static Token {
+        return Box::leak(Box::new(Token {
+            contract_address: Address::from_low_u64_be(address),
+            flash_loan_enabled: true,
+            decimals: 18,
+            ref_symbol: None,
+            transfer_fee: Some(0),
+            rebasing: false,
+        }));
+    }
+
+    fn get_market(
+        id: usize,
+        address: u64,
+        tokens: [&'static Token; 2],
+        protocol: Protocol,
+    ) -> &'static OrgValue<Market> {
+        return Box::leak(Box::new(OrgValue {
+            id,
+            value: Market::new(
+                Address::from_low_u64_be(address),
+                tokens,
+                30,
+                false,
+                protocol,
+            ),
+        }));
+    }
+
+    fn get_swap(market: &'static OrgValue<Market>, amount_in: u64, amount_out: u64) -> SwapLog {
+        return SwapLog {
+            market,
+            amount_in: U256::from(amount_in),
+            amount_0_out: U256::zero(),
+            amount_1_out: U256::from(amount_out),
+        };
+    }
+
+    fn get_route_result(source: FlashLiquiditySource, swaps: Vec<SwapLog>) -> RouteResult {
+        let mut transactions: OrganizedList<SwapLog> = OrganizedList::new();
+        let start_balance = swaps[0].amount_in;
+        let end_balance = swaps.last().unwrap().amount_out();
+        let base_token = swaps[0].token_in();
+
+        for swap in swaps {
+            transactions.add_value(swap);
+        }
+
+        return RouteResult {
+            base_token,
+            start_balance,
+            end_balance,
+            profit_loss: U256::zero(),
+            ref_profit_loss: U256::zero(),
+            flash_liquidity: FlashLiquidity {
+                source,
+                cost: U256::zero(),
+            },
+            cost: U256::zero(),
+            source: None,
+            transactions,
+            route_reserves: vec![],
+        };
+    }
+
+    fn get_guards() -> BundleGuards {
+        return BundleGuards {
+            min_profit: U256::from(1_000_000_007u64),
+            max_block_number: U256::from(150_000_000u64),
+            deadline: U256::from(1_700_000_000u64),
+        };
+    }
+
+    // A route through three pairs, each sending its output to the next one
+    fn get_pair_route(source: FlashLiquiditySource) -> RouteResult {
+        let (token_a, token_b, token_c) = (get_token(0xa), get_token(0xb), get_token(0xc));
+
+        return get_route_result(
+            source,
+            vec![
+                get_swap(
+                    get_market(0, 0x1, [token_a, token_b], Protocol::UniswapV2),
+                    5000,
+                    7000,
+                ),
+                get_swap(
+                    get_market(1, 0x2, [token_b, token_c], Protocol::UniswapV2),
+                    7000,
+                    9000,
+                ),
+                get_swap(
+                    get_market(2, 0x3, [token_c, token_a], Protocol::UniswapV2),
+                    9000,
+                    5100,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn header_round_trips_for_every_source() {
+        let executor_address = Address::from_low_u64_be(0xe);
+        let guards = get_guards();
+
+        for (source, packed_source) in [
+            (FlashLiquiditySource::Aave, SOURCE_AAVE),
+            (FlashLiquiditySource::Balancer, SOURCE_BALANCER),
+            (FlashLiquiditySource::Inventory, SOURCE_INVENTORY),
+            (FlashLiquiditySource::FlashSwap, SOURCE_FLASH_SWAP),
+        ] {
+            let route_result = get_pair_route(source);
+            let data = encode_packed_bundle(&route_result, executor_address, guards).unwrap();
+            let (header, _) = read_packed_header(&data);
+
+            let first_swap = &route_result.transactions[0].value;
+            let mut expected = PackedHeader {
+                source: packed_source,
+                token: Address::from_low_u64_be(0xa),
+                amount: route_result.start_balance,
+                min_profit: guards.min_profit,
+                max_block_number: guards.max_block_number,
+                deadline: guards.deadline,
+                ..Default::default()
+            };
+
+            if source == FlashLiquiditySource::FlashSwap {
+                expected.amount = first_swap.amount_in;
+                expected.flags = ZERO_FOR_ONE;
+                expected.pair = first_swap.market.value.contract_address;
+                expected.amount_out = first_swap.amount_out();
+            }
+
+            assert_eq!(header, expected);
+        }
+    }
+
+    #[test]
+    fn pair_swaps_send_their_output_to_the_next_pair() {
+        let executor_address = Address::from_low_u64_be(0xe);
+        let route_result = get_pair_route(FlashLiquiditySource::Aave);
+
+        let data = encode_packed_bundle(&route_result, executor_address, get_guards()).unwrap();
+        let (_, offset) = read_packed_header(&data);
+        let (targets, payloads) = read_packed_records(&data, offset, executor_address);
+
+        let swaps: Vec<&SwapLog> = route_result.transactions.iter().map(|x| &x.value).collect();
+        let pairs: Vec<Address> = swaps
+            .iter()
+            .map(|x| x.market.value.contract_address)
+            .collect();
+
+        assert_eq!(
+            targets,
+            vec![Address::from_low_u64_be(0xa), pairs[0], pairs[1], pairs[2]]
+        );
+        assert_eq!(
+            payloads,
+            vec![
+                populate_transfer(&pairs[0], swaps[0].amount_in).unwrap(),
+                populate_swap(swaps[0], &pairs[1], &executor_address).unwrap(),
+                populate_swap(swaps[1], &pairs[2], &executor_address).unwrap(),
+                populate_swap(swaps[2], &executor_address, &executor_address).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn flash_swaps_leave_the_first_pair_to_the_header() {
+        let executor_address = Address::from_low_u64_be(0xe);
+        let route_result = get_pair_route(FlashLiquiditySource::FlashSwap);
+
+        let data = encode_packed_bundle(&route_result, executor_address, get_guards()).unwrap();
+        let (_, offset) = read_packed_header(&data);
+        let (targets, payloads) = read_packed_records(&data, offset, executor_address);
+
+        let swaps: Vec<&SwapLog> = route_result.transactions.iter().map(|x| &x.value).collect();
+        let pairs: Vec<Address> = swaps
+            .iter()
+            .map(|x| x.market.value.contract_address)
+            .collect();
+
+        // the flash swapped output is held by the executor, it pushes it to the second pair
+        assert_eq!(
+            targets,
+            vec![Address::from_low_u64_be(0xb), pairs[1], pairs[2]]
+        );
+        assert_eq!(
+            payloads,
+            vec![
+                populate_transfer(&pairs[1], swaps[1].amount_in).unwrap(),
+                populate_swap(swaps[1], &pairs[2], &executor_address).unwrap(),
+                populate_swap(swaps[2], &executor_address, &executor_address).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn pair_swaps_send_their_output_to_push_targets() {
+        let executor_address = Address::from_low_u64_be(0xe);
+        let (token_a, token_b) = (get_token(0xa), get_token(0xb));
+
+        // a Liquidity Book pair is called with a payload but expects its input pushed
+        let route_result = get_route_result(
+            FlashLiquiditySource::Balancer,
+            vec![
+                get_swap(
+                    get_market(0, 0x1, [token_a, token_b], Protocol::UniswapV2),
+                    5000,
+                    7000,
+                ),
+                SwapLog {
+                    market: get_market(1, 0x2, [token_a, token_b], Protocol::LiquidityBook),
+                    amount_in: U256::from(7000),
+                    amount_0_out: U256::from(5100),
+                    amount_1_out: U256::zero(),
+                },
+            ],
+        );
+
+        let data = encode_packed_bundle(&route_result, executor_address, get_guards()).unwrap();
+        let (_, offset) = read_packed_header(&data);
+        let (targets, payloads) = read_packed_records(&data, offset, executor_address);
+
+        let swaps: Vec<&SwapLog> = route_result.transactions.iter().map(|x| &x.value).collect();
+        let pairs: Vec<Address> = swaps
+            .iter()
+            .map(|x| x.market.value.contract_address)
+            .collect();
+
+        assert_eq!(targets, vec![token_a.contract_address, pairs[0], pairs[1]]);
+        assert_eq!(
+            data[data.len() - payloads[2].len() - 23],
+            RECORD_CALL | PUSH_TARGET
+        );
+        assert_eq!(
+            payloads,
+            vec![
+                populate_transfer(&pairs[0], swaps[0].amount_in).unwrap(),
+                populate_swap(swaps[0], &pairs[1], &executor_address).unwrap(),
+                populate_swap(swaps[1], &executor_address, &executor_address).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_amounts_take_only_their_length_byte() {
+        let executor_address = Address::from_low_u64_be(0xe);
+        let token_a = get_token(0xa);
+        let token_b = get_token(0xb);
+
+        let route_result = get_route_result(
+            FlashLiquiditySource::Inventory,
+            vec![get_swap(
+                get_market(0, 0x1, [token_a, token_b], Protocol::UniswapV2),
+                0,
+                0,
+            )],
+        );
+        let guards = BundleGuards {
+            min_profit: U256::zero(),
+            max_block_number: U256::zero(),
+            deadline: U256::zero(),
+        };
+
+        let data = encode_packed_bundle(&route_result, executor_address, guards).unwrap();
+        assert_eq!(&data[21..25], &[0, 0, 0, 0]);
+
+        let (header, offset) = read_packed_header(&data);
+        assert_eq!(header.amount, U256::zero());
+        assert_eq!(header.deadline, U256::zero());
+
+        let (targets, payloads) = read_packed_records(&data, offset, executor_address);
+        let swap = &route_result.transactions[0].value;
+        assert_eq!(targets[1], swap.market.value.contract_address);
+        assert_eq!(
+            payloads[1],
+            populate_swap(swap, &executor_address, &executor_address).unwrap()
+        );
+    }
+
+    #[test]
+    fn amounts_keep_every_significant_byte() {
+        for amount in [U256::one(), U256::from(0x100), U256::MAX] {
+            let mut data: Vec<u8> = vec![];
+            push_amount(&mut data, amount);
+
+            assert_eq!(data[0] as usize, data.len() - 1);
+            assert_eq!(read_packed_amount(&data, 0), (amount, data.len()));
+        }
+    }
+
+    #[test]
+    fn call_payloads_are_limited_to_u16_lengths() {
+        let target = Address::from_low_u64_be(0x1);
+
+        let mut data: Vec<u8> = vec![];
+        let payload = Bytes::from(vec![0xab; u16::MAX as usize]);
+        push_call(&mut data, &target, payload.clone(), false).unwrap();
+
+        let (targets, payloads) = read_packed_records(&data, 0, Address::zero());
+        assert_eq!(targets, vec![target]);
+        assert_eq!(payloads, vec![payload]);
+
+        let mut data: Vec<u8> = vec![];
+        let payload = Bytes::from(vec![0xab; u16::MAX as usize + 1]);
+        assert!(push_call(&mut data, &target, payload, false).is_err());
+    }
+}
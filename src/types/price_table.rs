@@ -53,8 +53,18 @@ impl PriceTable {
         }
     }
 
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        return self.internal.is_empty();
+    }
+
+    // tokens without a reference price are valued at zero
     #[inline(always)]
     pub fn get_ref_price(&self, token: &Token, input_amout: U256) -> U256 {
+        if !self.contains_key(&token.contract_address) {
+            return U256::zero();
+        }
+
         let value = self.get_value(&token.contract_address);
         let token_dec_power = self
             .token_dec_powers
@@ -9,6 +9,9 @@ use ethers::utils::rlp::*;
 
 use super::TransactionDecodeResult;
 
+// nesting of batch messages the node accepts
+const MAX_BATCH_DEPTH: usize = 16;
+
 #[derive(Debug, Deserialize, PartialEq, Eq)]
 pub enum L1MessageType {
     L2Message = 3,
@@ -84,46 +87,75 @@ impl RelayMessage {
 
     #[inline(always)]
     pub fn decode(&self) -> Vec<TransactionDecodeResult> {
-        if self.messages.len() > 0 {
-            let mut result: Vec<TransactionDecodeResult> = vec![];
-
-            for message in &self.messages {
-                if let Ok(data) =
-                    general_purpose::STANDARD.decode(&message.message.message.l2_message)
-                {
-                    let (message_kind, message_data) = data.split_first().unwrap();
-                    if i8::from_be_bytes([*message_kind]) == L2MessageType::SignedTx as i8 {
-                        let hash = H256::from(keccak256(message_data));
-
-                        if let Ok(transaction) = Transaction::decode(&Rlp::new(message_data)).or(
-                            Transaction::decode(&Rlp::new(data.split_first().unwrap().1)),
-                        ) {
-                            if let Ok(from_address) = transaction.recover_from() {
-                                result.push(TransactionDecodeResult {
-                                    hash,
-                                    transaction: Transaction {
-                                        from: from_address,
-                                        gas_price: None,
-                                        gas: transaction.gas,
-                                        max_fee_per_gas: None,
-                                        max_priority_fee_per_gas: None,
-                                        access_list: None,
-                                        hash: transaction.hash,
-                                        transaction_index: None,
-                                        transaction_type: None,
-                                        chain_id: None,
-                                        ..transaction
-                                    },
-                                });
-                            }
-                        }
+        let mut result: Vec<TransactionDecodeResult> = vec![];
+
+        for message in &self.messages {
+            let data = match general_purpose::STANDARD.decode(&message.message.message.l2_message) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            let mut signed_transactions: Vec<&[u8]> = vec![];
+            read_signed_transactions(&data, 0, &mut signed_transactions);
+
+            for (index, transaction_data) in signed_transactions.into_iter().enumerate() {
+                let hash = H256::from(keccak256(transaction_data));
+
+                if let Ok(transaction) = Transaction::decode(&Rlp::new(transaction_data)) {
+                    if let Ok(from_address) = transaction.recover_from() {
+                        result.push(TransactionDecodeResult {
+                            hash,
+                            sequence_number: message.sequence_number,
+                            index: index as u32,
+                            timestamp: message.message.message.header.timestamp as u64,
+                            transaction: Transaction {
+                                from: from_address,
+                                gas_price: None,
+                                gas: transaction.gas,
+                                max_fee_per_gas: None,
+                                max_priority_fee_per_gas: None,
+                                access_list: None,
+                                hash: transaction.hash,
+                                transaction_index: None,
+                                transaction_type: None,
+                                chain_id: None,
+                                ..transaction
+                            },
+                        });
                     }
                 }
             }
-
-            return result;
         }
 
-        return vec![];
+        return result;
+    }
+}
+
+// Collects the signed transactions of an L2 message in order. A batch is a sequence of L2
+// messages, each prefixed with its length as a big-endian u64.
+#[inline(always)]
+fn read_signed_transactions<'a>(data: &'a [u8], depth: usize, result: &mut Vec<&'a [u8]>) {
+    let (message_kind, message_data) = match data.split_first() {
+        Some(value) => value,
+        None => return,
+    };
+
+    if *message_kind == L2MessageType::SignedTx as u8 {
+        result.push(message_data);
+    } else if *message_kind == L2MessageType::Batch as u8 && depth < MAX_BATCH_DEPTH {
+        let mut offset = 0;
+
+        while offset + 8 <= message_data.len() {
+            let length = u64::from_be_bytes(message_data[offset..offset + 8].try_into().unwrap());
+            let start = offset + 8;
+
+            if length > (message_data.len() - start) as u64 {
+                return;
+            }
+
+            let end = start + length as usize;
+            read_signed_transactions(&message_data[start..end], depth + 1, result);
+            offset = end;
+        }
     }
 }
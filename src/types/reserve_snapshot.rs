@@ -0,0 +1,37 @@
+use ethers::types::U64;
+
+use super::{OrganizedList, Reserves};
+
+// Reserves of the tracked markets as of one block. Markets whose reads kept failing are left out
+// and the snapshot is marked partial, they never fall back to the state of another block.
+#[derive(Clone)]
+pub struct ReserveSnapshot {
+    pub block_number: U64,
+    pub partial: bool,
+    pub reserves: OrganizedList<Reserves>,
+}
+
+impl ReserveSnapshot {
+    pub fn new() -> ReserveSnapshot {
+        return ReserveSnapshot {
+            block_number: U64::zero(),
+            partial: false,
+            reserves: OrganizedList::new(),
+        };
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        return self.reserves.is_empty();
+    }
+
+    #[inline(always)]
+    pub fn get_reserves(&self, market_id: usize) -> Option<Reserves> {
+        let index = self
+            .reserves
+            .binary_search_by_key(&market_id, |x| x.id)
+            .ok()?;
+
+        return Some(self.reserves[index].value);
+    }
+}
@@ -6,15 +6,24 @@ use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
     env::{RuntimeCache, RuntimeConfig},
+    exchanges::erc20::apply_transfer_fee,
     networks::Network,
+    flash_liquidity::{get_entry_gas, get_max_amount, select_source, FlashLiquidity},
+    price_oracle::GasPrices,
+    token_safety::denylist,
 };
 
 use super::{
-    market::Market, reserves::ReverseReserves, OrgValue, OrganizedList, PriceTable, Reserves,
-    SwapLog, Token,
+    market::Market, reserves::ReverseReserves, BalanceChange, OrgValue, OrganizedList,
+    PriceTable, Reserves, SwapLog, Token, TransactionSource,
 };
 
 const ZERO_VALUE: U256 = U256::zero();
+const SEARCH_ITERATIONS: usize = 96;
+// executor overhead of a bundle besides its entry point and gas of one swap in it, upper bounds
+// for cost estimates
+pub const BUNDLE_GAS: u64 = 50_000;
+pub const SWAP_GAS: u64 = 120_000;
 
 #[derive(Debug, Clone)]
 pub struct Route {
@@ -24,6 +33,7 @@ pub struct Route {
     //private
     market_fee_data: Vec<(&'static U256, &'static U256)>,
     market_ids: Vec<usize>,
+    constant_product: bool,
 }
 pub struct RouteResult {
     pub base_token: &'static Token,
@@ -31,19 +41,63 @@ pub struct RouteResult {
     pub end_balance: U256,
     pub profit_loss: U256,
     pub ref_profit_loss: U256,
+    pub flash_liquidity: FlashLiquidity,
+    // in wei, L2 gas and L1 data of sending the route alone, set once it's priced
+    pub cost: U256,
+    // the pending transaction the bundle should directly follow
+    pub source: Option<TransactionSource>,
     pub transactions: OrganizedList<SwapLog>,
     pub route_reserves: Vec<(OrgValue<Reserves>, &'static OrgValue<Market>)>,
 }
 
+impl RouteResult {
+    /// Tags the result with the latest transaction moving one of its markets, the last of them
+    /// in the sequencer feed is the one the bundle has to follow
+    pub fn tag_source(&mut self, balance_changes: &Vec<BalanceChange>) {
+        self.source = balance_changes
+            .iter()
+            .filter(|x| self.transactions.iter().any(|y| y.value.market.id == x.market.id))
+            .filter_map(|x| x.source)
+            .max_by_key(|x| (x.sequence_number, x.index));
+    }
+
+    #[inline(always)]
+    pub fn get_gas_limit(&self) -> U256 {
+        let entry_gas = get_entry_gas(self.flash_liquidity.source);
+        return U256::from(BUNDLE_GAS + entry_gas + SWAP_GAS * self.transactions.len() as u64);
+    }
+
+    // Reference profit left once the transaction is paid for
+    #[inline(always)]
+    pub fn get_net_ref_profit_loss(&self) -> U256 {
+        return self.ref_profit_loss.saturating_sub(self.cost);
+    }
+}
+
 impl Route {
     #[inline(always)]
     pub fn calculate_result(
         &self,
         reserve_table: &OrganizedList<Reserves>,
-        price_table: &'static PriceTable,
+        price_table: &PriceTable,
+        gas_prices: &GasPrices,
         affected_markets: &Vec<usize>,
     ) -> Option<RouteResult> {
-        if self.contains_any_market(affected_markets) {
+        if self.contains_any_market(affected_markets) && !self.contains_denied_token() {
+            if !self.constant_product {
+                if let Some(input_amount) = self.search_optimal_input(reserve_table) {
+                    return self.calculate_circ_profit(
+                        reserve_table,
+                        price_table,
+                        gas_prices,
+                        input_amount,
+                        self.base_token,
+                    );
+                }
+
+                return None;
+            }
+
             let liquidity: Reserves = self.calculate_circ_liquidity(reserve_table);
             let (fee_multiplier, multiplier) = self.market_fee_data[0];
 
@@ -51,12 +105,17 @@ impl Route {
                 ((liquidity.0 * liquidity.1 * fee_multiplier) / multiplier).integer_sqrt();
 
             if feed_liquidity_sqrt > liquidity.0 {
-                let input_amount =
-                    (feed_liquidity_sqrt - liquidity.0) * multiplier / fee_multiplier;
+                let input_amount = self.cap_input(
+                    (feed_liquidity_sqrt - liquidity.0) * multiplier / fee_multiplier,
+                );
+                if input_amount.is_zero() {
+                    return None;
+                }
 
                 return self.calculate_circ_profit(
                     reserve_table,
                     price_table,
+                    gas_prices,
                     input_amount,
                     self.base_token,
                 );
@@ -66,6 +125,81 @@ impl Route {
         return None;
     }
 
+    // The input can't exceed what the flash liquidity sources can provide
+    #[inline(always)]
+    fn cap_input(&self, input_amount: U256) -> U256 {
+        return match get_max_amount(self.base_token, &self.markets[0].value) {
+            Some(borrowable_amount) => input_amount.min(borrowable_amount),
+            None => input_amount,
+        };
+    }
+
+    // Routes through markets without a closed-form optimum are sized with a ternary search,
+    // bounded by the base token reserve of the first market and the available flash liquidity
+    #[inline(always)]
+    fn search_optimal_input(&self, reserve_table: &OrganizedList<Reserves>) -> Option<U256> {
+        let first_reserves = &reserve_table[self.markets[0].id].value;
+        let mut high = self.cap_input(if self.markets[0].value.tokens[0].eq(self.base_token) {
+            first_reserves.0
+        } else {
+            first_reserves.1
+        });
+        let mut low = ZERO_VALUE;
+
+        for _ in 0..SEARCH_ITERATIONS {
+            if high - low <= U256::from(2u8) {
+                break;
+            }
+
+            let step = (high - low) / 3;
+            let (left, right) = (low + step, high - step);
+
+            // out(left) - left < out(right) - right, without signed arithmetic
+            if self.calculate_circ_output(reserve_table, left) + right
+                < self.calculate_circ_output(reserve_table, right) + left
+            {
+                low = left;
+            } else {
+                high = right;
+            }
+        }
+
+        let input_amount = (low + high) / 2;
+        if self.calculate_circ_output(reserve_table, input_amount) > input_amount {
+            return Some(input_amount);
+        }
+
+        return None;
+    }
+
+    #[inline(always)]
+    fn calculate_circ_output(
+        &self,
+        reserve_table: &OrganizedList<Reserves>,
+        mut input_amount: U256,
+    ) -> U256 {
+        let mut token_in = self.base_token;
+
+        for market in &self.markets {
+            let reserves: Reserves = reserve_table[market.id].value;
+            let market_value = market.value;
+
+            if token_in == market_value.tokens[0] {
+                input_amount = market_value.amount_out(&reserves, &input_amount, true);
+                token_in = market_value.tokens[1];
+            } else {
+                input_amount = market_value.amount_out(&reserves.reverse(), &input_amount, false);
+                token_in = market_value.tokens[0];
+            }
+
+            if input_amount.is_zero() {
+                break;
+            }
+        }
+
+        return apply_transfer_fee(self.base_token, input_amount);
+    }
+
     #[inline(always)]
     fn calculate_circ_liquidity(&self, reserve_table: &OrganizedList<Reserves>) -> Reserves {
         let first_reserve = &reserve_table[self.markets[0].id];
@@ -107,7 +241,8 @@ impl Route {
     fn calculate_circ_profit(
         &self,
         reserve_table: &OrganizedList<Reserves>,
-        price_table: &'static PriceTable,
+        price_table: &PriceTable,
+        gas_prices: &GasPrices,
         mut input_amount: U256,
         mut token_in: &'static Token,
     ) -> Option<RouteResult> {
@@ -119,28 +254,42 @@ impl Route {
             let market_value = market.value;
             let token_0 = market_value.tokens[0];
 
+            let amount_in = input_amount;
+
             if token_in == token_0 {
-                input_amount = market_value.amount_out(&reserves, &input_amount);
+                input_amount = market_value.amount_out(&reserves, &input_amount, true);
                 token_in = market_value.tokens[1];
 
                 swap_transactions.add_value(SwapLog {
                     market: &market,
+                    amount_in,
                     amount_0_out: ZERO_VALUE,
                     amount_1_out: input_amount,
                 });
             } else {
-                input_amount = market_value.amount_out(&reserves.reverse(), &input_amount);
+                input_amount = market_value.amount_out(&reserves.reverse(), &input_amount, false);
                 token_in = token_0;
 
                 swap_transactions.add_value(SwapLog {
                     market: &market,
+                    amount_in,
                     amount_0_out: input_amount,
                     amount_1_out: ZERO_VALUE,
                 });
             }
         }
 
-        if input_amount > _start_balance {
+        // the last market's output is taxed on its way back to the executor
+        let input_amount = apply_transfer_fee(self.base_token, input_amount);
+        let flash_liquidity = select_source(
+            self.base_token,
+            _start_balance,
+            &self.markets[0].value,
+            price_table,
+            gas_prices,
+        )?;
+
+        if input_amount > _start_balance + flash_liquidity.cost {
             //TODO: REMOVE THIS
             let route_reserves: Vec<(OrgValue<Reserves>, &'static OrgValue<Market>)> =
                 swap_transactions
@@ -149,13 +298,16 @@ impl Route {
                     .sorted_by_key(|x| x.0.id)
                     .collect();
 
-            let profit_loss = input_amount - _start_balance;
+            let profit_loss = input_amount - _start_balance - flash_liquidity.cost;
             return Some(RouteResult {
                 base_token: self.base_token,
                 start_balance: _start_balance,
                 end_balance: input_amount,
                 profit_loss,
                 ref_profit_loss: price_table.get_ref_price(self.base_token, profit_loss),
+                flash_liquidity,
+                cost: U256::zero(),
+                source: None,
                 transactions: swap_transactions,
                 route_reserves, //TODO: REMOVE THIS
             });
@@ -164,6 +316,18 @@ impl Route {
         }
     }
 
+    // the denylist can be edited while running, routes are generated once
+    #[inline(always)]
+    fn contains_denied_token(&self) -> bool {
+        return self.markets.iter().any(|market| {
+            market
+                .value
+                .tokens
+                .iter()
+                .any(|token| denylist::is_denied(&token.contract_address))
+        });
+    }
+
     #[inline(always)]
     fn contains_any_market(&self, market_ids: &Vec<usize>) -> bool {
         for local_market_id in &self.market_ids {
@@ -181,12 +345,14 @@ impl Route {
             markets.iter().map(|x| x.value.get_fee_data()).collect_vec();
 
         let market_ids: Vec<usize> = markets.iter().map(|x| x.id).collect_vec();
+        let constant_product = markets.iter().all(|x| x.value.is_constant_product());
 
         return Route {
             markets,
             base_token,
             market_fee_data,
             market_ids,
+            constant_product,
         };
     }
 
@@ -199,15 +365,24 @@ impl Route {
             let base_tokens: Vec<&'static Token> = network
                 .tokens
                 .iter()
-                .filter(|x| x.flash_loan_enabled)
+                .filter(|x| x.flash_loan_enabled && !denylist::is_denied(&x.contract_address))
                 .collect();
 
-            let len = cache.markets.len();
+            let markets: Vec<&'static OrgValue<Market>> = cache
+                .markets
+                .filter(|x| {
+                    !x.value
+                        .tokens
+                        .iter()
+                        .any(|token| denylist::is_denied(&token.contract_address))
+                });
+
+            let len = markets.len();
             return base_tokens
                 .par_iter()
                 .flat_map(|base_token| {
                     generate_from_token(
-                        cache.markets.to_vec(),
+                        markets.clone(),
                         &base_token,
                         &base_token,
                         config.route_restraints,
@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+
+use ethers::types::{H160, U256, U64};
+
+use crate::{
+    exchanges::{erc20::apply_transfer_fee, is_priced_on_reserves, types::Protocol},
+    flash_liquidity::{aave, get_entry_gas, FlashLiquidity, FlashLiquiditySource},
+    price_oracle::GasPrices,
+};
+
+use super::{
+    market::Market, route::BUNDLE_GAS, BundleGuards, BundleLoan, OrganizedList, PriceTable,
+    Reserves, Route, RouteResult,
+};
+
+// Routes executed by a single `BundleExecutor` transaction. Batches of more than one route are
+// funded by one multi-asset Aave flash loan, every base token borrowed once.
+pub struct RouteBatch {
+    pub route_results: Vec<RouteResult>,
+}
+
+// Pool a market trades on, the markets expanded from one multi-coin pool share it
+type PoolKey = (H160, Option<usize>);
+
+#[inline(always)]
+fn get_pool_key(market: &Market) -> PoolKey {
+    return (market.contract_address, market.pool.map(|x| x.id));
+}
+
+// Pool indices of the market's tokens
+#[inline(always)]
+fn get_pool_coins(market: &Market) -> [usize; 2] {
+    return market.pool.map_or([0, 1], |x| x.coins);
+}
+
+#[inline(always)]
+fn get_market_ids(route_result: &RouteResult) -> Vec<usize> {
+    return route_result
+        .transactions
+        .iter()
+        .map(|x| x.value.market.id)
+        .collect();
+}
+
+// Records the pool coins moved by the route's swaps
+#[inline(always)]
+fn add_moved_coins(route_result: &RouteResult, moved_coins: &mut HashMap<PoolKey, Vec<usize>>) {
+    for transaction in route_result.transactions.iter() {
+        let market = &transaction.value.market.value;
+        let coins = moved_coins.entry(get_pool_key(market)).or_default();
+
+        for coin in get_pool_coins(market) {
+            if !coins.contains(&coin) {
+                coins.push(coin);
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn shares_pools(route_result: &RouteResult, moved_coins: &HashMap<PoolKey, Vec<usize>>) -> bool {
+    return route_result
+        .transactions
+        .iter()
+        .any(|x| moved_coins.contains_key(&get_pool_key(&x.value.market.value)));
+}
+
+// Markets of pools moved by another route are re-simulated on the reserve table. Liquidity
+// Book bins aren't in it, and pools quoting on every coin's balance can only have had the
+// market's own coins moved.
+#[inline(always)]
+fn can_resimulate(route_result: &RouteResult, moved_coins: &HashMap<PoolKey, Vec<usize>>) -> bool {
+    return route_result.transactions.iter().all(|x| {
+        let market = &x.value.market.value;
+        let coins = match moved_coins.get(&get_pool_key(market)) {
+            Some(value) => value,
+            None => return true,
+        };
+
+        if market.protocol == Protocol::LiquidityBook {
+            return false;
+        }
+
+        return is_priced_on_reserves(market)
+            || coins
+                .iter()
+                .all(|coin| get_pool_coins(market).contains(coin));
+    });
+}
+
+// Moves the reserves of every market of the route by its swaps, along with those of the other
+// markets of the same pools
+#[inline(always)]
+fn apply_swaps(
+    route_result: &RouteResult,
+    markets: &OrganizedList<Market>,
+    reserve_table: &mut OrganizedList<Reserves>,
+) {
+    for transaction in route_result.transactions.iter() {
+        let swap = &transaction.value;
+        let market = &swap.market.value;
+        let amount_in = apply_transfer_fee(swap.token_in(), swap.amount_in);
+
+        let coins = get_pool_coins(market);
+        let (coin_in, coin_out) = if swap.zero_for_one() {
+            (coins[0], coins[1])
+        } else {
+            (coins[1], coins[0])
+        };
+
+        let pool_markets = match market.pool {
+            Some(_) => markets.filter(|x| get_pool_key(&x.value) == get_pool_key(market)),
+            None => vec![swap.market],
+        };
+
+        for pool_market in pool_markets {
+            let pool_coins = get_pool_coins(&pool_market.value);
+
+            reserve_table.update_value_at(pool_market.id, |x| {
+                let mut reserves = [x.value.0, x.value.1];
+
+                for i in 0..2 {
+                    if pool_coins[i] == coin_in {
+                        reserves[i] += amount_in;
+                    } else if pool_coins[i] == coin_out {
+                        reserves[i] = reserves[i].saturating_sub(swap.amount_out());
+                    }
+                }
+
+                x.value = (reserves[0], reserves[1]);
+            });
+        }
+    }
+}
+
+// Profit of the route when funded by the batch's Aave flash loan
+#[inline(always)]
+fn get_aave_profit_loss(route_result: &RouteResult) -> Option<U256> {
+    let cost = aave::get_fee(route_result.start_balance);
+    if route_result.end_balance <= route_result.start_balance + cost {
+        return None;
+    }
+
+    return Some(route_result.end_balance - route_result.start_balance - cost);
+}
+
+// Reprices the route as funded by the batch's Aave flash loan, `None` when it's no longer
+// profitable
+#[inline(always)]
+fn with_aave_liquidity(
+    mut route_result: RouteResult,
+    price_table: &PriceTable,
+) -> Option<RouteResult> {
+    let profit_loss = get_aave_profit_loss(&route_result)?;
+
+    route_result.flash_liquidity = FlashLiquidity {
+        source: FlashLiquiditySource::Aave,
+        cost: aave::get_fee(route_result.start_balance),
+    };
+    route_result.profit_loss = profit_loss;
+    route_result.ref_profit_loss = price_table.get_ref_price(route_result.base_token, profit_loss);
+
+    return Some(route_result);
+}
+
+impl RouteBatch {
+    /// Picks the most valuable set of routes that can run in one bundle, valued net of what
+    /// sending each of them costs. Routes are taken by value: a route using none of the pools
+    /// taken so far is kept as calculated, one sharing pools is calculated again on the
+    /// reserves left by the routes before it. The most valuable route alone is kept when
+    /// batching doesn't add value.
+    pub fn select(
+        mut route_results: Vec<RouteResult>,
+        markets: &OrganizedList<Market>,
+        reserve_table: &OrganizedList<Reserves>,
+        price_table: &PriceTable,
+        gas_prices: &GasPrices,
+    ) -> RouteBatch {
+        route_results.sort_by(|a, b| {
+            b.get_net_ref_profit_loss()
+                .cmp(&a.get_net_ref_profit_loss())
+        });
+
+        let mut route_results = route_results.into_iter();
+        let best_route_result = match route_results.next() {
+            Some(value) => value,
+            None => {
+                return RouteBatch {
+                    route_results: vec![],
+                }
+            }
+        };
+
+        let mut local_reserves = reserve_table.clone();
+        let mut moved_coins: HashMap<PoolKey, Vec<usize>> = HashMap::new();
+        add_moved_coins(&best_route_result, &mut moved_coins);
+        let mut borrowed_amounts: HashMap<H160, U256> = HashMap::new();
+        borrowed_amounts.insert(
+            best_route_result.base_token.contract_address,
+            best_route_result.start_balance,
+        );
+        apply_swaps(&best_route_result, markets, &mut local_reserves);
+
+        let mut batched: Vec<RouteResult> = vec![];
+        for route_result in route_results {
+            let route_result = if shares_pools(&route_result, &moved_coins) {
+                if !can_resimulate(&route_result, &moved_coins) {
+                    continue;
+                }
+
+                let markets = route_result
+                    .transactions
+                    .iter()
+                    .map(|x| x.value.market)
+                    .collect();
+                match Route::new(markets, route_result.base_token).calculate_result(
+                    &local_reserves,
+                    price_table,
+                    gas_prices,
+                    &get_market_ids(&route_result),
+                ) {
+                    Some(value) => RouteResult {
+                        cost: route_result.cost,
+                        source: route_result.source,
+                        ..value
+                    },
+                    None => continue,
+                }
+            } else {
+                route_result
+            };
+
+            let route_result = match with_aave_liquidity(route_result, price_table) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            // the loans of routes with the same base token add up
+            let token = route_result.base_token;
+            let borrowed_amount = borrowed_amounts
+                .get(&token.contract_address)
+                .map_or(route_result.start_balance, |x| {
+                    *x + route_result.start_balance
+                });
+
+            if aave::get_borrowable_amount(token).map_or(false, |x| x < borrowed_amount) {
+                continue;
+            }
+
+            borrowed_amounts.insert(token.contract_address, borrowed_amount);
+            add_moved_coins(&route_result, &mut moved_coins);
+            apply_swaps(&route_result, markets, &mut local_reserves);
+            batched.push(route_result);
+        }
+
+        // the best route pays the Aave premium once batched, its own source may be cheaper. Every
+        // batched route is valued as if it paid for its own transaction.
+        let best_ref_profit_loss = get_aave_profit_loss(&best_route_result).map(|x| {
+            price_table
+                .get_ref_price(best_route_result.base_token, x)
+                .saturating_sub(best_route_result.cost)
+        });
+        let batched_ref_profit_loss = batched
+            .iter()
+            .fold(best_ref_profit_loss.unwrap_or_default(), |sum, x| {
+                sum + x.get_net_ref_profit_loss()
+            });
+
+        if batched.is_empty()
+            || best_ref_profit_loss.is_none()
+            || batched_ref_profit_loss <= best_route_result.get_net_ref_profit_loss()
+        {
+            return RouteBatch {
+                route_results: vec![best_route_result],
+            };
+        }
+
+        if let Some(best_route_result) = with_aave_liquidity(best_route_result, price_table) {
+            batched.insert(0, best_route_result);
+        }
+
+        return RouteBatch {
+            route_results: batched,
+        };
+    }
+
+    #[inline(always)]
+    pub fn is_batched(&self) -> bool {
+        return self.route_results.len() > 1;
+    }
+
+    #[inline(always)]
+    pub fn get_ref_profit_loss(&self) -> U256 {
+        return self
+            .route_results
+            .iter()
+            .fold(U256::zero(), |sum, x| sum + x.ref_profit_loss);
+    }
+
+    // The routes share the executor overhead and the flash loan of one bundle
+    #[inline(always)]
+    pub fn get_gas_limit(&self) -> U256 {
+        let gas_limit = self
+            .route_results
+            .iter()
+            .fold(U256::zero(), |sum, x| sum + x.get_gas_limit());
+        let shared_gas = BUNDLE_GAS + get_entry_gas(FlashLiquiditySource::Aave);
+
+        return gas_limit.saturating_sub(U256::from(
+            shared_gas * self.route_results.len().saturating_sub(1) as u64,
+        ));
+    }
+
+    /// One loan per base token, borrowing the inputs of all its routes and expecting their
+    /// profits reduced by `slippage_tolerance` (in 1/10000)
+    pub fn get_loans(&self, slippage_tolerance: u32, block_number: U64) -> Vec<BundleLoan> {
+        let mut loans: Vec<BundleLoan> = vec![];
+
+        for route_result in &self.route_results {
+            let token = route_result.base_token.contract_address;
+            let guards =
+                BundleGuards::from_route_result(route_result, slippage_tolerance, block_number);
+
+            match loans.iter_mut().find(|x| x.token == token) {
+                Some(loan) => {
+                    loan.amount += route_result.start_balance;
+                    loan.min_profit += guards.min_profit;
+                }
+                None => loans.push(BundleLoan {
+                    token,
+                    amount: route_result.start_balance,
+                    min_profit: guards.min_profit,
+                }),
+            }
+        }
+
+        return loans;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        flash_liquidity::aave::{set_reserve, FlashLoanReserve},
+        types::{OrgValue, SwapLog, Token},
+    };
+
+    use super::*;
+
+    lazy_static! {
+        // W, V, X, Y and Z tokens, every market takes its first token as the input of a route.
+        // X is taxed, routes through it are sized by the search instead of the closed form.
+        static ref TOKENS: Vec<&'static Token> = (0..5)
+            .map(|x| get_token(0x6000 + x, if x == X as u64 { Some(1) } else { None }))
+            .collect();
+        static ref MARKETS: OrganizedList<Market> = get_markets();
+    }
+
+    const W: usize = 0;
+    const V: usize = 1;
+    const X: usize = 2;
+    const Y: usize = 3;
+    const Z: usize = 4;
+
+    fn get_token(address: u64, transfer_fee: Option<u32>) -> &'static Token {
+        return Box::leak(Box::new(Token {
+            contract_address: H160::from_low_u64_be(address),
+            flash_loan_enabled: true,
+            decimals: 18,
+            ref_symbol: None,
+            transfer_fee,
+            rebasing: false,
+        }));
+    }
+
+    fn get_markets() -> OrganizedList<Market> {
+        let mut result = OrganizedList::new();
+        for (i, (token_0, token_1, protocol)) in [
+            (W, X, Protocol::UniswapV2),
+            (X, W, Protocol::UniswapV2),
+            (W, Y, Protocol::UniswapV2),
+            (Y, W, Protocol::UniswapV2),
+            (X, W, Protocol::UniswapV2),
+            (W, Z, Protocol::LiquidityBook),
+            (Z, W, Protocol::UniswapV2),
+            (Z, W, Protocol::UniswapV2),
+            (V, X, Protocol::UniswapV2),
+            (X, V, Protocol::UniswapV2),
+            (V, Y, Protocol::UniswapV2),
+            (Y, V, Protocol::UniswapV2),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            result.add_value(Market::new(
+                H160::from_low_u64_be(0x6100 + i as u64),
+                [TOKENS[token_0], TOKENS[token_1]],
+                30,
+                false,
+                protocol,
+            ));
+        }
+
+        return result;
+    }
+
+    fn get_units(value: u64) -> U256 {
+        return U256::exp10(18) * value;
+    }
+
+    // Pairs 0 and 1 price X at 0.5 and 0.6 W, pair 4 at 0.59 W
+    fn get_reserve_table() -> OrganizedList<Reserves> {
+        let mut result = OrganizedList::new();
+        for market in MARKETS.iter() {
+            result.add_value(match market.id {
+                1 => (get_units(2000), get_units(1200)),
+                4 => (get_units(2000), get_units(1180)),
+                _ => (get_units(1000), get_units(2000)),
+            });
+        }
+
+        return result;
+    }
+
+    fn get_price_table() -> PriceTable {
+        let mut result = PriceTable::new();
+        result.update_value(TOKENS[W], get_units(1));
+        result.update_value(TOKENS[V], get_units(1));
+
+        return result;
+    }
+
+    // A route swapping `start_balance` through the markets into `end_balance`, funded by a flash
+    // swap and valued in the base token
+    fn get_route_result(market_ids: &[usize], start_balance: u64, end_balance: u64) -> RouteResult {
+        let (start_balance, end_balance) = (get_units(start_balance), get_units(end_balance));
+        let mut transactions: OrganizedList<SwapLog> = OrganizedList::new();
+
+        for (i, id) in market_ids.iter().enumerate() {
+            transactions.add_value(SwapLog {
+                market: &MARKETS[*id],
+                amount_in: if i == 0 { start_balance } else { end_balance },
+                amount_0_out: U256::zero(),
+                amount_1_out: end_balance,
+            });
+        }
+
+        return RouteResult {
+            base_token: MARKETS[market_ids[0]].value.tokens[0],
+            start_balance,
+            end_balance,
+            profit_loss: end_balance - start_balance,
+            ref_profit_loss: end_balance - start_balance,
+            flash_liquidity: FlashLiquidity {
+                source: FlashLiquiditySource::FlashSwap,
+                cost: U256::zero(),
+            },
+            cost: U256::zero(),
+            source: None,
+            transactions,
+            route_reserves: vec![],
+        };
+    }
+
+    fn calculate_route_result(
+        market_ids: &[usize],
+        reserve_table: &OrganizedList<Reserves>,
+    ) -> Option<RouteResult> {
+        let markets: Vec<&'static OrgValue<Market>> =
+            market_ids.iter().map(|x| &MARKETS[*x]).collect();
+
+        return Route::new(markets, TOKENS[W]).calculate_result(
+            reserve_table,
+            &get_price_table(),
+            &GasPrices::default(),
+            &market_ids.to_vec(),
+        );
+    }
+
+    fn select(route_results: Vec<RouteResult>) -> RouteBatch {
+        return RouteBatch::select(
+            route_results,
+            &MARKETS,
+            &get_reserve_table(),
+            &get_price_table(),
+            &GasPrices::default(),
+        );
+    }
+
+    fn get_summary(route_batch: &RouteBatch) -> Vec<(Vec<usize>, U256, FlashLiquiditySource)> {
+        return route_batch
+            .route_results
+            .iter()
+            .map(|x| (get_market_ids(x), x.profit_loss, x.flash_liquidity.source))
+            .collect();
+    }
+
+    #[test]
+    fn disjoint_routes_are_batched_as_calculated() {
+        let route_batch = select(vec![
+            get_route_result(&[2, 3], 10, 12),
+            get_route_result(&[0, 1], 10, 13),
+        ]);
+
+        assert!(route_batch.is_batched());
+        assert_eq!(
+            get_summary(&route_batch),
+            vec![
+                (vec![0, 1], get_units(3), FlashLiquiditySource::Aave),
+                (vec![2, 3], get_units(2), FlashLiquiditySource::Aave),
+            ]
+        );
+        assert_eq!(route_batch.get_ref_profit_loss(), get_units(5));
+    }
+
+    #[test]
+    fn routes_sharing_pools_are_calculated_again() {
+        let reserve_table = get_reserve_table();
+        let best_route_result = calculate_route_result(&[0, 1], &reserve_table).unwrap();
+        let mut route_result = calculate_route_result(&[0, 4], &reserve_table).unwrap();
+        route_result.cost = U256::from(1000);
+
+        // the reserves left by the best route
+        let mut moved_table = reserve_table.clone();
+        for swap in best_route_result.transactions.iter() {
+            let amount_in = apply_transfer_fee(swap.value.token_in(), swap.value.amount_in);
+            moved_table.update_value_at(swap.value.market.id, |x| {
+                x.value = (x.value.0 + amount_in, x.value.1 - swap.value.amount_out());
+            });
+        }
+        let expected = calculate_route_result(&[0, 4], &moved_table).unwrap();
+        assert!(expected.profit_loss < route_result.profit_loss);
+
+        let best_profit_loss = best_route_result.profit_loss;
+        let route_batch = select(vec![route_result, best_route_result]);
+        assert_eq!(
+            get_summary(&route_batch),
+            vec![
+                (vec![0, 1], best_profit_loss, FlashLiquiditySource::Aave),
+                (vec![0, 4], expected.profit_loss, FlashLiquiditySource::Aave),
+            ]
+        );
+        assert_eq!(
+            route_batch.route_results[1].start_balance,
+            expected.start_balance
+        );
+        assert_eq!(route_batch.route_results[1].cost, U256::from(1000));
+    }
+
+    #[test]
+    fn liquidity_book_routes_sharing_pools_are_dropped() {
+        // the reserve table doesn't hold the bins the best route moved
+        let route_batch = select(vec![
+            get_route_result(&[5, 6], 10, 13),
+            get_route_result(&[5, 7], 10, 12),
+            get_route_result(&[2, 3], 10, 11),
+        ]);
+
+        assert_eq!(
+            get_summary(&route_batch),
+            vec![
+                (vec![5, 6], get_units(3), FlashLiquiditySource::Aave),
+                (vec![2, 3], get_units(1), FlashLiquiditySource::Aave),
+            ]
+        );
+    }
+
+    #[test]
+    fn loans_are_capped_by_the_aave_reserve() {
+        set_reserve(
+            TOKENS[V],
+            FlashLoanReserve {
+                available_liquidity: get_units(15),
+                active: true,
+                frozen: false,
+                paused: false,
+                flash_loan_enabled: true,
+            },
+        );
+
+        // the second V loan would take the total to 20
+        let route_batch = select(vec![
+            get_route_result(&[8, 9], 10, 13),
+            get_route_result(&[10, 11], 10, 12),
+            get_route_result(&[2, 3], 10, 11),
+        ]);
+
+        assert_eq!(
+            get_summary(&route_batch),
+            vec![
+                (vec![8, 9], get_units(3), FlashLiquiditySource::Aave),
+                (vec![2, 3], get_units(1), FlashLiquiditySource::Aave),
+            ]
+        );
+    }
+
+    #[test]
+    fn best_route_is_kept_alone_when_batching_adds_nothing() {
+        assert!(select(vec![]).route_results.is_empty());
+
+        // the second route doesn't pay for its own transaction
+        let mut route_result = get_route_result(&[2, 3], 10, 12);
+        route_result.cost = get_units(2);
+        let route_batch = select(vec![get_route_result(&[0, 1], 10, 13), route_result]);
+
+        assert!(!route_batch.is_batched());
+        assert_eq!(
+            get_summary(&route_batch),
+            vec![(vec![0, 1], get_units(3), FlashLiquiditySource::FlashSwap)]
+        );
+    }
+}
@@ -1,9 +1,33 @@
 use ethers::types::U256;
 
-use super::{market::Market, OrgValue};
+use super::{market::Market, OrgValue, Token};
 
 pub struct SwapLog {
     pub market: &'static OrgValue<Market>,
+    // amount sent to the market, before its transfer tax
+    pub amount_in: U256,
+    // amounts sent by the market, quoted on the taxed input
     pub amount_0_out: U256,
     pub amount_1_out: U256,
 }
+
+impl SwapLog {
+    #[inline(always)]
+    pub fn zero_for_one(&self) -> bool {
+        return self.amount_0_out.is_zero();
+    }
+
+    #[inline(always)]
+    pub fn token_in(&self) -> &'static Token {
+        if self.zero_for_one() {
+            return self.market.value.tokens[0];
+        }
+
+        return self.market.value.tokens[1];
+    }
+
+    #[inline(always)]
+    pub fn amount_out(&self) -> U256 {
+        return self.amount_0_out + self.amount_1_out;
+    }
+}
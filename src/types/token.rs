@@ -6,7 +6,13 @@ pub struct Token {
     pub contract_address: H160,
     pub flash_loan_enabled: bool,
     pub decimals: u32,
-    pub ref_symbol: Option<String>
+    pub ref_symbol: Option<String>,
+    // transfer tax in 1/10000, detected at startup when not declared
+    #[serde(default)]
+    pub transfer_fee: Option<u32>,
+    // balances change without transfers, markets holding it can't be quoted from reserves
+    #[serde(default)]
+    pub rebasing: bool,
 }
 
 
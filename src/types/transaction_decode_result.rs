@@ -2,5 +2,30 @@ use ethers::types::{H256, Transaction};
 
 pub struct TransactionDecodeResult {
     pub hash: H256,
+    // sequencer feed position of the message carrying the transaction
+    pub sequence_number: u32,
+    // position of the transaction within the message, batches carry several
+    pub index: u32,
+    // timestamp of the block the sequencer puts the message in
+    pub timestamp: u64,
     pub transaction: Transaction,
-}
\ No newline at end of file
+}
+
+// The pending transaction a balance change, and the opportunities it opens, comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionSource {
+    pub hash: H256,
+    pub sequence_number: u32,
+    pub index: u32,
+}
+
+impl TransactionDecodeResult {
+    #[inline(always)]
+    pub fn get_source(&self) -> TransactionSource {
+        return TransactionSource {
+            hash: self.hash,
+            sequence_number: self.sequence_number,
+            index: self.index,
+        };
+    }
+}
@@ -28,6 +28,15 @@ pub fn f32(input: String) -> f32 {
     return parse_result.unwrap();
 }
 
+pub fn bool(input: String) -> bool {
+    let parse_result: Result<bool, _> = input.parse();
+    if parse_result.is_err() {
+        panic!("not a valid bool: {}", input)
+    }
+
+    return parse_result.unwrap();
+}
+
 pub fn address(input: String) -> Address {
     let parse_result: Result<Address, _> = input.parse();
 